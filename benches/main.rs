@@ -7,7 +7,7 @@ use gcode::{Tokenizer, BasicParser, Parser, Result};
 // use gcode::type_check;
 
 
-const SRC: &'static str = include_str!("../tests/data/program_3.gcode");
+const SRC: &str = include_str!("../tests/data/program_3.gcode");
 
 #[bench]
 fn lex_program_3(b: &mut test::Bencher) {
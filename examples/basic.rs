@@ -4,7 +4,7 @@ use gcode::{Parser, Tokenizer};
 
 
 fn main() {
-    let src = include_str!("../tests/data/PI_octcat.gcode");
+    let src = include_str!("../tests/data/program_1.gcode");
 
     let lexer = Tokenizer::new(src.chars());
 
@@ -0,0 +1,537 @@
+//! Replacing runs of short straight moves with arcs.
+//!
+//! CAM packages and slicers often approximate curves with hundreds of tiny
+//! `G1` moves, which can starve a controller's motion planner. The
+//! `ArcFitter` looks for runs of straight feed moves which all lie within a
+//! tolerance of a circle and replaces them with a single `G2` or `G3`.
+//!
+//! Only moves in the `XY` plane (`G17`) with absolute coordinates (`G90`)
+//! which change nothing but `X` and `Y` are fitted. The feed rate of the
+//! first move is kept, and the arc extrudes as much as the moves it
+//! replaces.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::arc_fitting::ArcFitter;
+//! use gcode::writer::write_lines;
+//!
+//! // a quarter circle approximated by 8 segments, which stray up to 0.048
+//! // from the real circle
+//! let src = "G90 G0 X10 Y0\nG1 X9.8079 Y1.9509 F100\nX9.2388 Y3.8268\nX8.3147 Y5.5557
+//! X7.0711 Y7.0711\nX5.5557 Y8.3147\nX3.8268 Y9.2388\nX1.9509 Y9.8079\nX0 Y10\nG0 Z5";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let fitted = ArcFitter::new(0.05).fit(&lines);
+//!
+//! let mut written = String::new();
+//! write_lines(&fitted, &mut written).unwrap();
+//! assert_eq!(written, "G90 G0 X10 Y0\nG3 X0 Y10 I-9.9999 J0.0001 F100\nG0 Z5\n");
+//! ```
+
+use core::f32::consts::PI;
+use std::vec::Vec;
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use interpreter::DistanceMode;
+use helpers::{abs, sqrt, atan2, has_axis_words, is_motion, distance, Modal};
+
+
+/// Settings for fitting arcs to straight moves.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ArcFitter {
+    /// How far the arc may stray from the original moves.
+    pub tolerance: f32,
+    /// The fewest straight moves worth replacing with an arc.
+    pub min_segments: usize,
+    /// Moves which only curve very gently are left alone, rather than being
+    /// replaced by arcs larger than this.
+    pub max_radius: f32,
+    /// Write arcs using `R` instead of `I` and `J`.
+    pub use_radius: bool,
+}
+
+impl ArcFitter {
+    /// Create an `ArcFitter` with a particular tolerance.
+    pub fn new(tolerance: f32) -> ArcFitter {
+        ArcFitter {
+            tolerance,
+            min_segments: 3,
+            max_radius: 1000.0,
+            use_radius: false,
+        }
+    }
+
+    /// Fit arcs to a program.
+    ///
+    /// Line numbers on moves which are merged into an arc are dropped.
+    pub fn fit(&self, lines: &[Line]) -> Vec<Line> {
+        let mut fitting = Fitting {
+            fitter: self,
+            out: Vec::with_capacity(lines.len()),
+            state: ModalState::default(),
+            written_motion: None,
+            run: Vec::new(),
+        };
+
+        for &line in lines {
+            fitting.add(line);
+        }
+        fitting.flush();
+
+        fitting.out
+    }
+
+    /// Try to fit an arc through a set of points, within tolerance.
+    fn fit_arc(&self, points: &[(f32, f32)]) -> Option<Arc> {
+        let segments = points.len() - 1;
+        if segments < self.min_segments {
+            return None;
+        }
+
+        let start = points[0];
+        let end = points[segments];
+        let (centre, radius) = circumcircle(start, points[segments / 2], end)?;
+        if radius > self.max_radius {
+            return None;
+        }
+
+        let mut sweep = 0.0;
+        for pair in points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+
+            if abs(distance(b, centre) - radius) > self.tolerance {
+                return None;
+            }
+
+            // how far the middle of the segment is from the arc
+            let half_chord = distance(a, b) / 2.0;
+            if half_chord > radius || radius - sqrt(radius * radius - half_chord * half_chord) >
+                                      self.tolerance {
+                return None;
+            }
+
+            let (ax, ay) = (a.0 - centre.0, a.1 - centre.1);
+            let (bx, by) = (b.0 - centre.0, b.1 - centre.1);
+            let step = atan2(ax * by - ay * bx, ax * bx + ay * by);
+
+            // every segment needs to turn the same way
+            if step == 0.0 || (sweep != 0.0 && (step > 0.0) != (sweep > 0.0)) {
+                return None;
+            }
+            sweep += step;
+        }
+
+        if abs(sweep) >= 2.0 * PI - 1e-3 {
+            return None;
+        }
+
+        Some(Arc {
+                 centre,
+                 radius,
+                 sweep,
+             })
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Arc {
+    centre: (f32, f32),
+    radius: f32,
+    /// The angle swept out, positive for counter-clockwise.
+    sweep: f32,
+}
+
+
+/// The modal state which decides whether a move can be fitted.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct ModalState {
+    modal: Modal,
+    arc_distance_mode: DistanceMode,
+    xy_plane: bool,
+    relative_extrusion: bool,
+    feed_rate: Option<f32>,
+}
+
+impl Default for ModalState {
+    fn default() -> ModalState {
+        ModalState {
+            modal: Modal::default(),
+            arc_distance_mode: DistanceMode::Incremental,
+            xy_plane: true,
+            relative_extrusion: false,
+            feed_rate: None,
+        }
+    }
+}
+
+impl ModalState {
+    fn update(&mut self, line: &Line) {
+        self.modal.update(line);
+
+        let args = match *line {
+            Line::ProgramNumber(_) => return,
+            Line::Args { ref args, .. } => args,
+            Line::Cmd(ref cmd) => {
+                match (cmd.kind, cmd.number) {
+                    (CommandKind::G, Number::Integer(17)) => self.xy_plane = true,
+                    (CommandKind::G, Number::Integer(18)) |
+                    (CommandKind::G, Number::Integer(19)) => self.xy_plane = false,
+                    (CommandKind::G, Number::Decimal(90, 1)) => {
+                        self.arc_distance_mode = DistanceMode::Absolute
+                    }
+                    (CommandKind::G, Number::Decimal(91, 1)) => {
+                        self.arc_distance_mode = DistanceMode::Incremental
+                    }
+                    (CommandKind::M, Number::Integer(82)) => self.relative_extrusion = false,
+                    (CommandKind::M, Number::Integer(83)) => self.relative_extrusion = true,
+                    _ => {}
+                }
+                &cmd.args
+            }
+        };
+
+        if args.f.is_some() {
+            self.feed_rate = args.f;
+        }
+    }
+
+    /// Where the tool is in the `XY` plane, if known.
+    fn position(&self) -> Option<(f32, f32)> {
+        match self.modal.position {
+            [Some(x), Some(y), _] => Some((x, y)),
+            _ => None,
+        }
+    }
+}
+
+
+/// A straight move which might become part of an arc.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Segment {
+    line: Line,
+    end: (f32, f32),
+    e: Option<f32>,
+}
+
+
+#[derive(Debug)]
+struct Fitting<'a> {
+    fitter: &'a ArcFitter,
+    out: Vec<Line>,
+    state: ModalState,
+    /// The motion command the output is currently in.
+    written_motion: Option<Number>,
+    run: Vec<Segment>,
+}
+
+impl<'a> Fitting<'a> {
+    fn add(&mut self, line: Line) {
+        let start = self.state.position();
+        let mut state = self.state;
+        state.update(&line);
+
+        let segment = match (start, segment(&state, &line)) {
+            (Some(start), Some(segment)) => Some((start, segment)),
+            _ => None,
+        };
+
+        match segment {
+            Some((start, segment)) => {
+                if !self.continues_run(&segment, state.feed_rate) {
+                    self.flush();
+                }
+                self.state = state;
+
+                if self.run.is_empty() {
+                    self.run.push(Segment {
+                                      end: start,
+                                      e: None,
+                                      ..segment
+                                  });
+                }
+                self.run.push(segment);
+            }
+            None => {
+                self.flush();
+                self.state = state;
+                self.write(line);
+            }
+        }
+    }
+
+    /// Can a segment be added to the current run?
+    fn continues_run(&self, segment: &Segment, feed_rate: Option<f32>) -> bool {
+        if self.run.len() < 2 {
+            return true;
+        }
+
+        // the feed rate can't change, and extrusion can't be switched on or
+        // off part way through
+        feed_rate == self.state.feed_rate && segment.e.is_some() == self.run[1].e.is_some()
+    }
+
+    /// Fit arcs to the current run of straight moves and write it out.
+    fn flush(&mut self) {
+        let run = ::core::mem::take(&mut self.run);
+        if run.is_empty() {
+            return;
+        }
+
+        let points: Vec<_> = run.iter().map(|s| s.end).collect();
+        let mut i = 0;
+
+        while i + 1 < points.len() {
+            let mut best = None;
+            for j in i + self.fitter.min_segments..points.len() {
+                match self.fitter.fit_arc(&points[i..j + 1]) {
+                    Some(arc) => best = Some((j, arc)),
+                    None => break,
+                }
+            }
+
+            match best {
+                Some((j, arc)) => {
+                    let arc_line = self.arc_line(&run[i..j + 1], arc);
+                    self.write(arc_line);
+                    i = j;
+                }
+                None => {
+                    self.write(run[i + 1].line);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Build the arc which replaces a run of segments (the first of which
+    /// is only used as the start point).
+    fn arc_line(&self, run: &[Segment], arc: Arc) -> Line {
+        let start = run[0].end;
+        let end = run[run.len() - 1].end;
+        let first = &run[1];
+        let (line_number, span, f) = match first.line {
+            Line::Cmd(ref cmd) => (cmd.line_number, cmd.span, cmd.args.f),
+            Line::Args { line_number, span, ref args } => (line_number, span, args.f),
+            Line::ProgramNumber(_) => unreachable!(),
+        };
+
+        let e = if self.state.relative_extrusion {
+            run[1..].iter().fold(None, |total, s| match (total, s.e) {
+                (Some(total), Some(e)) => Some(total + e),
+                (None, e) => e,
+                (total, None) => total,
+            })
+        } else {
+            run[run.len() - 1].e
+        };
+
+        let mut args = Args {
+            x: Some(end.0),
+            y: Some(end.1),
+            e,
+            f,
+            ..Default::default()
+        };
+
+        if self.fitter.use_radius {
+            // arcs over half a circle use a negative radius
+            args.r = Some(if abs(arc.sweep) > PI { -arc.radius } else { arc.radius });
+        } else {
+            let (i, j) = match self.state.arc_distance_mode {
+                DistanceMode::Incremental => (arc.centre.0 - start.0, arc.centre.1 - start.1),
+                DistanceMode::Absolute => arc.centre,
+            };
+            args.i = Some(i);
+            args.j = Some(j);
+        }
+
+        let number = if arc.sweep < 0.0 { 2 } else { 3 };
+        Line::Cmd(Command {
+                      kind: CommandKind::G,
+                      number: Number::Integer(number),
+                      args,
+                      line_number,
+                      span,
+                  })
+    }
+
+    /// Write a line, making sure it uses the right motion command.
+    fn write(&mut self, line: Line) {
+        let line = match line {
+            Line::Args { args, line_number, span } if self.written_motion !=
+                                                      self.state.modal.motion &&
+                                                      has_axis_words(&args) => {
+                match self.state.modal.motion {
+                    Some(number) => {
+                        Line::Cmd(Command {
+                                      kind: CommandKind::G,
+                                      number,
+                                      args,
+                                      line_number,
+                                      span,
+                                  })
+                    }
+                    None => line,
+                }
+            }
+            other => other,
+        };
+
+        if let Line::Cmd(ref cmd) = line {
+            if cmd.kind == CommandKind::G && is_motion(cmd.number) {
+                self.written_motion = Some(cmd.number);
+            }
+        }
+
+        self.out.push(line);
+    }
+}
+
+
+/// Check whether a line is a straight move which could be fitted, given the
+/// state after it.
+fn segment(state: &ModalState, line: &Line) -> Option<Segment> {
+    if state.modal.motion != Some(Number::Integer(1)) ||
+       state.modal.distance_mode != DistanceMode::Absolute || !state.xy_plane {
+        return None;
+    }
+
+    let args = match *line {
+        Line::Cmd(ref cmd) if cmd.kind == CommandKind::G && cmd.number == Number::Integer(1) => {
+            &cmd.args
+        }
+        Line::Args { ref args, .. } => args,
+        _ => return None,
+    };
+
+    let only_xy = Args {
+        x: args.x,
+        y: args.y,
+        e: args.e,
+        f: args.f,
+        ..Default::default()
+    };
+    if *args != only_xy || (args.x.is_none() && args.y.is_none()) {
+        return None;
+    }
+
+    Some(Segment {
+             line: *line,
+             end: state.position()?,
+             e: args.e,
+         })
+}
+
+/// The circle passing through three points.
+fn circumcircle(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> Option<((f32, f32), f32)> {
+    let (bx, by) = (b.0 - a.0, b.1 - a.1);
+    let (cx, cy) = (c.0 - a.0, c.1 - a.1);
+    let d = 2.0 * (bx * cy - by * cx);
+
+    if abs(d) < 1e-9 {
+        return None;
+    }
+
+    let b_squared = bx * bx + by * by;
+    let c_squared = cx * cx + cy * cy;
+    let ux = (cy * b_squared - by * c_squared) / d;
+    let uy = (bx * c_squared - cx * b_squared) / d;
+
+    Some(((a.0 + ux, a.1 + uy), sqrt(ux * ux + uy * uy)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use writer::write_lines;
+    use helpers::{parse, sin, cos};
+
+    fn fit(fitter: &ArcFitter, src: &str) -> String {
+        let lines = parse(src);
+
+        let mut written = String::new();
+        write_lines(&fitter.fit(&lines), &mut written).unwrap();
+        written
+    }
+
+    /// Approximate an arc around the origin with straight moves.
+    fn polyline(radius: f32, from: f32, to: f32, segments: usize, e: Option<f32>) -> String {
+        let mut src = String::new();
+
+        for i in 1..segments + 1 {
+            let angle = (from + (to - from) * i as f32 / segments as f32) * PI / 180.0;
+            src.push_str(&format!("X{:.4} Y{:.4}", radius * cos(angle), radius * sin(angle)));
+            if let Some(e) = e {
+                src.push_str(&format!(" E{}", e));
+            }
+            src.push('\n');
+        }
+
+        src
+    }
+
+    #[test]
+    fn full_circles_need_more_than_one_move() {
+        let src = format!("G0 X20 Y0\nG1 F500\n{}G0 Z5", polyline(20.0, 0.0, -360.0, 64, None));
+        let got = fit(&ArcFitter::new(0.05), &src);
+
+        // an arc can't finish where it started, so the last segment is kept
+        let should_be = "G0 X20 Y0\nG1 F500\nG2 X19.9037 Y1.9603 I-20 J0\nG1 X20 Y0\nG0 Z5\n";
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn corners_are_left_alone() {
+        let src = "G0 X0 Y0\nG1 X10 F100\nY10\nX0\nY0\nX10";
+
+        assert_eq!(fit(&ArcFitter::new(0.1), src), "G0 X0 Y0\nG1 X10 F100\nY10\nX0\nY0\nX10\n");
+    }
+
+    #[test]
+    fn straight_moves_after_an_arc_switch_back_to_g1() {
+        let src = format!("G0 X10 Y0\nG1 F100\n{}X-10 Y10\nZ1", polyline(10.0, 0.0, 90.0, 8, None));
+        let got = fit(&ArcFitter::new(0.05), &src);
+
+        let should_be = "G0 X10 Y0\nG1 F100\nG3 X0 Y10 I-9.9999 J0.0001\nG1 X-10 Y10\nZ1\n";
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn extrusion_is_kept() {
+        let moves = polyline(10.0, 0.0, 90.0, 8, Some(0.5));
+        let src = format!("M83\nG0 X10 Y0\nG1 F100\n{}", moves);
+        let got = fit(&ArcFitter::new(0.05), &src);
+        assert_eq!(got, "M83\nG0 X10 Y0\nG1 F100\nG3 X0 Y10 I-9.9999 J0.0001 E4\n");
+
+        // with absolute extrusion the last E is used
+        let src = format!("M82\nG0 X10 Y0\nG1 X10 Y0 E1 F100\n{}", moves);
+        let got = fit(&ArcFitter::new(0.05), &src);
+        assert_eq!(got,
+                   "M82\nG0 X10 Y0\nG1 X10 Y0 E1 F100\nG3 X0 Y10 I-9.9999 J0.0001 E0.5\n");
+    }
+
+    #[test]
+    fn arcs_with_a_radius() {
+        let fitter = ArcFitter {
+            use_radius: true,
+            ..ArcFitter::new(0.05)
+        };
+        let src = format!("G0 X10 Y0\nG1 F100\n{}", polyline(10.0, 0.0, 270.0, 24, None));
+
+        assert_eq!(fit(&fitter, &src), "G0 X10 Y0\nG1 F100\nG3 X0 Y-10 R-10\n");
+    }
+
+    #[test]
+    fn incremental_moves_arent_fitted() {
+        let src = format!("G91 G0 X10 Y0\nG1 F100\n{}", polyline(10.0, 0.0, 90.0, 8, None));
+
+        let got = fit(&ArcFitter::new(0.05), &src);
+        assert!(!got.contains("G3"));
+    }
+}
@@ -0,0 +1,610 @@
+//! A command-line tool for checking, formatting and inspecting gcode
+//! programs.
+//!
+//! Run `gcode help` for usage. Every command reads the files it is given, or
+//! stdin if there are none, and exits with a non-zero status if there were
+//! any problems.
+
+extern crate gcode;
+extern crate serde_json;
+
+use std::cell::Cell;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use gcode::{Tokenizer, Parser, Error, Span};
+use gcode::parser::Line;
+use gcode::dialect::Dialect;
+use gcode::grbl::{Frontend, Block};
+use gcode::interpreter::{Interpreter, Event, Plane, Point, Profile, State};
+use gcode::stats::{Stats, Extent, BoundingBox};
+use gcode::lint::{Linter, Rule};
+use gcode::pretty::{self, Style};
+use gcode::json::Program;
+use gcode::svg::{Renderer, View, Colouring};
+use gcode::transform::{Transform, Transformer, Axis};
+use gcode::writer::write_lines;
+
+
+const USAGE: &str = "\
+Usage: gcode <COMMAND> [OPTIONS] [FILE...]
+
+Reads each FILE, or stdin if none are given (or FILE is \"-\").
+
+Options for every command:
+    --dialect <NAME>         rs274ngc (default), grbl or marlin
+
+Commands:
+    check          Parse and interpret programs, reporting any errors
+    fmt            Format programs in a consistent style
+        --check              Fail if any program isn't already formatted
+        --write              Format files in place
+        --leading-zeros      Write G01 instead of G1
+        --trailing-zeros     Always write every decimal place
+        --decimals <N>       The number of decimal places (default 4)
+        --no-align-comments  Don't line up trailing comments
+    stats          Print statistics about programs
+    lint           Look for likely mistakes
+        --enable <RULE>      Turn a rule on
+        --disable <RULE>     Turn a rule off
+        --list-rules         Print every rule and exit
+    tokens         Print the tokens in programs
+    ast            Print the parsed lines in programs
+        --json               Print JSON (see the gcode::json docs)
+    transform      Move programs around, applying each option in order
+        --translate <X,Y,Z>  Move by an offset (Z is optional)
+        --rotate <DEGREES>   Rotate anticlockwise about the origin, in XY
+        --scale <FACTOR>     Scale about the origin
+        --mirror <AXIS>      Mirror along x, y or z, about zero
+    render-svg     Render a program's toolpaths as an SVG image
+        --view <VIEW>        xy (default), xz, yz or isometric
+        --colour-by <WHAT>   tool (default) or feed
+        --annotate           Label each block with its line number
+    help           Print this message
+";
+
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let status = match run(&args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(Failure::Usage(msg)) => {
+            eprintln!("error: {}\n", msg);
+            eprint!("{}", USAGE);
+            2
+        }
+        Err(Failure::Fatal(msg)) => {
+            eprintln!("error: {}", msg);
+            1
+        }
+    };
+
+    process::exit(status);
+}
+
+/// Something which stops a command from running at all.
+#[derive(Debug)]
+enum Failure {
+    Usage(String),
+    Fatal(String),
+}
+
+/// Run a command, returning whether every input was free of problems.
+fn run(args: &[String]) -> Result<bool, Failure> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err(Failure::Usage(String::from("no command given"))),
+    };
+
+    match command {
+        "check" => check(&Options::parse(rest, &[], &[])?),
+        "fmt" => {
+            let flags = ["check", "write", "leading-zeros", "trailing-zeros", "no-align-comments"];
+            fmt(&Options::parse(rest, &["decimals"], &flags)?)
+        }
+        "stats" => stats(&Options::parse(rest, &[], &[])?),
+        "lint" => {
+            lint(&Options::parse(rest, &["enable", "disable"], &["list-rules"])?)
+        }
+        "tokens" => tokens(&Options::parse(rest, &[], &[])?),
+        "ast" => ast(&Options::parse(rest, &[], &["json"])?),
+        "transform" => {
+            transform(&Options::parse(rest, &["translate", "rotate", "scale", "mirror"], &[])?)
+        }
+        "render-svg" => {
+            render_svg(&Options::parse(rest, &["view", "colour-by"], &["annotate"])?)
+        }
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+            Ok(true)
+        }
+        other => Err(Failure::Usage(format!("unknown command \"{}\"", other))),
+    }
+}
+
+
+/// The options and files passed to a command. Every command takes
+/// `--dialect`.
+#[derive(Debug, Default)]
+struct Options {
+    flags: Vec<String>,
+    /// Options with values, in the order they were given.
+    values: Vec<(String, String)>,
+    files: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String], with_values: &[&str], flags: &[&str]) -> Result<Options, Failure> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                options.files.push(arg.clone());
+                continue;
+            }
+
+            let name = &arg[2..];
+            if flags.contains(&name) {
+                options.flags.push(name.to_string());
+            } else if with_values.contains(&name) || name == "dialect" {
+                match args.next() {
+                    Some(value) => options.values.push((name.to_string(), value.clone())),
+                    None => return Err(Failure::Usage(format!("{} needs a value", arg))),
+                }
+            } else {
+                return Err(Failure::Usage(format!("unknown option \"{}\"", arg)));
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.iter().rev().find(|v| v.0 == name).map(|v| v.1.as_str())
+    }
+
+    fn dialect(&self) -> Result<Dialect, Failure> {
+        match self.value("dialect") {
+            None | Some("rs274ngc") | Some("linuxcnc") => Ok(Dialect::Rs274Ngc),
+            Some("grbl") => Ok(Dialect::Grbl),
+            Some("marlin") => Ok(Dialect::Marlin),
+            Some(other) => Err(Failure::Usage(format!("unknown dialect \"{}\"", other))),
+        }
+    }
+
+    /// The kind of machine programs in the chosen dialect run on.
+    fn profile(&self) -> Result<Profile, Failure> {
+        match self.dialect()? {
+            Dialect::Marlin => Ok(Profile::Printer),
+            _ => Ok(Profile::Mill),
+        }
+    }
+
+    fn inputs(&self) -> Result<Vec<Input>, Failure> {
+        if self.files.is_empty() {
+            return Ok(vec![Input::read("-")?]);
+        }
+
+        self.files.iter().map(|path| Input::read(path)).collect()
+    }
+}
+
+/// A program and where it came from.
+#[derive(Debug)]
+struct Input {
+    name: String,
+    path: Option<String>,
+    src: String,
+}
+
+impl Input {
+    fn read(path: &str) -> Result<Input, Failure> {
+        if path == "-" {
+            let mut src = String::new();
+            io::stdin()
+                .read_to_string(&mut src)
+                .map_err(|e| Failure::Fatal(format!("unable to read stdin: {}", e)))?;
+
+            return Ok(Input {
+                          name: String::from("<stdin>"),
+                          path: None,
+                          src,
+                      });
+        }
+
+        let src = fs::read_to_string(path)
+            .map_err(|e| Failure::Fatal(format!("unable to read {}: {}", path, e)))?;
+
+        Ok(Input {
+               name: path.to_string(),
+               path: Some(path.to_string()),
+               src,
+           })
+    }
+
+    /// Where something is, as `name:line:column` with one-based lines.
+    fn location(&self, span: Span) -> String {
+        format!("{}:{}:{}", self.name, span.line + 1, span.column)
+    }
+
+    fn describe(&self, error: Error) -> String {
+        match error {
+            Error::UnknownToken(c, span) => {
+                format!("{}: unknown character {:?}", self.location(span), c)
+            }
+            Error::SyntaxError(msg, span) => format!("{}: {}", self.location(span), msg),
+            Error::UnexpectedEOF => format!("{}: unexpected end of input", self.name),
+            Error::InvalidCommand(msg) => format!("{}: {}", self.name, msg),
+        }
+    }
+
+    /// Describe an error found while working on the line at `current`.
+    fn describe_at(&self, error: Error, current: Option<Span>) -> String {
+        match (error, current) {
+            (Error::InvalidCommand(msg), Some(span)) => {
+                format!("{}: {}", self.location(span), msg)
+            }
+            (error, _) => self.describe(error),
+        }
+    }
+
+    fn parse_as(&self, dialect: Dialect) -> Result<Vec<Line>, String> {
+        if dialect == Dialect::Grbl {
+            return self.parse_grbl();
+        }
+
+        let tokens = Tokenizer::with_string_codes(self.src.chars(), dialect.string_codes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| self.describe(e))?;
+
+        Parser::with_dialect(tokens.into_iter(), dialect)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| self.describe(e))
+    }
+
+    /// Parse a Grbl program, checking any system and realtime commands but
+    /// only keeping the normal lines.
+    fn parse_grbl(&self) -> Result<Vec<Line>, String> {
+        let mut lines = Vec::new();
+
+        for block in Frontend::new(&self.src) {
+            if let Block::Line(line) = block.map_err(|e| self.describe(e))? {
+                lines.push(line);
+            }
+        }
+
+        Ok(lines)
+    }
+}
+
+
+/// Hand out a program's lines, remembering where the last one came from.
+/// Errors found while interpreting or transforming a line don't say where
+/// they are, so this is the only way to point at them.
+fn track<'a>(lines: Vec<Line>,
+             current: &'a Cell<Option<Span>>)
+             -> impl Iterator<Item = Line> + 'a {
+    lines.into_iter().inspect(move |line| current.set(line.span()))
+}
+
+/// Run a command on each input, reporting errors and carrying on with the
+/// next one.
+fn for_each_input<F>(options: &Options, mut command: F) -> Result<bool, Failure>
+    where F: FnMut(&Input) -> Result<(), String>
+{
+    let mut ok = true;
+
+    for input in options.inputs()? {
+        if let Err(msg) = command(&input) {
+            eprintln!("{}", msg);
+            ok = false;
+        }
+    }
+
+    Ok(ok)
+}
+
+fn check(options: &Options) -> Result<bool, Failure> {
+    let dialect = options.dialect()?;
+    let profile = options.profile()?;
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+        let current = Cell::new(None);
+
+        for event in Interpreter::with_profile(track(lines, &current), profile) {
+            event.map_err(|e| input.describe_at(e, current.get()))?;
+        }
+
+        Ok(())
+    })
+}
+
+fn fmt(options: &Options) -> Result<bool, Failure> {
+    let mut style = Style {
+        leading_zeros: options.flag("leading-zeros"),
+        trailing_zeros: options.flag("trailing-zeros"),
+        align_comments: !options.flag("no-align-comments"),
+        dialect: options.dialect()?,
+        ..Style::default()
+    };
+    if let Some(decimals) = options.value("decimals") {
+        style.decimal_places = parse_number(decimals)?;
+    }
+
+    for_each_input(options, |input| {
+        let formatted = pretty::format(&input.src, &style).map_err(|e| input.describe(e))?;
+
+        if options.flag("check") {
+            if formatted != input.src {
+                return Err(format!("{} isn't formatted", input.name));
+            }
+        } else if options.flag("write") {
+            let path = match input.path {
+                Some(ref path) => path,
+                None => return Err(String::from("stdin can't be formatted in place")),
+            };
+            if formatted != input.src {
+                fs::write(path, formatted)
+                    .map_err(|e| format!("unable to write {}: {}", path, e))?;
+            }
+        } else {
+            print!("{}", formatted);
+        }
+
+        Ok(())
+    })
+}
+
+fn stats(options: &Options) -> Result<bool, Failure> {
+    let many = options.files.len() > 1;
+    let dialect = options.dialect()?;
+    let profile = options.profile()?;
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+        let current = Cell::new(None);
+        let stats = Stats::with_state(track(lines, &current), State::new(profile))
+            .map_err(|e| input.describe_at(e, current.get()))?;
+
+        if many {
+            println!("{}:", input.name);
+        }
+        print_stats(&stats);
+        if many {
+            println!();
+        }
+
+        Ok(())
+    })
+}
+
+fn print_stats(stats: &Stats) {
+    fn bounds(b: &Option<BoundingBox>) -> String {
+        match *b {
+            Some(ref b) => format!("{} to {}", point(b.min), point(b.max)),
+            None => String::from("-"),
+        }
+    }
+    fn point(p: Point) -> String {
+        format!("({}, {}, {})", p.x, p.y, p.z)
+    }
+    fn extent(e: &Option<Extent>) -> String {
+        match *e {
+            Some(ref e) => format!("{} to {}", e.min, e.max),
+            None => String::from("-"),
+        }
+    }
+
+    println!("Cutting distance: {} mm", stats.cutting_distance);
+    println!("Rapid distance:   {} mm", stats.rapid_distance);
+    println!("Feed bounds:      {}", bounds(&stats.feed_bounds));
+    println!("Rapid bounds:     {}", bounds(&stats.rapid_bounds));
+    println!("Feed rates:       {}", extent(&stats.feed_rate));
+    println!("Spindle speeds:   {}", extent(&stats.spindle_speed));
+    println!("Z range:          {}", extent(&stats.z_range));
+    println!("Tools:            {}", join(stats.tools.iter()));
+    println!("Tool changes:     {}", stats.tool_changes);
+    println!("Codes:            {}",
+             join(stats.codes.iter().map(|(code, count)| format!("{} x{}", code, count))));
+}
+
+fn join<I>(items: I) -> String
+    where I: Iterator,
+          I::Item: Display
+{
+    let items: Vec<String> = items.map(|item| item.to_string()).collect();
+
+    if items.is_empty() {
+        String::from("-")
+    } else {
+        items.join(", ")
+    }
+}
+
+fn lint(options: &Options) -> Result<bool, Failure> {
+    if options.flag("list-rules") {
+        for rule in Rule::ALL.iter() {
+            println!("{:<24}{}", rule.id(), rule.description());
+        }
+        return Ok(true);
+    }
+
+    let dialect = options.dialect()?;
+    let mut linter = Linter::new(dialect);
+
+    for (name, id) in &options.values {
+        if name == "dialect" {
+            continue;
+        }
+
+        let rule = Rule::from_id(id)
+            .ok_or_else(|| Failure::Usage(format!("unknown rule \"{}\"", id)))?;
+        if name == "enable" {
+            linter.enable(rule);
+        } else {
+            linter.disable(rule);
+        }
+    }
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+        let lints = linter.lint(&lines);
+
+        for lint in &lints {
+            println!("{}: {} [{}]", input.location(lint.span), lint.message, lint.rule);
+        }
+
+        if lints.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{}: {} problem(s) found", input.name, lints.len()))
+        }
+    })
+}
+
+fn tokens(options: &Options) -> Result<bool, Failure> {
+    let string_codes = options.dialect()?.string_codes();
+
+    for_each_input(options, |input| {
+        for token in Tokenizer::with_string_codes(input.src.chars(), string_codes) {
+            let token = token.map_err(|e| input.describe(e))?;
+            println!("{}: {:?}", input.location(token.span()), token.kind());
+        }
+
+        Ok(())
+    })
+}
+
+fn ast(options: &Options) -> Result<bool, Failure> {
+    let dialect = options.dialect()?;
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+
+        if options.flag("json") {
+            let json = serde_json::to_string_pretty(&Program::new(lines))
+                .map_err(|e| format!("{}: {}", input.name, e))?;
+            println!("{}", json);
+        } else {
+            for line in lines {
+                println!("{:?}", line);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+fn transform(options: &Options) -> Result<bool, Failure> {
+    let dialect = options.dialect()?;
+    let mut transform = Transform::identity();
+
+    for (name, value) in &options.values {
+        let next = match name.as_str() {
+            "dialect" => continue,
+            "translate" => {
+                let numbers = value.split(',')
+                    .map(|n| parse_number::<f32>(n.trim()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let offset = match numbers[..] {
+                    [x, y] => Point::new(x, y, 0.0),
+                    [x, y, z] => Point::new(x, y, z),
+                    _ => {
+                        return Err(Failure::Usage(format!("can't translate by \"{}\"", value)))
+                    }
+                };
+                Transform::translate(offset)
+            }
+            "rotate" => Transform::rotate(Plane::XY, parse_number(value)?, Point::default()),
+            "scale" => Transform::scale(parse_number(value)?, Point::default()),
+            "mirror" => {
+                let axis = match value.as_str() {
+                    "x" | "X" => Axis::X,
+                    "y" | "Y" => Axis::Y,
+                    "z" | "Z" => Axis::Z,
+                    _ => return Err(Failure::Usage(format!("unknown axis \"{}\"", value))),
+                };
+                Transform::mirror(axis, 0.0)
+            }
+            _ => unreachable!(),
+        };
+
+        transform = transform.then(&next);
+    }
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+        let current = Cell::new(None);
+        let transformed = Transformer::new(track(lines, &current), transform)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| input.describe_at(e, current.get()))?;
+
+        let mut written = String::new();
+        write_lines(&transformed, &mut written).expect("Writing to a String never fails");
+        print!("{}", written);
+
+        Ok(())
+    })
+}
+
+fn render_svg(options: &Options) -> Result<bool, Failure> {
+    let view = match options.value("view") {
+        None | Some("xy") => View::XY,
+        Some("xz") => View::XZ,
+        Some("yz") => View::YZ,
+        Some("isometric") | Some("iso") => View::Isometric,
+        Some(other) => return Err(Failure::Usage(format!("unknown view \"{}\"", other))),
+    };
+    let colouring = match options.value("colour-by") {
+        None | Some("tool") => Colouring::Tool,
+        Some("feed") => Colouring::FeedRate,
+        Some(other) => return Err(Failure::Usage(format!("can't colour by \"{}\"", other))),
+    };
+
+    // SVG files can't be concatenated
+    if options.files.len() > 1 {
+        return Err(Failure::Usage(String::from("render-svg only renders one program at a time")));
+    }
+
+    let renderer = Renderer {
+        colouring,
+        annotate: options.flag("annotate"),
+        ..Renderer::new(view)
+    };
+    let dialect = options.dialect()?;
+    let profile = options.profile()?;
+
+    for_each_input(options, |input| {
+        let lines = input.parse_as(dialect)?;
+        let current = Cell::new(None);
+        let mut moves = Vec::new();
+
+        for event in Interpreter::with_profile(track(lines, &current), profile) {
+            if let Event::Move(m) = event.map_err(|e| input.describe_at(e, current.get()))? {
+                moves.push(m);
+            }
+        }
+
+        let mut svg = String::new();
+        renderer.render(&moves, &mut svg).expect("Writing to a String never fails");
+        print!("{}", svg);
+
+        Ok(())
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, Failure> {
+    value.parse()
+        .map_err(|_| Failure::Usage(format!("\"{}\" isn't a valid number", value)))
+}
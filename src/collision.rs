@@ -0,0 +1,378 @@
+//! Checking a toolpath for crashes into the stock, fixtures and clamps.
+//!
+//! The tool is modelled as a cylinder (the cutter) with another, usually
+//! wider, cylinder on top of it (the holder), both pointing along Z. Moves
+//! are sampled every `Setup::resolution` millimetres and the tool is checked
+//! against each obstacle at every sample.
+//!
+//! The stock is treated as if it had never been cut, so to avoid reporting
+//! the tool leaving the hole it just made, rapids which only go straight up
+//! never count as passing through the stock.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Point;
+//! use gcode::stats::BoundingBox;
+//! use gcode::collision::{Setup, Shape, ToolShape, CollisionKind};
+//!
+//! let mut stock = BoundingBox::new(Point::new(0.0, 0.0, -20.0));
+//! stock.add_point(Point::new(100.0, 100.0, 0.0));
+//! let clamp = Shape::Cylinder {
+//!     centre: Point::new(50.0, 110.0, 0.0),
+//!     radius: 8.0,
+//!     height: 15.0,
+//! };
+//!
+//! let mut setup = Setup::new(ToolShape::new(6.0, 20.0, 30.0));
+//! setup.stock.push(Shape::Box(stock));
+//! setup.fixtures.push(clamp);
+//!
+//! let src = "G0 X10 Y10 Z5\nG1 Z-2 F100\nY100\nG0 Z5\nX20 Y10 Z-1";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let collisions = setup.check(lines).unwrap();
+//!
+//! assert_eq!(collisions.len(), 1);
+//! assert_eq!(collisions[0].kind, CollisionKind::RapidThroughStock);
+//! assert_eq!(collisions[0].span.line, 4);
+//! ```
+
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+use lexer::Span;
+use parser::Line;
+use interpreter::{Event, Interpreter, Move, MoveKind, Point, State, Profile};
+use stats::BoundingBox;
+use errors::*;
+
+
+/// A solid object which the tool shouldn't go through.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shape {
+    /// An axis-aligned box.
+    Box(BoundingBox),
+    /// An upright cylinder.
+    Cylinder {
+        /// The centre of the cylinder's bottom face.
+        centre: Point,
+        /// The cylinder's radius.
+        radius: f32,
+        /// How tall the cylinder is.
+        height: f32,
+    },
+}
+
+impl Shape {
+    /// Does an upright column, centred on `(x, y)` and going from `bottom`
+    /// to `top`, overlap the shape? Touching doesn't count.
+    fn overlaps_column(&self, x: f32, y: f32, radius: f32, bottom: f32, top: f32) -> bool {
+        match *self {
+            Shape::Box(ref b) => {
+                let dx = (b.min.x - x).max(x - b.max.x).max(0.0);
+                let dy = (b.min.y - y).max(y - b.max.y).max(0.0);
+
+                // a column with no radius has to be strictly inside the box
+                let overlaps = if dx == 0.0 && dy == 0.0 {
+                    radius > 0.0 || inside(x, b.min.x, b.max.x) && inside(y, b.min.y, b.max.y)
+                } else {
+                    dx * dx + dy * dy < radius * radius
+                };
+
+                b.min.z < top && bottom < b.max.z && overlaps
+            }
+            Shape::Cylinder { centre, radius: r, height } => {
+                let (dx, dy) = (centre.x - x, centre.y - y);
+                let reach = radius + r;
+
+                centre.z < top && bottom < centre.z + height && dx * dx + dy * dy < reach * reach
+            }
+        }
+    }
+}
+
+fn inside(value: f32, min: f32, max: f32) -> bool {
+    min < value && value < max
+}
+
+
+/// The shape of a tool and its holder.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ToolShape {
+    /// The cutter's diameter.
+    pub diameter: f32,
+    /// How far the tool sticks out of the holder.
+    pub length: f32,
+    /// The holder's diameter.
+    pub holder_diameter: f32,
+    /// How long the holder is.
+    pub holder_length: f32,
+}
+
+impl ToolShape {
+    /// Create a tool with a holder which is 50mm long.
+    pub fn new(diameter: f32, length: f32, holder_diameter: f32) -> ToolShape {
+        ToolShape {
+            diameter,
+            length,
+            holder_diameter,
+            holder_length: 50.0,
+        }
+    }
+}
+
+
+/// Everything on the machine's table.
+///
+/// All positions are in the same (machine) coordinates as the
+/// `Interpreter`'s moves. The tool's tip is found by taking each move's tool
+/// length offset back off, so the tool shapes don't need to know about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setup {
+    /// The material being cut.
+    pub stock: Vec<Shape>,
+    /// Vices, clamps and other fixtures which must never be touched.
+    pub fixtures: Vec<Shape>,
+    /// The shape of each tool.
+    pub tools: BTreeMap<u32, ToolShape>,
+    /// The shape used for tools which aren't in `tools`.
+    pub default_tool: ToolShape,
+    /// The largest distance between the points checked along a move, in mm.
+    pub resolution: f32,
+}
+
+impl Setup {
+    /// Create an empty setup where every tool has the same shape.
+    pub fn new(default_tool: ToolShape) -> Setup {
+        Setup {
+            stock: Vec::new(),
+            fixtures: Vec::new(),
+            tools: BTreeMap::new(),
+            default_tool,
+            resolution: 0.1,
+        }
+    }
+
+    /// Run a program through an `Interpreter` for a milling machine and find
+    /// every collision.
+    pub fn check<I>(&self, lines: I) -> Result<Vec<Collision>>
+        where I: IntoIterator<Item = Line>
+    {
+        self.check_with_state(lines, State::new(Profile::Mill))
+    }
+
+    /// Check a program, starting the `Interpreter` with a particular state.
+    pub fn check_with_state<I>(&self, lines: I, state: State) -> Result<Vec<Collision>>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut collisions = Vec::new();
+
+        for event in Interpreter::with_state(lines.into_iter(), state) {
+            if let Event::Move(ref m) = event? {
+                self.check_move(m, &mut collisions);
+            }
+        }
+
+        Ok(collisions)
+    }
+
+    /// Check a single move, adding the first collision with each obstacle to
+    /// `collisions`.
+    pub fn check_move(&self, m: &Move, collisions: &mut Vec<Collision>) {
+        let tool = self.tools.get(&m.tool).unwrap_or(&self.default_tool);
+        let rapid = m.kind == MoveKind::Rapid;
+        let straight_up = m.end.x == m.start.x && m.end.y == m.start.y && m.end.z >= m.start.z;
+
+        let mut checks = Vec::new();
+        for (index, shape) in self.stock.iter().enumerate() {
+            let obstacle = Obstacle::Stock(index);
+            if rapid && !straight_up {
+                checks.push((shape, CollisionKind::RapidThroughStock, obstacle));
+            }
+            checks.push((shape, CollisionKind::Holder, obstacle));
+        }
+        for (index, shape) in self.fixtures.iter().enumerate() {
+            let obstacle = Obstacle::Fixture(index);
+            checks.push((shape, CollisionKind::Fixture, obstacle));
+            checks.push((shape, CollisionKind::Holder, obstacle));
+        }
+
+        let steps = (m.length() / self.resolution).ceil().max(1.0) as usize;
+        let mut found = vec![false; checks.len()];
+
+        for step in 1..=steps {
+            let p = m.point_at(step as f32 / steps as f32) - m.tool_offset;
+
+            for (i, &(shape, kind, obstacle)) in checks.iter().enumerate() {
+                if found[i] {
+                    continue;
+                }
+
+                let (radius, bottom, top) = match kind {
+                    CollisionKind::Holder => {
+                        (tool.holder_diameter / 2.0,
+                         p.z + tool.length,
+                         p.z + tool.length + tool.holder_length)
+                    }
+                    _ => (tool.diameter / 2.0, p.z, p.z + tool.length),
+                };
+
+                if shape.overlaps_column(p.x, p.y, radius, bottom, top) {
+                    found[i] = true;
+                    collisions.push(Collision {
+                                        kind,
+                                        obstacle,
+                                        position: p,
+                                        tool: m.tool,
+                                        span: m.span,
+                                    });
+                }
+            }
+        }
+    }
+}
+
+
+/// The tool hitting something it shouldn't.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Collision {
+    /// What sort of collision it was.
+    pub kind: CollisionKind,
+    /// What was hit.
+    pub obstacle: Obstacle,
+    /// Where the tip of the tool was when it first hit.
+    pub position: Point,
+    /// The tool in the spindle.
+    pub tool: u32,
+    /// The start of the block containing the move.
+    pub span: Span,
+}
+
+/// The different kinds of collision.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CollisionKind {
+    /// A rapid went through the stock.
+    RapidThroughStock,
+    /// The cutter hit a fixture.
+    Fixture,
+    /// The tool holder hit the stock or a fixture.
+    Holder,
+}
+
+/// Something which was hit, as an index into `Setup::stock` or
+/// `Setup::fixtures`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Obstacle {
+    /// A piece of stock.
+    Stock(usize),
+    /// A fixture.
+    Fixture(usize),
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helpers::parse;
+
+    /// 100x100x20 stock with its top at Z=0 and a clamp sitting on the
+    /// table just past its right-hand edge.
+    fn setup() -> Setup {
+        let mut stock = BoundingBox::new(Point::new(0.0, 0.0, -20.0));
+        stock.add_point(Point::new(100.0, 100.0, 0.0));
+        let mut clamp = BoundingBox::new(Point::new(105.0, 40.0, -20.0));
+        clamp.add_point(Point::new(125.0, 60.0, 5.0));
+
+        let mut setup = Setup::new(ToolShape::new(6.0, 15.0, 40.0));
+        setup.stock.push(Shape::Box(stock));
+        setup.fixtures.push(Shape::Box(clamp));
+        setup
+    }
+
+    fn collisions(setup: &Setup, src: &str) -> Vec<(usize, CollisionKind, Obstacle)> {
+        setup.check(parse(src))
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.span.line, c.kind, c.obstacle))
+            .collect()
+    }
+
+    #[test]
+    fn a_safe_program() {
+        let src = "G0 Z10\nX10 Y10\nG1 Z-5 F100\nX90\nG0 Z10\nX10 Y90\nG1 Z-5\nX90\nG0 Z50";
+
+        assert!(collisions(&setup(), src).is_empty());
+    }
+
+    #[test]
+    fn rapids_through_the_stock() {
+        let got = collisions(&setup(), "G0 Z10\nX10 Y10\nZ-1\nX50\nZ10");
+
+        assert_eq!(got,
+                   vec![(2, CollisionKind::RapidThroughStock, Obstacle::Stock(0)),
+                        (3, CollisionKind::RapidThroughStock, Obstacle::Stock(0))]);
+    }
+
+    #[test]
+    fn feeding_into_a_clamp() {
+        let got = collisions(&setup(), "G0 Z10\nX90 Y50\nG1 Z-2 F100\nX110\nX90");
+
+        // backing out of the clamp hits it too
+        assert_eq!(got,
+                   vec![(3, CollisionKind::Fixture, Obstacle::Fixture(0)),
+                        (4, CollisionKind::Fixture, Obstacle::Fixture(0))]);
+    }
+
+    #[test]
+    fn arcs_which_swing_into_a_clamp() {
+        // both ends are well clear of the clamp, but the middle isn't
+        let got = collisions(&setup(), "G0 Z10\nX90 Y30\nG1 Z-2 F100\nG3 X90 Y70 I0 J20");
+
+        assert_eq!(got, vec![(3, CollisionKind::Fixture, Obstacle::Fixture(0))]);
+    }
+
+    #[test]
+    fn plunging_deeper_than_the_tool_is_long() {
+        let got = collisions(&setup(), "G0 Z10\nX50 Y50\nG1 Z-16 F100");
+
+        assert_eq!(got, vec![(2, CollisionKind::Holder, Obstacle::Stock(0))]);
+    }
+
+    #[test]
+    fn the_holder_can_hit_a_clamp_the_cutter_misses() {
+        // the 6mm cutter is 5mm from the clamp, but the 40mm holder isn't
+        let got = collisions(&setup(), "G0 Z10\nX97 Y50\nG1 Z-12 F100\nG0 Z10");
+
+        assert_eq!(got,
+                   vec![(2, CollisionKind::Holder, Obstacle::Fixture(0)),
+                        (3, CollisionKind::Holder, Obstacle::Fixture(0))]);
+    }
+
+    #[test]
+    fn tool_length_offsets_are_taken_off_the_tip() {
+        // Z-5 is only 5mm below the stock's top once the 10mm offset is removed
+        let src = "G10 L1 P1 Z10\nT1 M6 G43 G0 Z10\nX-10 Y50\nZ-5\nX50\nG0 Z10";
+        let got = setup().check(parse(src)).unwrap();
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].kind, CollisionKind::RapidThroughStock);
+        assert_eq!(got[0].span.line, 4);
+        assert_eq!(got[0].position.z, -5.0);
+    }
+
+    #[test]
+    fn each_tool_has_its_own_shape() {
+        let mut setup = setup();
+        setup.tools.insert(2, ToolShape::new(3.0, 40.0, 20.0));
+
+        let src = "G0 Z10\nX50 Y50\nG1 Z-16 F100\nG0 Z10\nT2 M6\nG1 Z-16\nG0 Z10";
+        let got = collisions(&setup, src);
+
+        assert_eq!(got,
+                   vec![(2, CollisionKind::Holder, Obstacle::Stock(0)),
+                        (3, CollisionKind::Holder, Obstacle::Stock(0))]);
+    }
+}
@@ -0,0 +1,646 @@
+//! Cutter radius compensation (`G41`/`G42`), which offsets the programmed
+//! path by the tool's radius so the edge of the tool follows it instead of
+//! the centre.
+//!
+//! Each move in the compensation plane is held back until the next one is
+//! known, so the corner between them can be worked out. Outside corners get
+//! an arc around the programmed corner, while inside corners are trimmed to
+//! where the two offset paths cross. If they don't cross the tool would
+//! gouge the part, so an error is returned instead.
+
+use core::f32::consts::PI;
+use core::cmp::Ordering;
+use core::mem;
+use core::ops::{Add, Sub, Mul, Neg};
+use arrayvec::ArrayVec;
+
+use interpreter::{Event, Move, MoveKind, Direction, Plane, Point, Side};
+use errors::*;
+use helpers::{abs, sqrt, atan2};
+
+
+/// Distances (in mm) smaller than this are treated as zero.
+const EPSILON: f32 = 1e-4;
+/// How far (in mm) a crossing point may be past the end of a move before it
+/// counts as a gouge.
+const TOLERANCE: f32 = 1e-3;
+
+
+/// Applies cutter radius compensation to a stream of events.
+#[derive(Debug, Clone)]
+pub struct Compensator {
+    stage: Stage,
+    /// The last move in the compensation plane, and where the compensated
+    /// version of it starts.
+    held: Option<(Move, Vector)>,
+    /// Events which happened after the held move.
+    after: ArrayVec<[Event; 8]>,
+    output: ArrayVec<[Event; 32]>,
+    /// Where the last move we emitted finished.
+    position: Option<Point>,
+}
+
+impl Compensator {
+    pub fn new() -> Compensator {
+        Compensator {
+            stage: Stage::Off,
+            held: None,
+            after: ArrayVec::new(),
+            output: ArrayVec::new(),
+            position: None,
+        }
+    }
+
+    /// Start offsetting moves to one side of the programmed path. The next
+    /// move in the plane is the entry move.
+    pub fn start(&mut self, side: Side, radius: f32, plane: Plane) -> Result<()> {
+        self.finish()?;
+        self.stage = Stage::On(Offset { side, radius, plane });
+        Ok(())
+    }
+
+    /// Stop compensating. The next move in the plane is the exit move.
+    pub fn stop(&mut self) {
+        self.stage = match self.stage {
+            Stage::On(offset) if self.held.is_some() => Stage::Stopping(offset),
+            _ => Stage::Off,
+        };
+    }
+
+    pub fn push(&mut self, event: Event) -> Result<()> {
+        let m = match event {
+            Event::Move(m) => m,
+            Event::ProgramEnd => {
+                self.finish()?;
+                return self.emit(event);
+            }
+            _ => return self.defer(event),
+        };
+
+        match self.stage {
+            Stage::On(offset) if offset.in_plane(&m) => {
+                match self.held.take() {
+                    Some(held) => self.corner(offset, held, m),
+                    None => self.entry(offset, m),
+                }
+            }
+            Stage::Stopping(offset) if offset.in_plane(&m) => self.exit(offset, m),
+            _ => self.defer(event),
+        }
+    }
+
+    /// Emit the held move (if any) without waiting to see what comes next.
+    pub fn finish(&mut self) -> Result<()> {
+        let offset = match self.stage {
+            Stage::On(offset) | Stage::Stopping(offset) => offset,
+            Stage::Off => return Ok(()),
+        };
+
+        match self.held.take() {
+            Some((m, start)) => {
+                let end = offset.offset(&m, m.end);
+                self.release(offset, m, start, end)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Are there no events ready to be executed?
+    pub fn is_empty(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    /// Take the next event which is ready to be executed.
+    pub fn pop(&mut self) -> Option<Event> {
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(self.output.remove(0))
+        }
+    }
+
+    fn entry(&mut self, offset: Offset, m: Move) -> Result<()> {
+        if let MoveKind::Arc { .. } = m.kind {
+            return Err(Error::InvalidCommand("The cutter compensation entry move must be a \
+                                              straight line"));
+        }
+
+        let start = offset.project(self.position.unwrap_or(m.start));
+        if (offset.project(m.end) - start).length() <= offset.radius {
+            return Err(Error::InvalidCommand("The cutter compensation entry move must be longer \
+                                              than the tool radius"));
+        }
+
+        self.held = Some((m, start));
+        Ok(())
+    }
+
+    /// Work out how to get around the corner between the held move and the
+    /// next one.
+    fn corner(&mut self, offset: Offset, held: (Move, Vector), next: Move) -> Result<()> {
+        let (held, held_start) = held;
+        check_arc(offset, &next)?;
+
+        let incoming = offset.tangent(&held, held.end);
+        let outgoing = offset.tangent(&next, next.start);
+        let held_end = offset.offset(&held, held.end);
+        let next_start = offset.offset(&next, next.start);
+
+        // how sharply the path turns towards the side the tool is on
+        let mut turn = incoming.cross(outgoing);
+        if offset.side == Side::Right {
+            turn = -turn;
+        }
+
+        let start = if turn > EPSILON {
+            // an inside corner, trim both moves to where they cross
+            let first = offset.path(&held, held_start, held_end);
+            let second = offset.path(&next, next_start, offset.offset(&next, next.end));
+            let corner = offset.project(next.start);
+
+            let crossing = intersections(&first, &second)
+                .into_iter()
+                .filter(|p| first.contains(*p) && second.contains(*p))
+                .min_by(|a, b| {
+                            let a = (*a - corner).length();
+                            let b = (*b - corner).length();
+                            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                        });
+
+            match crossing {
+                Some(crossing) => {
+                    self.release(offset, held, held_start, crossing)?;
+                    crossing
+                }
+                None => return Err(Error::InvalidCommand("Cutter compensation would gouge the part")),
+            }
+        } else {
+            self.release(offset, held, held_start, held_end)?;
+
+            if turn < -EPSILON || incoming.dot(outgoing) < 0.0 {
+                // an outside corner, go around it
+                let arc = Move {
+                    kind: MoveKind::Arc {
+                        centre: next.start,
+                        direction: offset.outside_direction(),
+                        plane: offset.plane,
+                    },
+                    end: offset.unproject(next_start, next.start),
+                    extrusion: 0.0,
+                    ..next
+                };
+                self.emit(Event::Move(arc))?;
+            }
+
+            next_start
+        };
+
+        self.held = Some((next, start));
+        Ok(())
+    }
+
+    fn exit(&mut self, offset: Offset, m: Move) -> Result<()> {
+        if let MoveKind::Arc { .. } = m.kind {
+            return Err(Error::InvalidCommand("The cutter compensation exit move must be a \
+                                              straight line"));
+        }
+
+        self.finish()?;
+        debug_assert_eq!(self.stage, Stage::Stopping(offset));
+        self.stage = Stage::Off;
+        self.emit(Event::Move(m))
+    }
+
+    /// Emit a held move which ends at `end`, followed by anything which
+    /// happened after it.
+    fn release(&mut self, offset: Offset, mut m: Move, start: Vector, end: Vector) -> Result<()> {
+        m.start = offset.unproject(start, m.start);
+        m.end = offset.unproject(end, m.end);
+        self.emit(Event::Move(m))?;
+
+        let after = mem::replace(&mut self.after, ArrayVec::new());
+        for event in after {
+            let event = match event {
+                Event::Move(mut m) => {
+                    m.end = offset.unproject(end, m.end);
+                    Event::Move(m)
+                }
+                other => other,
+            };
+            self.emit(event)?;
+        }
+
+        Ok(())
+    }
+
+    fn defer(&mut self, event: Event) -> Result<()> {
+        if self.held.is_none() {
+            return self.emit(event);
+        }
+
+        self.after
+            .try_push(event)
+            .map_err(|_| {
+                         Error::InvalidCommand("Too many commands without motion in the cutter \
+                                                compensation plane")
+                     })
+    }
+
+    fn emit(&mut self, event: Event) -> Result<()> {
+        let event = match event {
+            Event::Move(mut m) => {
+                if let Some(position) = self.position {
+                    m.start = position;
+                }
+                self.position = Some(m.end);
+                Event::Move(m)
+            }
+            other => other,
+        };
+
+        self.output
+            .try_push(event)
+            .map_err(|_| Error::InvalidCommand("Cutter compensation produced too many moves"))
+    }
+}
+
+/// Make sure the tool fits inside an arc.
+fn check_arc(offset: Offset, m: &Move) -> Result<()> {
+    if let MoveKind::Arc { centre, .. } = m.kind {
+        let centre = offset.project(centre);
+        let start = offset.project(m.start);
+        let normal = offset.normal(offset.tangent(m, m.start));
+
+        let towards_centre = normal.dot(centre - start) > 0.0;
+        if towards_centre && (start - centre).length() - offset.radius <= EPSILON {
+            return Err(Error::InvalidCommand("The tool is too big for an arc"));
+        }
+    }
+
+    Ok(())
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Stage {
+    Off,
+    On(Offset),
+    /// Compensation has been turned off, but we're waiting for the exit
+    /// move.
+    Stopping(Offset),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Offset {
+    side: Side,
+    radius: f32,
+    plane: Plane,
+}
+
+impl Offset {
+    fn project(&self, p: Point) -> Vector {
+        let (a, b, _) = self.plane.split(p);
+        Vector::new(a, b)
+    }
+
+    /// Turn an in-plane point back into a `Point`, taking the normal
+    /// coordinate from `like`.
+    fn unproject(&self, v: Vector, like: Point) -> Point {
+        let (_, _, normal) = self.plane.split(like);
+        self.plane.join(v.a, v.b, normal)
+    }
+
+    fn in_plane(&self, m: &Move) -> bool {
+        match m.kind {
+            MoveKind::Arc { .. } => true,
+            _ => (self.project(m.end) - self.project(m.start)).length() > EPSILON,
+        }
+    }
+
+    /// The direction of travel at a point on a move.
+    fn tangent(&self, m: &Move, at: Point) -> Vector {
+        match m.kind {
+            MoveKind::Arc { centre, direction, .. } => {
+                let radial = (self.project(at) - self.project(centre)).unit();
+                match direction {
+                    Direction::CounterClockwise => radial.left(),
+                    Direction::Clockwise => -radial.left(),
+                }
+            }
+            _ => (self.project(m.end) - self.project(m.start)).unit(),
+        }
+    }
+
+    /// Which way the tool is offset when travelling along `tangent`.
+    fn normal(&self, tangent: Vector) -> Vector {
+        match self.side {
+            Side::Left => tangent.left(),
+            Side::Right => -tangent.left(),
+        }
+    }
+
+    /// Where the centre of the tool is when its edge is at a point on a move.
+    fn offset(&self, m: &Move, at: Point) -> Vector {
+        self.project(at) + self.normal(self.tangent(m, at)) * self.radius
+    }
+
+    /// The direction of the arc which takes the tool around outside corners.
+    fn outside_direction(&self) -> Direction {
+        match self.side {
+            Side::Left => Direction::Clockwise,
+            Side::Right => Direction::CounterClockwise,
+        }
+    }
+
+    /// The compensated version of a move.
+    fn path(&self, m: &Move, start: Vector, end: Vector) -> Path {
+        match m.kind {
+            MoveKind::Arc { centre, direction, .. } => {
+                let centre = self.project(centre);
+                Path::Arc {
+                    centre,
+                    radius: (start - centre).length(),
+                    start,
+                    end,
+                    direction,
+                }
+            }
+            _ => Path::Line { start, end },
+        }
+    }
+}
+
+
+/// Part of the compensated toolpath.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Path {
+    Line { start: Vector, end: Vector },
+    Arc {
+        centre: Vector,
+        radius: f32,
+        start: Vector,
+        end: Vector,
+        direction: Direction,
+    },
+}
+
+impl Path {
+    /// Does a point on the path's line (or circle) lie between its start
+    /// and end?
+    fn contains(&self, p: Vector) -> bool {
+        match *self {
+            Path::Line { start, end } => {
+                let length = (end - start).length();
+                if length < EPSILON {
+                    return (p - start).length() < TOLERANCE;
+                }
+
+                let along = (p - start).dot(end - start) / length;
+                -TOLERANCE <= along && along <= length + TOLERANCE
+            }
+            Path::Arc { centre, radius, start, end, direction } => {
+                if (p - start).length() < TOLERANCE || (p - end).length() < TOLERANCE {
+                    return true;
+                }
+
+                let sweep = |to: Vector| {
+                    let mut angle = (to - centre).angle() - (start - centre).angle();
+                    if direction == Direction::Clockwise {
+                        angle = -angle;
+                    }
+                    if angle < 0.0 {
+                        angle += 2.0 * PI;
+                    }
+                    angle
+                };
+
+                let tolerance = TOLERANCE / radius.max(EPSILON);
+                let mut total = sweep(end);
+                if total < tolerance {
+                    // a full circle
+                    total = 2.0 * PI;
+                }
+
+                sweep(p) <= total + tolerance
+            }
+        }
+    }
+}
+
+/// The points where two paths' lines (or circles) cross.
+fn intersections(first: &Path, second: &Path) -> ArrayVec<[Vector; 2]> {
+    match (*first, *second) {
+        (Path::Line { start: p, end: p2 }, Path::Line { start: q, end: q2 }) => {
+            let mut found = ArrayVec::new();
+            let d = (p2 - p).unit();
+            let e = (q2 - q).unit();
+            let denominator = d.cross(e);
+
+            if abs(denominator) > EPSILON {
+                let t = (q - p).cross(e) / denominator;
+                found.push(p + d * t);
+            }
+            found
+        }
+        (Path::Line { start, end }, Path::Arc { centre, radius, .. }) |
+        (Path::Arc { centre, radius, .. }, Path::Line { start, end }) => {
+            line_circle(start, (end - start).unit(), centre, radius)
+        }
+        (Path::Arc { centre: c1, radius: r1, .. }, Path::Arc { centre: c2, radius: r2, .. }) => {
+            circle_circle(c1, r1, c2, r2)
+        }
+    }
+}
+
+fn line_circle(point: Vector,
+               direction: Vector,
+               centre: Vector,
+               radius: f32)
+               -> ArrayVec<[Vector; 2]> {
+    let mut found = ArrayVec::new();
+    let f = point - centre;
+    let b = f.dot(direction);
+    let discriminant = b * b - (f.dot(f) - radius * radius);
+
+    if discriminant >= -EPSILON {
+        let root = sqrt(discriminant.max(0.0));
+        found.push(point + direction * (-b - root));
+        found.push(point + direction * (-b + root));
+    }
+
+    found
+}
+
+fn circle_circle(c1: Vector, r1: f32, c2: Vector, r2: f32) -> ArrayVec<[Vector; 2]> {
+    let mut found = ArrayVec::new();
+    let delta = c2 - c1;
+    let d = delta.length();
+
+    if d < EPSILON || d > r1 + r2 + EPSILON || d < abs(r1 - r2) - EPSILON {
+        return found;
+    }
+
+    let a = (r1 * r1 - r2 * r2 + d * d) / (2.0 * d);
+    let h = sqrt((r1 * r1 - a * a).max(0.0));
+    let midpoint = c1 + delta * (a / d);
+    let perpendicular = delta.unit().left();
+
+    found.push(midpoint + perpendicular * h);
+    found.push(midpoint - perpendicular * h);
+    found
+}
+
+
+/// A point or direction in the compensation plane.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Vector {
+    a: f32,
+    b: f32,
+}
+
+impl Vector {
+    fn new(a: f32, b: f32) -> Vector {
+        Vector { a, b }
+    }
+
+    fn dot(self, other: Vector) -> f32 {
+        self.a * other.a + self.b * other.b
+    }
+
+    /// The z component of the cross product, positive when `other` is
+    /// counter-clockwise from `self`.
+    fn cross(self, other: Vector) -> f32 {
+        self.a * other.b - self.b * other.a
+    }
+
+    fn length(self) -> f32 {
+        sqrt(self.dot(self))
+    }
+
+    fn unit(self) -> Vector {
+        let length = self.length();
+        if length < EPSILON {
+            Vector::default()
+        } else {
+            self * (1.0 / length)
+        }
+    }
+
+    /// Rotate 90 degrees counter-clockwise.
+    fn left(self) -> Vector {
+        Vector::new(-self.b, self.a)
+    }
+
+    fn angle(self) -> f32 {
+        atan2(self.b, self.a)
+    }
+}
+
+impl Add for Vector {
+    type Output = Vector;
+
+    fn add(self, other: Vector) -> Vector {
+        Vector::new(self.a + other.a, self.b + other.b)
+    }
+}
+
+impl Sub for Vector {
+    type Output = Vector;
+
+    fn sub(self, other: Vector) -> Vector {
+        Vector::new(self.a - other.a, self.b - other.b)
+    }
+}
+
+impl Mul<f32> for Vector {
+    type Output = Vector;
+
+    fn mul(self, scale: f32) -> Vector {
+        Vector::new(self.a * scale, self.b * scale)
+    }
+}
+
+impl Neg for Vector {
+    type Output = Vector;
+
+    fn neg(self) -> Vector {
+        Vector::new(-self.a, -self.b)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(left: Vector, right: Vector) -> bool {
+        (left - right).length() < 0.001
+    }
+
+    #[test]
+    fn crossing_lines() {
+        let first = Path::Line {
+            start: Vector::new(0.0, 1.0),
+            end: Vector::new(10.0, 1.0),
+        };
+        let second = Path::Line {
+            start: Vector::new(9.0, 0.0),
+            end: Vector::new(9.0, 0.5),
+        };
+
+        let got = intersections(&first, &second);
+
+        assert_eq!(got.len(), 1);
+        assert!(close(got[0], Vector::new(9.0, 1.0)));
+        assert!(first.contains(got[0]));
+        assert!(!second.contains(got[0]));
+    }
+
+    #[test]
+    fn line_crossing_a_circle() {
+        let line = Path::Line {
+            start: Vector::new(-10.0, 3.0),
+            end: Vector::new(10.0, 3.0),
+        };
+        let arc = Path::Arc {
+            centre: Vector::new(0.0, 0.0),
+            radius: 5.0,
+            start: Vector::new(5.0, 0.0),
+            end: Vector::new(0.0, 5.0),
+            direction: Direction::CounterClockwise,
+        };
+
+        let got = intersections(&line, &arc);
+
+        assert_eq!(got.len(), 2);
+        assert!(close(got[0], Vector::new(-4.0, 3.0)));
+        assert!(close(got[1], Vector::new(4.0, 3.0)));
+        assert!(!arc.contains(got[0]));
+        assert!(arc.contains(got[1]));
+    }
+
+    #[test]
+    fn crossing_circles() {
+        let got = circle_circle(Vector::new(0.0, 0.0), 5.0, Vector::new(8.0, 0.0), 5.0);
+
+        assert_eq!(got.len(), 2);
+        assert!(close(got[0], Vector::new(4.0, 3.0)));
+        assert!(close(got[1], Vector::new(4.0, -3.0)));
+        assert!(circle_circle(Vector::new(0.0, 0.0), 1.0, Vector::new(8.0, 0.0), 1.0).is_empty());
+    }
+
+    #[test]
+    fn clockwise_arcs_contain_points_on_the_right_side() {
+        let arc = Path::Arc {
+            centre: Vector::new(0.0, 0.0),
+            radius: 1.0,
+            start: Vector::new(0.0, 1.0),
+            end: Vector::new(1.0, 0.0),
+            direction: Direction::Clockwise,
+        };
+
+        let half = sqrt(0.5);
+        assert!(arc.contains(Vector::new(half, half)));
+        assert!(!arc.contains(Vector::new(-half, half)));
+    }
+}
@@ -0,0 +1,176 @@
+//! The plumbing shared by the hosts which stream gcode to a machine over a
+//! serial port (or anything else which implements `Read` and `Write`).
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::string::{String, ToString};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use parser::Line;
+use writer::write_lines;
+
+
+/// The longest to wait between polls of a transport with nothing to read.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A transport which is read from one line at a time.
+///
+/// The end of the stream is reported as an `io::ErrorKind::UnexpectedEof`
+/// error, and waiting longer than the timeout as `io::ErrorKind::TimedOut`.
+///
+/// Transports which don't block (e.g. a serial port with a short read
+/// timeout) are polled, sleeping a little longer each time nothing arrives
+/// up to `MAX_POLL_INTERVAL`, so waiting for a busy machine doesn't spin.
+#[derive(Debug)]
+pub struct Connection<T> {
+    transport: T,
+    /// Bytes which have been read, but don't make up a whole line yet.
+    received: Vec<u8>,
+    /// Messages which weren't a response to anything in particular.
+    feedback: Vec<String>,
+    timeout: Option<Duration>,
+}
+
+impl<T: Read + Write> Connection<T> {
+    pub fn new(transport: T) -> Connection<T> {
+        Connection {
+            transport,
+            received: Vec::new(),
+            feedback: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn push_feedback(&mut self, msg: String) {
+        self.feedback.push(msg);
+    }
+
+    pub fn take_feedback(&mut self) -> Vec<String> {
+        mem::take(&mut self.feedback)
+    }
+
+    /// Write a line, without flushing the transport.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.transport.write_all(line.as_bytes())?;
+        self.transport.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+
+    /// Wait for the next line which isn't blank, with the whitespace
+    /// trimmed off.
+    pub fn read_line(&mut self) -> io::Result<String> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut poll_interval = Duration::from_millis(1);
+
+        loop {
+            if let Some(end) = self.received.iter().position(|&b| b == b'\n') {
+                let raw: Vec<u8> = self.received.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&raw);
+                let line = line.trim();
+
+                if line.is_empty() {
+                    continue;
+                }
+                return Ok(line.to_string());
+            }
+
+            let mut buffer = [0; 64];
+            let bytes_read = match self.transport.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // serial ports time out when nothing has been sent, which
+                // is normal while the machine is busy or held
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut wait = poll_interval;
+                    if let Some(deadline) = deadline {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(io::ErrorKind::TimedOut.into());
+                        }
+                        wait = wait.min(deadline - now);
+                    }
+
+                    thread::sleep(wait);
+                    poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            self.received.extend_from_slice(&buffer[..bytes_read]);
+            poll_interval = Duration::from_millis(1);
+        }
+    }
+}
+
+
+/// Turn a program into the text which gets sent, one line at a time.
+pub fn program_text(lines: &[Line]) -> String {
+    let mut src = String::new();
+    write_lines(lines, &mut src).expect("Writing to a String never fails");
+
+    src
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transport which never has anything to say.
+    #[derive(Debug, Default)]
+    struct Silent {
+        reads: usize,
+    }
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn waiting_backs_off_instead_of_spinning() {
+        let mut connection = Connection::new(Silent::default());
+        connection.set_timeout(Some(Duration::from_millis(200)));
+
+        let got = connection.read_line();
+
+        assert_eq!(got.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        let reads = connection.transport().reads;
+        assert!(reads > 1 && reads < 20, "{} reads", reads);
+    }
+}
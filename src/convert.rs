@@ -0,0 +1,603 @@
+//! Rewriting programs to use different distance modes and units.
+//!
+//! Some controllers only accept absolute coordinates, or only understand
+//! millimetres. The iterator adaptors in this module take a parsed program
+//! and rewrite it so it does the same thing using a single distance mode
+//! (`DistanceConverter`) or a single set of units (`UnitConverter`).
+//!
+//! Both adaptors drop the original mode changes (e.g. `G91` or `G20`) and
+//! write the new mode once, at the start of the program.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::{DistanceMode, Units};
+//! use gcode::convert::{DistanceConverter, UnitConverter};
+//! use gcode::writer::write_lines;
+//!
+//! let src = "G20 G90 G0 X1 Y1\nG91 G1 X0.5 F10\nY-0.25";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let lines = DistanceConverter::new(lines, DistanceMode::Absolute)
+//!     .filter_map(|line| line.ok());
+//! let lines: Vec<_> = UnitConverter::new(lines, Units::Millimetres, Units::Millimetres)
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//!
+//! let mut written = String::new();
+//! write_lines(&lines, &mut written).unwrap();
+//! assert_eq!(written, "G21 G90 G0 X25.4 Y25.4\nG1 X38.1 F254\nY19.05\n");
+//! ```
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use interpreter::{Point, DistanceMode, Units};
+use lexer::Span;
+use errors::*;
+use helpers::Modal;
+
+
+const MM_PER_INCH: f32 = 25.4;
+const METRES_PER_FOOT: f32 = 0.3048;
+
+
+/// An iterator adaptor which rewrites a program to only use absolute
+/// (`G90`) or incremental (`G91`) distances.
+///
+/// Where the tool starts is unknown unless `starting_at()` is used, so
+/// without it converting the first move along an axis to a different
+/// distance mode is an error.
+///
+/// Arc centres (`I`, `J` and `K`) are controlled by `G90.1`/`G91.1`, so they
+/// aren't changed. Axis words used with `G53`, `G10`, `G43.1`, `G33.1` and
+/// `G76` are left as they are, and so are the `X`, `Y` and `Z` words of `M`
+/// codes, which are settings rather than moves.
+#[derive(Debug)]
+pub struct DistanceConverter<I>
+    where I: Iterator<Item = Line>
+{
+    lines: I,
+    target: DistanceMode,
+    modal: Modal,
+    started: bool,
+    pending: Option<Line>,
+}
+
+impl<I> DistanceConverter<I>
+    where I: Iterator<Item = Line>
+{
+    /// Create a new `DistanceConverter`.
+    pub fn new(lines: I, target: DistanceMode) -> DistanceConverter<I> {
+        DistanceConverter {
+            lines,
+            target,
+            modal: Modal::default(),
+            started: false,
+            pending: None,
+        }
+    }
+
+    /// Tell the converter where the tool is when the program starts.
+    pub fn starting_at(mut self, position: Point) -> DistanceConverter<I> {
+        self.modal.position = [Some(position.x), Some(position.y), Some(position.z)];
+        self
+    }
+
+    fn convert_line(&mut self, line: Line) -> Result<Option<Line>> {
+        let start = self.modal.position;
+        self.modal.update(&line);
+
+        let mut cmd = match line {
+            Line::ProgramNumber(_) => return Ok(Some(line)),
+            Line::Args { args, line_number, span } => {
+                return self.convert_args_line(args, line_number, span, start).map(Some);
+            }
+            Line::Cmd(ref cmd) if cmd.kind != CommandKind::G => return Ok(Some(line)),
+            Line::Cmd(cmd) => cmd,
+        };
+
+        match cmd.number {
+            Number::Integer(90) | Number::Integer(91) => {
+                if cmd.args.is_empty() && cmd.line_number.is_none() {
+                    return Ok(None);
+                }
+                return self.convert_args_line(cmd.args, cmd.line_number, cmd.span, start)
+                    .map(Some);
+            }
+            Number::Integer(28) | Number::Integer(30) => {
+                // the axis words are a point to go through on the way home
+                self.convert_args(&mut cmd.args, start)?;
+                return Ok(Some(Line::Cmd(cmd)));
+            }
+            Number::Integer(92) |
+            Number::Decimal(92, _) |
+            Number::Integer(10) |
+            Number::Decimal(33, 1) |
+            Number::Decimal(43, 1) |
+            Number::Integer(76) => return Ok(Some(Line::Cmd(cmd))),
+            _ => {}
+        }
+
+        if !self.modal.in_machine_coordinates(cmd.span) {
+            self.convert_args(&mut cmd.args, start)?;
+        }
+
+        Ok(Some(Line::Cmd(cmd)))
+    }
+
+    /// Convert arguments on their own, or the ones left behind when a mode
+    /// change is dropped.
+    fn convert_args_line(&mut self,
+                         mut args: Args,
+                         line_number: Option<u32>,
+                         span: Span,
+                         start: [Option<f32>; 3])
+                         -> Result<Line> {
+        if !self.modal.in_machine_coordinates(span) {
+            self.convert_args(&mut args, start)?;
+        }
+
+        Ok(Line::Args {
+               args,
+               line_number,
+               span,
+           })
+    }
+
+    /// Rewrite the axis words in `args` for a move from `start`.
+    fn convert_args(&self, args: &mut Args, start: [Option<f32>; 3]) -> Result<()> {
+        let (mode, target) = (self.modal.distance_mode, self.target);
+        let mut words = [&mut args.x, &mut args.y, &mut args.z];
+
+        for (word, &previous) in words.iter_mut().zip(&start) {
+            let value = match **word {
+                Some(value) => value,
+                None => continue,
+            };
+
+            **word = match (mode, target) {
+                (DistanceMode::Absolute, DistanceMode::Absolute) |
+                (DistanceMode::Incremental, DistanceMode::Incremental) => Some(value),
+                (DistanceMode::Incremental, DistanceMode::Absolute) => {
+                    previous.map(|p| p + value)
+                }
+                (DistanceMode::Absolute, DistanceMode::Incremental) => {
+                    previous.map(|p| value - p)
+                }
+            };
+
+            if word.is_none() {
+                return Err(Error::InvalidCommand("Can't convert a move from an unknown position"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<I> Iterator for DistanceConverter<I>
+    where I: Iterator<Item = Line>
+{
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            let mut first = self.lines.next()?;
+            if let Line::ProgramNumber(_) = first {
+                // the mode goes at the start of the program itself
+                self.started = false;
+                return Some(Ok(first));
+            }
+            let number = match self.target {
+                DistanceMode::Absolute => 90,
+                DistanceMode::Incremental => 91,
+            };
+            let mode = mode_change(number, &mut first);
+            self.pending = Some(first);
+            return Some(Ok(mode));
+        }
+
+        loop {
+            let line = match self.pending.take() {
+                Some(line) => line,
+                None => self.lines.next()?,
+            };
+
+            match self.convert_line(line) {
+                Ok(Some(line)) => return Some(Ok(line)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+
+/// An iterator adaptor which rewrites a program to use either inches
+/// (`G20`) or millimetres (`G21`).
+///
+/// Every word which is a length is scaled. That's the axis words, arc
+/// centres and radii (`I`, `J`, `K` and `R`), feed rates (except in inverse
+/// time mode, `G93`) and extrusion (`E`). `P`, `Q` and `R` only get scaled
+/// when the command uses them as lengths, like a `G83` peck depth or a `G76`
+/// pitch, and are left alone when they're angles, temperatures, dwell times
+/// or numbers. Spindle speeds are left alone, unless the spindle is in
+/// constant surface speed mode (`G96`), where `S` is converted between feet
+/// and metres per minute.
+///
+/// `M` codes only have their `S` word converted, because they use the other
+/// words for their own settings (e.g. the steps per millimetre in `M92`).
+#[derive(Debug)]
+pub struct UnitConverter<I>
+    where I: Iterator<Item = Line>
+{
+    lines: I,
+    target: Units,
+    units: Units,
+    inverse_time: bool,
+    constant_surface_speed: bool,
+    started: bool,
+    pending: Option<Line>,
+}
+
+impl<I> UnitConverter<I>
+    where I: Iterator<Item = Line>
+{
+    /// Create a new `UnitConverter`, assuming the program is in `initial`
+    /// units until it says otherwise.
+    pub fn new(lines: I, initial: Units, target: Units) -> UnitConverter<I> {
+        UnitConverter {
+            lines,
+            target,
+            units: initial,
+            inverse_time: false,
+            constant_surface_speed: false,
+            started: false,
+            pending: None,
+        }
+    }
+
+    fn convert_line(&mut self, line: Line) -> Result<Option<Line>> {
+        match line {
+            Line::ProgramNumber(_) => Ok(Some(line)),
+            Line::Args { mut args, line_number, span } => {
+                // the words are for the current motion mode
+                self.scale(&mut args, Lengths::MOTION);
+                Ok(Some(Line::Args {
+                            args,
+                            line_number,
+                            span,
+                        }))
+            }
+            Line::Cmd(mut cmd) => {
+                if cmd.kind == CommandKind::G {
+                    match cmd.number {
+                        Number::Integer(20) => {
+                            self.units = Units::Inches;
+                            return self.without_command(cmd);
+                        }
+                        Number::Integer(21) => {
+                            self.units = Units::Millimetres;
+                            return self.without_command(cmd);
+                        }
+                        Number::Integer(93) => self.inverse_time = true,
+                        Number::Integer(94) | Number::Integer(95) => self.inverse_time = false,
+                        Number::Integer(96) => self.constant_surface_speed = true,
+                        Number::Integer(97) => self.constant_surface_speed = false,
+                        _ => {}
+                    }
+                }
+
+                let lengths = Lengths::of(&cmd);
+                self.scale(&mut cmd.args, lengths);
+                Ok(Some(Line::Cmd(cmd)))
+            }
+        }
+    }
+
+    fn without_command(&mut self, cmd: Command) -> Result<Option<Line>> {
+        match without_command(cmd) {
+            Some(line) => self.convert_line(line),
+            None => Ok(None),
+        }
+    }
+
+    fn scale(&self, args: &mut Args, lengths: Lengths) {
+        let (length, surface_speed) = match (self.units, self.target) {
+            (Units::Inches, Units::Millimetres) => (MM_PER_INCH, METRES_PER_FOOT),
+            (Units::Millimetres, Units::Inches) => (1.0 / MM_PER_INCH, 1.0 / METRES_PER_FOOT),
+            _ => return,
+        };
+
+        {
+            let mut words = [(&mut args.x, lengths.axes),
+                             (&mut args.y, lengths.axes),
+                             (&mut args.z, lengths.axes),
+                             (&mut args.i, lengths.i_j),
+                             (&mut args.j, lengths.i_j),
+                             (&mut args.k, lengths.axes),
+                             (&mut args.e, lengths.axes),
+                             (&mut args.p, lengths.p),
+                             (&mut args.q, lengths.q),
+                             (&mut args.r, lengths.r)];
+
+            for &mut (ref mut word, is_length) in &mut words {
+                if is_length {
+                    **word = word.map(|value| value * length);
+                }
+            }
+        }
+
+        if lengths.axes && !self.inverse_time {
+            args.f = args.f.map(|f| f * length);
+        }
+        if self.constant_surface_speed {
+            args.s = args.s.map(|s| s * surface_speed);
+        }
+    }
+}
+
+/// Which of the words that mean different things to different commands are
+/// lengths.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Lengths {
+    /// The axis words, `K`, `E` and the feed rate.
+    axes: bool,
+    i_j: bool,
+    p: bool,
+    q: bool,
+    r: bool,
+}
+
+impl Lengths {
+    /// Arc centres and radii, and a canned cycle's retract plane and peck
+    /// depth.
+    const MOTION: Lengths = Lengths {
+        axes: true,
+        i_j: true,
+        p: false,
+        q: true,
+        r: true,
+    };
+
+    fn of(cmd: &Command) -> Lengths {
+        let nothing = Lengths {
+            axes: true,
+            i_j: true,
+            p: false,
+            q: false,
+            r: false,
+        };
+
+        match (cmd.kind, cmd.number) {
+            // the radius is a length, but the front and back angles and the
+            // orientation aren't
+            (CommandKind::G, Number::Integer(10)) if cmd.args.l == Some(1.0) => {
+                Lengths {
+                    i_j: false,
+                    r: true,
+                    ..nothing
+                }
+            }
+            // R is the coordinate system's rotation
+            (CommandKind::G, Number::Integer(10)) => nothing,
+            // spline control points and path blending tolerances
+            (CommandKind::G, Number::Integer(5)) |
+            (CommandKind::G, Number::Integer(64)) => {
+                Lengths {
+                    p: true,
+                    q: true,
+                    ..nothing
+                }
+            }
+            // the pitch, but Q is the compound angle and R the degression
+            (CommandKind::G, Number::Integer(76)) => Lengths { p: true, ..nothing },
+            (CommandKind::G, Number::Integer(73)) |
+            (CommandKind::G, Number::Integer(83)) => Lengths::MOTION,
+            (CommandKind::G, _) => Lengths { r: true, ..nothing },
+            // temperatures, pin numbers, durations, steps per millimetre, ...
+            _ => {
+                Lengths {
+                    axes: false,
+                    i_j: false,
+                    ..nothing
+                }
+            }
+        }
+    }
+}
+
+impl<I> Iterator for UnitConverter<I>
+    where I: Iterator<Item = Line>
+{
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            let mut first = self.lines.next()?;
+            if let Line::ProgramNumber(_) = first {
+                // the mode goes at the start of the program itself
+                self.started = false;
+                return Some(Ok(first));
+            }
+            let number = match self.target {
+                Units::Inches => 20,
+                Units::Millimetres => 21,
+            };
+            let units = mode_change(number, &mut first);
+            self.pending = Some(first);
+            return Some(Ok(units));
+        }
+
+        loop {
+            let line = match self.pending.take() {
+                Some(line) => line,
+                None => self.lines.next()?,
+            };
+
+            match self.convert_line(line) {
+                Ok(Some(line)) => return Some(Ok(line)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+
+/// A `G` command which goes at the start of the same line as `first`,
+/// taking its line number. `first` can't be a program number, because the
+/// mode needs to come after it.
+fn mode_change(number: u32, first: &mut Line) -> Line {
+    let span = match first.span() {
+        Some(span) => Span { column: 0, ..span },
+        None => Span::default(),
+    };
+    let line_number = match *first {
+        Line::Cmd(ref mut cmd) => cmd.line_number.take(),
+        Line::Args { ref mut line_number, .. } => line_number.take(),
+        Line::ProgramNumber(_) => None,
+    };
+
+    Line::Cmd(Command {
+                  kind: CommandKind::G,
+                  number: Number::Integer(number),
+                  args: Args::default(),
+                  line_number,
+                  span,
+              })
+}
+
+/// Remove a modal command, keeping any arguments or line number that came
+/// with it.
+fn without_command(cmd: Command) -> Option<Line> {
+    if cmd.args.is_empty() && cmd.line_number.is_none() {
+        return None;
+    }
+
+    Some(Line::Args {
+             args: cmd.args,
+             line_number: cmd.line_number,
+             span: cmd.span,
+         })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use std::string::String;
+    use writer::write_lines;
+    use helpers::parse;
+
+    fn write<J: Iterator<Item = Result<Line>>>(lines: J) -> Result<String> {
+        let lines = lines.collect::<Result<Vec<_>>>()?;
+
+        let mut written = String::new();
+        write_lines(&lines, &mut written).unwrap();
+        Ok(written)
+    }
+
+    #[test]
+    fn incremental_to_absolute() {
+        let src = "N10 G0 X10 Y10 Z5\nN20 G91 G1 Z-6 F100\nX5 Y-2\nG2 X5 Y5 I5 J0\nG90 Z5";
+        let got = write(DistanceConverter::new(parse(src).into_iter(), DistanceMode::Absolute));
+
+        let should_be = "N10 G90 G0 X10 Y10 Z5\nN20 G1 Z-1 F100\nX15 Y8\nG2 X20 Y13 I5 J0\nZ5\n";
+        assert_eq!(got.unwrap(), should_be);
+    }
+
+    #[test]
+    fn absolute_to_incremental() {
+        let src = "G90 G0 X10 Y10\nG1 X15 F100\nG91 Y5\nG90 X0 Y0";
+        let lines = DistanceConverter::new(parse(src).into_iter(), DistanceMode::Incremental)
+            .starting_at(Point::new(0.0, 0.0, 0.0));
+
+        let should_be = "G91 G0 X10 Y10\nG1 X5 F100\nY5\nX-15 Y-15\n";
+        assert_eq!(write(lines).unwrap(), should_be);
+    }
+
+    #[test]
+    fn converting_from_an_unknown_position() {
+        let lines = DistanceConverter::new(parse("G91 G0 X10").into_iter(),
+                                           DistanceMode::Absolute);
+        assert!(write(lines).is_err());
+
+        // G92 tells us where we are
+        let lines = DistanceConverter::new(parse("G92 X0 Y0\nG91 G0 X10").into_iter(),
+                                           DistanceMode::Absolute);
+        assert_eq!(write(lines).unwrap(), "G90 G92 X0 Y0\nG0 X10\n");
+    }
+
+    #[test]
+    fn inches_to_millimetres() {
+        let src = "G20 G0 X1 Y2\nG1 Z-0.1 F10 S1000\nG3 X2 Y2 R0.5 E0.01\nG4 P1.5\nG21 X10";
+        let lines = UnitConverter::new(parse(src).into_iter(),
+                                       Units::Millimetres,
+                                       Units::Millimetres);
+
+        let should_be = "G21 G0 X25.4 Y50.8\nG1 Z-2.54 F254 S1000\nG3 X50.8 Y50.8 R12.7 \
+                         E0.254\nG4 P1.5\nX10\n";
+        assert_eq!(write(lines).unwrap(), should_be);
+    }
+
+    #[test]
+    fn millimetres_to_inches() {
+        let src = "G0 X25.4\nG93 G1 X50.8 F2\nG94 G96 S100 F254";
+        let lines = UnitConverter::new(parse(src).into_iter(),
+                                       Units::Millimetres,
+                                       Units::Inches);
+
+        let should_be = "G20 G0 X1\nG93 G1 X2 F2\nG94 G96 F10 S328.084\n";
+        assert_eq!(write(lines).unwrap(), should_be);
+    }
+
+    #[test]
+    fn only_words_which_are_lengths_get_scaled() {
+        let src = "G20 G76 P0.05 Z-1 I-0.04 J0.01 K0.05 Q29.5 R1.5 H1\n\
+                   G10 L1 P2 R0.125 Z1 Q2 I10 J5\nG10 L2 P1 X1 R45\n\
+                   G83 X1 Z-1 R0.1 Q0.2\nX2\nG4 P0.5\nM109 R210";
+        let lines = UnitConverter::new(parse(src).into_iter(),
+                                       Units::Millimetres,
+                                       Units::Millimetres);
+
+        let should_be = "G21 G76 Z-25.4 I-1.016 J0.254 K1.27 R1.5 H1 P1.27 Q29.5\n\
+                         G10 Z25.4 I10 J5 R3.175 L1 P2 Q2\nG10 X25.4 R45 L2 P1\n\
+                         G83 X25.4 Z-25.4 R2.54 Q5.08\nX50.8\nG4 P0.5\nM109 R210\n";
+        assert_eq!(write(lines).unwrap(), should_be);
+    }
+
+    #[test]
+    fn m_code_settings_are_left_alone() {
+        let src = "G20 M92 X80 E93\nM203 X5 Z0.5 F10\nG96 M3 S100\nG0 X1";
+        let lines = UnitConverter::new(parse(src).into_iter(),
+                                       Units::Millimetres,
+                                       Units::Millimetres);
+
+        let should_be = "G21 M92 X80 E93\nM203 X5 Z0.5 F10\nG96 M3 S30.48\nG0 X25.4\n";
+        assert_eq!(write(lines).unwrap(), should_be);
+
+        let src = "G91 G0 X10\nM92 X80 E93\nM906 X800 Z900";
+        let lines = DistanceConverter::new(parse(src).into_iter(), DistanceMode::Absolute)
+            .starting_at(Point::new(0.0, 0.0, 0.0));
+
+        assert_eq!(write(lines).unwrap(), "G90 G0 X10\nM92 X80 E93\nM906 X800 Z900\n");
+    }
+
+    #[test]
+    fn modes_go_after_the_program_number() {
+        let src = "O1000\nG91 G0 X10";
+        let lines = DistanceConverter::new(parse(src).into_iter(), DistanceMode::Absolute)
+            .starting_at(Point::new(0.0, 0.0, 0.0));
+        assert_eq!(write(lines).unwrap(), "O1000\nG90 G0 X10\n");
+
+        let lines = UnitConverter::new(parse(src).into_iter(),
+                                       Units::Inches,
+                                       Units::Millimetres);
+        assert_eq!(write(lines).unwrap(), "O1000\nG21 G91 G0 X254\n");
+    }
+}
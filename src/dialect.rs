@@ -0,0 +1,138 @@
+//! The different flavours of gcode understood by this crate.
+//!
+//! Most of the crate follows RS274/NGC, but a handful of things (e.g. which
+//! codes exist, or what a `T` word means) depend on the firmware the program
+//! was written for.
+
+use parser::{CommandKind, Number};
+use stats::Code;
+
+
+/// A flavour of gcode, used to decide which codes are known.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dialect {
+    /// The NIST RS274/NGC language, as extended by LinuxCNC.
+    Rs274Ngc,
+    /// Grbl 1.1.
+    Grbl,
+    /// Marlin 2 (3D printers).
+    Marlin,
+}
+
+impl Dialect {
+    /// Does the dialect understand this code? Tool selection (`T`) is always
+    /// understood.
+    pub fn supports(&self, code: Code) -> bool {
+        match (*self, code.kind) {
+            (_, CommandKind::T) => true,
+            (Dialect::Rs274Ngc, CommandKind::G) => rs274ngc_g(code.number),
+            (Dialect::Rs274Ngc, CommandKind::M) => {
+                match code.number {
+                    Number::Integer(n) => {
+                        matches!(n, 0..=9 | 19 | 30 | 48..=53 | 60..=68 | 70..=73 | 98..=199)
+                    }
+                    Number::Decimal(..) => false,
+                }
+            }
+            (Dialect::Grbl, CommandKind::G) => grbl_g(code.number),
+            (Dialect::Grbl, CommandKind::M) => {
+                matches!(code.number,
+                         Number::Integer(0..=5) | Number::Integer(7..=9) |
+                         Number::Integer(30) | Number::Integer(56))
+            }
+            (Dialect::Marlin, CommandKind::G) => marlin_g(code.number),
+            (Dialect::Marlin, CommandKind::M) => {
+                match code.number {
+                    Number::Integer(n) => marlin_m(n),
+                    Number::Decimal(..) => false,
+                }
+            }
+        }
+    }
+
+    /// The codes where the rest of the line is a string argument, for use
+    /// with `Tokenizer::with_string_codes()`.
+    pub fn string_codes(&self) -> &'static [(char, u32)] {
+        match *self {
+            Dialect::Marlin => MARLIN_STRING_CODES,
+            Dialect::Rs274Ngc | Dialect::Grbl => &[],
+        }
+    }
+
+    /// Is a `T` word following this kind of command one of its arguments
+    /// (e.g. the extruder in Marlin's `M104 S200 T1`), rather than a tool
+    /// selection?
+    pub fn t_is_argument(&self, kind: CommandKind) -> bool {
+        *self == Dialect::Marlin && kind == CommandKind::M
+    }
+
+    /// Can a word be given without a value (e.g. the axes to home in
+    /// Marlin's `G28 X Y`)? Everywhere else that's a syntax error.
+    pub fn allows_flags(&self) -> bool {
+        *self == Dialect::Marlin
+    }
+}
+
+/// Marlin's pause prompts (`M0`/`M1`), file names (`M23`, `M28`, `M30` and
+/// `M32`) and messages (`M117`/`M118`).
+const MARLIN_STRING_CODES: &[(char, u32)] = &[('M', 0),
+                                              ('M', 1),
+                                              ('M', 23),
+                                              ('M', 28),
+                                              ('M', 30),
+                                              ('M', 32),
+                                              ('M', 117),
+                                              ('M', 118)];
+
+fn rs274ngc_g(number: Number) -> bool {
+    match number {
+        Number::Integer(n) => {
+            matches!(n,
+                     0..=5 | 7 | 8 | 10 | 17..=21 | 28 | 30 | 33 | 40..=43 | 49 | 52..=59 |
+                     61 | 64 | 70..=76 | 80..=99)
+        }
+        Number::Decimal(n, fraction) => {
+            matches!((n, fraction),
+                     (5, 1..=3) | (17..=19, 1) | (28, 1) | (30, 1) | (33, 1) | (38, 2..=5) |
+                     (41, 1) | (42, 1) | (43, 1..=2) | (59, 1..=3) | (61, 1) | (90, 1) |
+                     (91, 1) | (92, 1..=3))
+        }
+    }
+}
+
+fn grbl_g(number: Number) -> bool {
+    match number {
+        Number::Integer(n) => {
+            matches!(n,
+                     0..=4 | 10 | 17..=21 | 28 | 30 | 40 | 49 | 53..=59 | 61 | 80 | 90..=94)
+        }
+        Number::Decimal(n, fraction) => {
+            matches!((n, fraction),
+                     (28, 1) | (30, 1) | (38, 2..=5) | (43, 1) | (91, 1) | (92, 1))
+        }
+    }
+}
+
+fn marlin_g(number: Number) -> bool {
+    match number {
+        Number::Integer(n) => {
+            matches!(n,
+                     0..=6 | 10..=12 | 17..=21 | 26..=35 | 42 | 53..=61 | 76 | 80 | 90..=92 |
+                     425)
+        }
+        Number::Decimal(n, fraction) => {
+            matches!((n, fraction), (38, 2..=5) | (59, 1..=3))
+        }
+    }
+}
+
+fn marlin_m(n: u32) -> bool {
+    matches!(n,
+             0 | 1 | 3..=5 | 7..=12 | 16..=34 | 42 | 43 | 48 | 73 | 75..=78 | 80..=87 | 92 |
+             100 | 102 | 104..=125 | 126..=129 | 140..=145 | 149..=155 | 163..=166 |
+             190..=193 | 200..=221 | 226 | 240 | 250 | 256 | 260 | 261 | 280..=290 | 300..=306 |
+             350 | 351 | 355 | 360..=364 | 380 | 381 | 400..=430 | 486 | 500..=504 |
+             510..=512 | 524 | 540 | 569 | 575 | 593 | 600 | 603 | 605 | 665 | 666 | 672 |
+             701 | 702 | 710 | 808 | 851 | 852 | 871 | 876 | 900 | 906..=919 | 928 | 951 |
+             993..=999 | 7219)
+}
@@ -0,0 +1,682 @@
+//! Cycle time estimation.
+//!
+//! Dividing each move's length by its feed rate badly underestimates how
+//! long a program takes, because the machine spends a lot of its time
+//! speeding up and slowing down. The `Estimator` runs moves through the same
+//! kind of look-ahead planner a motion controller uses, limiting speeds by
+//! each axis' maximum velocity and acceleration and by how sharp the corners
+//! between moves are.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Interpreter;
+//! use gcode::estimate::{Estimator, Kinematics, Report};
+//!
+//! let src = "G0 X10 Y10\nT1 M6\nG1 X100 F1200\nG4 P2.5\nG0 Z5";
+//!
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//! let events = Interpreter::new(lines);
+//!
+//! let report: Report = Estimator::new(events, Kinematics::default())
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//!
+//! assert!(report.total > 2.5 + 90.0 / 20.0);
+//! assert_eq!(report.lines.len(), 5);
+//! ```
+
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
+use core::iter::FromIterator;
+use arrayvec::ArrayVec;
+
+use interpreter::{Event, Move, MoveKind, Plane, Point, Direction};
+use errors::*;
+use helpers::{abs, sqrt};
+
+
+/// The number of moves the planner looks ahead.
+pub const PLANNER_BUFFER: usize = 32;
+
+/// Moves shorter than this (in mm) take no time.
+const EPSILON: f32 = 1e-6;
+
+
+/// What the machine is capable of.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Kinematics {
+    /// The fastest each axis can move while feeding, in mm/min.
+    pub max_velocity: Point,
+    /// How fast each axis moves during rapids, in mm/min.
+    pub rapid_rate: Point,
+    /// The maximum acceleration of each axis, in mm/s².
+    pub max_acceleration: Point,
+    /// The maximum jerk in mm/s³. When set, speed changes follow an S-curve
+    /// instead of a trapezoid.
+    pub max_jerk: Option<f32>,
+    /// How far the tool may stray from a sharp corner, in mm. This is used
+    /// to work out how fast the machine can go around corners, in the same
+    /// way as Grbl and Marlin.
+    pub junction_deviation: f32,
+    /// How long a tool change takes, in seconds.
+    pub tool_change_time: f32,
+}
+
+impl Default for Kinematics {
+    fn default() -> Kinematics {
+        Kinematics {
+            max_velocity: Point::new(5000.0, 5000.0, 5000.0),
+            rapid_rate: Point::new(5000.0, 5000.0, 5000.0),
+            max_acceleration: Point::new(500.0, 500.0, 500.0),
+            max_jerk: None,
+            junction_deviation: 0.01,
+            tool_change_time: 0.0,
+        }
+    }
+}
+
+
+/// How long something took.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timing {
+    /// The event being timed.
+    pub event: Event,
+    /// How long it takes, in seconds.
+    pub duration: f32,
+    /// The tool in the spindle (the new tool for tool changes).
+    pub tool: u32,
+}
+
+
+/// An iterator adaptor which works out how long each `Event` takes.
+///
+/// Moves are held back until the planner has looked far enough ahead (or
+/// the machine has to stop anyway, e.g. for a dwell), so `Timing`s come out
+/// in bursts.
+#[derive(Debug, Clone)]
+pub struct Estimator<I>
+    where I: Iterator<Item = Result<Event>>
+{
+    events: I,
+    kinematics: Kinematics,
+    segments: ArrayVec<[Segment; PLANNER_BUFFER]>,
+    /// An event which is waiting for the moves before it to finish.
+    waiting: Option<Event>,
+    /// Should we come to a stop at the end of the buffered moves?
+    flushing: bool,
+    tool: u32,
+}
+
+impl<I> Estimator<I>
+    where I: Iterator<Item = Result<Event>>
+{
+    /// Create a new `Estimator`.
+    pub fn new(events: I, kinematics: Kinematics) -> Estimator<I> {
+        Estimator {
+            events,
+            kinematics,
+            segments: ArrayVec::new(),
+            waiting: None,
+            flushing: false,
+            tool: 0,
+        }
+    }
+
+    fn push_move(&mut self, m: Move) -> Result<()> {
+        let mut segment = Segment::new(m, &self.kinematics)?;
+
+        if let Some(previous) = self.segments.last() {
+            if segment.length < EPSILON {
+                // zero-length moves don't change direction
+                segment.start_direction = previous.end_direction;
+                segment.end_direction = previous.end_direction;
+            }
+
+            let acceleration = previous.acceleration.min(segment.acceleration);
+            let junction = self.junction_speed(previous.end_direction,
+                                               segment.start_direction,
+                                               acceleration);
+            segment.max_entry_speed = junction.min(previous.nominal_speed)
+                .min(segment.nominal_speed);
+        }
+
+        self.segments.push(segment);
+        self.plan();
+        Ok(())
+    }
+
+    /// The fastest the machine can go around a corner, using the junction
+    /// deviation approximation.
+    fn junction_speed(&self, incoming: Point, outgoing: Point, acceleration: f32) -> f32 {
+        let cos_theta = -dot(incoming, outgoing);
+
+        if cos_theta > 0.999_999 {
+            // a full reversal
+            0.0
+        } else if cos_theta < -0.999_999 {
+            // straight ahead
+            f32::INFINITY
+        } else {
+            let sin_half_theta = sqrt(0.5 * (1.0 - cos_theta));
+            sqrt(acceleration * self.kinematics.junction_deviation * sin_half_theta /
+                 (1.0 - sin_half_theta))
+        }
+    }
+
+    /// Work out the fastest each buffered move can be entered, assuming we
+    /// stop at the end of the buffer. The first move's entry speed is fixed.
+    fn plan(&mut self) {
+        let count = self.segments.len();
+
+        let mut next_entry = 0.0;
+        for segment in self.segments[1..].iter_mut().rev() {
+            let reachable = reachable(next_entry, segment.acceleration, segment.length);
+            segment.entry_speed = segment.max_entry_speed.min(reachable);
+            next_entry = segment.entry_speed;
+        }
+
+        for i in 1..count {
+            let previous = self.segments[i - 1];
+            let reachable = reachable(previous.entry_speed, previous.acceleration, previous.length);
+            let segment = &mut self.segments[i];
+            segment.entry_speed = segment.entry_speed.min(reachable);
+        }
+    }
+
+    fn pop_segment(&mut self) -> Timing {
+        let exit_speed = self.segments.get(1).map_or(0.0, |s| s.entry_speed);
+        let segment = self.segments.remove(0);
+
+        let duration = if segment.length < EPSILON {
+            0.0
+        } else {
+            let Segment { entry_speed, nominal_speed, acceleration, length, .. } = segment;
+            match self.kinematics.max_jerk {
+                Some(jerk) => {
+                    s_curve_time(entry_speed, exit_speed, nominal_speed, acceleration, jerk, length)
+                }
+                None => trapezoid_time(entry_speed, exit_speed, nominal_speed, acceleration, length),
+            }
+        };
+
+        self.tool = segment.m.tool;
+        Timing {
+            event: Event::Move(segment.m),
+            duration,
+            tool: segment.m.tool,
+        }
+    }
+
+    fn time(&mut self, event: Event) -> Timing {
+        let duration = match event {
+            Event::Dwell { seconds, .. } => seconds,
+            Event::ToolChange { tool, .. } => {
+                self.tool = tool;
+                self.kinematics.tool_change_time
+            }
+            // there's no way of knowing how long heating up will take
+            Event::Move(_) | Event::ProgramEnd | Event::WaitForTemperature { .. } => 0.0,
+        };
+
+        Timing {
+            event,
+            duration,
+            tool: self.tool,
+        }
+    }
+}
+
+impl<I> Iterator for Estimator<I>
+    where I: Iterator<Item = Result<Event>>
+{
+    type Item = Result<Timing>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if !self.segments.is_empty() && (self.flushing || self.segments.is_full()) {
+                return Some(Ok(self.pop_segment()));
+            }
+
+            if let Some(event) = self.waiting.take() {
+                self.flushing = false;
+                return Some(Ok(self.time(event)));
+            }
+
+            match self.events.next() {
+                Some(Ok(Event::Move(m))) => {
+                    if let Err(e) = self.push_move(m) {
+                        return Some(Err(e));
+                    }
+                }
+                Some(Ok(event)) => {
+                    // everything else needs the machine to stop first
+                    self.waiting = Some(event);
+                    self.flushing = true;
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None if self.segments.is_empty() => return None,
+                None => self.flushing = true,
+            }
+        }
+    }
+}
+
+
+/// A move, as seen by the planner. All speeds are in mm/s.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Segment {
+    m: Move,
+    length: f32,
+    /// The direction of travel at the start of the move.
+    start_direction: Point,
+    /// The direction of travel at the end of the move.
+    end_direction: Point,
+    /// The speed we'd like to travel at.
+    nominal_speed: f32,
+    acceleration: f32,
+    /// The fastest we could possibly enter this move, given the corner
+    /// between it and the previous move.
+    max_entry_speed: f32,
+    /// The planned entry speed.
+    entry_speed: f32,
+}
+
+impl Segment {
+    fn new(m: Move, kinematics: &Kinematics) -> Result<Segment> {
+        let length = m.length();
+        let (start_direction, end_direction) = directions(&m);
+
+        let (max_velocity, acceleration) = match m.kind {
+            MoveKind::Rapid => {
+                (along(kinematics.rapid_rate, start_direction),
+                 along(kinematics.max_acceleration, start_direction))
+            }
+            MoveKind::Arc { plane, .. } => {
+                // the arc's direction keeps changing, so use the slowest axis
+                let velocity = in_plane(kinematics.max_velocity, plane);
+                let acceleration = in_plane(kinematics.max_acceleration, plane);
+                (velocity, acceleration)
+            }
+            MoveKind::Linear | MoveKind::Threading { .. } => {
+                (along(kinematics.max_velocity, start_direction),
+                 along(kinematics.max_acceleration, start_direction))
+            }
+        };
+        let mut nominal_speed = max_velocity / 60.0;
+
+        if m.kind != MoveKind::Rapid && length >= EPSILON {
+            if m.feed_rate <= 0.0 {
+                return Err(Error::InvalidCommand("Feed moves need a positive feed rate"));
+            }
+            nominal_speed = nominal_speed.min(m.feed_rate / 60.0);
+        }
+
+        if let MoveKind::Arc { centre, plane, .. } = m.kind {
+            // stay within the acceleration limit going around the arc
+            let (a, b, _) = plane.split(m.start - centre);
+            let radius = sqrt(a * a + b * b);
+            nominal_speed = nominal_speed.min(sqrt(acceleration * radius));
+        }
+
+        if length < EPSILON {
+            nominal_speed = f32::INFINITY;
+        }
+
+        Ok(Segment {
+               m,
+               length,
+               start_direction,
+               end_direction,
+               nominal_speed,
+               acceleration,
+               max_entry_speed: 0.0,
+               entry_speed: 0.0,
+           })
+    }
+}
+
+/// The unit vectors for the direction of travel at the start and end of a
+/// move.
+fn directions(m: &Move) -> (Point, Point) {
+    match m.kind {
+        MoveKind::Arc { centre, direction, plane } => {
+            let tangent = |p: Point| {
+                let (a, b, _) = plane.split(p - centre);
+                let radius = sqrt(a * a + b * b);
+                if radius < EPSILON {
+                    return Point::default();
+                }
+
+                let (a, b) = match direction {
+                    Direction::CounterClockwise => (-b / radius, a / radius),
+                    Direction::Clockwise => (b / radius, -a / radius),
+                };
+                plane.join(a, b, 0.0)
+            };
+
+            (tangent(m.start), tangent(m.end))
+        }
+        _ => {
+            let delta = m.end - m.start;
+            let length = delta.length();
+            let direction = if length < EPSILON {
+                Point::default()
+            } else {
+                delta * (1.0 / length)
+            };
+
+            (direction, direction)
+        }
+    }
+}
+
+fn dot(left: Point, right: Point) -> f32 {
+    left.x * right.x + left.y * right.y + left.z * right.z
+}
+
+/// The limit for moving in a particular direction, given the limit for each
+/// axis.
+fn along(limits: Point, direction: Point) -> f32 {
+    let axis = |limit: f32, component: f32| if abs(component) < EPSILON {
+        f32::INFINITY
+    } else {
+        limit / abs(component)
+    };
+
+    axis(limits.x, direction.x)
+        .min(axis(limits.y, direction.y))
+        .min(axis(limits.z, direction.z))
+}
+
+/// The smaller of the limits for a plane's two axes.
+fn in_plane(limits: Point, plane: Plane) -> f32 {
+    let (a, b, _) = plane.split(limits);
+    a.min(b)
+}
+
+/// The fastest you can be going after accelerating over some distance.
+fn reachable(speed: f32, acceleration: f32, distance: f32) -> f32 {
+    if distance < EPSILON {
+        speed
+    } else {
+        sqrt(speed * speed + 2.0 * acceleration * distance)
+    }
+}
+
+/// How long a move takes when accelerating at a constant rate up to the
+/// nominal speed, cruising, then decelerating to the exit speed.
+fn trapezoid_time(entry: f32, exit: f32, nominal: f32, acceleration: f32, length: f32) -> f32 {
+    let accelerating = (nominal * nominal - entry * entry) / (2.0 * acceleration);
+    let decelerating = (nominal * nominal - exit * exit) / (2.0 * acceleration);
+
+    if accelerating + decelerating <= length {
+        let cruising = length - accelerating - decelerating;
+        (nominal - entry) / acceleration + (nominal - exit) / acceleration + cruising / nominal
+    } else {
+        // we never reach the nominal speed
+        let peak_squared = (2.0 * acceleration * length + entry * entry + exit * exit) / 2.0;
+        let peak = sqrt(peak_squared).max(entry).max(exit);
+        (peak - entry) / acceleration + (peak - exit) / acceleration
+    }
+}
+
+/// The time and distance needed to change speed when the rate of change of
+/// acceleration is limited.
+fn s_curve_change(from: f32, to: f32, acceleration: f32, jerk: f32) -> (f32, f32) {
+    let delta = abs(to - from);
+    let time = if delta >= acceleration * acceleration / jerk {
+        delta / acceleration + acceleration / jerk
+    } else {
+        2.0 * sqrt(delta / jerk)
+    };
+
+    (time, (from + to) / 2.0 * time)
+}
+
+/// Like `trapezoid_time()`, except with a jerk-limited (S-curve) profile.
+fn s_curve_time(entry: f32,
+                exit: f32,
+                nominal: f32,
+                acceleration: f32,
+                jerk: f32,
+                length: f32)
+                -> f32 {
+    let profile = |peak: f32| {
+        let (t1, d1) = s_curve_change(entry, peak, acceleration, jerk);
+        let (t2, d2) = s_curve_change(peak, exit, acceleration, jerk);
+        (t1 + t2, d1 + d2)
+    };
+
+    let (time, distance) = profile(nominal);
+    if distance <= length {
+        return time + (length - distance) / nominal;
+    }
+
+    let mut low = entry.max(exit);
+    let mut high = nominal;
+    if profile(low).1 > length {
+        // the planner asked for more than a jerk-limited machine can do
+        return trapezoid_time(entry, exit, nominal, acceleration, length);
+    }
+
+    // find the highest peak speed which fits
+    for _ in 0..32 {
+        let middle = (low + high) / 2.0;
+        if profile(middle).1 <= length {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    let (time, distance) = profile(low);
+    if low > 0.0 {
+        time + (length - distance) / low
+    } else {
+        time
+    }
+}
+
+
+/// A summary of how long a program takes to run.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// The total time, in seconds.
+    pub total: f32,
+    /// The time spent using each tool.
+    pub tools: BTreeMap<u32, f32>,
+    /// The time spent on each line of source code (zero-based).
+    pub lines: BTreeMap<usize, f32>,
+}
+
+#[cfg(feature = "std")]
+impl Report {
+    /// Create an empty `Report`.
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    /// Add a `Timing` to the report.
+    pub fn add(&mut self, timing: &Timing) {
+        self.total += timing.duration;
+        *self.tools.entry(timing.tool).or_insert(0.0) += timing.duration;
+
+        if let Some(span) = timing.event.span() {
+            *self.lines.entry(span.line).or_insert(0.0) += timing.duration;
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl FromIterator<Timing> for Report {
+    fn from_iter<I: IntoIterator<Item = Timing>>(iter: I) -> Report {
+        let mut report = Report::new();
+
+        for timing in iter {
+            report.add(&timing);
+        }
+
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use std::string::String;
+    use core::f32::consts::PI;
+    use interpreter::Interpreter;
+    use helpers::parse;
+
+    fn kinematics() -> Kinematics {
+        Kinematics {
+            max_velocity: Point::new(6000.0, 6000.0, 6000.0),
+            rapid_rate: Point::new(12000.0, 12000.0, 3000.0),
+            max_acceleration: Point::new(1000.0, 1000.0, 1000.0),
+            max_jerk: None,
+            junction_deviation: 0.01,
+            tool_change_time: 10.0,
+        }
+    }
+
+    fn timings(src: &str, kinematics: Kinematics) -> Result<Vec<Timing>> {
+        let lines = parse(src).into_iter();
+
+        Estimator::new(Interpreter::new(lines), kinematics).collect()
+    }
+
+    fn total(src: &str, kinematics: Kinematics) -> f32 {
+        timings(src, kinematics).unwrap().iter().map(|t| t.duration).sum()
+    }
+
+    fn assert_close(left: f32, right: f32) {
+        assert!((left - right).abs() < 0.001, "{} != {}", left, right);
+    }
+
+    #[test]
+    fn accelerate_cruise_then_decelerate() {
+        // 5mm to get up to 100mm/s, 90mm cruising, then 5mm to stop
+        assert_close(total("G1 X100 F6000", kinematics()), 0.1 + 0.9 + 0.1);
+    }
+
+    #[test]
+    fn short_moves_never_reach_full_speed() {
+        let peak = sqrt(1000.0 * 2.0);
+        assert_close(total("G1 X2 F6000", kinematics()), 2.0 * peak / 1000.0);
+    }
+
+    #[test]
+    fn moves_in_a_straight_line_dont_slow_down() {
+        let got = timings("G1 X50 F6000\nX100", kinematics()).unwrap();
+
+        assert_eq!(got.len(), 2);
+        assert_close(got[0].duration + got[1].duration, 1.1);
+    }
+
+    #[test]
+    fn the_planner_only_looks_so_far_ahead() {
+        let mut src = String::from("G1 F6000\n");
+        for i in 1..=200 {
+            src.push_str(&format!("X{}\n", i));
+        }
+
+        assert_close(total(&src, kinematics()), 0.1 + 1.9 + 0.1);
+    }
+
+    #[test]
+    fn corners_slow_things_down() {
+        let straight = total("G1 X100 F6000", kinematics());
+        let stopping = 2.0 * total("G1 X50 F6000", kinematics());
+        let corner = total("G1 X50 F6000\nY50", kinematics());
+
+        assert!(straight < corner && corner < stopping,
+                "{} < {} < {}",
+                straight,
+                corner,
+                stopping);
+
+        // a sloppier machine gets around corners faster
+        let mut sloppy = kinematics();
+        sloppy.junction_deviation = 1.0;
+        assert!(total("G1 X50 F6000\nY50", sloppy) < corner);
+    }
+
+    #[test]
+    fn axes_have_their_own_limits() {
+        let mut slow_z = kinematics();
+        slow_z.max_velocity.z = 600.0;
+
+        // 10mm/s means 0.5mm to accelerate and decelerate
+        assert_close(total("G1 Z-10 F6000", slow_z), 0.01 + 0.99 + 0.01);
+        assert_close(total("G1 X100 F6000", slow_z), 1.1);
+    }
+
+    #[test]
+    fn rapids_use_the_rapid_rate() {
+        // 200mm/s means 20mm to accelerate and decelerate
+        assert_close(total("G0 X100", kinematics()), 0.2 + 0.3 + 0.2);
+        assert_close(total("G0 Z100", kinematics()), 0.05 + 1.95 + 0.05);
+    }
+
+    #[test]
+    fn arcs_are_limited_by_centripetal_acceleration() {
+        let radius = 1.0;
+        let speed = sqrt(1000.0 * radius);
+        let circumference = 2.0 * PI * radius;
+
+        let got = total("G3 X0 Y0 I1 J0 F6000", kinematics());
+
+        let accelerating = speed / 1000.0;
+        let distance = speed * accelerating;
+        assert_close(got, 2.0 * accelerating + (circumference - distance) / speed);
+    }
+
+    #[test]
+    fn s_curves_take_longer() {
+        let mut jerky = kinematics();
+        jerky.max_jerk = Some(10_000.0);
+
+        let trapezoid = total("G1 X100 F6000", kinematics());
+        let s_curve = total("G1 X100 F6000", jerky);
+
+        // reaching full acceleration takes 0.1s, so each speed change takes
+        // an extra 0.1s
+        assert_close(s_curve, trapezoid + 0.1);
+    }
+
+    #[test]
+    fn dwells_and_tool_changes() {
+        let got = timings("G1 X100 F6000\nG4 P2.5\nT3 M6\nG0 Z100", kinematics()).unwrap();
+
+        let durations: Vec<_> = got.iter().map(|t| t.duration).collect();
+        assert_close(durations[0], 1.1);
+        assert_eq!(durations[1..3], [2.5, 10.0]);
+        assert_eq!(got[3].tool, 3);
+    }
+
+    #[test]
+    fn feed_moves_need_a_feed_rate() {
+        assert!(timings("G1 X10", kinematics()).is_err());
+        assert!(timings("G0 X10", kinematics()).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn report_breakdowns() {
+        let src = "G1 X100 F6000\nG4 P2.5\nT3 M6\nG0 Z100\nM2";
+        let report: Report = timings(src, kinematics()).unwrap().into_iter().collect();
+
+        assert_close(report.total, 1.1 + 2.5 + 10.0 + 2.05);
+        assert_close(report.tools[&0], 1.1 + 2.5);
+        assert_close(report.tools[&3], 10.0 + 2.05);
+        assert_eq!(report.lines.keys().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_close(report.lines[&1], 2.5);
+    }
+}
@@ -0,0 +1,811 @@
+//! Reading the extra commands understood by Grbl.
+//!
+//! As well as normal gcode, a program (or anything else sent to a Grbl
+//! controller) can contain:
+//!
+//! - system commands, which are lines starting with a `$` (e.g. `$H` to
+//!   home, `$X` to clear an alarm or `$J=G91 X10 F500` to jog)
+//! - realtime commands, which are single bytes (e.g. `?` for a status report
+//!   or `!` for a feed hold) that Grbl picks out of the stream as soon as it
+//!   receives them, even in the middle of a line
+//!
+//! The `Frontend` splits a program into `Block`s, giving back realtime
+//! commands before the rest of the line they were found in, the same way
+//! Grbl would act on them. Most realtime commands aren't valid UTF-8 on
+//! their own, so `Frontend::from_bytes()` reads what was actually sent down
+//! the wire.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::grbl::{Frontend, Block, SystemCommand, Realtime};
+//!
+//! let src = "$X\n$J=G91 X10 F500\nG0 X1?\n$110=500.5";
+//! let blocks: Vec<_> = Frontend::new(src).collect::<Result<_, _>>().unwrap();
+//!
+//! assert_eq!(blocks[0], Block::System(SystemCommand::KillAlarmLock));
+//! match blocks[1] {
+//!     Block::System(SystemCommand::Jog(ref jog)) => {
+//!         assert_eq!(jog.x, Some(10.0));
+//!         assert_eq!(jog.feed_rate, 500.0);
+//!     }
+//!     ref other => panic!("Unexpected block: {:?}", other),
+//! }
+//! assert_eq!(blocks[2], Block::Realtime(Realtime::StatusReport));
+//! match blocks[3] {
+//!     Block::Line(ref line) => assert_eq!(line.span().unwrap().line, 2),
+//!     ref other => panic!("Unexpected block: {:?}", other),
+//! }
+//! assert_eq!(blocks[4],
+//!            Block::System(SystemCommand::SetSetting {
+//!                              number: 110,
+//!                              value: 500.5,
+//!                          }));
+//! ```
+
+use core::fmt::{self, Display, Formatter, Write};
+use core::iter::{Enumerate, Filter, FilterMap};
+use core::slice::{self, Split};
+use core::str::{Chars, Lines};
+use arrayvec::ArrayString;
+
+use lexer::{Tokenizer, Token, TokenKind, Span, Text};
+use parser::{Parser, Line, Args, CommandKind, Number};
+use interpreter::{DistanceMode, Units};
+use writer::PRECISION;
+use errors::*;
+use helpers::{Decimals, move_to_line, error_on_line};
+
+
+/// The longest line Grbl will accept, not counting spaces.
+pub const LINE_BUFFER_SIZE: usize = 80;
+
+
+/// Something read by the `Frontend`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Block {
+    /// A realtime command.
+    Realtime(Realtime),
+    /// A `$` system command.
+    System(SystemCommand),
+    /// A normal line of gcode.
+    Line(Line),
+}
+
+/// A command which Grbl acts on as soon as it receives it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Realtime {
+    /// Reset the controller, stopping immediately (`0x18`, ctrl-x).
+    SoftReset,
+    /// Ask for a status report (`?`).
+    StatusReport,
+    /// Start or resume a cycle (`~`).
+    CycleStart,
+    /// Pause the current motion in a controlled way (`!`).
+    FeedHold,
+    /// Act as if the safety door was opened (`0x84`).
+    SafetyDoor,
+    /// Stop jogging (`0x85`).
+    JogCancel,
+    /// Change the feed rate override (`0x90` to `0x94`).
+    FeedOverride(Adjustment),
+    /// Change the rapid override (`0x95` to `0x97`).
+    RapidOverride(RapidOverride),
+    /// Change the spindle speed override (`0x99` to `0x9D`).
+    SpindleOverride(Adjustment),
+    /// Stop or restart the spindle while in a feed hold (`0x9E`).
+    ToggleSpindleStop,
+    /// Turn flood coolant on or off (`0xA0`).
+    ToggleFloodCoolant,
+    /// Turn mist coolant on or off (`0xA1`).
+    ToggleMistCoolant,
+}
+
+/// A change to the feed rate or spindle speed override.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Adjustment {
+    /// Go back to 100%.
+    Reset,
+    /// Add 10%.
+    CoarseIncrease,
+    /// Subtract 10%.
+    CoarseDecrease,
+    /// Add 1%.
+    FineIncrease,
+    /// Subtract 1%.
+    FineDecrease,
+}
+
+/// How fast rapid moves go, compared to their normal speed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RapidOverride {
+    /// 100%.
+    Full,
+    /// 50%.
+    Half,
+    /// 25%.
+    Quarter,
+}
+
+impl Realtime {
+    /// The realtime command sent as this byte, if there is one.
+    pub fn from_byte(byte: u8) -> Option<Realtime> {
+        let realtime = match byte {
+            0x18 => Realtime::SoftReset,
+            b'?' => Realtime::StatusReport,
+            b'~' => Realtime::CycleStart,
+            b'!' => Realtime::FeedHold,
+            0x84 => Realtime::SafetyDoor,
+            0x85 => Realtime::JogCancel,
+            0x90..=0x94 => Realtime::FeedOverride(adjustment(byte - 0x90)),
+            0x95 => Realtime::RapidOverride(RapidOverride::Full),
+            0x96 => Realtime::RapidOverride(RapidOverride::Half),
+            0x97 => Realtime::RapidOverride(RapidOverride::Quarter),
+            0x99..=0x9D => Realtime::SpindleOverride(adjustment(byte - 0x99)),
+            0x9E => Realtime::ToggleSpindleStop,
+            0xA0 => Realtime::ToggleFloodCoolant,
+            0xA1 => Realtime::ToggleMistCoolant,
+            _ => return None,
+        };
+
+        Some(realtime)
+    }
+
+    /// The byte which sends this command.
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            Realtime::SoftReset => 0x18,
+            Realtime::StatusReport => b'?',
+            Realtime::CycleStart => b'~',
+            Realtime::FeedHold => b'!',
+            Realtime::SafetyDoor => 0x84,
+            Realtime::JogCancel => 0x85,
+            Realtime::FeedOverride(a) => 0x90 + adjustment_offset(a),
+            Realtime::RapidOverride(RapidOverride::Full) => 0x95,
+            Realtime::RapidOverride(RapidOverride::Half) => 0x96,
+            Realtime::RapidOverride(RapidOverride::Quarter) => 0x97,
+            Realtime::SpindleOverride(a) => 0x99 + adjustment_offset(a),
+            Realtime::ToggleSpindleStop => 0x9E,
+            Realtime::ToggleFloodCoolant => 0xA0,
+            Realtime::ToggleMistCoolant => 0xA1,
+        }
+    }
+
+    /// The realtime command sent as this character, if there is one.
+    /// Characters above `U+00FF` never are, and the rest are treated as
+    /// the byte with the same value.
+    pub fn from_char(c: char) -> Option<Realtime> {
+        if (c as u32) <= 0xFF {
+            Realtime::from_byte(c as u8)
+        } else {
+            None
+        }
+    }
+}
+
+fn adjustment(offset: u8) -> Adjustment {
+    match offset {
+        0 => Adjustment::Reset,
+        1 => Adjustment::CoarseIncrease,
+        2 => Adjustment::CoarseDecrease,
+        3 => Adjustment::FineIncrease,
+        _ => Adjustment::FineDecrease,
+    }
+}
+
+fn adjustment_offset(adjustment: Adjustment) -> u8 {
+    match adjustment {
+        Adjustment::Reset => 0,
+        Adjustment::CoarseIncrease => 1,
+        Adjustment::CoarseDecrease => 2,
+        Adjustment::FineIncrease => 3,
+        Adjustment::FineDecrease => 4,
+    }
+}
+
+
+/// A line starting with `$`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SystemCommand {
+    /// Print a summary of the system commands (`$`).
+    Help,
+    /// Print every setting (`$$`).
+    ViewSettings,
+    /// Print the work offsets, tool length offset and probe result (`$#`).
+    ViewParameters,
+    /// Print the active modal state (`$G`).
+    ViewParserState,
+    /// Print the version and build options (`$I`).
+    ViewBuildInfo,
+    /// Print the blocks run at startup (`$N`).
+    ViewStartupBlocks,
+    /// Change a setting (e.g. `$110=500`).
+    SetSetting {
+        /// The setting's number.
+        number: u32,
+        /// Its new value.
+        value: f32,
+    },
+    /// Change one of the blocks run at startup (e.g. `$N0=G20G54`).
+    SetStartupBlock {
+        /// Which block to change (`0` or `1`).
+        index: u32,
+        /// The new block, which may be empty.
+        block: Text,
+    },
+    /// Store a string with the build info (`$I=...`).
+    SetBuildInfo(Text),
+    /// Reset settings or parameters to their defaults (`$RST=...`).
+    Restore(Restore),
+    /// Run the homing cycle (`$H`).
+    Home,
+    /// Clear an alarm without homing (`$X`).
+    KillAlarmLock,
+    /// Toggle check mode, where programs are parsed but not run (`$C`).
+    CheckMode,
+    /// Go to sleep (`$SLP`).
+    Sleep,
+    /// Jog the machine (`$J=...`).
+    Jog(Jog),
+}
+
+/// What to reset with `$RST=`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Restore {
+    /// The `$` settings (`$RST=$`).
+    Settings,
+    /// The work offsets (`$RST=#`).
+    Parameters,
+    /// Everything (`$RST=*`).
+    All,
+}
+
+/// A jogging motion (`$J=...`).
+///
+/// Jogs only accept `G20`/`G21`, `G90`/`G91`, `G53`, axis words and a feed
+/// rate, and only apply to the jog itself.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Jog {
+    /// The units, if they were given.
+    pub units: Option<Units>,
+    /// Whether the axis words are absolute or incremental, if given.
+    pub distance_mode: Option<DistanceMode>,
+    /// Are the axis words in machine coordinates (`G53`)?
+    pub machine_coordinates: bool,
+    /// Where to go on the X axis.
+    pub x: Option<f32>,
+    /// Where to go on the Y axis.
+    pub y: Option<f32>,
+    /// Where to go on the Z axis.
+    pub z: Option<f32>,
+    /// How fast to go.
+    pub feed_rate: f32,
+}
+
+impl SystemCommand {
+    /// Parse a system command, ignoring whitespace and case the same way
+    /// Grbl does.
+    pub fn parse(line: &str) -> Result<SystemCommand> {
+        SystemCommand::from_chars(line.chars())
+    }
+
+    fn from_chars<I>(chars: I) -> Result<SystemCommand>
+        where I: Iterator<Item = char>
+    {
+        let span = Span::from((0, 1));
+        let mut buffer = ArrayString::<[u8; 128]>::new();
+
+        for c in chars.filter(|c| !c.is_whitespace() && !c.is_control()) {
+            let c = c.to_ascii_uppercase();
+            if buffer.len() + c.len_utf8() > LINE_BUFFER_SIZE {
+                return Err(Error::SyntaxError("The line is too long", span));
+            }
+            buffer.push(c);
+        }
+
+        let rest = match buffer.as_str().strip_prefix('$') {
+            Some(rest) => rest,
+            None => return Err(Error::SyntaxError("System commands start with a \"$\"", span)),
+        };
+
+        let cmd = match rest {
+            "" => SystemCommand::Help,
+            "$" => SystemCommand::ViewSettings,
+            "#" => SystemCommand::ViewParameters,
+            "G" => SystemCommand::ViewParserState,
+            "I" => SystemCommand::ViewBuildInfo,
+            "N" => SystemCommand::ViewStartupBlocks,
+            "H" => SystemCommand::Home,
+            "X" => SystemCommand::KillAlarmLock,
+            "C" => SystemCommand::CheckMode,
+            "SLP" => SystemCommand::Sleep,
+            "RST=$" => SystemCommand::Restore(Restore::Settings),
+            "RST=#" => SystemCommand::Restore(Restore::Parameters),
+            "RST=*" => SystemCommand::Restore(Restore::All),
+            _ => {
+                if let Some(jog) = rest.strip_prefix("J=") {
+                    SystemCommand::Jog(parse_jog(jog)?)
+                } else if let Some(info) = rest.strip_prefix("I=") {
+                    SystemCommand::SetBuildInfo(text(info)?)
+                } else if let Some(startup) = rest.strip_prefix('N') {
+                    let (index, block) = split_assignment(startup)?;
+                    SystemCommand::SetStartupBlock {
+                        index,
+                        block: text(block)?,
+                    }
+                } else {
+                    let (number, value) = split_assignment(rest)?;
+                    let value = parse_number(value)
+                        .ok_or(Error::SyntaxError("Settings need a numeric value", span))?;
+                    SystemCommand::SetSetting { number, value }
+                }
+            }
+        };
+
+        Ok(cmd)
+    }
+}
+
+/// Split `"12=value"` into its number and value.
+fn split_assignment(s: &str) -> Result<(u32, &str)> {
+    let span = Span::from((0, 1));
+
+    match s.find('=') {
+        Some(equals) => {
+            match s[..equals].parse() {
+                Ok(number) => Ok((number, &s[equals + 1..])),
+                Err(_) => Err(Error::SyntaxError("Unknown system command", span)),
+            }
+        }
+        None => Err(Error::SyntaxError("Unknown system command", span)),
+    }
+}
+
+fn text(s: &str) -> Result<Text> {
+    Text::new(s).ok_or(Error::SyntaxError("The line is too long", Span::from((0, 1))))
+}
+
+/// Parse a (possibly negative) number using the `Tokenizer`.
+fn parse_number(s: &str) -> Option<f32> {
+    let mut tokens = Tokenizer::new(s.chars());
+
+    let number = match tokens.next() {
+        Some(Ok(t)) if t.kind() == TokenKind::Minus => {
+            match tokens.next() {
+                Some(Ok(t)) => {
+                    match t.kind() {
+                        TokenKind::Number(n) => -n,
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(Ok(t)) => {
+            match t.kind() {
+                TokenKind::Number(n) => n,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    match tokens.next() {
+        None => Some(number),
+        Some(_) => None,
+    }
+}
+
+fn parse_jog(src: &str) -> Result<Jog> {
+    let invalid = Error::SyntaxError("Invalid jog command", Span::from((0, 1)));
+    let mut jog = Jog {
+        units: None,
+        distance_mode: None,
+        machine_coordinates: false,
+        x: None,
+        y: None,
+        z: None,
+        feed_rate: 0.0,
+    };
+    let mut feed_rate = None;
+
+    if let Some(Err(e)) = Tokenizer::new(src.chars()).find(|t| t.is_err()) {
+        return Err(e);
+    }
+
+    let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+    for line in Parser::new(tokens) {
+        let args = match line? {
+            Line::Cmd(cmd) => {
+                match (cmd.kind, cmd.number) {
+                    (CommandKind::G, Number::Integer(20)) => jog.units = Some(Units::Inches),
+                    (CommandKind::G, Number::Integer(21)) => jog.units = Some(Units::Millimetres),
+                    (CommandKind::G, Number::Integer(53)) => jog.machine_coordinates = true,
+                    (CommandKind::G, Number::Integer(90)) => {
+                        jog.distance_mode = Some(DistanceMode::Absolute)
+                    }
+                    (CommandKind::G, Number::Integer(91)) => {
+                        jog.distance_mode = Some(DistanceMode::Incremental)
+                    }
+                    _ => return Err(invalid),
+                }
+                cmd.args
+            }
+            Line::Args { args, .. } => args,
+            Line::ProgramNumber(_) => return Err(invalid),
+        };
+
+        let others = Args {
+            x: None,
+            y: None,
+            z: None,
+            f: None,
+            ..args
+        };
+        if !others.is_empty() {
+            return Err(invalid);
+        }
+
+        jog.x = args.x.or(jog.x);
+        jog.y = args.y.or(jog.y);
+        jog.z = args.z.or(jog.z);
+        feed_rate = args.f.or(feed_rate);
+    }
+
+    match feed_rate {
+        Some(f) if f > 0.0 && (jog.x.is_some() || jog.y.is_some() || jog.z.is_some()) => {
+            jog.feed_rate = f;
+            Ok(jog)
+        }
+        _ => Err(invalid),
+    }
+}
+
+impl Display for SystemCommand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SystemCommand::Help => f.write_str("$"),
+            SystemCommand::ViewSettings => f.write_str("$$"),
+            SystemCommand::ViewParameters => f.write_str("$#"),
+            SystemCommand::ViewParserState => f.write_str("$G"),
+            SystemCommand::ViewBuildInfo => f.write_str("$I"),
+            SystemCommand::ViewStartupBlocks => f.write_str("$N"),
+            SystemCommand::SetSetting { number, value } => {
+                write!(f, "${}={}", number, Decimals(value, PRECISION))
+            }
+            SystemCommand::SetStartupBlock { index, ref block } => {
+                write!(f, "$N{}={}", index, block)
+            }
+            SystemCommand::SetBuildInfo(ref info) => write!(f, "$I={}", info),
+            SystemCommand::Restore(Restore::Settings) => f.write_str("$RST=$"),
+            SystemCommand::Restore(Restore::Parameters) => f.write_str("$RST=#"),
+            SystemCommand::Restore(Restore::All) => f.write_str("$RST=*"),
+            SystemCommand::Home => f.write_str("$H"),
+            SystemCommand::KillAlarmLock => f.write_str("$X"),
+            SystemCommand::CheckMode => f.write_str("$C"),
+            SystemCommand::Sleep => f.write_str("$SLP"),
+            SystemCommand::Jog(ref jog) => write!(f, "$J={}", jog),
+        }
+    }
+}
+
+impl Display for Jog {
+    /// Write the jog's words without spaces (e.g. `G91G21X10F500`).
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.distance_mode {
+            Some(DistanceMode::Absolute) => f.write_str("G90")?,
+            Some(DistanceMode::Incremental) => f.write_str("G91")?,
+            None => {}
+        }
+        match self.units {
+            Some(Units::Inches) => f.write_str("G20")?,
+            Some(Units::Millimetres) => f.write_str("G21")?,
+            None => {}
+        }
+        if self.machine_coordinates {
+            f.write_str("G53")?;
+        }
+
+        for &(letter, value) in &[('X', self.x), ('Y', self.y), ('Z', self.z)] {
+            if let Some(value) = value {
+                f.write_char(letter)?;
+                write!(f, "{}", Decimals(value, PRECISION))?;
+            }
+        }
+
+        write!(f, "F{}", Decimals(self.feed_rate, PRECISION))
+    }
+}
+
+
+type Source<'a> = Filter<InputChars<'a>, fn(&char) -> bool>;
+type LineParser<'a> = Parser<FilterMap<Tokenizer<Source<'a>>, fn(Result<Token>) -> Option<Token>>>;
+
+/// Splits a program into realtime commands, system commands and normal
+/// lines of gcode.
+///
+/// Realtime characters are taken out of each line before anything else
+/// looks at it, so the columns in a line's spans don't count them.
+#[derive(Debug)]
+pub struct Frontend<'a> {
+    lines: Enumerate<InputLines<'a>>,
+    /// The current line, and what's left to scan for realtime commands.
+    current: Option<(usize, Input<'a>, InputChars<'a>)>,
+    parser: Option<(usize, LineParser<'a>)>,
+}
+
+impl<'a> Frontend<'a> {
+    /// Create a new `Frontend` which reads from some source code.
+    pub fn new(src: &'a str) -> Frontend<'a> {
+        Frontend {
+            lines: InputLines::Str(src.lines()).enumerate(),
+            current: None,
+            parser: None,
+        }
+    }
+
+    /// Create a new `Frontend` which reads the raw bytes sent to Grbl.
+    ///
+    /// Like Grbl, every byte is a character of its own, so realtime
+    /// commands (e.g. `0x85` to cancel a jog) are picked out before any
+    /// UTF-8 is decoded.
+    pub fn from_bytes(src: &'a [u8]) -> Frontend<'a> {
+        let newline: fn(&u8) -> bool = is_newline;
+
+        Frontend {
+            lines: InputLines::Bytes(src.split(newline)).enumerate(),
+            current: None,
+            parser: None,
+        }
+    }
+
+    /// Work out what the rest of a line is, once its realtime commands
+    /// have been pulled out.
+    fn start_line(&mut self, number: usize, line: Input<'a>) -> Option<Result<Block>> {
+        let not_realtime: fn(&char) -> bool = not_realtime;
+        let source = || line.chars().filter(not_realtime);
+
+        if source().find(|c| !c.is_whitespace()) == Some('$') {
+            let cmd = SystemCommand::from_chars(source()).map_err(|e| error_on_line(e, number));
+            return Some(cmd.map(Block::System));
+        }
+
+        if let Some(Err(e)) = Tokenizer::new(source()).find(|t| t.is_err()) {
+            return Some(Err(error_on_line(e, number)));
+        }
+
+        let ok: fn(Result<Token>) -> Option<Token> = Result::ok;
+        let tokens = Tokenizer::new(source()).filter_map(ok);
+        self.parser = Some((number, Parser::new(tokens)));
+        None
+    }
+}
+
+impl<'a> Iterator for Frontend<'a> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((number, line, ref mut scan)) = self.current {
+                if let Some(realtime) = scan.by_ref().filter_map(Realtime::from_char).next() {
+                    return Some(Ok(Block::Realtime(realtime)));
+                }
+
+                self.current = None;
+                if let Some(block) = self.start_line(number, line) {
+                    return Some(block);
+                }
+            }
+
+            if let Some((number, ref mut parser)) = self.parser {
+                match parser.next() {
+                    Some(Ok(mut line)) => {
+                        move_to_line(&mut line, number);
+                        return Some(Ok(Block::Line(line)));
+                    }
+                    Some(Err(e)) => return Some(Err(error_on_line(e, number))),
+                    None => self.parser = None,
+                }
+            }
+
+            match self.lines.next() {
+                Some((number, line)) => self.current = Some((number, line, line.chars())),
+                None => return None,
+            }
+        }
+    }
+}
+
+fn not_realtime(c: &char) -> bool {
+    Realtime::from_char(*c).is_none()
+}
+
+fn is_newline(b: &u8) -> bool {
+    *b == b'\n'
+}
+
+/// A line given to the `Frontend`, either as text or the raw bytes Grbl
+/// would receive.
+#[derive(Debug, Copy, Clone)]
+enum Input<'a> {
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Input<'a> {
+    fn chars(&self) -> InputChars<'a> {
+        match *self {
+            Input::Str(s) => InputChars::Str(s.chars()),
+            Input::Bytes(b) => InputChars::Bytes(b.iter()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum InputChars<'a> {
+    Str(Chars<'a>),
+    /// Each byte is read as the character with the same value.
+    Bytes(slice::Iter<'a, u8>),
+}
+
+impl<'a> Iterator for InputChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        match *self {
+            InputChars::Str(ref mut chars) => chars.next(),
+            InputChars::Bytes(ref mut bytes) => bytes.next().map(|&b| char::from(b)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum InputLines<'a> {
+    Str(Lines<'a>),
+    Bytes(Split<'a, u8, fn(&u8) -> bool>),
+}
+
+impl<'a> Iterator for InputLines<'a> {
+    type Item = Input<'a>;
+
+    fn next(&mut self) -> Option<Input<'a>> {
+        match *self {
+            InputLines::Str(ref mut lines) => lines.next().map(Input::Str),
+            InputLines::Bytes(ref mut lines) => {
+                lines.next().map(|line| match line.split_last() {
+                                     Some((&b'\r', rest)) => Input::Bytes(rest),
+                                     _ => Input::Bytes(line),
+                                 })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use std::string::ToString;
+
+    fn blocks(src: &str) -> Vec<Block> {
+        Frontend::new(src).collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn system_commands() {
+        let inputs = [("$", SystemCommand::Help),
+                      ("$$", SystemCommand::ViewSettings),
+                      ("$#", SystemCommand::ViewParameters),
+                      ("$g", SystemCommand::ViewParserState),
+                      ("$I", SystemCommand::ViewBuildInfo),
+                      ("$N", SystemCommand::ViewStartupBlocks),
+                      ("$H", SystemCommand::Home),
+                      ("$X", SystemCommand::KillAlarmLock),
+                      ("$C", SystemCommand::CheckMode),
+                      ("$SLP", SystemCommand::Sleep),
+                      ("$RST=*", SystemCommand::Restore(Restore::All)),
+                      ("$100 = 250.5",
+                       SystemCommand::SetSetting {
+                           number: 100,
+                           value: 250.5,
+                       }),
+                      ("$N0=G20 G54",
+                       SystemCommand::SetStartupBlock {
+                           index: 0,
+                           block: Text::new("G20G54").unwrap(),
+                       }),
+                      ("$N1=",
+                       SystemCommand::SetStartupBlock {
+                           index: 1,
+                           block: Text::new("").unwrap(),
+                       }),
+                      ("$I=my machine", SystemCommand::SetBuildInfo(Text::new("MYMACHINE").unwrap()))];
+
+        for &(src, should_be) in &inputs {
+            assert_eq!(SystemCommand::parse(src), Ok(should_be), "{}", src);
+        }
+    }
+
+    #[test]
+    fn invalid_system_commands() {
+        for src in &["$Q", "$abc=1", "$1=x", "$J=G1 X1 F10", "$J=X1", "$J=F100", "$J=G91 X1 S5 F1"] {
+            assert!(SystemCommand::parse(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn jogging() {
+        let got = SystemCommand::parse("$J=G91 G21 X10 Y-2.5 F500").unwrap();
+
+        let should_be = Jog {
+            units: Some(Units::Millimetres),
+            distance_mode: Some(DistanceMode::Incremental),
+            machine_coordinates: false,
+            x: Some(10.0),
+            y: Some(-2.5),
+            z: None,
+            feed_rate: 500.0,
+        };
+        assert_eq!(got, SystemCommand::Jog(should_be));
+        assert_eq!(got.to_string(), "$J=G91G21X10Y-2.5F500");
+        assert_eq!(SystemCommand::parse(&got.to_string()), Ok(got));
+    }
+
+    #[test]
+    fn realtime_bytes_round_trip() {
+        for byte in 0..=255 {
+            if let Some(realtime) = Realtime::from_byte(byte) {
+                assert_eq!(realtime.to_byte(), byte);
+            }
+        }
+
+        assert_eq!(Realtime::from_byte(0x93), Some(Realtime::FeedOverride(Adjustment::FineIncrease)));
+        assert_eq!(Realtime::from_byte(b'G'), None);
+    }
+
+    #[test]
+    fn realtime_commands_come_before_their_line() {
+        let got = blocks("G0 X1 ! Y2\n~\n\u{18}$H");
+
+        assert_eq!(got.len(), 5);
+        assert_eq!(got[0], Block::Realtime(Realtime::FeedHold));
+        match got[1] {
+            Block::Line(Line::Cmd(ref cmd)) => {
+                assert_eq!(cmd.args.x, Some(1.0));
+                assert_eq!(cmd.args.y, Some(2.0));
+            }
+            ref other => panic!("Unexpected block: {:?}", other),
+        }
+        assert_eq!(got[2], Block::Realtime(Realtime::CycleStart));
+        assert_eq!(got[3], Block::Realtime(Realtime::SoftReset));
+        assert_eq!(got[4], Block::System(SystemCommand::Home));
+    }
+
+    #[test]
+    fn realtime_bytes_which_arent_utf8() {
+        let src = b"$J=G91 X10\x85 F500\r\nG1 X1\x91 F100\n\xa0";
+        let got: Vec<_> = Frontend::from_bytes(src).collect::<Result<_>>().unwrap();
+
+        assert_eq!(got.len(), 5);
+        assert_eq!(got[0], Block::Realtime(Realtime::JogCancel));
+        match got[1] {
+            Block::System(SystemCommand::Jog(ref jog)) => assert_eq!(jog.feed_rate, 500.0),
+            ref other => panic!("Unexpected block: {:?}", other),
+        }
+        assert_eq!(got[2], Block::Realtime(Realtime::FeedOverride(Adjustment::CoarseIncrease)));
+        match got[3] {
+            Block::Line(ref line) => assert_eq!(line.span().unwrap().line, 1),
+            ref other => panic!("Unexpected block: {:?}", other),
+        }
+        assert_eq!(got[4], Block::Realtime(Realtime::ToggleFloodCoolant));
+    }
+
+    #[test]
+    fn errors_point_at_the_right_line() {
+        let got: Vec<_> = Frontend::new("G0 X1\n$Q\nG1 X&").collect();
+
+        assert_eq!(got[1], Err(Error::SyntaxError("Unknown system command", Span::from((1, 1)))));
+        assert_eq!(got[2], Err(Error::UnknownToken('&', Span::from((2, 5)))));
+    }
+}
@@ -0,0 +1,901 @@
+//! Streaming programs to a Grbl controller.
+//!
+//! Grbl reads lines into a 127 byte serial buffer and sends back an `ok` (or
+//! `error:N`) as each one is taken out. The `Host` keeps track of how many
+//! bytes are still sitting in that buffer, so it can send lines as fast as
+//! Grbl can take them without ever overflowing it (the "character counting"
+//! protocol).
+//!
+//! A `Host` can talk to anything which implements `Read` and `Write`, such as
+//! a serial port or a TCP connection. The `FakeGrbl` behaves enough like the
+//! real thing to test with when there's no hardware around.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::grbl_host::{Host, FakeGrbl, MachineState};
+//!
+//! let src = "G21 G90\nG0 X10 Y5\nG1 Z-1 F200";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let mut host = Host::new(FakeGrbl::new());
+//! host.stream(&lines).unwrap();
+//!
+//! let status = host.status().unwrap();
+//! assert_eq!(status.state, MachineState::Idle);
+//! assert_eq!(status.machine_position.unwrap().z, -1.0);
+//! ```
+
+use std::collections::VecDeque;
+use std::error;
+use std::io::{self, Read, Write};
+use std::string::{String, ToString};
+use std::time::Duration;
+use std::vec::Vec;
+use core::cmp;
+use core::fmt::{self, Display, Formatter};
+
+use parser::{Line, Number};
+use interpreter::{DistanceMode, Point};
+use dialect::Dialect;
+use stats::Code;
+use connection::{Connection, program_text};
+use grbl::{Frontend, Block, Realtime, SystemCommand};
+
+
+/// How many bytes Grbl's serial receive buffer can hold.
+pub const RX_BUFFER_SIZE: usize = 127;
+
+
+/// Something went wrong while talking to the controller.
+#[derive(Debug)]
+pub enum HostError {
+    /// Reading from or writing to the transport failed.
+    Io(io::Error),
+    /// The transport was closed.
+    Disconnected,
+    /// Grbl rejected a line (`error:N`).
+    Rejected {
+        /// Grbl's error code.
+        code: u32,
+        /// The line which was rejected.
+        line: String,
+    },
+    /// Grbl raised an alarm (`ALARM:N`) and has locked itself until it's
+    /// homed or unlocked.
+    Alarm(u32),
+    /// The line wouldn't fit in Grbl's receive buffer.
+    LineTooLong(String),
+    /// The controller didn't say anything before the timeout ran out.
+    TimedOut,
+}
+
+impl From<io::Error> for HostError {
+    fn from(other: io::Error) -> HostError {
+        match other.kind() {
+            io::ErrorKind::UnexpectedEof => HostError::Disconnected,
+            io::ErrorKind::TimedOut => HostError::TimedOut,
+            _ => HostError::Io(other),
+        }
+    }
+}
+
+impl Display for HostError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            HostError::Io(ref e) => write!(f, "{}", e),
+            HostError::Disconnected => f.write_str("The controller disconnected"),
+            HostError::Rejected { code, ref line } => {
+                write!(f, "\"{}\" was rejected with error {}", line, code)
+            }
+            HostError::Alarm(code) => write!(f, "Alarm {}", code),
+            HostError::LineTooLong(ref line) => {
+                write!(f, "\"{}\" is longer than {} bytes", line, RX_BUFFER_SIZE - 1)
+            }
+            HostError::TimedOut => f.write_str("The controller stopped responding"),
+        }
+    }
+}
+
+impl error::Error for HostError {}
+
+
+/// What the controller is doing, as given in a status report.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MachineState {
+    /// Waiting for something to do.
+    Idle,
+    /// Running a program.
+    Run,
+    /// In a feed hold. The number is `0` once the machine has stopped and
+    /// `1` while it's still slowing down.
+    Hold(u32),
+    /// Jogging.
+    Jog,
+    /// Locked after an alarm.
+    Alarm,
+    /// The safety door is open (the number says how far Grbl is through
+    /// parking or restoring).
+    Door(u32),
+    /// In check mode (`$C`).
+    Check,
+    /// Homing.
+    Home,
+    /// Asleep (`$SLP`).
+    Sleep,
+}
+
+impl MachineState {
+    fn parse(s: &str) -> Option<MachineState> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next()?;
+        let substate = match parts.next() {
+            Some(n) => n.parse().ok()?,
+            None => 0,
+        };
+
+        let state = match name {
+            "Idle" => MachineState::Idle,
+            "Run" => MachineState::Run,
+            "Hold" => MachineState::Hold(substate),
+            "Jog" => MachineState::Jog,
+            "Alarm" => MachineState::Alarm,
+            "Door" => MachineState::Door(substate),
+            "Check" => MachineState::Check,
+            "Home" => MachineState::Home,
+            "Sleep" => MachineState::Sleep,
+            _ => return None,
+        };
+
+        Some(state)
+    }
+}
+
+impl Display for MachineState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            MachineState::Idle => f.write_str("Idle"),
+            MachineState::Run => f.write_str("Run"),
+            MachineState::Hold(n) => write!(f, "Hold:{}", n),
+            MachineState::Jog => f.write_str("Jog"),
+            MachineState::Alarm => f.write_str("Alarm"),
+            MachineState::Door(n) => write!(f, "Door:{}", n),
+            MachineState::Check => f.write_str("Check"),
+            MachineState::Home => f.write_str("Home"),
+            MachineState::Sleep => f.write_str("Sleep"),
+        }
+    }
+}
+
+/// A status report (e.g. `<Idle|MPos:1.000,2.000,0.000|FS:500,0>`).
+///
+/// Grbl only includes some fields in each report, depending on its
+/// settings, so anything which wasn't there is `None`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Status {
+    /// What the controller is doing.
+    pub state: MachineState,
+    /// Where the machine is, in machine coordinates (`MPos`).
+    pub machine_position: Option<Point>,
+    /// Where the machine is, in work coordinates (`WPos`).
+    pub work_position: Option<Point>,
+    /// The current work coordinate offset (`WCO`).
+    pub work_offset: Option<Point>,
+    /// The current feed rate (`FS` or `F`).
+    pub feed_rate: Option<f32>,
+    /// The current spindle speed (`FS`).
+    pub spindle_speed: Option<f32>,
+}
+
+impl Status {
+    /// Create a status report which only says what the controller is doing.
+    pub fn new(state: MachineState) -> Status {
+        Status {
+            state,
+            machine_position: None,
+            work_position: None,
+            work_offset: None,
+            feed_rate: None,
+            spindle_speed: None,
+        }
+    }
+
+    /// Parse a status report, ignoring any fields which aren't understood.
+    pub fn parse(report: &str) -> Option<Status> {
+        let inner = report.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let mut fields = inner.split('|');
+        let mut status = Status::new(MachineState::parse(fields.next()?)?);
+
+        for field in fields {
+            let mut parts = field.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue,
+            };
+
+            match name {
+                "MPos" => status.machine_position = Some(parse_point(value)?),
+                "WPos" => status.work_position = Some(parse_point(value)?),
+                "WCO" => status.work_offset = Some(parse_point(value)?),
+                "F" => status.feed_rate = Some(value.parse().ok()?),
+                "FS" => {
+                    let mut values = value.split(',');
+                    status.feed_rate = Some(values.next()?.parse().ok()?);
+                    status.spindle_speed = Some(values.next()?.parse().ok()?);
+                }
+                _ => {}
+            }
+        }
+
+        Some(status)
+    }
+}
+
+/// Parse the first three axes of a position (e.g. `1.000,2.000,0.000`).
+fn parse_point(s: &str) -> Option<Point> {
+    let mut values = s.split(',').map(|v| v.parse().ok());
+
+    Some(Point::new(values.next()??, values.next()??, values.next()??))
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<{}", self.state)?;
+
+        let positions = [("MPos", self.machine_position),
+                         ("WPos", self.work_position),
+                         ("WCO", self.work_offset)];
+        for &(name, position) in &positions {
+            if let Some(p) = position {
+                write!(f, "|{}:{:.3},{:.3},{:.3}", name, p.x, p.y, p.z)?;
+            }
+        }
+
+        match (self.feed_rate, self.spindle_speed) {
+            (Some(feed), Some(speed)) => write!(f, "|FS:{},{}", feed, speed)?,
+            (Some(feed), None) => write!(f, "|F:{}", feed)?,
+            _ => {}
+        }
+
+        f.write_str(">")
+    }
+}
+
+
+/// A line sent back by the controller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The oldest line in the receive buffer was accepted.
+    Ok,
+    /// The oldest line in the receive buffer was rejected.
+    Error(u32),
+    /// An alarm was raised.
+    Alarm(u32),
+    /// A status report.
+    Status(Status),
+    /// The banner printed after a reset (e.g. `Grbl 1.1h ['$' for help]`).
+    Welcome(String),
+    /// Anything else, such as a `[MSG:...]` or the output of a `$` command.
+    Feedback(String),
+}
+
+impl Response {
+    /// Work out what a line from the controller means.
+    pub fn parse(line: &str) -> Response {
+        let line = line.trim();
+
+        if line == "ok" {
+            return Response::Ok;
+        }
+        if let Some(code) = line.strip_prefix("error:").and_then(|n| n.parse().ok()) {
+            return Response::Error(code);
+        }
+        if let Some(code) = line.strip_prefix("ALARM:").and_then(|n| n.parse().ok()) {
+            return Response::Alarm(code);
+        }
+        if let Some(status) = Status::parse(line) {
+            return Response::Status(status);
+        }
+        if line.starts_with("Grbl ") {
+            return Response::Welcome(line.to_string());
+        }
+
+        Response::Feedback(line.to_string())
+    }
+}
+
+
+/// Something which streams gcode to a Grbl controller.
+#[derive(Debug)]
+pub struct Host<T> {
+    connection: Connection<T>,
+    /// The lines Grbl hasn't responded to yet, oldest first.
+    in_flight: VecDeque<String>,
+    last_status: Option<Status>,
+}
+
+impl<T: Read + Write> Host<T> {
+    /// Create a `Host` which talks over a transport. Grbl should already be
+    /// ready for commands.
+    pub fn new(transport: T) -> Host<T> {
+        Host {
+            connection: Connection::new(transport),
+            in_flight: VecDeque::new(),
+            last_status: None,
+        }
+    }
+
+    /// Get a reference to the transport.
+    pub fn transport(&self) -> &T {
+        self.connection.transport()
+    }
+
+    /// Get a mutable reference to the transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        self.connection.transport_mut()
+    }
+
+    /// Stop using the `Host`, giving back its transport.
+    pub fn into_inner(self) -> T {
+        self.connection.into_inner()
+    }
+
+    /// Give up waiting for the controller if it doesn't say anything for
+    /// this long. By default the `Host` waits forever, because Grbl is
+    /// silent for as long as it's in a feed hold.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.connection.set_timeout(timeout);
+    }
+
+    /// How many bytes are in Grbl's receive buffer, as far as we know.
+    pub fn buffered(&self) -> usize {
+        self.in_flight.iter().map(|line| line.len() + 1).sum()
+    }
+
+    /// The most recent status report.
+    pub fn last_status(&self) -> Option<&Status> {
+        self.last_status.as_ref()
+    }
+
+    /// Take any messages the controller has sent which weren't responses to
+    /// a line or status reports.
+    pub fn take_feedback(&mut self) -> Vec<String> {
+        self.connection.take_feedback()
+    }
+
+    /// Send a whole program, waiting until Grbl has accepted every line.
+    ///
+    /// If a line is rejected, lines which were already sent may still be
+    /// run. Use `soft_reset()` to throw them away.
+    pub fn stream(&mut self, lines: &[Line]) -> Result<(), HostError> {
+        for line in program_text(lines).lines() {
+            self.send(line)?;
+        }
+
+        self.wait_until_done()
+    }
+
+    /// Send a single line, first waiting for there to be enough room in
+    /// Grbl's receive buffer.
+    pub fn send(&mut self, line: &str) -> Result<(), HostError> {
+        let line = line.trim();
+        let length = line.len() + 1;
+
+        if length > RX_BUFFER_SIZE {
+            return Err(HostError::LineTooLong(line.to_string()));
+        }
+
+        while self.buffered() + length > RX_BUFFER_SIZE {
+            self.read_response()?;
+        }
+
+        self.connection.write_line(line)?;
+        self.connection.flush()?;
+        self.in_flight.push_back(line.to_string());
+
+        Ok(())
+    }
+
+    /// Wait until Grbl has responded to every line that was sent.
+    pub fn wait_until_done(&mut self) -> Result<(), HostError> {
+        while !self.in_flight.is_empty() {
+            self.read_response()?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a realtime command. These skip the receive buffer, so they
+    /// are acted on straight away.
+    pub fn realtime(&mut self, command: Realtime) -> Result<(), HostError> {
+        let transport = self.connection.transport_mut();
+        transport.write_all(&[command.to_byte()])?;
+        transport.flush()?;
+
+        Ok(())
+    }
+
+    /// Pause the current motion (`!`).
+    pub fn feed_hold(&mut self) -> Result<(), HostError> {
+        self.realtime(Realtime::FeedHold)
+    }
+
+    /// Carry on after a feed hold (`~`).
+    pub fn resume(&mut self) -> Result<(), HostError> {
+        self.realtime(Realtime::CycleStart)
+    }
+
+    /// Reset the controller, throwing away anything it hasn't run yet, and
+    /// wait for it to start back up.
+    pub fn soft_reset(&mut self) -> Result<(), HostError> {
+        self.realtime(Realtime::SoftReset)?;
+        self.in_flight.clear();
+
+        loop {
+            let line = self.connection.read_line()?;
+            if let Response::Welcome(_) = Response::parse(&line) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Ask for a status report and wait for it to arrive.
+    pub fn status(&mut self) -> Result<Status, HostError> {
+        self.realtime(Realtime::StatusReport)?;
+
+        loop {
+            if let Response::Status(status) = self.read_response()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Wait for the next line from the controller, keeping track of which
+    /// lines have been responded to.
+    ///
+    /// `error:N` and `ALARM:N` responses are turned into a `HostError`.
+    pub fn read_response(&mut self) -> Result<Response, HostError> {
+        let line = self.connection.read_line()?;
+        let response = Response::parse(&line);
+
+        match response {
+            Response::Ok => {
+                self.in_flight.pop_front();
+            }
+            Response::Error(code) => {
+                let line = self.in_flight.pop_front().unwrap_or_default();
+                return Err(HostError::Rejected { code, line });
+            }
+            Response::Alarm(code) => {
+                // an alarm kills everything in the buffer
+                self.in_flight.clear();
+                return Err(HostError::Alarm(code));
+            }
+            Response::Status(status) => self.last_status = Some(status),
+            Response::Welcome(_) => {}
+            Response::Feedback(ref msg) => self.connection.push_feedback(msg.clone()),
+        }
+
+        Ok(response)
+    }
+}
+
+
+/// A pretend Grbl controller, for testing without any hardware.
+///
+/// Lines are only taken out of the receive buffer when something is read
+/// from the `FakeGrbl`, so it looks like a controller which is a lot slower
+/// than the host. Moves finish instantly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeGrbl {
+    /// Bytes which have been received but not processed.
+    rx: Vec<u8>,
+    /// Bytes waiting to be sent back.
+    tx: VecDeque<u8>,
+    state: MachineState,
+    position: Point,
+    distance_mode: DistanceMode,
+    feed_rate: f32,
+    executed: Vec<String>,
+    max_buffered: usize,
+    overflowed: bool,
+    /// How many more times the host can ask for something during a feed
+    /// hold before the operator presses cycle start.
+    cycle_start_after: Option<usize>,
+}
+
+impl FakeGrbl {
+    /// Create a `FakeGrbl` which is idle at the origin.
+    pub fn new() -> FakeGrbl {
+        FakeGrbl {
+            rx: Vec::new(),
+            tx: VecDeque::new(),
+            state: MachineState::Idle,
+            position: Point::default(),
+            distance_mode: DistanceMode::Absolute,
+            feed_rate: 0.0,
+            executed: Vec::new(),
+            max_buffered: 0,
+            overflowed: false,
+            cycle_start_after: None,
+        }
+    }
+
+    /// What the controller is doing.
+    pub fn state(&self) -> MachineState {
+        self.state
+    }
+
+    /// Where the machine is.
+    pub fn position(&self) -> Point {
+        self.position
+    }
+
+    /// Every line which has been accepted, in order.
+    pub fn executed(&self) -> &[String] {
+        &self.executed
+    }
+
+    /// The most bytes there have ever been in the receive buffer.
+    pub fn max_buffered(&self) -> usize {
+        self.max_buffered
+    }
+
+    /// Did the receive buffer ever fill up, losing data?
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Pretend the operator presses cycle start once the host has tried to
+    /// read `reads` times while the controller is in a feed hold.
+    pub fn cycle_start_after(&mut self, reads: usize) {
+        self.cycle_start_after = Some(reads);
+    }
+
+    /// Pretend something went wrong (e.g. a limit switch was hit), raising
+    /// an alarm and throwing away everything in the receive buffer.
+    pub fn trigger_alarm(&mut self, code: u32) {
+        self.state = MachineState::Alarm;
+        self.rx.clear();
+        self.respond(&format!("ALARM:{}", code));
+    }
+
+    fn respond(&mut self, line: &str) {
+        self.tx.extend(line.bytes());
+        self.tx.extend(b"\r\n");
+    }
+
+    fn realtime(&mut self, command: Realtime) {
+        match command {
+            Realtime::StatusReport => {
+                let status = Status {
+                    machine_position: Some(self.position),
+                    feed_rate: Some(self.feed_rate),
+                    spindle_speed: Some(0.0),
+                    ..Status::new(self.state)
+                };
+                self.respond(&status.to_string());
+            }
+            Realtime::FeedHold if self.state == MachineState::Idle ||
+                                  self.state == MachineState::Run => {
+                self.state = MachineState::Hold(0);
+            }
+            Realtime::CycleStart => {
+                if let MachineState::Hold(_) = self.state {
+                    self.state = MachineState::Idle;
+                }
+            }
+            Realtime::SoftReset => {
+                self.rx.clear();
+                self.tx.clear();
+                if self.state != MachineState::Alarm {
+                    self.state = MachineState::Idle;
+                }
+                self.tx.extend(b"\r\n");
+                self.respond("Grbl 1.1h ['$' for help]");
+            }
+            _ => {}
+        }
+    }
+
+    /// Take the oldest line out of the receive buffer and respond to it.
+    fn process_line(&mut self) {
+        if let MachineState::Hold(_) = self.state {
+            return;
+        }
+
+        let end = match self.rx.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => return,
+        };
+        let raw: Vec<u8> = self.rx.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&raw).trim().to_string();
+
+        match self.execute(&line) {
+            Ok(()) => {
+                self.executed.push(line);
+                self.respond("ok");
+            }
+            Err(code) => self.respond(&format!("error:{}", code)),
+        }
+    }
+
+    fn execute(&mut self, line: &str) -> Result<(), u32> {
+        for block in Frontend::new(line) {
+            match block {
+                Ok(Block::System(cmd)) => self.system_command(cmd)?,
+                Ok(Block::Line(line)) => self.gcode(line)?,
+                Ok(Block::Realtime(_)) => {}
+                Err(_) if line.starts_with('$') => return Err(3),
+                Err(_) => return Err(1),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn system_command(&mut self, cmd: SystemCommand) -> Result<(), u32> {
+        match cmd {
+            SystemCommand::Home => {
+                self.state = MachineState::Idle;
+                self.position = Point::default();
+            }
+            SystemCommand::KillAlarmLock => self.state = MachineState::Idle,
+            SystemCommand::Jog(jog) => {
+                if self.state == MachineState::Alarm {
+                    return Err(8);
+                }
+                let mode = jog.distance_mode.unwrap_or(self.distance_mode);
+                self.move_to(mode, jog.x, jog.y, jog.z);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn gcode(&mut self, line: Line) -> Result<(), u32> {
+        if self.state == MachineState::Alarm {
+            return Err(9);
+        }
+
+        let args = match line {
+            Line::Cmd(cmd) => {
+                if !Dialect::Grbl.supports(Code::new(cmd.kind, cmd.number)) {
+                    return Err(20);
+                }
+                match cmd.number {
+                    Number::Integer(90) => self.distance_mode = DistanceMode::Absolute,
+                    Number::Integer(91) => self.distance_mode = DistanceMode::Incremental,
+                    _ => {}
+                }
+                cmd.args
+            }
+            Line::Args { args, .. } => args,
+            Line::ProgramNumber(_) => return Ok(()),
+        };
+
+        if let Some(f) = args.f {
+            self.feed_rate = f;
+        }
+        let mode = self.distance_mode;
+        self.move_to(mode, args.x, args.y, args.z);
+
+        Ok(())
+    }
+
+    fn move_to(&mut self, mode: DistanceMode, x: Option<f32>, y: Option<f32>, z: Option<f32>) {
+        let position = &mut self.position;
+        let axes = [(&mut position.x, x), (&mut position.y, y), (&mut position.z, z)];
+
+        for (axis, value) in axes {
+            if let Some(value) = value {
+                match mode {
+                    DistanceMode::Absolute => *axis = value,
+                    DistanceMode::Incremental => *axis += value,
+                }
+            }
+        }
+    }
+}
+
+impl Default for FakeGrbl {
+    fn default() -> FakeGrbl {
+        FakeGrbl::new()
+    }
+}
+
+impl Write for FakeGrbl {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if let Some(command) = Realtime::from_byte(byte) {
+                self.realtime(command);
+            } else if self.rx.len() < RX_BUFFER_SIZE {
+                self.rx.push(byte);
+                self.max_buffered = cmp::max(self.max_buffered, self.rx.len());
+            } else {
+                // the real Grbl would silently drop the byte
+                self.overflowed = true;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FakeGrbl {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tx.is_empty() {
+            self.process_line();
+        }
+        if self.tx.is_empty() {
+            if let MachineState::Hold(_) = self.state {
+                match self.cycle_start_after {
+                    Some(0) => {
+                        self.cycle_start_after = None;
+                        self.realtime(Realtime::CycleStart);
+                    }
+                    Some(ref mut reads) => *reads -= 1,
+                    None => {}
+                }
+            }
+
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Grbl has nothing to say"));
+        }
+
+        let bytes_read = cmp::min(buf.len(), self.tx.len());
+        for (dest, byte) in buf.iter_mut().zip(self.tx.drain(..bytes_read)) {
+            *dest = byte;
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helpers::parse;
+
+    #[test]
+    fn parse_responses() {
+        let inputs = vec![("ok", Response::Ok),
+                          ("error:20\r", Response::Error(20)),
+                          ("ALARM:1", Response::Alarm(1)),
+                          ("<Jog|WPos:1.000,-2.500,0.000|F:500>",
+                           Response::Status(Status {
+                                                work_position: Some(Point::new(1.0, -2.5, 0.0)),
+                                                feed_rate: Some(500.0),
+                                                ..Status::new(MachineState::Jog)
+                                            })),
+                          ("Grbl 1.1h ['$' for help]",
+                           Response::Welcome(String::from("Grbl 1.1h ['$' for help]"))),
+                          ("[MSG:'$H'|'$X' to unlock]",
+                           Response::Feedback(String::from("[MSG:'$H'|'$X' to unlock]")))];
+
+        for (src, should_be) in inputs {
+            assert_eq!(Response::parse(src), should_be, "{}", src);
+        }
+    }
+
+    #[test]
+    fn status_reports() {
+        let src = "<Hold:1|MPos:10.000,5.000,-1.000,0.000|Bf:15,128|FS:250,12000|WCO:1.000,1.000,0.000>";
+
+        let got = Status::parse(src).unwrap();
+
+        assert_eq!(got.state, MachineState::Hold(1));
+        assert_eq!(got.machine_position, Some(Point::new(10.0, 5.0, -1.0)));
+        assert_eq!(got.work_offset, Some(Point::new(1.0, 1.0, 0.0)));
+        assert_eq!(got.feed_rate, Some(250.0));
+        assert_eq!(got.spindle_speed, Some(12000.0));
+        assert_eq!(Status::parse(&got.to_string()), Some(got));
+
+        assert_eq!(Status::parse("<Flying|MPos:0,0,0>"), None);
+    }
+
+    #[test]
+    fn the_receive_buffer_never_overflows() {
+        let src: String = (0..200).map(|i| format!("G1 X{} Y{} F500\n", i, i % 7)).collect();
+        let lines = parse(&src);
+
+        let mut host = Host::new(FakeGrbl::new());
+        host.stream(&lines).unwrap();
+
+        let grbl = host.into_inner();
+        assert_eq!(grbl.executed().len(), 200);
+        assert!(!grbl.overflowed());
+        assert!(grbl.max_buffered() <= RX_BUFFER_SIZE);
+        assert!(grbl.max_buffered() > RX_BUFFER_SIZE - 20);
+        assert_eq!(grbl.position(), Point::new(199.0, 3.0, 0.0));
+    }
+
+    #[test]
+    fn rejected_lines_are_reported() {
+        let mut host = Host::new(FakeGrbl::new());
+
+        let got = host.stream(&parse("G0 X1\nG1 Y2 F100\nG99\nG0 X0"));
+
+        match got {
+            Err(HostError::Rejected { code: 20, ref line }) => assert_eq!(line, "G99"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lines_are_too_long() {
+        let mut host = Host::new(FakeGrbl::new());
+        let line = format!("G1 X{}", "1".repeat(RX_BUFFER_SIZE));
+
+        assert!(matches!(host.send(&line), Err(HostError::LineTooLong(_))));
+    }
+
+    #[test]
+    fn alarms_lock_the_controller() {
+        let mut host = Host::new(FakeGrbl::new());
+        host.transport_mut().trigger_alarm(1);
+
+        assert!(matches!(host.status(), Err(HostError::Alarm(1))));
+        assert_eq!(host.status().unwrap().state, MachineState::Alarm);
+
+        host.send("G0 X1").unwrap();
+        assert!(matches!(host.wait_until_done(), Err(HostError::Rejected { code: 9, .. })));
+
+        host.send("$X").unwrap();
+        host.send("G0 X1").unwrap();
+        host.wait_until_done().unwrap();
+        assert_eq!(host.status().unwrap().state, MachineState::Idle);
+    }
+
+    #[test]
+    fn feed_hold_and_resume() {
+        let mut host = Host::new(FakeGrbl::new());
+        host.send("G0 X5").unwrap();
+
+        host.feed_hold().unwrap();
+        assert_eq!(host.status().unwrap().state, MachineState::Hold(0));
+        host.set_timeout(Some(Duration::from_millis(10)));
+        assert!(matches!(host.wait_until_done(), Err(HostError::TimedOut)));
+        assert_eq!(host.buffered(), 6);
+
+        host.resume().unwrap();
+        host.wait_until_done().unwrap();
+        assert_eq!(host.transport().position().x, 5.0);
+    }
+
+    #[test]
+    fn stream_through_a_feed_hold() {
+        let mut host = Host::new(FakeGrbl::new());
+        host.feed_hold().unwrap();
+        host.transport_mut().cycle_start_after(5);
+
+        host.stream(&parse("G0 X5\nG0 Y5\nG1 Z-1 F100")).unwrap();
+
+        let grbl = host.into_inner();
+        assert_eq!(grbl.state(), MachineState::Idle);
+        assert_eq!(grbl.executed().len(), 3);
+        assert_eq!(grbl.position(), Point::new(5.0, 5.0, -1.0));
+    }
+
+    #[test]
+    fn soft_reset_throws_away_buffered_lines() {
+        let mut host = Host::new(FakeGrbl::new());
+        host.send("G0 X5").unwrap();
+        host.send("G0 Y5").unwrap();
+
+        host.soft_reset().unwrap();
+
+        assert_eq!(host.buffered(), 0);
+        assert!(host.transport().executed().is_empty());
+        host.send("$J=G91 X-2 F100").unwrap();
+        host.wait_until_done().unwrap();
+        assert_eq!(host.transport().position(), Point::new(-2.0, 0.0, 0.0));
+    }
+}
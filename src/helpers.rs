@@ -1,33 +1,29 @@
-//! Some helper traits because a lot of the useful unicode stuff isn't
-//! included in `core::char`.
+//! Bits and pieces shared by the rest of the crate.
+//!
+//! This has ASCII helpers (a lot of the useful unicode stuff isn't included
+//! in `core::char`), the floating point maths `core` leaves out, the modal
+//! state passes use to follow a program's moves, helpers for building and
+//! writing programs, and the parse fixtures used by tests.
 
+use core::fmt::{self, Display, Formatter, Write};
+use arrayvec::ArrayString;
+#[cfg(any(test, feature = "std"))]
+use std::vec::Vec;
 
-pub trait MaybeWhitespace {
-    fn is_whitespace(&self) -> bool;
-}
-
-impl MaybeWhitespace for char {
-    fn is_whitespace(&self) -> bool {
-        match *self {
-            '\r' | ' ' | '\t' | '\n' => true,
-            _ => false,
-        }
-    }
-}
-
-pub trait MaybeAlphabetic {
-    fn is_alphabetic(&self) -> bool;
-}
+use lexer::Span;
+#[cfg(test)]
+use lexer::Tokenizer;
+#[cfg(test)]
+use parser::Parser;
+#[cfg(test)]
+use dialect::Dialect;
+use errors::Error;
+use parser::Line;
+use parser::{Args, Command, CommandKind, Number};
+use interpreter::DistanceMode;
 
-impl MaybeAlphabetic for char {
-    fn is_alphabetic(&self) -> bool {
-        match *self {
-            'a'...'z' | 'A'...'Z' => true,
-            _ => false,
-        }
-    }
-}
 
+#[allow(dead_code)]
 pub trait AsciiSwapCase {
     fn uppercase(&self) -> Self;
     fn lowercase(&self) -> Self;
@@ -36,7 +32,7 @@ pub trait AsciiSwapCase {
 impl AsciiSwapCase for char {
     fn uppercase(&self) -> Self {
         match *self {
-            'a'...'z' => {
+            'a'..='z' => {
                 let diff = b'a' - b'A';
                 (*self as u8 - diff) as Self
             }
@@ -46,7 +42,7 @@ impl AsciiSwapCase for char {
 
     fn lowercase(&self) -> Self {
         match *self {
-            'A'...'Z' => {
+            'A'..='Z' => {
                 let diff = b'a' - b'A';
                 (*self as u8 + diff) as Self
             }
@@ -72,9 +68,294 @@ fn pow(n: u32, exp: u32) -> u32 {
     (1..exp).fold(n, |acc, _| acc * n)
 }
 
+
+/// A number written with at most a certain number of decimal places and no
+/// trailing zeroes (e.g. `Decimals(2.50, 3)` is written as `2.5`).
+pub struct Decimals(pub f32, pub usize);
+
+impl Display for Decimals {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut buffer = ArrayString::<[u8; 64]>::new();
+        write!(buffer, "{:.*}", self.1, self.0)?;
+
+        let mut s = buffer.as_str();
+        if s.contains('.') {
+            s = s.trim_end_matches('0').trim_end_matches('.');
+        }
+        if s == "-0" {
+            s = "0";
+        }
+
+        f.write_str(s)
+    }
+}
+
+
+// `core` doesn't give us any of the floating point maths functions, so we
+// defer to `libm` for them.
+
+pub fn abs(n: f32) -> f32 {
+    ::libm::fabsf(n)
+}
+
+pub fn sqrt(n: f32) -> f32 {
+    ::libm::sqrtf(n)
+}
+
+pub fn powf(n: f32, exp: f32) -> f32 {
+    ::libm::powf(n, exp)
+}
+
+pub fn tan(radians: f32) -> f32 {
+    ::libm::tanf(radians)
+}
+
+pub fn atan2(y: f32, x: f32) -> f32 {
+    ::libm::atan2f(y, x)
+}
+
+pub fn sin(radians: f32) -> f32 {
+    ::libm::sinf(radians)
+}
+
+pub fn cos(radians: f32) -> f32 {
+    ::libm::cosf(radians)
+}
+
+/// Does a command have any `X`, `Y` or `Z` words?
+pub fn has_axis_words(args: &Args) -> bool {
+    args.x.is_some() || args.y.is_some() || args.z.is_some()
+}
+
+/// Is this G code in the motion group (i.e. does it change the motion mode
+/// or cancel it)?
+pub fn is_motion(number: Number) -> bool {
+    match number {
+        Number::Integer(n) => matches!(n, 0..=3 | 33 | 73 | 76 | 80..=89),
+        Number::Decimal(33, 1) | Number::Decimal(38, _) => true,
+        Number::Decimal(..) => false,
+    }
+}
+
+/// The modal state which passes over a program use to follow where the
+/// tool goes, without running the whole interpreter.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Modal {
+    /// The motion command which axis words on their own continue.
+    pub motion: Option<Number>,
+    /// Whether axis words are absolute (`G90`) or incremental (`G91`).
+    pub distance_mode: DistanceMode,
+    /// Where the tool is in the program's coordinates, if known.
+    pub position: [Option<f32>; 3],
+    /// The source line which used `G53`, so its moves are in machine
+    /// coordinates.
+    machine_block: Option<usize>,
+    /// The source line which last chose a motion command.
+    motion_line: Option<usize>,
+}
+
+impl Default for Modal {
+    fn default() -> Modal {
+        Modal {
+            motion: None,
+            distance_mode: DistanceMode::Absolute,
+            position: [None; 3],
+            machine_block: None,
+            motion_line: None,
+        }
+    }
+}
+
+impl Modal {
+    /// Follow a line of the program, moving to its axis words.
+    pub fn update(&mut self, line: &Line) {
+        let (args, span) = match *line {
+            Line::ProgramNumber(_) => return,
+            Line::Args { ref args, span, .. } => (args, span),
+            Line::Cmd(ref cmd) if cmd.kind == CommandKind::G => {
+                if !self.command(cmd) {
+                    return;
+                }
+                (&cmd.args, cmd.span)
+            }
+            // M codes use X, Y and Z for their own settings (e.g. M92)
+            Line::Cmd(_) => return,
+        };
+
+        let words = [args.x, args.y, args.z];
+        let machine_coordinates = self.in_machine_coordinates(span);
+
+        for (position, &word) in self.position.iter_mut().zip(&words) {
+            let value = match word {
+                Some(value) => value,
+                None => continue,
+            };
+
+            *position = match self.distance_mode {
+                // we don't know where the move leaves us in the program's
+                // coordinates
+                _ if machine_coordinates => None,
+                DistanceMode::Absolute => Some(value),
+                DistanceMode::Incremental => position.map(|p| p + value),
+            };
+        }
+    }
+
+    /// Are the moves on this source line in machine coordinates (`G53`)?
+    pub fn in_machine_coordinates(&self, span: Span) -> bool {
+        self.machine_block == Some(span.line)
+    }
+
+    /// Follow a G code, returning whether its axis words are a move.
+    fn command(&mut self, cmd: &Command) -> bool {
+        let line = cmd.span.line;
+
+        match cmd.number {
+            // "G0 G90 G80" is a common way to start a program, so only
+            // motion from an earlier line is cancelled
+            Number::Integer(80) if self.motion_line != Some(line) => self.motion = None,
+            Number::Integer(80) => {}
+            Number::Integer(10) |
+            Number::Decimal(33, 1) |
+            Number::Decimal(43, 1) |
+            Number::Integer(76) => return false,
+            number if is_motion(number) => {
+                self.motion = Some(number);
+                self.motion_line = Some(line);
+            }
+            Number::Integer(90) => self.distance_mode = DistanceMode::Absolute,
+            Number::Integer(91) => self.distance_mode = DistanceMode::Incremental,
+            Number::Integer(53) => self.machine_block = Some(line),
+            Number::Integer(28) | Number::Integer(30) | Number::Decimal(92, _) => {
+                // the axis words of G28 and G30 are only somewhere to go
+                // through on the way home
+                self.position = [None; 3];
+                return false;
+            }
+            Number::Integer(92) => {
+                // the current position becomes the axis words
+                let words = [cmd.args.x, cmd.args.y, cmd.args.z];
+                for (position, &word) in self.position.iter_mut().zip(&words) {
+                    if word.is_some() {
+                        *position = word;
+                    }
+                }
+                return false;
+            }
+            _ => {}
+        }
+
+        true
+    }
+}
+
+/// The straight line distance between two points on a plane.
+#[cfg(feature = "std")]
+pub fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    sqrt(dx * dx + dy * dy)
+}
+
+/// Change which line of the source code a `Line` says it came from.
+pub fn move_to_line(line: &mut Line, number: usize) {
+    match *line {
+        Line::Cmd(ref mut cmd) => cmd.span.line = number,
+        Line::Args { ref mut span, .. } => span.line = number,
+        Line::ProgramNumber(_) => {}
+    }
+}
+
+/// Change which line of the source code an error points at.
+pub fn error_on_line(error: Error, number: usize) -> Error {
+    match error {
+        Error::UnknownToken(c, span) => Error::UnknownToken(c, Span::from((number, span.column))),
+        Error::SyntaxError(msg, span) => Error::SyntaxError(msg, Span::from((number, span.column))),
+        other => other,
+    }
+}
+
+/// A program being generated, renumbering source lines as it goes so lines
+/// copied from different places are never written out together.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct Output {
+    pub lines: Vec<Line>,
+    line: usize,
+    last_source_line: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl Output {
+    /// Start a new line.
+    pub fn new_line(&mut self) {
+        if !self.lines.is_empty() {
+            self.line += 1;
+        }
+        self.last_source_line = None;
+    }
+
+    /// Add a command to the current line.
+    pub fn push(&mut self, kind: CommandKind, number: Number, args: Args) {
+        self.lines.push(Line::Cmd(Command {
+                                      kind,
+                                      number,
+                                      args,
+                                      line_number: None,
+                                      span: Span {
+                                          line: self.line,
+                                          column: 0,
+                                      },
+                                  }));
+    }
+
+    /// Add arguments on their own to the current line.
+    pub fn push_args(&mut self, args: Args) {
+        self.lines.push(Line::Args {
+                            args,
+                            line_number: None,
+                            span: Span {
+                                line: self.line,
+                                column: 0,
+                            },
+                        });
+    }
+
+    /// Copy a line, starting a new line whenever its source line changes.
+    pub fn copy(&mut self, mut line: Line) {
+        let source_line = line.span().map(|s| s.line);
+        if source_line.is_none() || source_line != self.last_source_line {
+            self.new_line();
+        }
+        self.last_source_line = source_line;
+
+        match line {
+            Line::Cmd(ref mut cmd) => cmd.span.line = self.line,
+            Line::Args { ref mut span, .. } => span.line = self.line,
+            Line::ProgramNumber(_) => {}
+        }
+
+        self.lines.push(line);
+    }
+}
+
+/// Parse a program which is known to be valid, for use in tests.
+#[cfg(test)]
+pub fn parse(src: &str) -> Vec<Line> {
+    parse_dialect(src, Dialect::Rs274Ngc)
+}
+
+/// Parse a program in a particular dialect, for use in tests.
+#[cfg(test)]
+pub fn parse_dialect(src: &str, dialect: Dialect) -> Vec<Line> {
+    let tokens = Tokenizer::with_string_codes(src.chars(), dialect.string_codes())
+        .map(|t| t.unwrap());
+    Parser::with_dialect(tokens, dialect).map(|l| l.unwrap()).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::string::ToString;
 
     #[test]
     fn check_alphabetic() {
@@ -110,6 +391,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn trim_decimals() {
+        let inputs = [((1.0, 3), "1"),
+                      ((-0.0001, 3), "0"),
+                      ((2.5, 3), "2.5"),
+                      ((1.23456, 3), "1.235"),
+                      ((-12.0, 4), "-12"),
+                      ((0.12345, 4), "0.1235")];
+
+        for &((n, places), should_be) in &inputs {
+            assert_eq!(Decimals(n, places).to_string(), should_be);
+        }
+    }
+
+    fn follow(src: &str) -> Modal {
+        let mut modal = Modal::default();
+        for line in &parse(src) {
+            modal.update(line);
+        }
+        modal
+    }
+
+    #[test]
+    fn follow_moves() {
+        let got = follow("G0 X1 Y2\nG91 X1 Z3\nM92 X80 E93");
+        assert_eq!(got.position, [Some(2.0), Some(2.0), None]);
+
+        let got = follow("G0 X1 Y2 Z3\nG92 X0\nG53 G0 Y0\nG28 Z5");
+        assert_eq!(got.position, [None; 3]);
+
+        let got = follow("G0 X1 Y2 Z3\nG53 G0 Y0\nG92 X0");
+        assert_eq!(got.position, [Some(0.0), None, Some(3.0)]);
+    }
+
+    #[test]
+    fn g80_only_cancels_motion_from_earlier_lines() {
+        assert_eq!(follow("G0 G90 G80").motion, Some(Number::Integer(0)));
+        assert_eq!(follow("G81 X1 Y1 Z-1 R1\nG80").motion, None);
+    }
+
     #[test]
     fn swapping_case() {
         let inputs = [('a', 'A'), ('m', 'M'), ('$', '$'), ('z', 'Z'), ('s', 'S')];
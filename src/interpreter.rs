@@ -0,0 +1,2291 @@
+//! An interpreter which keeps track of the machine's modal state and turns a
+//! stream of commands into the things a machine would actually do.
+
+use core::ops::{Add, Sub, Mul};
+use core::f32::consts::PI;
+use arrayvec::ArrayVec;
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use lexer::Span;
+use offsets::{WorkOffsets, CoordinateSystem};
+use tools::{ToolTable, Tool};
+use compensation::Compensator;
+use errors::*;
+use helpers::*;
+
+
+/// The number of millimetres in an inch.
+const MM_PER_INCH: f32 = 25.4;
+/// The number of metres in a foot.
+const METRES_PER_FOOT: f32 = 0.3048;
+/// The most passes a `G76` threading cycle is allowed to make.
+const MAX_THREADING_PASSES: u32 = 1000;
+/// How many extruders a 3D printer can have.
+pub const MAX_EXTRUDERS: usize = 8;
+
+
+/// An interpreter which turns a stream of parsed `Line`s into a stream of
+/// `Event`s.
+///
+/// Every length reported by the interpreter is in millimetres, regardless of
+/// whether the program was written using `G20` or `G21`, and positions are
+/// in machine coordinates (i.e. with all work and tool offsets applied).
+///
+/// While cutter radius compensation (`G41`/`G42`) is on, moves are held back
+/// until the next move is known so the corner between them can be worked
+/// out, and the `Move`s which come out follow the centre of the tool.
+///
+/// Commands the interpreter doesn't know about are silently skipped.
+///
+/// # Examples
+///
+/// ```rust
+/// use gcode::{Tokenizer, Parser};
+/// use gcode::interpreter::{Interpreter, Event};
+///
+/// let src = "G90 G0 X10 Y10 G1 X20 F500";
+///
+/// let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+/// let lines = Parser::new(tokens).filter_map(|line| line.ok());
+///
+/// for event in Interpreter::new(lines) {
+///     if let Event::Move(m) = event.unwrap() {
+///         println!("{:?} -> {:?}", m.start, m.end);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Interpreter<I>
+    where I: Iterator<Item = Line>
+{
+    lines: I,
+    state: State,
+    /// The source line of the block currently being executed.
+    block: Option<usize>,
+    /// Where the current block starts.
+    span: Span,
+    line_number: Option<u32>,
+    /// Has `G53` been used in the current block?
+    machine_coordinates: bool,
+    compensation: Compensator,
+    cycle: Option<ThreadingCycle>,
+}
+
+impl<I> Interpreter<I>
+    where I: Iterator<Item = Line>
+{
+    /// Create a new `Interpreter` for a milling machine.
+    pub fn new(lines: I) -> Interpreter<I> {
+        Interpreter::with_profile(lines, Profile::Mill)
+    }
+
+    /// Create a new `Interpreter` for a particular kind of machine.
+    pub fn with_profile(lines: I, profile: Profile) -> Interpreter<I> {
+        Interpreter::with_state(lines, State::new(profile))
+    }
+
+    /// Create a new `Interpreter` which starts with a particular state (e.g.
+    /// with work offsets loaded from a parameter file).
+    pub fn with_state(lines: I, state: State) -> Interpreter<I> {
+        Interpreter {
+            lines,
+            state,
+            block: None,
+            span: Span::default(),
+            line_number: None,
+            machine_coordinates: false,
+            compensation: Compensator::new(),
+            cycle: None,
+        }
+    }
+
+    /// The machine's current state.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    fn execute(&mut self, line: &Line) -> Result<()> {
+        match *line {
+            Line::Cmd(ref cmd) => {
+                self.start_block(cmd.span, cmd.line_number);
+                self.execute_command(cmd)
+            }
+            Line::Args { ref args, line_number, span } => {
+                self.start_block(span, line_number);
+                reject_flags(args)?;
+                self.feed_and_speed(args, true);
+                self.modal_move(args)
+            }
+            Line::ProgramNumber(_) => Ok(()),
+        }
+    }
+
+    /// Keep track of which block (line of source code) we are in, so
+    /// non-modal codes like `G53` only apply to their own block.
+    fn start_block(&mut self, span: Span, line_number: Option<u32>) {
+        if self.block != Some(span.line) {
+            self.block = Some(span.line);
+            self.span = span;
+            self.line_number = line_number;
+            self.machine_coordinates = false;
+        } else if line_number.is_some() {
+            self.line_number = line_number;
+        }
+    }
+
+    fn feed_and_speed(&mut self, args: &Args, set_speed: bool) {
+        if let Some(f) = args.f {
+            self.state.feed_rate = self.length(f);
+        }
+
+        if set_speed {
+            if let Some(s) = args.s {
+                self.set_spindle_speed(s);
+            }
+        }
+    }
+
+    fn execute_command(&mut self, cmd: &Command) -> Result<()> {
+        let lathe = self.state.profile == Profile::Lathe;
+        let printer = self.state.profile == Profile::Printer;
+        let args = &cmd.args;
+
+        if cmd.kind == CommandKind::G && is_motion(cmd.number) {
+            reject_flags(args)?;
+        }
+
+        // Units need to be set before we read any other lengths
+        match (cmd.kind, cmd.number) {
+            (CommandKind::G, Number::Integer(20)) => self.state.units = Units::Inches,
+            (CommandKind::G, Number::Integer(21)) => self.state.units = Units::Millimetres,
+            _ => {}
+        }
+
+        // S words mean something else for these commands
+        let set_speed = match (cmd.kind, cmd.number) {
+            (CommandKind::G, Number::Integer(50)) => !lathe,
+            (CommandKind::G, Number::Integer(96)) |
+            (CommandKind::G, Number::Integer(97)) => false,
+            (CommandKind::M, Number::Integer(104)) |
+            (CommandKind::M, Number::Integer(106)) |
+            (CommandKind::M, Number::Integer(109)) |
+            (CommandKind::M, Number::Integer(140)) |
+            (CommandKind::M, Number::Integer(190)) => !printer,
+            _ => true,
+        };
+        self.feed_and_speed(args, set_speed);
+
+        match (cmd.kind, cmd.number) {
+            (CommandKind::G, Number::Integer(0)) => {
+                self.state.motion_mode = Some(MotionMode::Rapid);
+            }
+            (CommandKind::G, Number::Integer(1)) => {
+                self.state.motion_mode = Some(MotionMode::Linear);
+            }
+            (CommandKind::G, Number::Integer(2)) => {
+                self.state.motion_mode = Some(MotionMode::Clockwise);
+            }
+            (CommandKind::G, Number::Integer(3)) => {
+                self.state.motion_mode = Some(MotionMode::CounterClockwise);
+            }
+            (CommandKind::G, Number::Integer(4)) => {
+                match args.p {
+                    Some(seconds) if seconds >= 0.0 => {
+                        let span = self.span;
+                        self.push(Event::Dwell { seconds, span })?
+                    }
+                    Some(_) => {
+                        return Err(Error::InvalidCommand("Dwell duration cannot be negative"))
+                    }
+                    None => return Err(Error::InvalidCommand("Must provide a dwell duration")),
+                }
+            }
+            (CommandKind::G, Number::Integer(7)) if lathe => self.state.diameter_mode = true,
+            (CommandKind::G, Number::Integer(10)) => self.set_offsets(args)?,
+            (CommandKind::G, Number::Integer(8)) if lathe => self.state.diameter_mode = false,
+            (CommandKind::G, Number::Integer(17)) => self.set_plane(Plane::XY)?,
+            (CommandKind::G, Number::Integer(18)) => self.set_plane(Plane::ZX)?,
+            (CommandKind::G, Number::Integer(19)) => self.set_plane(Plane::YZ)?,
+            (CommandKind::G, Number::Integer(28)) if printer => self.home(args)?,
+            (CommandKind::G, Number::Integer(28)) => {
+                let home = self.state.offsets.g28_home;
+                self.return_home(args, home)?
+            }
+            (CommandKind::G, Number::Integer(30)) => {
+                let home = self.state.offsets.g30_home;
+                self.return_home(args, home)?
+            }
+            (CommandKind::G, Number::Decimal(28, 1)) => {
+                self.state.offsets.g28_home = self.state.position
+            }
+            (CommandKind::G, Number::Decimal(30, 1)) => {
+                self.state.offsets.g30_home = self.state.position
+            }
+            (CommandKind::G, Number::Integer(33)) => {
+                match args.k {
+                    Some(pitch) if pitch > 0.0 => self.state.thread_pitch = self.length(pitch),
+                    _ => return Err(Error::InvalidCommand("G33 needs a positive pitch (K)")),
+                }
+                self.state.motion_mode = Some(MotionMode::SpindleSynchronised);
+            }
+            (CommandKind::G, Number::Decimal(33, 1)) => self.rigid_tap(args)?,
+            (CommandKind::G, Number::Integer(40)) => {
+                self.state.cutter_compensation = None;
+                self.compensation.stop();
+            }
+            (CommandKind::G, Number::Integer(41)) => self.start_compensation(Side::Left, args)?,
+            (CommandKind::G, Number::Integer(42)) => self.start_compensation(Side::Right, args)?,
+            (CommandKind::G, Number::Decimal(41, 1)) => {
+                self.start_dynamic_compensation(Side::Left, args)?
+            }
+            (CommandKind::G, Number::Decimal(42, 1)) => {
+                self.start_dynamic_compensation(Side::Right, args)?
+            }
+            (CommandKind::G, Number::Integer(43)) => self.tool_length_offset(args),
+            (CommandKind::G, Number::Decimal(43, 1)) => {
+                self.state.tool_offset = Point {
+                    x: args.x.map_or(0.0, |x| self.length(x)),
+                    y: args.y.map_or(0.0, |y| self.length(y)),
+                    z: args.z.map_or(0.0, |z| self.length(z)),
+                };
+            }
+            (CommandKind::G, Number::Integer(49)) => self.state.tool_offset = Point::default(),
+            (CommandKind::G, Number::Integer(50)) if lathe => {
+                self.state.max_spindle_speed = args.s;
+            }
+            (CommandKind::G, Number::Integer(53)) => {
+                if self.state.distance_mode == DistanceMode::Incremental {
+                    return Err(Error::InvalidCommand("G53 can't be used in incremental mode"));
+                }
+                self.machine_coordinates = true;
+            }
+            (CommandKind::G, Number::Integer(54..=59)) |
+            (CommandKind::G, Number::Decimal(59, 1..=3)) => {
+                if let Some(cs) = coordinate_system(cmd.number) {
+                    self.state.offsets.active = cs;
+                }
+            }
+            (CommandKind::G, Number::Integer(76)) if lathe => self.threading_cycle(args)?,
+            (CommandKind::G, Number::Integer(90)) => {
+                self.state.distance_mode = DistanceMode::Absolute;
+                self.state.extruder_mode = DistanceMode::Absolute;
+            }
+            (CommandKind::G, Number::Integer(91)) => {
+                self.state.distance_mode = DistanceMode::Incremental;
+                self.state.extruder_mode = DistanceMode::Incremental;
+            }
+            (CommandKind::G, Number::Decimal(90, 1)) => {
+                self.state.arc_distance_mode = DistanceMode::Absolute;
+            }
+            (CommandKind::G, Number::Decimal(91, 1)) => {
+                self.state.arc_distance_mode = DistanceMode::Incremental;
+            }
+            (CommandKind::G, Number::Integer(92)) if printer && args.e.is_some() => {
+                self.state.extruder_position = self.length(args.e.unwrap_or(0.0));
+                if has_axis_words(args) {
+                    self.set_g92_offset(args)?;
+                }
+            }
+            (CommandKind::G, Number::Integer(92)) => self.set_g92_offset(args)?,
+            (CommandKind::G, Number::Decimal(92, 1)) => {
+                self.state.offsets.g92 = Point::default();
+                self.state.offsets.saved_g92 = Point::default();
+            }
+            (CommandKind::G, Number::Decimal(92, 2)) => self.state.offsets.g92 = Point::default(),
+            (CommandKind::G, Number::Decimal(92, 3)) => {
+                self.state.offsets.g92 = self.state.offsets.saved_g92;
+            }
+            (CommandKind::G, Number::Integer(96)) => {
+                let surface_speed = match args.s {
+                    Some(s) if s > 0.0 => s,
+                    _ => {
+                        return Err(Error::InvalidCommand("G96 needs a positive surface speed (S)"))
+                    }
+                };
+
+                if args.d.is_some() {
+                    self.state.max_spindle_speed = args.d;
+                }
+                self.state.spindle_speed = SpindleSpeed::ConstantSurface(0.0);
+                self.set_spindle_speed(surface_speed);
+            }
+            (CommandKind::G, Number::Integer(97)) => {
+                let rpm = args.s.unwrap_or_else(|| self.state.spindle_rpm());
+                self.state.spindle_speed = SpindleSpeed::Rpm(rpm);
+            }
+
+            (CommandKind::M, Number::Integer(2)) => {
+                self.push(Event::ProgramEnd)?;
+                self.state.cutter_compensation = None;
+                self.compensation.stop();
+            }
+            // Marlin uses M30 for deleting files from the SD card
+            (CommandKind::M, Number::Integer(30)) if !printer => {
+                self.push(Event::ProgramEnd)?;
+                self.state.cutter_compensation = None;
+                self.compensation.stop();
+            }
+            (CommandKind::M, Number::Integer(3)) => self.state.spindle = Spindle::Clockwise,
+            (CommandKind::M, Number::Integer(4)) => self.state.spindle = Spindle::CounterClockwise,
+            (CommandKind::M, Number::Integer(5)) => self.state.spindle = Spindle::Off,
+            (CommandKind::M, Number::Integer(6)) => {
+                self.state.tool = self.state.selected_tool;
+                let (tool, span) = (self.state.tool, self.span);
+                self.push(Event::ToolChange { tool, span })?;
+            }
+
+            (CommandKind::M, Number::Integer(82)) if printer => {
+                self.state.extruder_mode = DistanceMode::Absolute;
+            }
+            (CommandKind::M, Number::Integer(83)) if printer => {
+                self.state.extruder_mode = DistanceMode::Incremental;
+            }
+            (CommandKind::M, Number::Integer(104)) if printer => {
+                self.set_temperature(Heater::Hotend(self.extruder(args)?), args.s, false)?
+            }
+            (CommandKind::M, Number::Integer(109)) if printer => {
+                let target = args.s.or(args.r);
+                self.set_temperature(Heater::Hotend(self.extruder(args)?), target, true)?
+            }
+            (CommandKind::M, Number::Integer(140)) if printer => {
+                self.set_temperature(Heater::Bed, args.s, false)?
+            }
+            (CommandKind::M, Number::Integer(190)) if printer => {
+                self.set_temperature(Heater::Bed, args.s.or(args.r), true)?
+            }
+            (CommandKind::M, Number::Integer(106)) if printer => {
+                let s = args.s.unwrap_or(255.0);
+                self.state.fan_speed = (s / 255.0).clamp(0.0, 1.0);
+            }
+            (CommandKind::M, Number::Integer(107)) if printer => self.state.fan_speed = 0.0,
+
+            // Printers switch extruders straight away, without an M6
+            (CommandKind::T, Number::Integer(n)) if printer => {
+                if n as usize >= MAX_EXTRUDERS {
+                    return Err(Error::InvalidCommand("Unknown extruder (T)"));
+                }
+                self.state.selected_tool = n;
+                self.state.tool = n;
+                let span = self.span;
+                self.push(Event::ToolChange { tool: n, span })?;
+            }
+            (CommandKind::T, Number::Integer(n)) => self.state.selected_tool = n,
+
+            _ => {}
+        }
+
+        if consumes_axis_words(cmd) {
+            Ok(())
+        } else {
+            self.modal_move(args)
+        }
+    }
+
+    /// Axis words without a motion command continue using the current
+    /// motion mode.
+    fn modal_move(&mut self, args: &Args) -> Result<()> {
+        let extrudes = self.state.profile == Profile::Printer && args.e.is_some();
+        if !has_axis_words(args) && !extrudes {
+            return Ok(());
+        }
+
+        match self.state.motion_mode {
+            Some(MotionMode::Rapid) => self.straight_move(MoveKind::Rapid, args),
+            Some(MotionMode::Linear) => self.straight_move(MoveKind::Linear, args),
+            Some(MotionMode::Clockwise) => self.arc(Direction::Clockwise, args),
+            Some(MotionMode::CounterClockwise) => self.arc(Direction::CounterClockwise, args),
+            Some(MotionMode::SpindleSynchronised) => {
+                let pitch = self.state.thread_pitch;
+                self.straight_move(MoveKind::Threading { pitch }, args)
+            }
+            None => {
+                Err(Error::InvalidCommand("Axis words were provided without an active motion mode"))
+            }
+        }
+    }
+
+    fn straight_move(&mut self, kind: MoveKind, args: &Args) -> Result<()> {
+        let end = self.target(args);
+        let mut m = self.make_move(kind, end);
+        m.extrusion = self.extrude(args);
+        self.push(Event::Move(m))
+    }
+
+    fn arc(&mut self, direction: Direction, args: &Args) -> Result<()> {
+        let start = self.state.position;
+        let end = self.target(args);
+        let plane = self.state.plane;
+
+        let centre = if let Some(r) = args.r {
+            centre_from_radius(start, end, self.length(r), direction, plane)?
+        } else if args.i.is_some() || args.j.is_some() || args.k.is_some() {
+            self.centre_from_offsets(start, args)
+        } else {
+            return Err(Error::InvalidCommand("Arcs need either a radius or a centre point"));
+        };
+
+        let kind = MoveKind::Arc {
+            centre,
+            direction,
+            plane,
+        };
+        let mut m = self.make_move(kind, end);
+        m.extrusion = self.extrude(args);
+        self.push(Event::Move(m))
+    }
+
+    /// Work out how much filament a move pushes out (`E`), updating the
+    /// extruder's position.
+    fn extrude(&mut self, args: &Args) -> f32 {
+        let e = match args.e {
+            Some(e) if self.state.profile == Profile::Printer => self.length(e),
+            _ => return 0.0,
+        };
+
+        let extrusion = match self.state.extruder_mode {
+            DistanceMode::Absolute => e - self.state.extruder_position,
+            DistanceMode::Incremental => e,
+        };
+        self.state.extruder_position += extrusion;
+        extrusion
+    }
+
+    /// Home the axes given as axis words (`G28 X Y`), or every axis when
+    /// there aren't any. Homing is treated as a rapid move to machine zero,
+    /// and clears any `G92` offset on the homed axes.
+    fn home(&mut self, args: &Args) -> Result<()> {
+        let all = !args.has('X') && !args.has('Y') && !args.has('Z');
+        let start = self.state.position;
+        let g92 = self.state.offsets.g92;
+        let homed = |letter: char, position: f32, offset: f32| if all || args.has(letter) {
+            (0.0, 0.0)
+        } else {
+            (position, offset)
+        };
+
+        let (x, gx) = homed('X', start.x, g92.x);
+        let (y, gy) = homed('Y', start.y, g92.y);
+        let (z, gz) = homed('Z', start.z, g92.z);
+        self.state.offsets.g92 = Point::new(gx, gy, gz);
+        self.state.offsets.saved_g92 = self.state.offsets.g92;
+
+        let m = self.make_move(MoveKind::Rapid, Point::new(x, y, z));
+        self.push(Event::Move(m))
+    }
+
+    /// Go back to a stored home position (`G28`/`G30`, set with
+    /// `G28.1`/`G30.1`), first rapiding to the intermediate point given by
+    /// any axis words. Only the axes which were given go home, or all of them
+    /// if there weren't any.
+    fn return_home(&mut self, args: &Args, home: Point) -> Result<()> {
+        let start = self.state.position;
+        let all = !has_axis_words(args);
+
+        let intermediate = self.target(args);
+        if intermediate != start {
+            let m = self.make_move(MoveKind::Rapid, intermediate);
+            self.push(Event::Move(m))?;
+        }
+
+        let axis = |word: Option<f32>, home: f32, position: f32| if all || word.is_some() {
+            home
+        } else {
+            position
+        };
+        let home = Point::new(axis(args.x, home.x, intermediate.x),
+                              axis(args.y, home.y, intermediate.y),
+                              axis(args.z, home.z, intermediate.z));
+
+        let m = self.make_move(MoveKind::Rapid, home);
+        self.push(Event::Move(m))
+    }
+
+    /// Which extruder a temperature command is for (`T`), defaulting to the
+    /// active one.
+    fn extruder(&self, args: &Args) -> Result<u32> {
+        let extruder = args.t.map_or(self.state.tool, |t| t as u32);
+
+        if (extruder as usize) < MAX_EXTRUDERS {
+            Ok(extruder)
+        } else {
+            Err(Error::InvalidCommand("Unknown extruder (T)"))
+        }
+    }
+
+    fn set_temperature(&mut self, heater: Heater, target: Option<f32>, wait: bool) -> Result<()> {
+        let target = match target {
+            Some(t) if t >= 0.0 => t,
+            Some(_) => return Err(Error::InvalidCommand("Temperatures cannot be negative")),
+            None if wait => return Err(Error::InvalidCommand("Must provide a temperature (S)")),
+            None => return Ok(()),
+        };
+
+        match heater {
+            Heater::Hotend(n) => self.state.hotend_temperatures[n as usize] = target,
+            Heater::Bed => self.state.bed_temperature = target,
+        }
+
+        if wait {
+            let span = self.span;
+            self.push(Event::WaitForTemperature { heater, target, span })?;
+        }
+
+        Ok(())
+    }
+
+    fn centre_from_offsets(&self, start: Point, args: &Args) -> Point {
+        let origin = self.origin();
+        let offset = |word: Option<f32>, current: f32, origin: f32| match word {
+            Some(w) => {
+                match self.state.arc_distance_mode {
+                    DistanceMode::Absolute => origin + self.length(w),
+                    DistanceMode::Incremental => current + self.length(w),
+                }
+            }
+            None => current,
+        };
+
+        let centre = Point {
+            x: offset(args.i, start.x, origin.x),
+            y: offset(args.j, start.y, origin.y),
+            z: offset(args.k, start.z, origin.z),
+        };
+
+        // the centre always lies on the same plane as the start point
+        let (a, b, _) = self.state.plane.split(centre);
+        let (_, _, n) = self.state.plane.split(start);
+        self.state.plane.join(a, b, n)
+    }
+
+    fn rigid_tap(&mut self, args: &Args) -> Result<()> {
+        let pitch = match args.k {
+            Some(pitch) if pitch > 0.0 => self.length(pitch),
+            _ => return Err(Error::InvalidCommand("G33.1 needs a positive pitch (K)")),
+        };
+
+        let start = self.state.position;
+        let bottom = self.target(args);
+
+        // Feed down to the bottom of the hole, then reverse the spindle and
+        // come back out the way we went in
+        let down = self.make_move(MoveKind::Threading { pitch }, bottom);
+        let up = self.make_move(MoveKind::Threading { pitch }, start);
+        self.push(Event::Move(down))?;
+        self.push(Event::Move(up))
+    }
+
+    fn threading_cycle(&mut self, args: &Args) -> Result<()> {
+        let pitch = match args.p {
+            Some(p) if p > 0.0 => self.length(p),
+            _ => return Err(Error::InvalidCommand("G76 needs a positive pitch (P)")),
+        };
+        let peak_offset = match args.i {
+            Some(i) if i != 0.0 => self.length(i),
+            _ => return Err(Error::InvalidCommand("G76 needs a non-zero thread peak offset (I)")),
+        };
+        let initial_depth = match args.j {
+            Some(j) if j > 0.0 => self.length(j),
+            _ => return Err(Error::InvalidCommand("G76 needs a positive initial cut depth (J)")),
+        };
+        let full_depth = match args.k {
+            Some(k) if k > 0.0 => self.length(k),
+            _ => return Err(Error::InvalidCommand("G76 needs a positive thread depth (K)")),
+        };
+        if args.z.is_none() {
+            return Err(Error::InvalidCommand("G76 needs a final Z position"));
+        }
+
+        let degression = args.r.unwrap_or(1.0);
+        if degression < 1.0 {
+            return Err(Error::InvalidCommand("The depth degression (R) must be at least 1.0"));
+        }
+
+        let taper_ends = match args.l.map(|l| l as u32) {
+            None | Some(0) => Taper::None,
+            Some(1) => Taper::Entry,
+            Some(2) => Taper::Exit,
+            Some(3) => Taper::Both,
+            Some(_) => {
+                return Err(Error::InvalidCommand("The taper ends (L) must be between 0 and 3"))
+            }
+        };
+
+        let params = ThreadingParameters {
+            start: self.state.position,
+            end_z: self.target(args).z,
+            pitch,
+            peak_offset,
+            initial_depth,
+            full_depth,
+            degression,
+            compound_angle: args.q.unwrap_or(0.0),
+            spring_passes: args.h.unwrap_or(0.0) as u32,
+            taper_length: args.e.map(|e| self.length(e)).unwrap_or(0.0),
+            taper_ends,
+        };
+
+        self.cycle = Some(ThreadingCycle::new(params)?);
+        Ok(())
+    }
+
+    fn set_offsets(&mut self, args: &Args) -> Result<()> {
+        match args.l.map(|l| l as u32) {
+            Some(1) => self.set_tool(args),
+            Some(2) => self.set_work_offset(args, false),
+            Some(20) => self.set_work_offset(args, true),
+            _ => Err(Error::InvalidCommand("Unsupported G10 command (L)")),
+        }
+    }
+
+    /// Update a tool table entry (`G10 L1`).
+    fn set_tool(&mut self, args: &Args) -> Result<()> {
+        let number = match args.p {
+            Some(p) if p >= 0.0 => p as u32,
+            _ => return Err(Error::InvalidCommand("G10 L1 needs a tool number (P)")),
+        };
+
+        let mut tool = self.state.tools.get(number).cloned().unwrap_or_else(|| Tool::new(number));
+        if let Some(r) = args.r {
+            tool.diameter = 2.0 * self.length(r);
+        }
+        if let Some(x) = args.x {
+            tool.x_offset = self.length(x);
+        }
+        if let Some(z) = args.z {
+            tool.length = self.length(z);
+        }
+        if let Some(q) = args.q {
+            tool.orientation = q as u32;
+        }
+
+        self.state.tools.insert(tool)
+    }
+
+    /// Apply a tool's length offset (`G43`), using the current tool unless
+    /// another one is given with an `H` word. Tools which aren't in the tool
+    /// table have no offset.
+    fn tool_length_offset(&mut self, args: &Args) {
+        let number = args.h.map_or(self.state.tool, |h| h as u32);
+
+        self.state.tool_offset = match self.state.tools.get(number) {
+            Some(tool) => Point::new(tool.x_offset, 0.0, tool.length),
+            None => Point::default(),
+        };
+    }
+
+    /// Turn on cutter radius compensation using a tool's diameter from the
+    /// tool table (`G41`/`G42`).
+    fn start_compensation(&mut self, side: Side, args: &Args) -> Result<()> {
+        let number = args.d.map_or(self.state.tool, |d| d as u32);
+
+        let radius = if number == 0 {
+            0.0
+        } else {
+            match self.state.tools.get(number) {
+                Some(tool) => tool.radius(),
+                None => {
+                    return Err(Error::InvalidCommand("Cutter compensation refers to an unknown \
+                                                      tool (D)"))
+                }
+            }
+        };
+
+        self.compensate(side, radius)
+    }
+
+    /// Turn on cutter radius compensation with the diameter given in the
+    /// `D` word (`G41.1`/`G42.1`).
+    fn start_dynamic_compensation(&mut self, side: Side, args: &Args) -> Result<()> {
+        match args.d {
+            Some(d) if d >= 0.0 => {
+                let radius = self.length(d) / 2.0;
+                self.compensate(side, radius)
+            }
+            _ => Err(Error::InvalidCommand("Cutter compensation needs a positive diameter (D)")),
+        }
+    }
+
+    fn compensate(&mut self, side: Side, radius: f32) -> Result<()> {
+        if self.state.cutter_compensation.is_some() {
+            return Err(Error::InvalidCommand("Cutter compensation is already on"));
+        }
+
+        self.state.cutter_compensation = Some(CutterCompensation { side, radius });
+        if radius > 0.0 {
+            self.compensation.start(side, radius, self.state.plane)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_plane(&mut self, plane: Plane) -> Result<()> {
+        if plane != self.state.plane && self.state.cutter_compensation.is_some() {
+            return Err(Error::InvalidCommand("The plane can't be changed while cutter \
+                                              compensation is on"));
+        }
+
+        self.state.plane = plane;
+        Ok(())
+    }
+
+    /// Set a coordinate system's origin (`G10 L2`), or set it so the current
+    /// position has the given coordinates (`G10 L20`).
+    fn set_work_offset(&mut self, args: &Args, relative_to_current: bool) -> Result<()> {
+        let cs = match args.p.map(|p| p as u32) {
+            Some(0) => self.state.offsets.active,
+            Some(p) => {
+                match CoordinateSystem::from_number(p) {
+                    Some(cs) => cs,
+                    None => return Err(Error::InvalidCommand("Unknown coordinate system (P)")),
+                }
+            }
+            None => return Err(Error::InvalidCommand("G10 needs a coordinate system (P)")),
+        };
+        if matches!(args.r, Some(r) if r != 0.0) {
+            return Err(Error::InvalidCommand("Rotated coordinate systems aren't supported"));
+        }
+
+        let position = self.state.position - self.state.tool_offset;
+        let g92 = self.state.offsets.g92;
+        let origin = self.state.offsets.get(cs);
+        let (x, y, z) = self.axis_words(args);
+
+        let update = |word: Option<f32>, origin: f32, position: f32, g92: f32| match word {
+            Some(w) if relative_to_current => position - g92 - w,
+            Some(w) => w,
+            None => origin,
+        };
+
+        let origin = Point {
+            x: update(x, origin.x, position.x, g92.x),
+            y: update(y, origin.y, position.y, g92.y),
+            z: update(z, origin.z, position.z, g92.z),
+        };
+        self.state.offsets.set(cs, origin);
+
+        Ok(())
+    }
+
+    /// Set the `G92` offset so the current position has the given
+    /// coordinates.
+    fn set_g92_offset(&mut self, args: &Args) -> Result<()> {
+        if !has_axis_words(args) {
+            return Err(Error::InvalidCommand("G92 needs at least one axis word"));
+        }
+
+        let position = self.state.position - self.state.tool_offset;
+        let origin = self.state.offsets.get(self.state.offsets.active);
+        let g92 = self.state.offsets.g92;
+        let (x, y, z) = self.axis_words(args);
+
+        let update = |word: Option<f32>, g92: f32, position: f32, origin: f32| match word {
+            Some(w) => position - origin - w,
+            None => g92,
+        };
+
+        let g92 = Point {
+            x: update(x, g92.x, position.x, origin.x),
+            y: update(y, g92.y, position.y, origin.y),
+            z: update(z, g92.z, position.z, origin.z),
+        };
+        self.state.offsets.g92 = g92;
+        self.state.offsets.saved_g92 = g92;
+
+        Ok(())
+    }
+
+    /// Work out where a move will end up.
+    fn target(&self, args: &Args) -> Point {
+        let current = self.state.position;
+        let origin = self.origin();
+        let (x, y, z) = self.axis_words(args);
+
+        Point {
+            x: self.axis(current.x, x, origin.x),
+            y: self.axis(current.y, y, origin.y),
+            z: self.axis(current.z, z, origin.z),
+        }
+    }
+
+    /// Where the program's origin is in machine coordinates, including the
+    /// tool length offset.
+    fn origin(&self) -> Point {
+        if self.machine_coordinates {
+            self.state.tool_offset
+        } else {
+            self.state.offsets.current() + self.state.tool_offset
+        }
+    }
+
+    fn axis(&self, current: f32, word: Option<f32>, origin: f32) -> f32 {
+        match word {
+            Some(value) => {
+                match self.state.distance_mode {
+                    DistanceMode::Absolute => origin + value,
+                    DistanceMode::Incremental => current + value,
+                }
+            }
+            None => current,
+        }
+    }
+
+    /// Get the X, Y and Z words in millimetres, taking diameter mode into
+    /// account.
+    fn axis_words(&self, args: &Args) -> (Option<f32>, Option<f32>, Option<f32>) {
+        let mut x = args.x.map(|x| self.length(x));
+
+        if self.state.diameter_mode {
+            x = x.map(|x| x / 2.0);
+        }
+
+        (x, args.y.map(|y| self.length(y)), args.z.map(|z| self.length(z)))
+    }
+
+    /// Convert a length from the current units to millimetres.
+    fn length(&self, value: f32) -> f32 {
+        match self.state.units {
+            Units::Millimetres => value,
+            Units::Inches => value * MM_PER_INCH,
+        }
+    }
+
+    fn set_spindle_speed(&mut self, s: f32) {
+        self.state.spindle_speed = match self.state.spindle_speed {
+            SpindleSpeed::Rpm(_) => SpindleSpeed::Rpm(s),
+            SpindleSpeed::ConstantSurface(_) => {
+                // surface speed is in m/min or ft/min depending on the units
+                let surface_speed = match self.state.units {
+                    Units::Millimetres => s,
+                    Units::Inches => s * METRES_PER_FOOT,
+                };
+                SpindleSpeed::ConstantSurface(surface_speed)
+            }
+        };
+    }
+
+    fn make_move(&mut self, kind: MoveKind, end: Point) -> Move {
+        let spindle_speed = match self.state.spindle {
+            Spindle::Off => 0.0,
+            _ => self.state.spindle_rpm(),
+        };
+        let feed_rate = match kind {
+            MoveKind::Threading { pitch } => pitch * spindle_speed,
+            _ => self.state.feed_rate,
+        };
+
+        let m = Move {
+            kind,
+            start: self.state.position,
+            end,
+            feed_rate,
+            spindle_speed,
+            tool: self.state.tool,
+            tool_offset: self.state.tool_offset,
+            extrusion: 0.0,
+            line_number: self.line_number,
+            span: self.span,
+        };
+
+        self.state.position = end;
+        m
+    }
+
+    fn push(&mut self, event: Event) -> Result<()> {
+        self.compensation.push(event)
+    }
+}
+
+impl<I> Iterator for Interpreter<I>
+    where I: Iterator<Item = Line>
+{
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.compensation.pop() {
+                return Some(Ok(event));
+            }
+
+            let result = if let Some((kind, end)) = self.cycle.as_mut().and_then(|c| c.next()) {
+                let m = self.make_move(kind, end);
+                self.push(Event::Move(m))
+            } else if let Some(line) = self.lines.next() {
+                self.cycle = None;
+                self.execute(&line)
+            } else {
+                // there's nothing left to look ahead to
+                self.cycle = None;
+                match self.compensation.finish() {
+                    Ok(_) if self.compensation.is_empty() => return None,
+                    other => other,
+                }
+            };
+
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Moves need a value for every word (`G1 X` doesn't say where to go).
+fn reject_flags(args: &Args) -> Result<()> {
+    if args.flags.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidCommand("Every word in a move needs a value"))
+    }
+}
+
+/// Does this command use the axis words itself, rather than them triggering
+/// a move using the current motion mode?
+fn consumes_axis_words(cmd: &Command) -> bool {
+    matches!((cmd.kind, cmd.number),
+             (CommandKind::G, Number::Integer(10)) |
+             (CommandKind::G, Number::Integer(28)) |
+             (CommandKind::G, Number::Integer(30)) |
+             (CommandKind::G, Number::Decimal(33, 1)) |
+             (CommandKind::G, Number::Decimal(43, 1)) |
+             (CommandKind::G, Number::Integer(76)) |
+             (CommandKind::G, Number::Integer(92)))
+}
+
+/// The coordinate system selected by `G54` to `G59.3`.
+fn coordinate_system(number: Number) -> Option<CoordinateSystem> {
+    match number {
+        Number::Integer(n @ 54..=59) => CoordinateSystem::from_number(n - 53),
+        Number::Decimal(59, n) => CoordinateSystem::from_number(6 + n),
+        _ => None,
+    }
+}
+
+fn centre_from_radius(start: Point,
+                      end: Point,
+                      radius: f32,
+                      direction: Direction,
+                      plane: Plane)
+                      -> Result<Point> {
+    let (start_a, start_b, normal) = plane.split(start);
+    let (end_a, end_b, _) = plane.split(end);
+
+    let (da, db) = (end_a - start_a, end_b - start_b);
+    let chord = sqrt(da * da + db * db);
+
+    if chord == 0.0 {
+        return Err(Error::InvalidCommand("A full circle can't be specified using a radius"));
+    }
+
+    let half_chord = chord / 2.0;
+    let abs_radius = abs(radius);
+    if half_chord - abs_radius > 0.001 {
+        return Err(Error::InvalidCommand("The arc's radius is too small to reach its end point"));
+    }
+
+    // distance from the middle of the chord to the centre
+    let mut offset = sqrt((abs_radius * abs_radius - half_chord * half_chord).max(0.0));
+
+    // Counter-clockwise arcs with a positive radius have their centre to the
+    // left of the chord, a negative radius means we take the long way around
+    if direction == Direction::Clockwise {
+        offset = -offset;
+    }
+    if radius < 0.0 {
+        offset = -offset;
+    }
+
+    let (left_a, left_b) = (-db / chord, da / chord);
+    Ok(plane.join(start_a + da / 2.0 + left_a * offset,
+                  start_b + db / 2.0 + left_b * offset,
+                  normal))
+}
+
+
+/// The kind of machine being programmed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Profile {
+    /// A milling machine or router, working in the XY plane by default.
+    Mill,
+    /// A lathe, working in the XZ plane by default and accepting the
+    /// turning-specific codes (`G7`/`G8`, `G50` and `G76`).
+    Lathe,
+    /// A 3D printer running Marlin (or other RepRap firmware), working in
+    /// the XY plane and accepting the printer-specific codes (`E`
+    /// extrusion, `M82`/`M83`, temperatures, fans and `G28` homing).
+    Printer,
+}
+
+/// The machine's modal state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    /// The kind of machine being programmed.
+    pub profile: Profile,
+    /// Where the tool currently is, in machine coordinates (ignoring cutter
+    /// radius compensation).
+    pub position: Point,
+    /// The work offsets and active coordinate system.
+    pub offsets: WorkOffsets,
+    /// The tools available to the machine.
+    pub tools: ToolTable,
+    /// The tool length offset (`G43`).
+    pub tool_offset: Point,
+    /// The cutter radius compensation being applied (`G41`/`G42`).
+    pub cutter_compensation: Option<CutterCompensation>,
+    /// The units used by the program.
+    pub units: Units,
+    /// Whether axis words are absolute or incremental (`G90`/`G91`).
+    pub distance_mode: DistanceMode,
+    /// Whether arc centres are absolute or incremental (`G90.1`/`G91.1`).
+    pub arc_distance_mode: DistanceMode,
+    /// The active plane for arcs (`G17`, `G18` or `G19`).
+    pub plane: Plane,
+    /// The motion used when axis words are given on their own.
+    pub motion_mode: Option<MotionMode>,
+    /// The feed rate in mm/min.
+    pub feed_rate: f32,
+    /// The distance travelled per spindle revolution during spindle
+    /// synchronised motion (`G33`).
+    pub thread_pitch: f32,
+    /// Which way the spindle is turning.
+    pub spindle: Spindle,
+    /// How the spindle speed is controlled.
+    pub spindle_speed: SpindleSpeed,
+    /// The fastest the spindle may turn while using constant surface speed,
+    /// in RPM (`G50 S-` or `G96 D-`).
+    pub max_spindle_speed: Option<f32>,
+    /// Are X words diameters rather than radii (`G7`)?
+    pub diameter_mode: bool,
+    /// The tool currently in the spindle.
+    pub tool: u32,
+    /// The tool which will be loaded at the next tool change.
+    pub selected_tool: u32,
+    /// How far the extruder has pushed filament, in mm. This is the
+    /// position `E` words are measured from, and can be reset with
+    /// `G92 E-`.
+    pub extruder_position: f32,
+    /// Whether `E` words are absolute or incremental (`M82`/`M83`).
+    pub extruder_mode: DistanceMode,
+    /// The target temperature of each extruder's hotend, in degrees Celsius.
+    pub hotend_temperatures: [f32; MAX_EXTRUDERS],
+    /// The target temperature of the heated bed, in degrees Celsius.
+    pub bed_temperature: f32,
+    /// How fast the part cooling fan is turning, from `0.0` (off) to `1.0`
+    /// (full speed).
+    pub fan_speed: f32,
+}
+
+impl State {
+    /// The state of a machine when it is first turned on.
+    pub fn new(profile: Profile) -> State {
+        let plane = match profile {
+            Profile::Mill | Profile::Printer => Plane::XY,
+            Profile::Lathe => Plane::ZX,
+        };
+
+        State {
+            profile,
+            position: Point::default(),
+            offsets: WorkOffsets::default(),
+            tools: ToolTable::new(),
+            tool_offset: Point::default(),
+            cutter_compensation: None,
+            units: Units::Millimetres,
+            distance_mode: DistanceMode::Absolute,
+            arc_distance_mode: DistanceMode::Incremental,
+            plane,
+            motion_mode: None,
+            feed_rate: 0.0,
+            thread_pitch: 0.0,
+            spindle: Spindle::Off,
+            spindle_speed: SpindleSpeed::Rpm(0.0),
+            max_spindle_speed: None,
+            diameter_mode: false,
+            tool: 0,
+            selected_tool: 0,
+            extruder_position: 0.0,
+            extruder_mode: DistanceMode::Absolute,
+            hotend_temperatures: [0.0; MAX_EXTRUDERS],
+            bed_temperature: 0.0,
+            fan_speed: 0.0,
+        }
+    }
+
+    /// Where the tool currently is, in the active coordinate system.
+    ///
+    /// This is the programmed position, so it doesn't take cutter radius
+    /// compensation into account.
+    pub fn program_position(&self) -> Point {
+        self.position - self.offsets.current() - self.tool_offset
+    }
+
+    /// How fast the spindle would be turning at the current position, in
+    /// RPM.
+    ///
+    /// When using constant surface speed the spindle speed depends on the
+    /// distance from the work's centreline (`X0` in the active coordinate
+    /// system), and is infinite on the centreline unless a maximum spindle
+    /// speed has been set.
+    pub fn spindle_rpm(&self) -> f32 {
+        match self.spindle_speed {
+            SpindleSpeed::Rpm(rpm) => rpm,
+            SpindleSpeed::ConstantSurface(surface_speed) => {
+                let circumference = 2.0 * PI * abs(self.program_position().x);
+                let rpm = if circumference > 0.0 {
+                    surface_speed * 1000.0 / circumference
+                } else {
+                    f32::INFINITY
+                };
+
+                match self.max_spindle_speed {
+                    Some(max) => rpm.min(max),
+                    None => rpm,
+                }
+            }
+        }
+    }
+}
+
+/// The units used by a program.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Units {
+    /// Millimetres (`G21`).
+    Millimetres,
+    /// Inches (`G20`).
+    Inches,
+}
+
+/// How to interpret distances.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DistanceMode {
+    /// Distances are relative to the origin.
+    Absolute,
+    /// Distances are relative to the current position.
+    Incremental,
+}
+
+/// A plane which arcs are drawn in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Plane {
+    /// The XY plane (`G17`).
+    XY,
+    /// The ZX plane (`G18`).
+    ZX,
+    /// The YZ plane (`G19`).
+    YZ,
+}
+
+impl Plane {
+    /// Split a point into its two in-plane coordinates and the coordinate
+    /// along the plane's normal.
+    pub fn split(&self, p: Point) -> (f32, f32, f32) {
+        match *self {
+            Plane::XY => (p.x, p.y, p.z),
+            Plane::ZX => (p.z, p.x, p.y),
+            Plane::YZ => (p.y, p.z, p.x),
+        }
+    }
+
+    /// The inverse of `split()`.
+    pub fn join(&self, a: f32, b: f32, normal: f32) -> Point {
+        match *self {
+            Plane::XY => Point::new(a, b, normal),
+            Plane::ZX => Point::new(b, normal, a),
+            Plane::YZ => Point::new(normal, a, b),
+        }
+    }
+}
+
+/// Which side of the programmed path the tool is kept on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Side {
+    /// To the left, when looking in the direction of travel (`G41`).
+    Left,
+    /// To the right, when looking in the direction of travel (`G42`).
+    Right,
+}
+
+/// Cutter radius compensation settings.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CutterCompensation {
+    /// Which side of the path the tool is kept on.
+    pub side: Side,
+    /// How far the tool is offset from the path, in mm.
+    pub radius: f32,
+}
+
+/// The motion used when axis words are given without a motion command.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MotionMode {
+    /// Rapid linear motion (`G0`).
+    Rapid,
+    /// Linear motion at the feed rate (`G1`).
+    Linear,
+    /// Clockwise arcs (`G2`).
+    Clockwise,
+    /// Counter-clockwise arcs (`G3`).
+    CounterClockwise,
+    /// Spindle synchronised motion (`G33`).
+    SpindleSynchronised,
+}
+
+/// Which way the spindle is turning.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Spindle {
+    /// Stopped (`M5`).
+    Off,
+    /// Clockwise (`M3`).
+    Clockwise,
+    /// Counter-clockwise (`M4`).
+    CounterClockwise,
+}
+
+/// How the spindle speed is controlled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpindleSpeed {
+    /// A fixed speed in RPM (`G97`).
+    Rpm(f32),
+    /// Constant surface speed (`G96`), in metres per minute.
+    ConstantSurface(f32),
+}
+
+
+/// Something the machine does.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Event {
+    /// Move the tool from one place to another.
+    Move(Move),
+    /// Wait for a number of seconds (`G4`).
+    Dwell {
+        /// How long to wait.
+        seconds: f32,
+        /// Where the dwell came from.
+        span: Span,
+    },
+    /// Load a different tool into the spindle (`M6`).
+    ToolChange {
+        /// The new tool.
+        tool: u32,
+        /// Where the tool change came from.
+        span: Span,
+    },
+    /// The end of the program was reached (`M2` or `M30`).
+    ProgramEnd,
+    /// Wait for a heater to reach its target temperature (`M109` or
+    /// `M190`).
+    WaitForTemperature {
+        /// The heater being waited on.
+        heater: Heater,
+        /// The temperature it needs to reach, in degrees Celsius.
+        target: f32,
+        /// Where the wait came from.
+        span: Span,
+    },
+}
+
+impl Event {
+    /// The start of the block which caused this event, if known.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Event::Move(ref m) => Some(m.span),
+            Event::Dwell { span, .. } |
+            Event::ToolChange { span, .. } |
+            Event::WaitForTemperature { span, .. } => Some(span),
+            Event::ProgramEnd => None,
+        }
+    }
+}
+
+/// A single movement of the tool.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Move {
+    /// How the tool gets from `start` to `end`.
+    pub kind: MoveKind,
+    /// Where the move started.
+    pub start: Point,
+    /// Where the move finished.
+    pub end: Point,
+    /// The feed rate in mm/min (meaningless for rapids).
+    pub feed_rate: f32,
+    /// The spindle speed in RPM at the start of the move.
+    pub spindle_speed: f32,
+    /// The tool in the spindle.
+    pub tool: u32,
+    /// The tool length offset (`G43`) in effect, so `start - tool_offset`
+    /// is where the tip of the tool started.
+    pub tool_offset: Point,
+    /// How much filament a 3D printer pushes out during the move, in mm.
+    pub extrusion: f32,
+    /// The line number (`N` word) of the command which caused this move.
+    pub line_number: Option<u32>,
+    /// The start of the block which caused this move.
+    pub span: Span,
+}
+
+impl Move {
+    /// How far the tool travels.
+    pub fn length(&self) -> f32 {
+        match self.kind {
+            MoveKind::Arc { centre, plane, .. } => {
+                let (a, b, _) = plane.split(self.start - centre);
+                let (_, _, start_normal) = plane.split(self.start);
+                let (_, _, end_normal) = plane.split(self.end);
+
+                let radius = sqrt(a * a + b * b);
+                let around = radius * self.sweep().unwrap_or(0.0);
+                let along = end_normal - start_normal;
+
+                sqrt(around * around + along * along)
+            }
+            _ => (self.end - self.start).length(),
+        }
+    }
+
+    /// The point a fraction of the way along the move, where `0.0` is the
+    /// start and `1.0` is the end.
+    pub fn point_at(&self, fraction: f32) -> Point {
+        match self.kind {
+            MoveKind::Arc { centre, direction, plane } => {
+                let (a, b, start_normal) = plane.split(self.start - centre);
+                let (_, _, end_normal) = plane.split(self.end - centre);
+
+                let mut turned = self.sweep().unwrap_or(0.0) * fraction;
+                if direction == Direction::Clockwise {
+                    turned = -turned;
+                }
+                let radius = sqrt(a * a + b * b);
+                let angle = atan2(b, a) + turned;
+                let normal = start_normal + (end_normal - start_normal) * fraction;
+
+                centre + plane.join(radius * cos(angle), radius * sin(angle), normal)
+            }
+            _ => self.start + (self.end - self.start) * fraction,
+        }
+    }
+
+    /// The angle an arc turns through, in radians. Arcs which end where
+    /// they started are full circles.
+    pub fn sweep(&self) -> Option<f32> {
+        match self.kind {
+            MoveKind::Arc { centre, direction, plane } => {
+                let (start_a, start_b, _) = plane.split(self.start - centre);
+                let (end_a, end_b, _) = plane.split(self.end - centre);
+
+                let mut sweep = atan2(end_b, end_a) - atan2(start_b, start_a);
+                if direction == Direction::Clockwise {
+                    sweep = -sweep;
+                }
+                if sweep <= 1e-6 {
+                    sweep += 2.0 * PI;
+                }
+
+                Some(sweep)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Something on a 3D printer which can be heated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Heater {
+    /// The hotend on one of the extruders.
+    Hotend(u32),
+    /// The heated bed.
+    Bed,
+}
+
+/// The different ways a tool can move.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MoveKind {
+    /// Move as quickly as possible.
+    Rapid,
+    /// Move in a straight line at the feed rate.
+    Linear,
+    /// A circular (or helical) arc.
+    Arc {
+        /// The arc's centre, on the same plane as the start point.
+        centre: Point,
+        /// Which way the arc goes when viewed from the positive end of the
+        /// plane's normal.
+        direction: Direction,
+        /// The plane the arc lies in.
+        plane: Plane,
+    },
+    /// A straight line where the tool advances `pitch` mm for every
+    /// revolution of the spindle.
+    Threading {
+        /// The distance travelled per revolution.
+        pitch: f32,
+    },
+}
+
+/// The direction an arc goes in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    /// Clockwise (`G2`).
+    Clockwise,
+    /// Counter-clockwise (`G3`).
+    CounterClockwise,
+}
+
+/// A location in 3D space.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[allow(missing_docs)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Point {
+    /// Create a new `Point`.
+    pub fn new(x: f32, y: f32, z: f32) -> Point {
+        Point { x, y, z }
+    }
+
+    /// The distance from the origin.
+    pub fn length(&self) -> f32 {
+        sqrt(self.x * self.x + self.y * self.y + self.z * self.z)
+    }
+}
+
+impl Add for Point {
+    type Output = Point;
+
+    fn add(self, other: Point) -> Point {
+        Point::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Point {
+    type Output = Point;
+
+    fn sub(self, other: Point) -> Point {
+        Point::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f32> for Point {
+    type Output = Point;
+
+    fn mul(self, scale: f32) -> Point {
+        Point::new(self.x * scale, self.y * scale, self.z * scale)
+    }
+}
+
+
+/// Which ends of a thread are tapered.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Taper {
+    None,
+    Entry,
+    Exit,
+    Both,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct ThreadingParameters {
+    start: Point,
+    end_z: f32,
+    pitch: f32,
+    peak_offset: f32,
+    initial_depth: f32,
+    full_depth: f32,
+    degression: f32,
+    compound_angle: f32,
+    spring_passes: u32,
+    taper_length: f32,
+    taper_ends: Taper,
+}
+
+/// The expansion of a `G76` threading cycle into individual moves.
+///
+/// Each pass starts on the drive line (the X position when the cycle
+/// started), plunges to the cut depth, cuts the thread, retracts back to the
+/// drive line then returns to the start. Thread depths (`I`, `J` and `K`)
+/// are always radii, even in diameter mode.
+#[derive(Debug, Clone)]
+struct ThreadingCycle {
+    params: ThreadingParameters,
+    /// The first pass which reaches the full thread depth.
+    final_pass: u32,
+    pass: u32,
+    moves: ArrayVec<[(MoveKind, Point); 6]>,
+}
+
+impl ThreadingCycle {
+    fn new(params: ThreadingParameters) -> Result<ThreadingCycle> {
+        let mut final_pass = 1;
+        while params.initial_depth * powf(final_pass as f32, 1.0 / params.degression) <
+              params.full_depth {
+            final_pass += 1;
+
+            if final_pass > MAX_THREADING_PASSES {
+                return Err(Error::InvalidCommand("The threading cycle would need too many passes"));
+            }
+        }
+
+        Ok(ThreadingCycle {
+               params,
+               final_pass,
+               pass: 0,
+               moves: ArrayVec::new(),
+           })
+    }
+
+    /// How deep should the nth pass (counting from 1) cut?
+    fn depth(&self, pass: u32) -> Option<f32> {
+        let p = &self.params;
+
+        if pass < self.final_pass {
+            Some(p.initial_depth * powf(pass as f32, 1.0 / p.degression))
+        } else if pass <= self.final_pass + p.spring_passes {
+            Some(p.full_depth)
+        } else {
+            None
+        }
+    }
+
+    fn plan_next_pass(&mut self) {
+        let p = self.params;
+        let threading = MoveKind::Threading { pitch: p.pitch };
+        let drive_x = p.start.x;
+        let y = p.start.y;
+
+        self.pass += 1;
+        let depth = match self.depth(self.pass) {
+            Some(d) => d,
+            None => {
+                // go back to where we started, once
+                if self.pass == self.final_pass + p.spring_passes + 1 {
+                    self.moves.push((MoveKind::Rapid, p.start));
+                }
+                return;
+            }
+        };
+
+        let direction = if p.end_z < p.start.z { -1.0 } else { 1.0 };
+        let outwards = if p.peak_offset < 0.0 { -1.0 } else { 1.0 };
+        let peak_x = drive_x + p.peak_offset;
+        let cut_x = peak_x + outwards * depth;
+
+        // the compound angle shifts each pass back along the drive line so
+        // we only cut on one flank
+        let start_z = p.start.z - direction * depth * tan(p.compound_angle.to_radians());
+
+        let (entry_taper, exit_taper) = match p.taper_ends {
+            Taper::None => (false, false),
+            Taper::Entry => (true, false),
+            Taper::Exit => (false, true),
+            Taper::Both => (true, true),
+        };
+
+        self.moves.push((MoveKind::Rapid, Point::new(drive_x, y, start_z)));
+
+        if entry_taper && p.taper_length > 0.0 {
+            self.moves.push((MoveKind::Rapid, Point::new(peak_x, y, start_z)));
+            self.moves.push((threading,
+                             Point::new(cut_x, y, start_z + direction * p.taper_length)));
+        } else {
+            self.moves.push((MoveKind::Rapid, Point::new(cut_x, y, start_z)));
+        }
+
+        if exit_taper && p.taper_length > 0.0 {
+            self.moves.push((threading,
+                             Point::new(cut_x, y, p.end_z - direction * p.taper_length)));
+            self.moves.push((threading, Point::new(peak_x, y, p.end_z)));
+        } else {
+            self.moves.push((threading, Point::new(cut_x, y, p.end_z)));
+        }
+
+        self.moves.push((MoveKind::Rapid, Point::new(drive_x, y, p.end_z)));
+    }
+}
+
+impl Iterator for ThreadingCycle {
+    type Item = (MoveKind, Point);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.moves.is_empty() {
+            self.plan_next_pass();
+        }
+
+        if self.moves.is_empty() {
+            None
+        } else {
+            Some(self.moves.remove(0))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use dialect::Dialect;
+    use offsets::{WorkOffsets, CoordinateSystem};
+    use tools::ToolTable;
+    use helpers::{parse, parse_dialect};
+
+    fn interpret(src: &str, profile: Profile) -> Result<Vec<Event>> {
+        let dialect = match profile {
+            Profile::Printer => Dialect::Marlin,
+            _ => Dialect::Rs274Ngc,
+        };
+        let lines = parse_dialect(src, dialect).into_iter();
+
+        Interpreter::with_profile(lines, profile).collect()
+    }
+
+    fn moves(src: &str, profile: Profile) -> Vec<Move> {
+        interpret(src, profile)
+            .unwrap()
+            .into_iter()
+            .filter_map(|ev| match ev {
+                            Event::Move(m) => Some(m),
+                            _ => None,
+                        })
+            .collect()
+    }
+
+    fn assert_close(left: Point, right: Point) {
+        assert!((left - right).length() < 0.001,
+                "{:?} != {:?}",
+                left,
+                right);
+    }
+
+    #[test]
+    fn rapid_then_feed() {
+        let got = moves("G0 X10 Y10 G1 X20 F500", Profile::Mill);
+
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].kind, MoveKind::Rapid);
+        assert_eq!(got[0].end, Point::new(10.0, 10.0, 0.0));
+        assert_eq!(got[1].kind, MoveKind::Linear);
+        assert_eq!(got[1].start, Point::new(10.0, 10.0, 0.0));
+        assert_eq!(got[1].end, Point::new(20.0, 10.0, 0.0));
+        assert_eq!(got[1].feed_rate, 500.0);
+    }
+
+    #[test]
+    fn axis_words_use_the_modal_motion() {
+        let got = moves("G1 X1 F100\nG54 X2 Y3\nM63 P1 Z-1", Profile::Mill);
+
+        let ends: Vec<_> = got.iter().map(|m| m.end).collect();
+        assert_eq!(ends,
+                   vec![Point::new(1.0, 0.0, 0.0),
+                        Point::new(2.0, 3.0, 0.0),
+                        Point::new(2.0, 3.0, -1.0)]);
+        assert!(got.iter().all(|m| m.kind == MoveKind::Linear));
+    }
+
+    #[test]
+    fn move_lengths() {
+        let got = moves("G1 X3 Y4 F100\nG2 X-3 Y-4 I-3 J-4\nG3 X-3 Y-4 Z10 I3 J4",
+                        Profile::Mill);
+
+        assert_eq!(got[0].length(), 5.0);
+        assert!((got[1].sweep().unwrap() - PI).abs() < 0.001);
+        assert!((got[1].length() - 5.0 * PI).abs() < 0.001);
+        // a full helical turn
+        assert!((got[2].sweep().unwrap() - 2.0 * PI).abs() < 0.001);
+        let circumference = 10.0 * PI;
+        assert!((got[2].length() - (circumference * circumference + 100.0).sqrt()).abs() < 0.001);
+    }
+
+    #[test]
+    fn points_along_a_move() {
+        let got = moves("G1 X10 F100\nG2 X-10 Y0 Z4 I-10 J0", Profile::Mill);
+
+        assert_close(got[0].point_at(0.25), Point::new(2.5, 0.0, 0.0));
+        // clockwise from +X goes underneath the centre
+        assert_close(got[1].point_at(0.5), Point::new(0.0, -10.0, 2.0));
+        assert_close(got[1].point_at(1.0), got[1].end);
+    }
+
+    #[test]
+    fn incremental_inches() {
+        let got = moves("G20 G91 G0 X1 G0 X1 Y-2", Profile::Mill);
+
+        assert_close(got[1].end, Point::new(50.8, -50.8, 0.0));
+    }
+
+    #[test]
+    fn axis_words_need_a_motion_mode() {
+        assert!(interpret("G17 X10", Profile::Mill).is_err());
+    }
+
+    #[test]
+    fn arc_with_centre_offsets() {
+        let got = moves("G0 X10 G3 X0 Y10 I-10 J0", Profile::Mill);
+
+        match got[1].kind {
+            MoveKind::Arc { centre, direction, plane } => {
+                assert_close(centre, Point::default());
+                assert_eq!(direction, Direction::CounterClockwise);
+                assert_eq!(plane, Plane::XY);
+            }
+            other => panic!("Expected an arc, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arcs_with_a_radius() {
+        let inputs = [("G2 X10 Y10 R10", Point::new(10.0, 0.0, 0.0)),
+                      ("G3 X10 Y10 R10", Point::new(0.0, 10.0, 0.0)),
+                      ("G2 X10 Y10 R-10", Point::new(0.0, 10.0, 0.0)),
+                      ("G3 X10 Y10 R-10", Point::new(10.0, 0.0, 0.0))];
+
+        for &(src, should_be) in &inputs {
+            let got = moves(src, Profile::Mill);
+
+            match got[0].kind {
+                MoveKind::Arc { centre, .. } => assert_close(centre, should_be),
+                other => panic!("Expected an arc, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_arcs() {
+        let inputs = ["G2 X10 Y10", "G2 X10 R2", "G0 X5 G2 X5 R3"];
+
+        for src in &inputs {
+            assert!(interpret(src, Profile::Mill).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn dwell() {
+        let got = interpret("G4 P2.5", Profile::Mill).unwrap();
+        assert_eq!(got,
+                   vec![Event::Dwell {
+                            seconds: 2.5,
+                            span: Span::from((0, 1)),
+                        }]);
+
+        assert!(interpret("G4", Profile::Mill).is_err());
+    }
+
+    #[test]
+    fn tool_changes() {
+        let got = interpret("T3 M6 G0 X1", Profile::Mill).unwrap();
+
+        assert_eq!(got[0],
+                   Event::ToolChange {
+                       tool: 3,
+                       span: Span::from((0, 1)),
+                   });
+        match got[1] {
+            Event::Move(m) => assert_eq!(m.tool, 3),
+            other => panic!("Expected a move, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn printers_extrude_filament() {
+        let src = "M82 G1 X10 E1.5 F1800\nG1 X20 E4\nG1 E3 F2400\nG92 E0\n\
+                   M83 G1 X30 E0.5\nG1 X40";
+        let got = moves(src, Profile::Printer);
+
+        let extrusion: Vec<f32> = got.iter().map(|m| m.extrusion).collect();
+        assert_eq!(extrusion, vec![1.5, 2.5, -1.0, 0.5, 0.0]);
+        // a retraction doesn't go anywhere
+        assert_eq!(got[2].start, got[2].end);
+
+        // for everyone else E is just another word
+        let got = moves("G1 X10 E1.5 F1800\nG1 E3", Profile::Mill);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].extrusion, 0.0);
+    }
+
+    #[test]
+    fn g91_makes_extrusion_relative_too() {
+        let got = moves("G91 G1 X1 E1 F100\nX1 E1\nM82 X1 E1", Profile::Printer);
+
+        let extrusion: Vec<f32> = got.iter().map(|m| m.extrusion).collect();
+        assert_eq!(extrusion, vec![1.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn printer_temperatures_and_fans() {
+        let src = "M140 S60\nM104 S210 T1\nM190 S60\nT1\nM109 R200\nM106 S127.5\nM104 S0 T0";
+        let lines = parse_dialect(src, Dialect::Marlin).into_iter();
+        let mut interpreter = Interpreter::with_profile(lines, Profile::Printer);
+
+        let got: Vec<_> = interpreter.by_ref().collect::<Result<_>>().unwrap();
+
+        assert_eq!(got,
+                   vec![Event::WaitForTemperature {
+                            heater: Heater::Bed,
+                            target: 60.0,
+                            span: Span::from((2, 1)),
+                        },
+                        Event::ToolChange {
+                            tool: 1,
+                            span: Span::from((3, 1)),
+                        },
+                        Event::WaitForTemperature {
+                            heater: Heater::Hotend(1),
+                            target: 200.0,
+                            span: Span::from((4, 1)),
+                        }]);
+        let state = interpreter.state();
+        assert_eq!(state.bed_temperature, 60.0);
+        assert_eq!(state.hotend_temperatures[0], 0.0);
+        assert_eq!(state.hotend_temperatures[1], 200.0);
+        assert_eq!(state.fan_speed, 0.5);
+        // S words are temperatures, not spindle speeds
+        assert_eq!(state.spindle_rpm(), 0.0);
+
+        for src in &["M109", "M104 S-10", "M104 S200 T8", "T9"] {
+            assert!(interpret(src, Profile::Printer).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn moves_need_a_value_for_every_word() {
+        for src in &["G1 X Y5 F100", "G1 X5 F100\nY E", "G0 Z"] {
+            assert!(interpret(src, Profile::Printer).is_err(), "{}", src);
+        }
+
+        // other commands can take flags
+        assert!(interpret("G28 X\nM84 E", Profile::Printer).is_ok());
+    }
+
+    #[test]
+    fn printers_home_to_machine_zero() {
+        let got = moves("G0 X10 Y20 Z5\nG92 X0\nG28 X Y\nG1 X5 F100\nG28", Profile::Printer);
+
+        assert_eq!(got.len(), 4);
+        assert_eq!(got[1].kind, MoveKind::Rapid);
+        assert_eq!(got[1].end, Point::new(0.0, 0.0, 5.0));
+        // homing X clears its G92 offset
+        assert_eq!(got[2].end, Point::new(5.0, 0.0, 5.0));
+        assert_eq!(got[3].end, Point::default());
+    }
+
+    #[test]
+    fn mills_return_home_through_the_intermediate_point() {
+        let got = moves("G0 X10 Y10 Z5\nG91 G28 Z0\nG90 G30 X20\nG28", Profile::Mill);
+
+        let ends: Vec<_> = got.iter().map(|m| m.end).collect();
+        assert_eq!(ends,
+                   vec![Point::new(10.0, 10.0, 5.0),
+                        Point::new(10.0, 10.0, 0.0),
+                        Point::new(20.0, 10.0, 0.0),
+                        Point::new(0.0, 10.0, 0.0),
+                        Point::default()]);
+        assert!(got.iter().all(|m| m.kind == MoveKind::Rapid));
+    }
+
+    #[test]
+    fn return_to_the_stored_home_positions() {
+        let src = "G0 X10 Y20 Z30\nG28.1\nG0 X0 Y0 Z0\nG30.1\nG0 X5\nG28\nG30 Z40";
+        let got = moves(src, Profile::Mill);
+
+        let ends: Vec<_> = got.iter().map(|m| m.end).collect();
+        assert_eq!(ends,
+                   vec![Point::new(10.0, 20.0, 30.0),
+                        Point::default(),
+                        Point::new(5.0, 0.0, 0.0),
+                        Point::new(10.0, 20.0, 30.0),
+                        Point::new(10.0, 20.0, 40.0),
+                        Point::new(10.0, 20.0, 0.0)]);
+    }
+
+    #[test]
+    fn lathes_default_to_the_xz_plane() {
+        let got = moves("G0 Z10 G2 X5 Z5 K-5", Profile::Lathe);
+
+        match got[1].kind {
+            MoveKind::Arc { centre, plane, .. } => {
+                assert_eq!(plane, Plane::ZX);
+                assert_close(centre, Point::new(0.0, 0.0, 5.0));
+            }
+            other => panic!("Expected an arc, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn diameter_mode_halves_x() {
+        let got = moves("G7 G0 X20 Z1 G91 X-4 G8 G90 X3", Profile::Lathe);
+        let xs: Vec<_> = got.iter().map(|m| m.end.x).collect();
+
+        assert_eq!(xs, vec![10.0, 8.0, 3.0]);
+    }
+
+    #[test]
+    fn diameter_mode_is_only_for_lathes() {
+        let got = moves("G7 G0 X20", Profile::Mill);
+
+        assert_eq!(got[0].end.x, 20.0);
+    }
+
+    #[test]
+    fn constant_surface_speed() {
+        // 100 m/min at a 50mm diameter is 636.6 RPM
+        let got = moves("G0 X25 G96 S100 M3 G1 X10 F100 G1 X1", Profile::Lathe);
+
+        assert!((got[1].spindle_speed - 636.62).abs() < 0.01);
+        assert!((got[2].spindle_speed - 1591.55).abs() < 0.01);
+    }
+
+    #[test]
+    fn constant_surface_speed_uses_the_work_centreline() {
+        let got = moves("G10 L2 P1 X50\nG0 X25 G96 S100 M3 G1 X10 F100 G1 X1", Profile::Lathe);
+
+        assert_eq!(got[1].start.x, 75.0);
+        assert!((got[1].spindle_speed - 636.62).abs() < 0.01);
+        assert!((got[2].spindle_speed - 1591.55).abs() < 0.01);
+    }
+
+    #[test]
+    fn constant_surface_speed_is_clamped() {
+        let got = moves("G50 S1000 G0 X25 G96 S100 M3 G1 X1 F100 G1 X0.1", Profile::Lathe);
+        assert_eq!(got[2].spindle_speed, 1000.0);
+
+        let got = moves("G0 X0 G96 D1500 S100 M3 G1 X1 F100 G97 X2", Profile::Lathe);
+        assert_eq!(got[1].spindle_speed, 1500.0);
+        assert_eq!(got[2].spindle_speed, 1500.0);
+    }
+
+    #[test]
+    fn spindle_synchronised_motion() {
+        let got = moves("M3 S500 G33 Z-10 K1.5 G33 Z-20 K1.5", Profile::Lathe);
+
+        assert_eq!(got.len(), 2);
+        for m in &got {
+            assert_eq!(m.kind, MoveKind::Threading { pitch: 1.5 });
+            assert_eq!(m.feed_rate, 750.0);
+        }
+        assert_eq!(got[1].end.z, -20.0);
+    }
+
+    #[test]
+    fn rigid_tapping_returns_to_the_start() {
+        let got = moves("G0 X5 Z2 S200 M3 G33.1 Z-10 K1.25", Profile::Mill);
+
+        assert_eq!(got.len(), 3);
+        assert_eq!(got[1].end, Point::new(5.0, 0.0, -10.0));
+        assert_eq!(got[2].end, Point::new(5.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn threading_cycle() {
+        let src = "G0 X10 Z1 S300 M3 G76 P1.5 Z-20 I-1 J0.2 K1 R2 H1";
+        let got = moves(src, Profile::Lathe);
+
+        let cuts: Vec<_> = got.iter()
+            .filter(|m| m.kind == MoveKind::Threading { pitch: 1.5 })
+            .collect();
+
+        // depths are 0.2*sqrt(n) until we hit 1.0 at n = 25, plus a
+        // spring pass
+        assert_eq!(cuts.len(), 26);
+        assert!((cuts[0].start.x - 8.8).abs() < 0.001);
+        assert!((cuts[3].start.x - 8.6).abs() < 0.001);
+        for cut in &cuts[24..] {
+            assert!((cut.start.x - 8.0).abs() < 0.001);
+            assert_eq!(cut.end.z, -20.0);
+            assert_eq!(cut.feed_rate, 450.0);
+        }
+
+        // and we finish back where we started
+        assert_eq!(got.last().unwrap().end, Point::new(10.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn tapered_threads() {
+        let src = "G0 X10 Z0 S300 M3 G76 P1 Z-10 I1 J1 K1 E2 L3";
+        let got = moves(src, Profile::Lathe);
+
+        let cuts: Vec<_> = got.iter()
+            .filter(|m| m.kind == MoveKind::Threading { pitch: 1.0 })
+            .map(|m| (m.start, m.end))
+            .collect();
+
+        assert_eq!(cuts,
+                   vec![(Point::new(11.0, 0.0, 0.0), Point::new(12.0, 0.0, -2.0)),
+                        (Point::new(12.0, 0.0, -2.0), Point::new(12.0, 0.0, -8.0)),
+                        (Point::new(12.0, 0.0, -8.0), Point::new(11.0, 0.0, -10.0))]);
+    }
+
+    #[test]
+    fn invalid_threading_cycles() {
+        let inputs = ["G76 Z-10 I-1 J0.1 K1",
+                      "G76 P1 I-1 J0.1 K1",
+                      "G76 P1 Z-10 J0.1 K1",
+                      "G76 P1 Z-10 I-1 K1",
+                      "G76 P1 Z-10 I-1 J0.1",
+                      "G76 P1 Z-10 I-1 J0.1 K1 R0.5",
+                      "G76 P1 Z-10 I-1 J0.1 K1 L4"];
+
+        for src in &inputs {
+            assert!(interpret(src, Profile::Lathe).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn axis_words_on_their_own_line_continue_the_motion() {
+        let got = moves("G1 X1 F100\nX2\nN20 X3 Y1", Profile::Mill);
+
+        assert_eq!(got.len(), 3);
+        assert!(got.iter().all(|m| m.kind == MoveKind::Linear));
+        assert_eq!(got[2].end, Point::new(3.0, 1.0, 0.0));
+        assert_eq!(got[2].line_number, Some(20));
+    }
+
+    #[test]
+    fn select_a_coordinate_system() {
+        let mut offsets = WorkOffsets::default();
+        offsets.set(CoordinateSystem::G55, Point::new(100.0, 0.0, 0.0));
+        offsets.set(CoordinateSystem::G59_2, Point::new(0.0, 0.0, -50.0));
+        let mut state = State::new(Profile::Mill);
+        state.offsets = offsets;
+
+        let src = "G0 X10\nG55 G0 X10\nG59.2\nG0 X10 Z5";
+        let lines = parse(src).into_iter();
+        let mut interpreter = Interpreter::with_state(lines, state);
+        let got: Vec<_> = interpreter.by_ref().map(|ev| ev.unwrap()).collect();
+
+        let ends: Vec<_> = got.iter()
+            .map(|ev| match *ev {
+                     Event::Move(m) => m.end,
+                     _ => unreachable!(),
+                 })
+            .collect();
+        assert_eq!(ends,
+                   vec![Point::new(10.0, 0.0, 0.0),
+                        Point::new(110.0, 0.0, 0.0),
+                        Point::new(10.0, 0.0, -45.0)]);
+        assert_eq!(interpreter.state().offsets.active, CoordinateSystem::G59_2);
+        assert_eq!(interpreter.state().program_position(), Point::new(10.0, 0.0, 5.0));
+    }
+
+    #[test]
+    fn set_a_coordinate_system_with_g10() {
+        let src = "G10 L2 P2 X10 Y20\nG55 G0 X0 Y0\nG10 L2 P0 Z-5\nG0 Z0";
+        let got = moves(src, Profile::Mill);
+
+        assert_eq!(got[0].end, Point::new(10.0, 20.0, 0.0));
+        assert_eq!(got[1].end, Point::new(10.0, 20.0, -5.0));
+    }
+
+    #[test]
+    fn g10_l20_makes_the_current_position_the_given_value() {
+        let src = "G0 X30 Y40\nG10 L20 P1 X5\nG0 X0 Y0";
+        let got = moves(src, Profile::Mill);
+
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[1].end, Point::new(25.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn g92_offsets() {
+        let src = "G10 L2 P1 X100\n\
+                   G0 X10\n\
+                   G92 X0\n\
+                   G0 X5\n\
+                   G92.2\n\
+                   G0 X5\n\
+                   G92.3\n\
+                   G0 X5\n\
+                   G92.1\n\
+                   G92.3\n\
+                   G0 X5";
+        let got = moves(src, Profile::Mill);
+
+        let xs: Vec<_> = got.iter().map(|m| m.end.x).collect();
+        assert_eq!(xs, vec![110.0, 115.0, 105.0, 115.0, 105.0]);
+    }
+
+    #[test]
+    fn g53_only_applies_to_its_own_block() {
+        let src = "G10 L2 P1 X100 Z-10\nG53 G0 X0 Z0\nG0 X0\nG53\nG0 Z0";
+        let got = moves(src, Profile::Mill);
+
+        assert_eq!(got[0].end, Point::new(0.0, 0.0, 0.0));
+        assert_eq!(got[1].end, Point::new(100.0, 0.0, 0.0));
+        assert_eq!(got[2].end, Point::new(100.0, 0.0, -10.0));
+    }
+
+    #[test]
+    fn invalid_offset_commands() {
+        let inputs = ["G10 L2 X10",
+                      "G10 L2 P10 X10",
+                      "G10 L3 P1 X10",
+                      "G10 L2 P1 X10 R45",
+                      "G92",
+                      "G91 G53 G0 X0"];
+
+        for src in &inputs {
+            assert!(interpret(src, Profile::Mill).is_err(), "{}", src);
+        }
+    }
+
+    fn with_tools(src: &str, tools: &str) -> Result<Vec<Move>> {
+        let mut state = State::new(Profile::Mill);
+        state.tools = ToolTable::from_tbl_file(tools).unwrap();
+
+        let lines = parse(src).into_iter();
+
+        Interpreter::with_state(lines, state)
+            .filter_map(|ev| match ev {
+                            Ok(Event::Move(m)) => Some(Ok(m)),
+                            Ok(_) => None,
+                            Err(e) => Some(Err(e)),
+                        })
+            .collect()
+    }
+
+    fn ends(moves: &[Move]) -> Vec<(f32, f32)> {
+        moves.iter()
+            .map(|m| {
+                     let round = |n: f32| (n * 1000.0).round() / 1000.0;
+                     (round(m.end.x), round(m.end.y))
+                 })
+            .collect()
+    }
+
+    #[test]
+    fn tool_length_offsets() {
+        let src = "T1 M6 G43 G0 Z0\nG43 H2 Z0\nG49 Z0\nG43.1 Z-3\nG0 Z0";
+        let got = with_tools(src, "T1 P1 Z10\nT2 P2 Z25.5").unwrap();
+
+        let zs: Vec<_> = got.iter().map(|m| m.end.z).collect();
+        assert_eq!(zs, vec![10.0, 25.5, 0.0, -3.0]);
+    }
+
+    #[test]
+    fn g10_l1_updates_the_tool_table() {
+        let src = "G10 L1 P3 R2 Z5\nT3 M6 G43 G0 Z0";
+        let lines = parse(src).into_iter();
+        let mut interpreter = Interpreter::new(lines);
+
+        let got: Vec<_> = interpreter.by_ref().collect::<Result<_>>().unwrap();
+
+        assert_eq!(got.last(),
+                   Some(&Event::Move(Move {
+                                         kind: MoveKind::Rapid,
+                                         start: Point::default(),
+                                         end: Point::new(0.0, 0.0, 5.0),
+                                         feed_rate: 0.0,
+                                         spindle_speed: 0.0,
+                                         tool: 3,
+                                         tool_offset: Point::new(0.0, 0.0, 5.0),
+                                         extrusion: 0.0,
+                                         line_number: None,
+                                         span: Span::from((1, 1)),
+                                     })));
+        assert_eq!(interpreter.state().tools.get(3).unwrap().diameter, 4.0);
+        assert_eq!(interpreter.state().program_position(), Point::default());
+    }
+
+    #[test]
+    fn unknown_tools() {
+        let got = with_tools("G43 H5 G0 Z0", "T1 P1 Z10").unwrap();
+        assert_eq!(got[0].end.z, 0.0);
+
+        // offsetting by nothing would cut in the wrong place
+        assert!(with_tools("G41 D5", "T1 P1 D10").is_err());
+    }
+
+    /// A 10mm square, starting from the left.
+    const SQUARE: &str = "G0 X-5 Y0\n\
+                          G1 X0 Y0 F100\n\
+                          X10\n\
+                          Y10\n\
+                          X0\n\
+                          Y0\n\
+                          G40\n\
+                          G0 X-5 Y0";
+
+    #[test]
+    fn cutter_compensation_on_the_inside_of_a_corner() {
+        let src = SQUARE.replace("G1 X0 Y0", "G41 D1 G1 X0 Y0");
+        let got = with_tools(&src, "T1 P1 D2").unwrap();
+
+        assert_eq!(ends(&got),
+                   vec![(-5.0, 0.0), (0.0, 1.0), (9.0, 1.0), (9.0, 9.0), (1.0, 9.0), (1.0, 0.0),
+                        (-5.0, 0.0)]);
+        // every move starts where the last one finished
+        for pair in got.windows(2) {
+            assert_close(pair[0].end, pair[1].start);
+        }
+    }
+
+    #[test]
+    fn cutter_compensation_on_the_outside_of_a_corner() {
+        let src = SQUARE.replace("G1 X0 Y0", "G42.1 D2 G1 X0 Y0");
+        let got = with_tools(&src, "").unwrap();
+
+        assert_eq!(ends(&got),
+                   vec![(-5.0, 0.0),
+                        (0.0, -1.0),
+                        (10.0, -1.0),
+                        (11.0, 0.0),
+                        (11.0, 10.0),
+                        (10.0, 11.0),
+                        (0.0, 11.0),
+                        (-1.0, 10.0),
+                        (-1.0, 0.0),
+                        (-5.0, 0.0)]);
+
+        let corner = MoveKind::Arc {
+            centre: Point::new(10.0, 0.0, 0.0),
+            direction: Direction::CounterClockwise,
+            plane: Plane::XY,
+        };
+        assert_eq!(got[3].kind, corner);
+    }
+
+    #[test]
+    fn compensated_arcs() {
+        let src = "G0 X-5 Y0\nG41.1 D2 G1 X0 Y0 F100\nG3 X10 Y0 I5 J0\nG1 X15\nG40 G0 X20";
+        let got = with_tools(src, "").unwrap();
+
+        assert_eq!(ends(&got),
+                   vec![(-5.0, 0.0), (0.0, 1.0), (1.0, 0.0), (9.0, 0.0), (10.0, 1.0), (15.0, 1.0),
+                        (20.0, 0.0)]);
+
+        // the arc is on the tool's side, so it gets smaller
+        let centre = Point::new(5.0, 0.0, 0.0);
+        assert_eq!(got[3].kind,
+                   MoveKind::Arc {
+                       centre,
+                       direction: Direction::CounterClockwise,
+                       plane: Plane::XY,
+                   });
+        assert_close(got[3].start - centre, Point::new(-4.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn moves_out_of_the_plane_happen_at_the_corner() {
+        let src = "G0 X-5 Y0\nG41.1 D2 G1 X0 Y0 F100\nX10\nZ-1\nY10\nG40 G0 X20";
+        let got = with_tools(src, "").unwrap();
+
+        assert_eq!(got[3].start, Point::new(9.0, 1.0, 0.0));
+        assert_eq!(got[3].end, Point::new(9.0, 1.0, -1.0));
+        assert_eq!(got[4].start, Point::new(9.0, 1.0, -1.0));
+    }
+
+    #[test]
+    fn cutter_compensation_detects_gouges() {
+        let inputs = [// the tool doesn't fit between the corners
+                      "G41.1 D2 G1 X0 Y0\nX10\nY0.5\nX0",
+                      // the arc is smaller than the tool
+                      "G41.1 D2 G1 X0 Y0\nX10\nG3 X11 Y1 I0 J1\nG1 Y10",
+                      // entry moves need to be longer than the radius
+                      "G0 X-0.5\nG41.1 D2 G1 X0 Y0\nX10",
+                      // and straight
+                      "G0 X-5\nG41.1 D2 G3 X0 Y0 R5\nX10",
+                      // you can't change plane
+                      "G41.1 D2 G1 X5\nG18"];
+
+        for src in &inputs {
+            assert!(with_tools(src, "").is_err(), "{}", src);
+        }
+    }
+}
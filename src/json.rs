@@ -0,0 +1,186 @@
+//! A stable JSON representation of parsed programs.
+//!
+//! With the `serde` feature enabled, `Token`, `TokenKind`, `Span`, `Line`,
+//! `Command`, `CommandKind`, `Number` and `Args` can all be serialized and
+//! deserialized. A whole program should be wrapped in a `Program`, which
+//! records the version of the schema it was written with.
+//!
+//! # Schema
+//!
+//! A `Program` is an object with two fields:
+//!
+//! - `schema_version` - an integer, currently [`SCHEMA_VERSION`]. It only
+//!   changes when the format changes in a way older readers can't handle.
+//! - `lines` - an array of lines, in the order they appear in the source.
+//!
+//! Each line is an object with exactly one of these keys:
+//!
+//! - `program_number` - an integer, for an `O` word (e.g. `O100`).
+//! - `cmd` - a command (`G`, `M` or `T`), which is an object with:
+//!   - `kind` - `"G"`, `"M"` or `"T"`
+//!   - `number` - either `{"integer": n}` (e.g. `G1`) or
+//!     `{"decimal": [n, fraction]}` (e.g. `G33.1` is `[33, 1]`)
+//!   - `args` - the arguments (see below)
+//!   - `line_number` - the `N` number as an integer, or `null`
+//!   - `span` - where the command starts
+//! - `args` - arguments on their own, which continue on from the previous
+//!   motion command. This is an object with `args`, `line_number` and
+//!   `span` fields, the same as for a command.
+//!
+//! Arguments are an object mapping lowercase letters (`x`, `y`, `z`, `s`,
+//! `t`, `f`, `i`, `j`, `h`, `p`, `r`, `e`, `k`, `l`, `q` and `d`) to numbers.
+//! Only the arguments which were given are included, so a command without
+//! any arguments has `"args": {}`. Two other keys may also be present:
+//!
+//! - `flags` - the words given without a value as a string of uppercase
+//!   letters, so `G28 X Y` has `"flags": "XY"`
+//! - `text` - a string argument, such as the message in `M117 Hello`
+//!
+//! A span is an object with `line` (counting from zero) and `column`
+//! integers.
+//!
+//! New argument letters may be added without changing the schema version,
+//! so readers should ignore keys they don't recognise.
+//!
+//! [`SCHEMA_VERSION`]: constant.SCHEMA_VERSION.html
+//!
+//! # Examples
+//!
+//! ```rust
+//! extern crate gcode;
+//! extern crate serde_json;
+//!
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::json::Program;
+//!
+//! # fn main() {
+//! let src = "O100\nN10 G1 X10 F500\nY5";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let json = serde_json::to_string(&Program::new(lines)).unwrap();
+//!
+//! let should_be = r#"{"schema_version":1,"lines":[
+//!     {"program_number":100},
+//!     {"cmd":{"kind":"G","number":{"integer":1},"args":{"x":10.0,"f":500.0},
+//!             "line_number":10,"span":{"line":1,"column":1}}},
+//!     {"args":{"args":{"y":5.0},"line_number":null,"span":{"line":2,"column":1}}}]}"#;
+//! let should_be: String = should_be.split_whitespace().collect();
+//! assert_eq!(json, should_be);
+//! # }
+//! ```
+
+use std::vec::Vec;
+
+use parser::Line;
+
+
+/// The version of the JSON schema written by this version of the crate.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A whole program, as it's written to JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    /// The version of the schema the program was written with.
+    pub schema_version: u32,
+    /// Every line in the program.
+    pub lines: Vec<Line>,
+}
+
+impl Program {
+    /// Wrap up a program, using the current `SCHEMA_VERSION`.
+    pub fn new(lines: Vec<Line>) -> Program {
+        Program {
+            schema_version: SCHEMA_VERSION,
+            lines,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use lexer::{Tokenizer, Token, TokenKind, Span, Text};
+    use parser::{Command, CommandKind, Number, Args};
+    use helpers::parse;
+
+    #[test]
+    fn programs_survive_a_round_trip() {
+        let program = Program::new(parse("O1\nN10 G90.1 G2 X1.5 Y-2 I0.5 J0.5 F200\nX5\nT2 M6"));
+
+        let json = serde_json::to_string(&program).unwrap();
+        let got: Program = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(got, program);
+    }
+
+    #[test]
+    fn commands_are_written_in_the_documented_format() {
+        let cmd = Command {
+            kind: CommandKind::G,
+            number: Number::Decimal(33, 1),
+            args: Args::default(),
+            line_number: None,
+            span: Span::from((3, 1)),
+        };
+
+        let got = serde_json::to_value(Line::Cmd(cmd)).unwrap();
+
+        let should_be = json!({
+            "cmd": {
+                "kind": "G",
+                "number": {"decimal": [33, 1]},
+                "args": {},
+                "line_number": null,
+                "span": {"line": 3, "column": 1},
+            }
+        });
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn unknown_and_missing_keys_are_fine() {
+        let src = r#"{"schema_version": 1, "lines": [
+            {"args": {"args": {"x": 1, "w": 2}, "span": {"line": 0, "column": 1}}}
+        ]}"#;
+
+        let got: Program = serde_json::from_str(src).unwrap();
+
+        let should_be = Line::Args {
+            args: Args {
+                x: Some(1.0),
+                ..Default::default()
+            },
+            line_number: None,
+            span: Span::from((0, 1)),
+        };
+        assert_eq!(got.lines, vec![should_be]);
+    }
+
+    #[test]
+    fn flags_and_text_are_strings() {
+        let mut args = Args::default();
+        args.flags.insert('Y');
+        args.flags.insert('x');
+        args.text = Text::new("Hello");
+
+        let got = serde_json::to_value(args).unwrap();
+
+        assert_eq!(got, json!({"flags": "XY", "text": "Hello"}));
+        assert_eq!(serde_json::from_value::<Args>(got).unwrap(), args);
+        assert!(serde_json::from_value::<Args>(json!({"flags": "X1"})).is_err());
+    }
+
+    #[test]
+    fn tokens_can_be_serialized() {
+        let tokens: Vec<Token> = Tokenizer::new("G1 X-2.5".chars()).map(|t| t.unwrap()).collect();
+
+        let json = serde_json::to_string(&tokens).unwrap();
+        let got: Vec<Token> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(got, tokens);
+        assert_eq!(got[3].kind(), TokenKind::Minus);
+    }
+}
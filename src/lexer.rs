@@ -1,7 +1,8 @@
 //! A module for turning raw gcode into tokens to be processed by the parser.
 
 use core::iter::Peekable;
-use core::fmt::{self, Display, Formatter};
+use core::fmt::{self, Debug, Display, Formatter};
+use arrayvec::ArrayString;
 
 
 #[cfg(test)]
@@ -13,6 +14,10 @@ use errors::*;
 use helpers::*;
 
 
+/// The longest string argument a `Text` can hold, in bytes.
+pub const MAX_TEXT_LENGTH: usize = 64;
+
+
 /// A zero-allocation tokenizer.
 ///
 /// # Examples
@@ -29,6 +34,11 @@ pub struct Tokenizer<I>
     src: Peekable<I>,
     line: usize,
     column: usize,
+    /// Codes where the rest of the line is a string argument.
+    string_codes: &'static [(char, u32)],
+    previous: Option<TokenKind>,
+    /// Is the rest of the current line a string argument?
+    text_pending: bool,
 }
 
 
@@ -37,14 +47,33 @@ impl<I> Tokenizer<I>
 {
     /// Create a new `Tokenizer` from some `char` iterator.
     pub fn new(src: I) -> Self {
+        Tokenizer::with_string_codes(src, &[])
+    }
+
+    /// Create a `Tokenizer` where everything after one of the `string_codes`
+    /// (e.g. `('M', 117)`) up to the end of the line is a single
+    /// `TokenKind::Text`, so `M117 Printing...` keeps its message.
+    ///
+    /// `Dialect::string_codes()` has the codes used by each dialect.
+    pub fn with_string_codes(src: I, string_codes: &'static [(char, u32)]) -> Self {
         Tokenizer {
             src: src.peekable(),
             line: 0,
             column: 0,
+            string_codes,
+            previous: None,
+            text_pending: false,
         }
     }
 
     fn next_token(&mut self) -> Option<Result<Token>> {
+        if self.text_pending {
+            self.text_pending = false;
+            if let Some(text) = self.tokenize_text() {
+                return Some(text);
+            }
+        }
+
         while let Some(peek) = self.next_char() {
             if peek.is_whitespace() {
                 continue;
@@ -56,7 +85,11 @@ impl<I> Tokenizer<I>
             };
 
             let tok = match peek {
-                d if d.is_digit(10) => self.tokenize_number(d, span),
+                d if d.is_ascii_digit() => self.tokenize_number(d, span),
+                // slicers like to leave off the leading zero (e.g. "E.0321")
+                '.' if matches!(self.src.peek(), Some(c) if c.is_ascii_digit()) => {
+                    Ok(self.tokenize_fraction(0, span))
+                }
                 a if a.is_alphabetic() => self.tokenize_alpha(a, span),
 
                 ';' => {
@@ -79,13 +112,17 @@ impl<I> Tokenizer<I>
                 '-' => {
                     Ok(Token {
                            kind: TokenKind::Minus,
-                           span: span,
+                           span,
                        })
                 }
 
                 other => Err(Error::UnknownToken(other, span)),
             };
 
+            if let Ok(ref token) = tok {
+                self.text_pending = self.starts_text(token.kind);
+                self.previous = Some(token.kind);
+            }
             return Some(tok);
         }
 
@@ -119,9 +156,14 @@ impl<I> Tokenizer<I>
         }
 
         let _ = self.next_char();
+        Ok(self.tokenize_fraction(integer_part, span))
+    }
 
+    /// Read the digits after a decimal point (which has already been
+    /// consumed).
+    fn tokenize_fraction(&mut self, integer_part: u32, span: Span) -> Token {
         let kind = match self.src.peek().cloned() {
-            Some(d) if d.is_digit(10) => {
+            Some(d) if d.is_ascii_digit() => {
                 let next = self.next_char().unwrap();
                 let (fractional_part, length) = self.tokenize_integer(next);
 
@@ -131,7 +173,62 @@ impl<I> Tokenizer<I>
             _ => TokenKind::Number(integer_part as f32),
         };
 
-        Ok(Token { kind, span })
+        Token { kind, span }
+    }
+
+    /// Is this the number of a code whose arguments are a string?
+    fn starts_text(&self, kind: TokenKind) -> bool {
+        let letter = match self.previous {
+            Some(TokenKind::G) => 'G',
+            Some(TokenKind::M) => 'M',
+            Some(TokenKind::T) => 'T',
+            _ => return false,
+        };
+
+        match kind {
+            TokenKind::Number(n) if n == (n as u32) as f32 => {
+                self.string_codes.contains(&(letter, n as u32))
+            }
+            _ => false,
+        }
+    }
+
+    /// Read a string argument, which runs until the end of the line or a
+    /// `;` comment.
+    fn tokenize_text(&mut self) -> Option<Result<Token>> {
+        while matches!(self.src.peek(), Some(&' ') | Some(&'\t')) {
+            let _ = self.next_char();
+        }
+
+        let span = Span {
+            line: self.line,
+            column: self.column + 1,
+        };
+        let mut buffer = ArrayString::<[u8; MAX_TEXT_LENGTH]>::new();
+        let mut too_long = false;
+
+        while let Some(peek) = self.src.peek().cloned() {
+            if peek == '\n' || peek == ';' {
+                break;
+            }
+
+            let _ = self.next_char();
+            too_long = too_long || buffer.try_push(peek).is_err();
+        }
+
+        if too_long {
+            return Some(Err(Error::SyntaxError("String arguments can't be longer than 64 bytes",
+                                               span)));
+        }
+
+        Text::new(buffer.trim_end())
+            .filter(|text| !text.as_str().is_empty())
+            .map(|text| {
+                     Ok(Token {
+                            kind: TokenKind::Text(text),
+                            span,
+                        })
+                 })
     }
 
     fn tokenize_integer(&mut self, first: char) -> (u32, u32) {
@@ -140,7 +237,7 @@ impl<I> Tokenizer<I>
         let mut count = 1;
 
         while let Some(peek) = self.src.peek().cloned() {
-            if !peek.is_digit(10) {
+            if !peek.is_ascii_digit() {
                 break;
             }
 
@@ -174,6 +271,10 @@ impl<I> Tokenizer<I>
             'I' => TokenKind::I,
             'J' => TokenKind::J,
             'E' => TokenKind::E,
+            'K' => TokenKind::K,
+            'L' => TokenKind::L,
+            'Q' => TokenKind::Q,
+            'D' => TokenKind::D,
 
             _ => TokenKind::Other(first),
         };
@@ -193,7 +294,7 @@ impl<I> Tokenizer<I>
     }
 
     fn skip_comment(&mut self) {
-        while self.src.peek().map_or(false, |&peek| peek != ')') {
+        while matches!(self.src.peek(), Some(&peek) if peek != ')') {
             let _ = self.next_char();
         }
 
@@ -215,6 +316,7 @@ impl<I> Iterator for Tokenizer<I>
 
 /// A gcode Token.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Token {
     kind: TokenKind,
     span: Span,
@@ -237,6 +339,7 @@ impl Token {
 
 /// A `gcode` token.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub enum TokenKind {
     /// A floating point number.
@@ -261,10 +364,17 @@ pub enum TokenKind {
     I,
     J,
     E,
+    K,
+    L,
+    Q,
+    D,
 
     Minus,
     Percent,
 
+    /// A string argument (see `Tokenizer::with_string_codes()`).
+    Text(Text),
+
     /// An escape hatch which matches any other single alphabetic character.
     ///
     /// # Note
@@ -277,8 +387,74 @@ pub enum TokenKind {
 }
 
 
+/// The string argument to a command, such as the message in
+/// `M117 Printing...` or the file name in `M23 part.gco`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Text(ArrayString<[u8; MAX_TEXT_LENGTH]>);
+
+impl Text {
+    /// Create a new `Text`, or `None` if the string is longer than
+    /// `MAX_TEXT_LENGTH` bytes.
+    pub fn new(s: &str) -> Option<Text> {
+        ArrayString::from(s).ok().map(Text)
+    }
+
+    /// Get the text as a string slice.
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Debug for Text {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Display for Text {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Text {
+    fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+        where S: ::serde::Serializer
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Text {
+    fn deserialize<D>(deserializer: D) -> ::core::result::Result<Text, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        struct TextVisitor;
+
+        impl<'de> ::serde::de::Visitor<'de> for TextVisitor {
+            type Value = Text;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                write!(f, "a string of at most {} bytes", MAX_TEXT_LENGTH)
+            }
+
+            fn visit_str<E>(self, s: &str) -> ::core::result::Result<Text, E>
+                where E: ::serde::de::Error
+            {
+                Text::new(s).ok_or_else(|| E::invalid_length(s.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_str(TextVisitor)
+    }
+}
+
+
 /// A representation of a position in source code.
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Span {
     /// The line number (counting from zero).
     pub line: usize,
@@ -343,6 +519,10 @@ impl Rand for TokenKind {
                 17 => TokenKind::E,
                 18 => TokenKind::Minus,
                 19 => TokenKind::Percent,
+                20 => TokenKind::K,
+                21 => TokenKind::L,
+                22 => TokenKind::Q,
+                23 => TokenKind::D,
                 _ => continue,
             };
 
@@ -389,6 +569,10 @@ mod tests {
                       ("I", TokenKind::I),
                       ("J", TokenKind::J),
                       ("E", TokenKind::E),
+                      ("K", TokenKind::K),
+                      ("L", TokenKind::L),
+                      ("Q", TokenKind::Q),
+                      ("D", TokenKind::D),
 
                       ("w", TokenKind::Other('w'))];
 
@@ -408,7 +592,9 @@ mod tests {
                       ("12.", TokenKind::Number(12.0)),
                       ("12.34", TokenKind::Number(12.34)),
                       ("00012312.00000001", TokenKind::Number(12312.00000001)),
-                      ("12.34.", TokenKind::Number(12.34))];
+                      ("12.34.", TokenKind::Number(12.34)),
+                      (".5", TokenKind::Number(0.5)),
+                      (".0321", TokenKind::Number(0.0321))];
 
         for &(src, should_be) in &inputs {
             println!("{} => {:?}", src, should_be);
@@ -419,6 +605,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn the_rest_of_the_line_can_be_a_string() {
+        let src = "M117 Printing   layer 1 ; comment\nM117\nm23 Part.gco\nM1170 S5";
+        let tokens: ::std::vec::Vec<_> = Tokenizer::with_string_codes(src.chars(),
+                                                                      &[('M', 117), ('M', 23)])
+            .map(|t| t.unwrap())
+            .collect();
+
+        assert_eq!(tokens[2].kind(),
+                   TokenKind::Text(Text::new("Printing   layer 1").unwrap()));
+        assert_eq!(tokens[2].span(), Span::from((0, 6)));
+        assert_eq!(tokens[3].kind(), TokenKind::M);
+        assert_eq!(tokens[7].kind(), TokenKind::Text(Text::new("Part.gco").unwrap()));
+        assert_eq!(tokens[10], TokenKind::S);
+
+        // without the table it's just a bunch of letters
+        let got = Tokenizer::new("M117 Hi".chars()).nth(2).unwrap().unwrap();
+        assert_eq!(got.kind(), TokenKind::H);
+    }
+
+    #[test]
+    fn strings_have_a_maximum_length() {
+        let src = format!("M117 {}\nG0", "x".repeat(MAX_TEXT_LENGTH + 1));
+        let tokens: ::std::vec::Vec<_> = Tokenizer::with_string_codes(src.chars(), &[('M', 117)])
+            .collect();
+
+        assert_eq!(tokens.len(), 5);
+        assert!(tokens[2].is_err());
+        let g = tokens[3].unwrap();
+        assert_eq!(g.kind(), TokenKind::G);
+        assert_eq!(g.span(), Span::from((1, 1)));
+    }
+
+    #[test]
+    fn a_lonely_decimal_point_is_still_unknown() {
+        let got: ::std::vec::Vec<_> = Tokenizer::new("X.".chars()).collect();
+
+        assert_eq!(got[1], Err(Error::UnknownToken('.', Span::from((0, 2)))));
+    }
+
     #[test]
     fn tokenize_integers() {
         let inputs = [("12", (12, 2)),
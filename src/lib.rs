@@ -3,6 +3,9 @@
 //! The crate uses iterators extensively to implement a zero-allocation lexer
 //! and parser.
 //!
+//! Anything which needs to allocate (e.g. the cycle time `Report`) is only
+//! available when the `std` feature is enabled (the default).
+//!
 //!
 //! # Examples
 //!
@@ -49,8 +52,14 @@
         unused_qualifications,
         unstable_features)]
 #![allow(deprecated)]
+// the original tests were written long before these lints existed
+#![cfg_attr(test, allow(clippy::approx_constant,
+                        clippy::excessive_precision,
+                        clippy::zero_prefixed_literal,
+                        clippy::is_digit_ascii_radix,
+                        clippy::useless_vec))]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 
@@ -62,12 +71,58 @@ extern crate quickcheck;
 extern crate rand;
 
 extern crate arrayvec;
+extern crate libm;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_json;
 
 #[deprecated(since="0.2.0", note="Please use the `parser` module instead")]
 pub mod low_level;
 pub mod lexer;
 mod helpers;
 pub mod parser;
+pub mod interpreter;
+pub mod offsets;
+pub mod tools;
+pub mod estimate;
+pub mod stats;
+pub mod dialect;
+pub mod grbl;
+#[cfg(feature = "std")]
+mod connection;
+#[cfg(feature = "std")]
+pub mod grbl_host;
+#[cfg(feature = "std")]
+pub mod marlin_host;
+pub mod receiver;
+#[cfg(feature = "std")]
+pub mod validate;
+#[cfg(feature = "std")]
+pub mod collision;
+#[cfg(feature = "std")]
+pub mod simulation;
+pub mod svg;
+pub mod writer;
+pub mod transform;
+pub mod convert;
+#[cfg(feature = "std")]
+pub mod arc_fitting;
+#[cfg(feature = "std")]
+pub mod travel;
+#[cfg(feature = "std")]
+pub mod nest;
+#[cfg(feature = "std")]
+pub mod minify;
+#[cfg(feature = "std")]
+pub mod pretty;
+#[cfg(feature = "std")]
+pub mod lint;
+#[cfg(all(feature = "serde", feature = "std"))]
+pub mod json;
+mod compensation;
 
 pub use parser::Parser;
 pub use lexer::{Tokenizer, Span};
@@ -0,0 +1,568 @@
+//! Finding things in a program which are probably mistakes.
+//!
+//! The `Linter` walks through a parsed program checking it against a set of
+//! `Rule`s, each of which can be turned on or off. Unlike
+//! `validate::Machine`, nothing here depends on the machine the program will
+//! run on (other than the dialect of gcode it understands), and the program
+//! doesn't need to be valid for the `Interpreter`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::lint::{Linter, Dialect, Rule};
+//!
+//! let src = "G21 G90\nG0 X0 Y0\nG1 X10\nM30";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let mut linter = Linter::new(Dialect::Rs274Ngc);
+//! linter.disable(Rule::SpindleOff);
+//! let lints = linter.lint(&lines);
+//!
+//! assert_eq!(lints.len(), 1);
+//! assert_eq!(lints[0].rule, Rule::NoFeedRate);
+//! assert_eq!(lints[0].span.line, 2);
+//! assert_eq!(lints[0].to_string(),
+//!            "line: 2, column: 1: feed move without a feed rate [no-feed-rate]");
+//! ```
+
+use std::collections::BTreeSet;
+use std::vec::Vec;
+use std::string::{String, ToString};
+use core::fmt::{self, Display, Formatter};
+
+use lexer::Span;
+use parser::{Line, Command, CommandKind, Number, Args};
+use stats::Code;
+use helpers::{abs, distance};
+
+pub use dialect::Dialect;
+
+
+/// Something the `Linter` can check for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rule {
+    /// A feed move (`G1`, `G2` or `G3`) happens before any feed rate is set,
+    /// or without an `F` word while in inverse time mode (`G93`).
+    NoFeedRate,
+    /// An arc's centre isn't the same distance from its start and end, or
+    /// its `R` is too small to reach the end point.
+    ArcRadius,
+    /// The tool feeds while the spindle is off. In Marlin, which is mostly
+    /// used for 3D printers, this is only checked once the program has
+    /// turned a spindle or laser on.
+    SpindleOff,
+    /// An arc is given both a radius (`R`) and a centre (`I`, `J` or `K`).
+    RadiusAndCentre,
+    /// The program never ends with `M2` or `M30`. Marlin programs don't need
+    /// to end, and `M30` deletes a file instead.
+    MissingProgramEnd,
+    /// The machine moves before the units (`G20` or `G21`) are set.
+    UnitsNotSet,
+    /// The same `N` number is used twice in a program.
+    DuplicateLineNumber,
+    /// There's code after the end of the program.
+    Unreachable,
+    /// A code the chosen `Dialect` doesn't understand.
+    UnknownCode,
+}
+
+impl Rule {
+    /// Every rule, in the order they're documented.
+    pub const ALL: [Rule; 9] = [Rule::NoFeedRate,
+                                Rule::ArcRadius,
+                                Rule::SpindleOff,
+                                Rule::RadiusAndCentre,
+                                Rule::MissingProgramEnd,
+                                Rule::UnitsNotSet,
+                                Rule::DuplicateLineNumber,
+                                Rule::Unreachable,
+                                Rule::UnknownCode];
+
+    /// The rule's ID, as used when reporting and when turning it on or
+    /// off.
+    pub fn id(&self) -> &'static str {
+        match *self {
+            Rule::NoFeedRate => "no-feed-rate",
+            Rule::ArcRadius => "arc-radius",
+            Rule::SpindleOff => "spindle-off",
+            Rule::RadiusAndCentre => "radius-and-centre",
+            Rule::MissingProgramEnd => "missing-program-end",
+            Rule::UnitsNotSet => "units-not-set",
+            Rule::DuplicateLineNumber => "duplicate-line-number",
+            Rule::Unreachable => "unreachable",
+            Rule::UnknownCode => "unknown-code",
+        }
+    }
+
+    /// Look up a rule by its ID.
+    pub fn from_id(id: &str) -> Option<Rule> {
+        Rule::ALL.iter().find(|rule| rule.id() == id).cloned()
+    }
+
+    /// A short description of what the rule checks for.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Rule::NoFeedRate => "feed move without a feed rate",
+            Rule::ArcRadius => "arc radius is different at the start and end",
+            Rule::SpindleOff => "feed move while the spindle is off",
+            Rule::RadiusAndCentre => "arc with both a radius and a centre",
+            Rule::MissingProgramEnd => "the program doesn't end with M2 or M30",
+            Rule::UnitsNotSet => "moving before the units are set",
+            Rule::DuplicateLineNumber => "duplicate line number",
+            Rule::Unreachable => "code after the end of the program",
+            Rule::UnknownCode => "unknown code",
+        }
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+
+/// A possible mistake found by the `Linter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    /// The rule which found it.
+    pub rule: Rule,
+    /// The start of the block it was found in.
+    pub span: Span,
+    /// A description of what's wrong.
+    pub message: String,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {} [{}]", self.span, self.message, self.rule)
+    }
+}
+
+/// Checks programs against a set of `Rule`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linter {
+    enabled: BTreeSet<Rule>,
+    /// The dialect the program is written in, which decides which codes
+    /// `Rule::UnknownCode` accepts and whether a program has to end.
+    pub dialect: Dialect,
+    /// How different the radius at either end of an arc can be before
+    /// `Rule::ArcRadius` complains, in the program's units.
+    pub arc_tolerance: f32,
+}
+
+impl Linter {
+    /// Create a `Linter` with every rule turned on.
+    pub fn new(dialect: Dialect) -> Linter {
+        Linter {
+            enabled: Rule::ALL.iter().cloned().collect(),
+            dialect,
+            arc_tolerance: 0.005,
+        }
+    }
+
+    /// Turn a rule on.
+    pub fn enable(&mut self, rule: Rule) {
+        let _ = self.enabled.insert(rule);
+    }
+
+    /// Turn a rule off.
+    pub fn disable(&mut self, rule: Rule) {
+        let _ = self.enabled.remove(&rule);
+    }
+
+    /// Is this rule turned on?
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        self.enabled.contains(&rule)
+    }
+
+    /// Check a program, returning everything found in the order it appears
+    /// in the source code.
+    pub fn lint(&self, lines: &[Line]) -> Vec<Lint> {
+        let mut state = State {
+            linter: self,
+            lints: Vec::new(),
+            motion: None,
+            inverse_time: false,
+            feed_rate: None,
+            spindle_on: false,
+            spindle_used: false,
+            units_set: false,
+            absolute: true,
+            absolute_arcs: false,
+            plane: (0, 1),
+            position: [None; 3],
+            line_numbers: BTreeSet::new(),
+            ended: false,
+            ever_ended: false,
+            reported: BTreeSet::new(),
+        };
+
+        for &line in lines {
+            state.check(line);
+        }
+
+        if !state.ever_ended && self.dialect != Dialect::Marlin {
+            let span = lines.iter().rev().filter_map(Line::span).next().unwrap_or_default();
+            state.report(Rule::MissingProgramEnd, span, None);
+        }
+
+        let mut lints = state.lints;
+        lints.sort_by_key(|lint| (lint.span.line, lint.span.column));
+        lints
+    }
+}
+
+
+/// What the program has done so far.
+#[derive(Debug)]
+struct State<'a> {
+    linter: &'a Linter,
+    lints: Vec<Lint>,
+    motion: Option<Number>,
+    inverse_time: bool,
+    feed_rate: Option<f32>,
+    spindle_on: bool,
+    /// Has the spindle ever been turned on?
+    spindle_used: bool,
+    units_set: bool,
+    absolute: bool,
+    absolute_arcs: bool,
+    /// The indices of the two axes in the current plane.
+    plane: (usize, usize),
+    position: [Option<f32>; 3],
+    line_numbers: BTreeSet<u32>,
+    ended: bool,
+    ever_ended: bool,
+    /// Rules which only get reported once until something changes.
+    reported: BTreeSet<Rule>,
+}
+
+impl<'a> State<'a> {
+    fn report(&mut self, rule: Rule, span: Span, message: Option<String>) {
+        if self.linter.is_enabled(rule) {
+            self.lints.push(Lint {
+                                rule,
+                                span,
+                                message: message.unwrap_or_else(|| rule.description().to_string()),
+                            });
+        }
+    }
+
+    /// Report something, but only the first time it happens in a row.
+    fn report_once(&mut self, rule: Rule, span: Span) {
+        if self.reported.insert(rule) {
+            self.report(rule, span, None);
+        }
+    }
+
+    fn check(&mut self, line: Line) {
+        let (line_number, span) = match line {
+            Line::ProgramNumber(_) => {
+                // a new (sub)program starts, which can be reached by calling it
+                self.ended = false;
+                self.line_numbers.clear();
+                let _ = self.reported.remove(&Rule::Unreachable);
+                return;
+            }
+            Line::Cmd(ref cmd) => (cmd.line_number, cmd.span),
+            Line::Args { line_number, span, .. } => (line_number, span),
+        };
+
+        if self.ended {
+            self.report_once(Rule::Unreachable, span);
+            return;
+        }
+
+        if let Some(n) = line_number {
+            if !self.line_numbers.insert(n) {
+                self.report(Rule::DuplicateLineNumber, span, Some(format!("N{} is used twice", n)));
+            }
+        }
+
+        match line {
+            Line::Cmd(cmd) => self.check_command(cmd),
+            Line::Args { args, span, .. } => self.check_args(args, span),
+            Line::ProgramNumber(_) => unreachable!(),
+        }
+    }
+
+    fn check_command(&mut self, cmd: Command) {
+        let code = Code::new(cmd.kind, cmd.number);
+        if !self.linter.dialect.supports(code) {
+            let message = format!("{} isn't part of the {:?} dialect", code, self.linter.dialect);
+            self.report(Rule::UnknownCode, cmd.span, Some(message));
+        }
+
+        match (cmd.kind, cmd.number) {
+            (CommandKind::G, Number::Integer(80)) => self.motion = None,
+            (CommandKind::G, Number::Integer(0..=3)) |
+            (CommandKind::G, Number::Integer(73)) |
+            (CommandKind::G, Number::Integer(76)) |
+            (CommandKind::G, Number::Integer(81..=89)) |
+            (CommandKind::G, Number::Decimal(38, _)) => self.motion = Some(cmd.number),
+            (CommandKind::G, Number::Integer(17)) => self.plane = (0, 1),
+            (CommandKind::G, Number::Integer(18)) => self.plane = (2, 0),
+            (CommandKind::G, Number::Integer(19)) => self.plane = (1, 2),
+            (CommandKind::G, Number::Integer(20)) |
+            (CommandKind::G, Number::Integer(21)) => {
+                self.units_set = true;
+                self.position = [None; 3];
+            }
+            (CommandKind::G, Number::Integer(90)) => self.absolute = true,
+            (CommandKind::G, Number::Integer(91)) => self.absolute = false,
+            (CommandKind::G, Number::Decimal(90, 1)) => self.absolute_arcs = true,
+            (CommandKind::G, Number::Decimal(91, 1)) => self.absolute_arcs = false,
+            (CommandKind::G, Number::Integer(93)) => self.inverse_time = true,
+            (CommandKind::G, Number::Integer(94)) |
+            (CommandKind::G, Number::Integer(95)) => self.inverse_time = false,
+            (CommandKind::G, Number::Integer(4)) |
+            (CommandKind::G, Number::Integer(40)) |
+            (CommandKind::G, Number::Integer(49)) |
+            (CommandKind::G, Number::Integer(61)) |
+            (CommandKind::G, Number::Integer(64)) |
+            (CommandKind::G, Number::Decimal(64, _)) => {}
+            (CommandKind::G, _) => {
+                // work offsets, homing, etc. so we don't know where we are
+                // any more, and any axis words belong to the command
+                self.position = [None; 3];
+                self.set_feed_rate(cmd.args.f);
+                return;
+            }
+            (CommandKind::M, Number::Integer(3)) |
+            (CommandKind::M, Number::Integer(4)) => {
+                self.spindle_on = true;
+                self.spindle_used = true;
+                let _ = self.reported.remove(&Rule::SpindleOff);
+            }
+            (CommandKind::M, Number::Integer(5)) => self.spindle_on = false,
+            (CommandKind::M, Number::Integer(2)) |
+            (CommandKind::M, Number::Integer(30)) if self.linter.dialect != Dialect::Marlin => {
+                self.ended = true;
+                self.ever_ended = true;
+            }
+            (CommandKind::M, Number::Integer(6)) |
+            (CommandKind::M, Number::Integer(98)) => self.position = [None; 3],
+            _ => {}
+        }
+
+        if cmd.kind == CommandKind::G {
+            self.check_args(cmd.args, cmd.span);
+        } else {
+            self.set_feed_rate(cmd.args.f);
+        }
+    }
+
+    fn set_feed_rate(&mut self, feed_rate: Option<f32>) {
+        if feed_rate.is_some() {
+            self.feed_rate = feed_rate;
+            let _ = self.reported.remove(&Rule::NoFeedRate);
+        }
+    }
+
+    /// Check the arguments for whichever motion command is in effect.
+    fn check_args(&mut self, args: Args, span: Span) {
+        self.set_feed_rate(args.f);
+
+        let motion = match self.motion {
+            Some(Number::Integer(n)) if n <= 3 => n,
+            Some(_) => {
+                // canned cycles and probing
+                if args.x.is_some() || args.y.is_some() || args.z.is_some() {
+                    self.position = [None; 3];
+                }
+                return;
+            }
+            None => return,
+        };
+
+        let axes = [args.x, args.y, args.z];
+        let centre = [args.i, args.j, args.k];
+        let is_arc = motion == 2 || motion == 3;
+        let moves = axes.iter().any(Option::is_some) ||
+                    is_arc && (centre.iter().any(Option::is_some) || args.r.is_some());
+        if !moves {
+            return;
+        }
+
+        if !self.units_set {
+            self.report_once(Rule::UnitsNotSet, span);
+        }
+
+        if motion != 0 {
+            if self.inverse_time && args.f.is_none() {
+                let message = "feed move without a feed rate in inverse time mode";
+                self.report(Rule::NoFeedRate, span, Some(message.to_string()));
+            } else if self.feed_rate.is_none() {
+                self.report_once(Rule::NoFeedRate, span);
+            }
+
+            // printers don't have a spindle
+            let has_spindle = self.linter.dialect != Dialect::Marlin || self.spindle_used;
+            if !self.spindle_on && has_spindle {
+                self.report_once(Rule::SpindleOff, span);
+            }
+        }
+
+        let mut end = self.position;
+        for (end, &value) in end.iter_mut().zip(&axes) {
+            if let Some(value) = value {
+                *end = if self.absolute {
+                    Some(value)
+                } else {
+                    end.map(|start| start + value)
+                };
+            }
+        }
+
+        if is_arc {
+            if args.r.is_some() && centre.iter().any(Option::is_some) {
+                self.report(Rule::RadiusAndCentre, span, None);
+            } else {
+                self.check_arc(args, end, span);
+            }
+        }
+
+        self.position = end;
+    }
+
+    fn check_arc(&mut self, args: Args, end: [Option<f32>; 3], span: Span) {
+        let (a, b) = self.plane;
+        let (start_a, start_b, end_a, end_b) = match (self.position[a],
+                                                      self.position[b],
+                                                      end[a],
+                                                      end[b]) {
+            (Some(start_a), Some(start_b), Some(end_a), Some(end_b)) => {
+                (start_a, start_b, end_a, end_b)
+            }
+            _ => return,
+        };
+        let tolerance = self.linter.arc_tolerance;
+
+        if let Some(r) = args.r {
+            let chord = distance((start_a, start_b), (end_a, end_b));
+            if 2.0 * abs(r) < chord - tolerance {
+                let message = format!("a radius of {} is too small to reach the end of the arc",
+                                      abs(r));
+                self.report(Rule::ArcRadius, span, Some(message));
+            }
+            return;
+        }
+
+        let offsets = [args.i, args.j, args.k];
+        let (centre_a, centre_b) = if self.absolute_arcs {
+            match (offsets[a], offsets[b]) {
+                (Some(centre_a), Some(centre_b)) => (centre_a, centre_b),
+                _ => return,
+            }
+        } else {
+            (start_a + offsets[a].unwrap_or(0.0), start_b + offsets[b].unwrap_or(0.0))
+        };
+
+        let start_radius = distance((start_a, start_b), (centre_a, centre_b));
+        let end_radius = distance((end_a, end_b), (centre_a, centre_b));
+        if abs(start_radius - end_radius) > tolerance {
+            let message = format!("the arc's radius is {} at the start but {} at the end",
+                                  start_radius,
+                                  end_radius);
+            self.report(Rule::ArcRadius, span, Some(message));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helpers::parse_dialect;
+
+    fn lint_with(linter: &Linter, src: &str) -> Vec<(usize, Rule)> {
+        let lines = parse_dialect(src, linter.dialect);
+
+        linter.lint(&lines).into_iter().map(|lint| (lint.span.line, lint.rule)).collect()
+    }
+
+    fn lint(src: &str) -> Vec<(usize, Rule)> {
+        lint_with(&Linter::new(Dialect::Rs274Ngc), src)
+    }
+
+    #[test]
+    fn good_programs_have_no_lints() {
+        let src = "O100\nN10 G21 G90 G17\nN20 M3 S1000\nN30 G0 X0 Y0\nN40 G1 Z-1 F100\n\
+                   N50 G2 X10 Y0 I5 J0\nN60 G3 X0 Y0 R5\nN70 M5\nN80 M30";
+
+        assert!(lint(src).is_empty());
+    }
+
+    #[test]
+    fn feeding_without_a_feed_rate_or_spindle() {
+        let src = "G21 G90\nG0 X0\nG1 X10\nX20\nM3 S100\nG1 X30 F100\nM5\nX40\nM30";
+
+        assert_eq!(lint(src),
+                   vec![(2, Rule::NoFeedRate), (2, Rule::SpindleOff), (7, Rule::SpindleOff)]);
+    }
+
+    #[test]
+    fn inverse_time_moves_need_a_feed_rate_every_time() {
+        let src = "G21 G90 M3 S100\nG0 X0\nG93 G1 X10 F2\nX20\nM30";
+
+        assert_eq!(lint(src), vec![(3, Rule::NoFeedRate)]);
+    }
+
+    #[test]
+    fn arcs_with_inconsistent_radii() {
+        let src = "G21 G90 M3 S100 F100\nG0 X0 Y0\nG2 X10 Y0 I5 J0\nG2 X0 Y0 I-4 J0\n\
+                   G3 X30 Y0 R5\nG3 X0 Y0 R5 I1\nM30";
+
+        assert_eq!(lint(src),
+                   vec![(3, Rule::ArcRadius), (4, Rule::ArcRadius), (5, Rule::RadiusAndCentre)]);
+    }
+
+    #[test]
+    fn program_structure() {
+        let src = "N10 G0 X0\nN20 G21\nN10 G0 X1\nM30\nG0 X2\nG0 X3\nO200\nG0 X5\nM99";
+
+        assert_eq!(lint(src),
+                   vec![(0, Rule::UnitsNotSet),
+                        (2, Rule::DuplicateLineNumber),
+                        (4, Rule::Unreachable)]);
+        assert_eq!(lint("G21\nG0 X1"), vec![(1, Rule::MissingProgramEnd)]);
+    }
+
+    #[test]
+    fn unknown_codes_depend_on_the_dialect() {
+        let src = "G21 G90\nG81 X0 Y0 Z-1 R1 F100\nG80\nM7\nM56\nM104 S200\nM30";
+
+        assert_eq!(lint(src), vec![(4, Rule::UnknownCode)]);
+        assert_eq!(lint_with(&Linter::new(Dialect::Grbl), src),
+                   vec![(1, Rule::UnknownCode), (5, Rule::UnknownCode)]);
+        assert_eq!(lint_with(&Linter::new(Dialect::Marlin), src),
+                   vec![(1, Rule::UnknownCode), (4, Rule::UnknownCode)]);
+    }
+
+    #[test]
+    fn printers_dont_need_a_spindle_or_a_program_end() {
+        let linter = Linter::new(Dialect::Marlin);
+        let src = "G21 G90\nM104 S200\nG28\nG1 X10 E5 F1200\nM30 old.gco\nG1 X20 E10\nM84";
+
+        assert!(lint_with(&linter, src).is_empty());
+
+        // unless it's a laser or CNC running Marlin
+        let src = "G21 G90\nM3 S100\nG1 X10 F1200\nM5\nG1 X20\nM84";
+        assert_eq!(lint_with(&linter, src), vec![(4, Rule::SpindleOff)]);
+    }
+
+    #[test]
+    fn rules_can_be_turned_off() {
+        let mut linter = Linter::new(Dialect::Rs274Ngc);
+        for rule in Rule::ALL.iter() {
+            linter.disable(*rule);
+        }
+        linter.enable(Rule::from_id("missing-program-end").unwrap());
+
+        assert_eq!(lint_with(&linter, "G1 X10 N1\nN1 G2 X5 R1 I1 M99 G12"),
+                   vec![(1, Rule::MissingProgramEnd)]);
+    }
+}
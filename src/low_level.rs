@@ -123,7 +123,7 @@ impl<I> BasicParser<I>
             _ => unreachable!(),
         };
 
-        if is_negative { Ok(-1.0 * n) } else { Ok(n) }
+        if is_negative { Ok(-n) } else { Ok(n) }
     }
 
     fn command(&mut self) -> Result<Command> {
@@ -207,7 +207,7 @@ impl<I> BasicParser<I>
         if let Ok(kind) = self.arg_kind() {
             let n = self.number()?;
             Ok(Some(Argument {
-                        kind: kind,
+                        kind,
                         value: n,
                     }))
 
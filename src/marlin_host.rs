@@ -0,0 +1,924 @@
+//! Sending programs to a 3D printer running Marlin (or another RepRap
+//! style firmware).
+//!
+//! Every line is sent with a line number and a checksum (e.g.
+//! `N12 G1 X10*98`), and the `Host` waits for the printer to say `ok` before
+//! sending the next one. If a line gets corrupted on the way the printer
+//! asks for it again (`Resend: 12`), so the `Host` remembers the last few
+//! lines it sent.
+//!
+//! While it's busy with something slow, like heating up or homing, the
+//! printer sends `busy:` and temperature reports so the connection doesn't
+//! look dead. The `Host` can also poll temperatures with `M105` while
+//! streaming a program.
+//!
+//! The `FakePrinter` can be used instead of a real printer, and can be told
+//! to corrupt lines to exercise the resend handling.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::Parser;
+//! use gcode::dialect::Dialect;
+//! use gcode::lexer::Tokenizer;
+//! use gcode::marlin_host::{Host, FakePrinter};
+//!
+//! let src = "M140 S60\nM109 S210\nG28\nM117 Printing\nG1 X10 E2 F1800";
+//! let tokens = Tokenizer::with_string_codes(src.chars(), Dialect::Marlin.string_codes())
+//!     .filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::with_dialect(tokens, Dialect::Marlin)
+//!     .filter_map(|line| line.ok())
+//!     .collect();
+//!
+//! // every third line gets garbled on the way to the printer
+//! let mut host = Host::new(FakePrinter::new().corrupt_every(3));
+//! host.stream(&lines).unwrap();
+//!
+//! let temperatures = host.temperatures().unwrap();
+//! assert_eq!(temperatures.hotends[0].target, 210.0);
+//! assert_eq!(temperatures.bed.unwrap().target, 60.0);
+//!
+//! let printer = host.into_inner();
+//! assert_eq!(printer.commands().len(), 6);
+//! assert!(printer.resends() > 0);
+//! ```
+
+use std::collections::VecDeque;
+use std::error;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::string::{String, ToString};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use core::cmp;
+use core::fmt::{self, Display, Formatter};
+
+use lexer::Tokenizer;
+use parser::{Parser, Line, CommandKind, Number};
+use dialect::Dialect;
+use stats::Code;
+use connection::{Connection, program_text};
+
+
+/// How many times in a row the same line will be resent before giving up.
+pub const MAX_RESENDS: usize = 10;
+
+/// How many of the most recently sent lines are kept around in case the
+/// printer asks for them again.
+const HISTORY_LENGTH: usize = 16;
+
+
+/// Something went wrong while talking to the printer.
+#[derive(Debug)]
+pub enum HostError {
+    /// Reading from or writing to the transport failed.
+    Io(io::Error),
+    /// The transport was closed.
+    Disconnected,
+    /// The printer kept asking for this line again.
+    TooManyResends(u32),
+    /// The printer asked for a line which is too old (or hasn't been sent).
+    CantResend(u32),
+    /// The printer stopped itself (e.g. `Error:Printer halted. kill()
+    /// called!`) and needs to be reset.
+    Halted(String),
+    /// The printer didn't say what its temperatures are.
+    NoTemperatureReport,
+    /// The printer didn't say anything before the timeout ran out.
+    TimedOut,
+}
+
+impl From<io::Error> for HostError {
+    fn from(other: io::Error) -> HostError {
+        match other.kind() {
+            io::ErrorKind::UnexpectedEof => HostError::Disconnected,
+            io::ErrorKind::TimedOut => HostError::TimedOut,
+            _ => HostError::Io(other),
+        }
+    }
+}
+
+impl Display for HostError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            HostError::Io(ref e) => write!(f, "{}", e),
+            HostError::Disconnected => f.write_str("The printer disconnected"),
+            HostError::TooManyResends(n) => write!(f, "Line {} was resent too many times", n),
+            HostError::CantResend(n) => write!(f, "Line {} can't be resent", n),
+            HostError::Halted(ref msg) => write!(f, "The printer halted: {}", msg),
+            HostError::NoTemperatureReport => f.write_str("The printer didn't report its temperatures"),
+            HostError::TimedOut => f.write_str("The printer stopped responding"),
+        }
+    }
+}
+
+impl error::Error for HostError {}
+
+
+/// XOR all the bytes in a line together, the way Marlin checks them.
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// Add a line number and checksum to a command (e.g. `G28` becomes
+/// `N3 G28*16`).
+pub fn numbered(number: u32, command: &str) -> String {
+    let line = format!("N{} {}", number, command);
+    let sum = checksum(&line);
+
+    format!("{}*{}", line, sum)
+}
+
+
+/// A heater's temperature, in degrees celsius.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Reading {
+    /// How hot it is.
+    pub current: f32,
+    /// How hot it's meant to be.
+    pub target: f32,
+}
+
+/// A temperature report (e.g. `T:210.0 /210.0 B:60.0 /60.0 @:0 B@:0`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Temperatures {
+    /// Each hotend, in order. Printers with a single hotend only report
+    /// `T`, which is used as the first hotend.
+    pub hotends: Vec<Reading>,
+    /// The bed, if there is one.
+    pub bed: Option<Reading>,
+    /// The heated chamber, if there is one.
+    pub chamber: Option<Reading>,
+}
+
+impl Temperatures {
+    /// Parse a temperature report, returning `None` if it doesn't contain
+    /// any temperatures.
+    pub fn parse(report: &str) -> Option<Temperatures> {
+        let mut temperatures = Temperatures::default();
+        let mut active = None;
+        let mut words = report.split_whitespace().peekable();
+
+        while let Some(word) = words.next() {
+            let mut parts = word.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue,
+            };
+
+            // the target is either stuck on the end ("T:20/210") or the
+            // next word ("T:20 /210")
+            let mut values = value.splitn(2, '/');
+            let current = match values.next().and_then(|v| v.parse().ok()) {
+                Some(current) => current,
+                None => continue,
+            };
+            let target = match values.next() {
+                Some(target) => target.parse().ok(),
+                None => {
+                    match words.peek().and_then(|w| w.strip_prefix('/')) {
+                        Some(target) => {
+                            words.next();
+                            target.parse().ok()
+                        }
+                        None => None,
+                    }
+                }
+            };
+            let reading = Reading {
+                current,
+                target: target.unwrap_or(0.0),
+            };
+
+            match name {
+                "T" => active = Some(reading),
+                "B" => temperatures.bed = Some(reading),
+                "C" => temperatures.chamber = Some(reading),
+                _ => {
+                    let index = name.strip_prefix('T').and_then(|n| n.parse::<usize>().ok());
+                    if let Some(index) = index {
+                        if temperatures.hotends.len() <= index {
+                            temperatures.hotends.resize(index + 1, Reading::default());
+                        }
+                        temperatures.hotends[index] = reading;
+                    }
+                }
+            }
+        }
+
+        if temperatures.hotends.is_empty() {
+            temperatures.hotends.extend(active);
+        }
+
+        if temperatures.hotends.is_empty() && temperatures.bed.is_none() &&
+           temperatures.chamber.is_none() {
+            None
+        } else {
+            Some(temperatures)
+        }
+    }
+}
+
+impl Display for Temperatures {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut readings = Vec::new();
+
+        if let Some(first) = self.hotends.first() {
+            readings.push((String::from("T"), *first));
+        }
+        if self.hotends.len() > 1 {
+            for (i, reading) in self.hotends.iter().enumerate() {
+                readings.push((format!("T{}", i), *reading));
+            }
+        }
+        readings.extend(self.bed.map(|bed| (String::from("B"), bed)));
+        readings.extend(self.chamber.map(|chamber| (String::from("C"), chamber)));
+
+        for (i, &(ref name, reading)) in readings.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}:{:.2} /{:.2}", name, reading.current, reading.target)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// A line sent back by the printer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The last line was accepted, possibly with a temperature report
+    /// (`ok T:...`) if it was an `M105`.
+    Ok(Option<Temperatures>),
+    /// Send everything again, starting from this line number.
+    Resend(u32),
+    /// The printer is still working on the last line.
+    Busy(String),
+    /// A temperature report on its own, usually while heating.
+    Temperatures(Temperatures),
+    /// Something went wrong (e.g. `Error:checksum mismatch`).
+    Error(String),
+    /// The printer has just started up.
+    Start,
+    /// Anything else, such as `echo:` messages.
+    Feedback(String),
+}
+
+impl Response {
+    /// Work out what a line from the printer means.
+    pub fn parse(line: &str) -> Response {
+        let line = line.trim();
+
+        if line == "ok" {
+            return Response::Ok(None);
+        }
+        if let Some(rest) = line.strip_prefix("ok ") {
+            return Response::Ok(Temperatures::parse(rest));
+        }
+
+        let resend = line.strip_prefix("Resend:").or_else(|| line.strip_prefix("rs "));
+        if let Some(n) = resend.and_then(|n| n.trim().trim_start_matches('N').parse().ok()) {
+            return Response::Resend(n);
+        }
+        if let Some(state) = line.strip_prefix("busy:") {
+            return Response::Busy(state.trim().to_string());
+        }
+        if let Some(msg) = line.strip_prefix("Error:").or_else(|| line.strip_prefix("!!")) {
+            return Response::Error(msg.trim().to_string());
+        }
+        if line == "start" {
+            return Response::Start;
+        }
+        if let Some(temperatures) = Temperatures::parse(line) {
+            return Response::Temperatures(temperatures);
+        }
+
+        Response::Feedback(line.to_string())
+    }
+}
+
+
+/// Something which sends gcode to a printer, one line at a time.
+#[derive(Debug)]
+pub struct Host<T> {
+    connection: Connection<T>,
+    next_line: u32,
+    /// The most recently sent lines, with their numbers and checksums.
+    history: VecDeque<(u32, String)>,
+    last_temperatures: Option<Temperatures>,
+    poll_interval: Option<Duration>,
+    last_poll: Option<Instant>,
+}
+
+impl<T: Read + Write> Host<T> {
+    /// Create a `Host` which talks over a transport. The printer should
+    /// have just started up, so the first line sent is `N1`.
+    pub fn new(transport: T) -> Host<T> {
+        Host {
+            connection: Connection::new(transport),
+            next_line: 1,
+            history: VecDeque::new(),
+            last_temperatures: None,
+            poll_interval: None,
+            last_poll: None,
+        }
+    }
+
+    /// Get a reference to the transport.
+    pub fn transport(&self) -> &T {
+        self.connection.transport()
+    }
+
+    /// Get a mutable reference to the transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        self.connection.transport_mut()
+    }
+
+    /// Stop using the `Host`, giving back its transport.
+    pub fn into_inner(self) -> T {
+        self.connection.into_inner()
+    }
+
+    /// Give up waiting for the printer if it doesn't say anything for this
+    /// long. By default the `Host` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.connection.set_timeout(timeout);
+    }
+
+    /// Poll the temperatures with `M105` while streaming, at most this
+    /// often. Polling is turned off by default.
+    pub fn set_poll_interval(&mut self, interval: Option<Duration>) {
+        self.poll_interval = interval;
+    }
+
+    /// The most recent temperature report.
+    pub fn last_temperatures(&self) -> Option<&Temperatures> {
+        self.last_temperatures.as_ref()
+    }
+
+    /// Take any messages the printer has sent which weren't responses to a
+    /// line or temperature reports.
+    pub fn take_feedback(&mut self) -> Vec<String> {
+        self.connection.take_feedback()
+    }
+
+    /// Tell the printer to start counting lines from zero again (`M110`),
+    /// such as after connecting to a printer which has already been used.
+    pub fn reset_line_numbers(&mut self) -> Result<(), HostError> {
+        self.next_line = 0;
+        self.history.clear();
+
+        self.send("M110 N0")
+    }
+
+    /// Send a whole program, polling the temperatures along the way if a
+    /// poll interval was set.
+    ///
+    /// Any line numbers in the program are dropped, because the `Host`
+    /// numbers each line itself.
+    pub fn stream(&mut self, lines: &[Line]) -> Result<(), HostError> {
+        let lines: Vec<Line> = lines.iter().map(|&line| without_line_number(line)).collect();
+        for line in program_text(&lines).lines() {
+            if self.poll_due() {
+                self.temperatures()?;
+            }
+            self.send(line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a single command and wait for the printer to accept it,
+    /// resending it if it gets corrupted.
+    pub fn send(&mut self, command: &str) -> Result<(), HostError> {
+        let number = self.next_line;
+        self.next_line += 1;
+
+        self.history.push_back((number, numbered(number, command.trim())));
+        if self.history.len() > HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+
+        self.resend(number, number)?;
+        self.wait_for_ok(number)
+    }
+
+    /// Ask for the temperatures (`M105`).
+    pub fn temperatures(&mut self) -> Result<Temperatures, HostError> {
+        self.last_temperatures = None;
+        self.last_poll = Some(Instant::now());
+        self.send("M105")?;
+
+        self.last_temperatures.clone().ok_or(HostError::NoTemperatureReport)
+    }
+
+    fn poll_due(&self) -> bool {
+        match (self.poll_interval, self.last_poll) {
+            (Some(interval), Some(last_poll)) => last_poll.elapsed() >= interval,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Wait until every line up to `last` has been accepted.
+    fn wait_for_ok(&mut self, last: u32) -> Result<(), HostError> {
+        let mut outstanding = 1;
+        let mut resend_from = None;
+        let mut resends = 0;
+
+        loop {
+            match self.read_response()? {
+                Response::Ok(_) => {
+                    // Marlin follows a "Resend: N" with an "ok", which is
+                    // our cue to go back to line N
+                    if let Some(from) = resend_from.take() {
+                        resends += 1;
+                        if resends > MAX_RESENDS {
+                            return Err(HostError::TooManyResends(from));
+                        }
+                        if from > last {
+                            // the printer is still counting from before we
+                            // connected, so get it back in step with us
+                            self.resync(last)?;
+                            outstanding = 2;
+                        } else {
+                            self.resend(from, last)?;
+                            outstanding = last - from + 1;
+                        }
+                    } else {
+                        outstanding -= 1;
+                        if outstanding == 0 {
+                            return Ok(());
+                        }
+                    }
+                }
+                Response::Resend(n) => resend_from = Some(n),
+                _ => {}
+            }
+        }
+    }
+
+    /// Tell the printer the line before `last` was the last one it got
+    /// (`M110`), then send `last` again.
+    fn resync(&mut self, last: u32) -> Result<(), HostError> {
+        let previous = last.saturating_sub(1);
+        let line = numbered(previous, &format!("M110 N{}", previous));
+
+        self.connection.write_line(&line)?;
+        self.resend(last, last)
+    }
+
+    /// Write the lines from `first` to `last` (inclusive) again.
+    fn resend(&mut self, first: u32, last: u32) -> Result<(), HostError> {
+        for number in first..=last {
+            let line = match self.history.iter().find(|entry| entry.0 == number) {
+                Some((_, line)) => line,
+                None => return Err(HostError::CantResend(number)),
+            };
+
+            self.connection.write_line(line)?;
+        }
+
+        self.connection.flush()?;
+        Ok(())
+    }
+
+    /// Wait for the next line from the printer, keeping track of any
+    /// temperature reports or messages.
+    pub fn read_response(&mut self) -> Result<Response, HostError> {
+        let line = self.connection.read_line()?;
+        let response = Response::parse(&line);
+
+        match response {
+            Response::Ok(Some(ref temperatures)) |
+            Response::Temperatures(ref temperatures) => {
+                self.last_temperatures = Some(temperatures.clone());
+            }
+            Response::Error(ref msg) => {
+                if msg.contains("halted") || msg.contains("kill()") {
+                    return Err(HostError::Halted(msg.clone()));
+                }
+                self.connection.push_feedback(line.clone());
+            }
+            Response::Feedback(ref msg) => self.connection.push_feedback(msg.clone()),
+            _ => {}
+        }
+
+        Ok(response)
+    }
+}
+
+fn without_line_number(mut line: Line) -> Line {
+    match line {
+        Line::Cmd(ref mut cmd) => cmd.line_number = None,
+        Line::Args { ref mut line_number, .. } => *line_number = None,
+        Line::ProgramNumber(_) => {}
+    }
+
+    line
+}
+
+
+/// A pretend printer, for testing without any hardware.
+///
+/// Heaters get up to temperature instantly and moves finish straight away,
+/// but the printer still sends `busy:` and temperature reports while it's
+/// "waiting" in an `M109`, `M190` or `G28`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakePrinter {
+    /// Bytes which have been received but don't make up a whole line.
+    rx: Vec<u8>,
+    /// Bytes waiting to be sent back.
+    tx: VecDeque<u8>,
+    last_line: u32,
+    corrupt_every: Option<usize>,
+    lines_received: usize,
+    resends: usize,
+    hotend: Reading,
+    bed: Reading,
+    commands: Vec<String>,
+}
+
+impl FakePrinter {
+    /// Create a `FakePrinter` which has just started up, with its heaters
+    /// at room temperature.
+    pub fn new() -> FakePrinter {
+        let room_temperature = Reading {
+            current: 20.0,
+            target: 0.0,
+        };
+
+        FakePrinter {
+            rx: Vec::new(),
+            tx: VecDeque::new(),
+            last_line: 0,
+            corrupt_every: None,
+            lines_received: 0,
+            resends: 0,
+            hotend: room_temperature,
+            bed: room_temperature,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Pretend the printer was already used by another host, which got as
+    /// far as line `n`.
+    pub fn already_at_line(self, n: u32) -> FakePrinter {
+        FakePrinter {
+            last_line: n,
+            ..self
+        }
+    }
+
+    /// Garble every `n`th line received, as if there was noise on the
+    /// line.
+    pub fn corrupt_every(self, n: usize) -> FakePrinter {
+        FakePrinter {
+            corrupt_every: Some(n),
+            ..self
+        }
+    }
+
+    /// Every command which has been accepted, without its line number and
+    /// checksum.
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    /// How many times a line has been asked for again.
+    pub fn resends(&self) -> usize {
+        self.resends
+    }
+
+    /// The current temperatures.
+    pub fn temperatures(&self) -> Temperatures {
+        Temperatures {
+            hotends: vec![self.hotend],
+            bed: Some(self.bed),
+            chamber: None,
+        }
+    }
+
+    fn respond(&mut self, line: &str) {
+        self.tx.extend(line.bytes());
+        self.tx.extend(b"\n");
+    }
+
+    fn ask_for_resend(&mut self, msg: &str) {
+        let last_line = self.last_line;
+
+        self.resends += 1;
+        self.respond(&format!("Error:{}, Last Line: {}", msg, last_line));
+        self.respond(&format!("Resend: {}", last_line + 1));
+        self.respond("ok");
+    }
+
+    fn receive_line(&mut self, mut raw: Vec<u8>) {
+        self.lines_received += 1;
+        if let Some(n) = self.corrupt_every {
+            if self.lines_received % n == 0 && !raw.is_empty() {
+                let middle = raw.len() / 2;
+                raw[middle] ^= 0x02;
+            }
+        }
+
+        let line = String::from_utf8_lossy(&raw).trim().to_string();
+        if line.is_empty() {
+            return;
+        }
+        if !line.starts_with('N') && !line.contains('*') {
+            return self.execute(&line);
+        }
+
+        let (body, sum) = match line.rfind('*') {
+            Some(star) => (&line[..star], &line[star + 1..]),
+            None => return self.ask_for_resend("No Checksum with line number"),
+        };
+        if !body.starts_with('N') {
+            return self.ask_for_resend("No Line Number with checksum");
+        }
+        if sum.parse() != Ok(checksum(body)) {
+            return self.ask_for_resend("checksum mismatch");
+        }
+
+        let mut parts = body[1..].splitn(2, ' ');
+        let number = match parts.next().and_then(|n| n.parse::<u32>().ok()) {
+            Some(number) => number,
+            None => return self.ask_for_resend("Line Number is not Last Line Number+1"),
+        };
+        let command = parts.next().unwrap_or("").trim();
+
+        if !command.contains("M110") && number != self.last_line + 1 {
+            return self.ask_for_resend("Line Number is not Last Line Number+1");
+        }
+
+        self.last_line = number;
+        self.execute(command);
+    }
+
+    fn execute(&mut self, command: &str) {
+        // the line number has already been taken off, so there shouldn't be
+        // another one
+        if command.starts_with('N') {
+            self.respond(&format!("echo:Unknown command: \"{}\"", command));
+            return self.respond("ok");
+        }
+
+        let tokens = Tokenizer::with_string_codes(command.chars(),
+                                                  Dialect::Marlin.string_codes());
+        let lines: Result<Vec<Line>, _> = match tokens.collect::<Result<Vec<_>, _>>() {
+            Ok(tokens) => Parser::with_dialect(tokens.into_iter(), Dialect::Marlin).collect(),
+            Err(e) => Err(e),
+        };
+        let lines = match lines {
+            Ok(lines) => lines,
+            Err(_) => {
+                self.respond(&format!("echo:Unknown command: \"{}\"", command));
+                return self.respond("ok");
+            }
+        };
+
+        let mut temperature_report = false;
+        for line in &lines {
+            if let Line::Cmd(ref cmd) = *line {
+                if !Dialect::Marlin.supports(Code::new(cmd.kind, cmd.number)) {
+                    self.respond(&format!("echo:Unknown command: \"{}\"", command));
+                    continue;
+                }
+
+                let target = cmd.args.s.or(cmd.args.r);
+                match (cmd.kind, cmd.number) {
+                    (CommandKind::M, Number::Integer(104)) => self.heat(false, target, false),
+                    (CommandKind::M, Number::Integer(109)) => self.heat(false, target, true),
+                    (CommandKind::M, Number::Integer(140)) => self.heat(true, target, false),
+                    (CommandKind::M, Number::Integer(190)) => self.heat(true, target, true),
+                    (CommandKind::M, Number::Integer(105)) => temperature_report = true,
+                    (CommandKind::G, Number::Integer(28)) => self.respond("busy: processing"),
+                    _ => {}
+                }
+            }
+        }
+
+        self.commands.push(command.to_string());
+        if temperature_report {
+            let report = format!("ok {} @:0 B@:0", self.temperatures());
+            self.respond(&report);
+        } else {
+            self.respond("ok");
+        }
+    }
+
+    fn heat(&mut self, bed: bool, target: Option<f32>, wait: bool) {
+        let target = match target {
+            Some(target) => target,
+            None => return,
+        };
+
+        if wait {
+            self.respond("busy: processing");
+            let report = format!("{} W:?", self.temperatures());
+            self.respond(&report);
+        }
+
+        let heater = if bed { &mut self.bed } else { &mut self.hotend };
+        heater.target = target;
+        heater.current = target.max(20.0);
+    }
+}
+
+impl Default for FakePrinter {
+    fn default() -> FakePrinter {
+        FakePrinter::new()
+    }
+}
+
+impl Write for FakePrinter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                let raw = mem::take(&mut self.rx);
+                self.receive_line(raw);
+            } else {
+                self.rx.push(byte);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FakePrinter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tx.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "The printer has nothing to say"));
+        }
+
+        let bytes_read = cmp::min(buf.len(), self.tx.len());
+        for (dest, byte) in buf.iter_mut().zip(self.tx.drain(..bytes_read)) {
+            *dest = byte;
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helpers::parse_dialect;
+
+    #[test]
+    fn lines_get_numbers_and_checksums() {
+        assert_eq!(checksum("N1 G28"), 18);
+        assert_eq!(numbered(1, "G28"), "N1 G28*18");
+        assert_eq!(numbered(0, "M110 N0"), "N0 M110 N0*125");
+    }
+
+    #[test]
+    fn parse_responses() {
+        let hotend = Reading {
+            current: 210.0,
+            target: 210.0,
+        };
+        let bed = Reading {
+            current: 59.5,
+            target: 60.0,
+        };
+        let report = Temperatures {
+            hotends: vec![hotend],
+            bed: Some(bed),
+            chamber: None,
+        };
+
+        let inputs = vec![("ok", Response::Ok(None)),
+                          ("ok T:210.0 /210.0 B:59.5 /60.0 @:0 B@:0",
+                           Response::Ok(Some(report.clone()))),
+                          ("T:210.0 /210.0 B:59.5 /60.0 W:?", Response::Temperatures(report)),
+                          ("Resend: 12", Response::Resend(12)),
+                          ("rs N7", Response::Resend(7)),
+                          ("busy: processing", Response::Busy(String::from("processing"))),
+                          ("Error:checksum mismatch, Last Line: 3",
+                           Response::Error(String::from("checksum mismatch, Last Line: 3"))),
+                          ("start", Response::Start),
+                          ("echo:SD card ok", Response::Feedback(String::from("echo:SD card ok"))),
+                          ("X:10.00 Y:0.00 Z:0.00 E:0.00 Count X:800",
+                           Response::Feedback(String::from("X:10.00 Y:0.00 Z:0.00 E:0.00 \
+                                                            Count X:800")))];
+
+        for (src, should_be) in inputs {
+            assert_eq!(Response::parse(src), should_be, "{}", src);
+        }
+    }
+
+    #[test]
+    fn reports_with_several_hotends() {
+        let src = "T:200.0 /200.0 B:60.0/60.0 T0:200.0 /200.0 T1:25.3 /0.0 @:0 B@:0";
+
+        let got = Temperatures::parse(src).unwrap();
+
+        assert_eq!(got.hotends.len(), 2);
+        assert_eq!(got.hotends[1].current, 25.3);
+        assert_eq!(got.bed.unwrap().target, 60.0);
+        assert_eq!(Temperatures::parse(&got.to_string()), Some(got));
+    }
+
+    #[test]
+    fn corrupted_lines_are_resent() {
+        let src: String = (0..50).map(|i| format!("G1 X{} E{}\n", i, i)).collect();
+
+        let mut host = Host::new(FakePrinter::new().corrupt_every(4));
+        host.stream(&parse_dialect(&src, Dialect::Marlin)).unwrap();
+
+        let printer = host.into_inner();
+        let should_be: Vec<String> = (0..50).map(|i| format!("G1 X{} E{}", i, i)).collect();
+        assert_eq!(printer.commands(), &should_be[..]);
+        assert!(printer.resends() >= 12);
+    }
+
+    #[test]
+    fn give_up_when_every_line_is_corrupted() {
+        let mut host = Host::new(FakePrinter::new().corrupt_every(1));
+
+        match host.send("G28") {
+            Err(HostError::TooManyResends(1)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn catch_up_with_a_printer_which_was_already_used() {
+        let mut host = Host::new(FakePrinter::new().already_at_line(100));
+
+        host.send("G28").unwrap();
+        host.send("G1 X5").unwrap();
+
+        let printer = host.into_inner();
+        assert_eq!(printer.commands(), &["M110 N0", "G28", "G1 X5"]);
+        assert_eq!(printer.last_line, 2);
+        assert_eq!(printer.resends(), 1);
+    }
+
+    #[test]
+    fn line_numbers_can_be_reset() {
+        let mut host = Host::new(FakePrinter::new());
+        host.send("G28").unwrap();
+        host.send("G1 X5").unwrap();
+
+        host.reset_line_numbers().unwrap();
+        host.send("G1 X10").unwrap();
+
+        assert_eq!(host.transport().last_line, 1);
+        assert_eq!(host.transport().commands().len(), 4);
+    }
+
+    #[test]
+    fn waiting_for_heaters_keeps_the_connection_alive() {
+        let mut host = Host::new(FakePrinter::new());
+
+        host.send("M190 S60").unwrap();
+        host.send("M109 S215").unwrap();
+
+        let temperatures = host.last_temperatures().unwrap();
+        assert_eq!(temperatures.hotends[0].target, 0.0);
+        assert_eq!(temperatures.bed.unwrap().current, 60.0);
+        assert_eq!(host.temperatures().unwrap().hotends[0].current, 215.0);
+    }
+
+    #[test]
+    fn temperatures_are_polled_while_streaming() {
+        let mut host = Host::new(FakePrinter::new());
+        host.set_poll_interval(Some(Duration::from_secs(0)));
+
+        host.stream(&parse_dialect("M104 S200\nG1 X1\nG1 X2", Dialect::Marlin)).unwrap();
+
+        let commands = host.transport().commands();
+        assert_eq!(commands, &["M105", "M104 S200", "M105", "G1 X1", "M105", "G1 X2"]);
+        assert_eq!(host.last_temperatures().unwrap().hotends[0].target, 200.0);
+    }
+
+    #[test]
+    fn the_programs_own_line_numbers_are_dropped() {
+        let mut host = Host::new(FakePrinter::new());
+
+        host.stream(&parse_dialect("N10 G28\nN20 G1 X5\nG1 Y5", Dialect::Marlin)).unwrap();
+
+        assert_eq!(host.transport().commands(), &["G28", "G1 X5", "G1 Y5"]);
+        assert!(host.take_feedback().is_empty());
+    }
+
+    #[test]
+    fn unknown_commands_are_reported() {
+        let mut host = Host::new(FakePrinter::new());
+
+        host.send("M9999").unwrap();
+
+        assert_eq!(host.take_feedback(), vec![String::from("echo:Unknown command: \"M9999\"")]);
+    }
+}
@@ -0,0 +1,492 @@
+//! Making programs as small as possible.
+//!
+//! Old controllers often have very little memory and get their programs over
+//! a slow serial link, so every byte counts. The `Minifier` removes anything
+//! which doesn't change what the machine does:
+//!
+//! - comments (these never make it past the `Tokenizer`)
+//! - modal `G` words which are already in effect (e.g. a repeated `G1`)
+//! - feed rates which haven't changed
+//! - axis words for straight moves which wouldn't change that axis
+//! - moves which don't go anywhere
+//!
+//! Redundant zeroes (e.g. `G01` or `X1.500`) are removed when the program
+//! is written, and `write_compact()` also leaves out the spaces between
+//! words.
+//!
+//! The program is assumed to run from top to bottom. Axis words are only
+//! removed once the program has said whether it uses absolute (`G90`) or
+//! incremental (`G91`) coordinates, and anything which might move the
+//! machine or change the coordinate system (e.g. `G28`, `G92` or a tool
+//! change) makes the minifier forget where it is.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::minify::{Minifier, LineNumbers, write_compact};
+//!
+//! let src = "N10 G90 G01 X10.000 Y0 F500 (start)\nN20 G01 X10 Y5.0 F500\nN30 G01 X10 Y5";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let mut minifier = Minifier::new();
+//! minifier.line_numbers = LineNumbers::Strip;
+//! let minified = minifier.minify(&lines);
+//!
+//! let mut written = String::new();
+//! write_compact(&minified, &mut written).unwrap();
+//! assert_eq!(written, "G90G1X10Y0F500\nY5\n");
+//! ```
+
+use core::fmt::{self, Write};
+use std::vec::Vec;
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use lexer::Span;
+use helpers::{abs, is_motion, has_axis_words, Modal};
+
+
+/// How close two numbers need to be before they're written the same.
+const SAME: f32 = 0.00005;
+
+/// The modal groups a repeated `G` word can be removed from.
+const MODAL_GROUPS: [&[Number]; 5] = [&[Number::Integer(17), Number::Integer(18),
+                                        Number::Integer(19)],
+                                      &[Number::Integer(20), Number::Integer(21)],
+                                      &[Number::Integer(90), Number::Integer(91)],
+                                      &[Number::Decimal(90, 1), Number::Decimal(91, 1)],
+                                      &[Number::Integer(93), Number::Integer(94),
+                                        Number::Integer(95)]];
+const UNITS: usize = 1;
+const DISTANCE: usize = 2;
+const FEED_MODE: usize = 4;
+
+
+/// What to do with `N` words.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LineNumbers {
+    /// Leave them alone.
+    Keep,
+    /// Remove them all.
+    Strip,
+    /// Number every line, counting up from `start` in steps of `step`.
+    Renumber {
+        /// The first line's number.
+        start: u32,
+        /// How much to add for each line.
+        step: u32,
+    },
+}
+
+/// Removes everything from a program which doesn't change what the machine
+/// does.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Minifier {
+    /// What to do with `N` words. Line numbers are kept by default because
+    /// a program might use them to jump around (e.g. `M99 P20`).
+    pub line_numbers: LineNumbers,
+}
+
+impl Minifier {
+    /// Create a `Minifier` which keeps line numbers.
+    pub fn new() -> Minifier {
+        Minifier { line_numbers: LineNumbers::Keep }
+    }
+
+    /// Minify a program.
+    ///
+    /// Lines which end up doing nothing are removed, but with
+    /// `LineNumbers::Keep` their line number is left behind in case
+    /// something jumps to it.
+    pub fn minify(&self, lines: &[Line]) -> Vec<Line> {
+        let mut state = State::default();
+        let mut minified = Vec::new();
+
+        for &line in lines {
+            state.minify(line, &mut minified);
+        }
+
+        if self.line_numbers != LineNumbers::Keep {
+            minified.retain(|line| match *line {
+                                Line::Args { ref args, .. } => !args.is_empty(),
+                                _ => true,
+                            });
+        }
+
+        match self.line_numbers {
+            LineNumbers::Keep => {}
+            LineNumbers::Strip => {
+                for line in &mut minified {
+                    set_line_number(line, None);
+                }
+            }
+            LineNumbers::Renumber { start, step } => {
+                let mut next = start;
+                let mut last_source_line = None;
+
+                for line in &mut minified {
+                    let source_line = line.span().map(|s| s.line);
+                    if source_line.is_some() && source_line == last_source_line {
+                        set_line_number(line, None);
+                    } else if source_line.is_some() {
+                        set_line_number(line, Some(next));
+                        next += step;
+                    }
+                    last_source_line = source_line;
+                }
+            }
+        }
+
+        minified
+    }
+}
+
+impl Default for Minifier {
+    fn default() -> Minifier {
+        Minifier::new()
+    }
+}
+
+/// Write a program the same way as `writer::write_lines()`, but without any
+/// spaces between words.
+///
+/// String arguments (e.g. the message in `M117 Hello World`) are written
+/// as-is, after a single space so they can't run into the code's number.
+pub fn write_compact<'a, W, I>(lines: I, w: &mut W) -> fmt::Result
+    where W: Write,
+          I: IntoIterator<Item = &'a Line>
+{
+    let mut current_line = None;
+    let mut first = true;
+
+    for &line in lines {
+        let source_line = line.span().map(|s| s.line);
+        if !first && (source_line.is_none() || source_line != current_line) {
+            writeln!(w)?;
+        }
+
+        let mut line = line;
+        let text = match line {
+            Line::Cmd(ref mut cmd) => cmd.args.text.take(),
+            Line::Args { ref mut args, .. } => args.text.take(),
+            Line::ProgramNumber(_) => None,
+        };
+
+        write!(WithoutSpaces(w), "{}", line)?;
+        if let Some(text) = text {
+            write!(w, " {}", text)?;
+        }
+
+        current_line = source_line;
+        first = false;
+    }
+
+    if !first {
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+struct WithoutSpaces<'a, W: 'a>(&'a mut W);
+
+impl<'a, W: Write> Write for WithoutSpaces<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for piece in s.split(' ') {
+            self.0.write_str(piece)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn set_line_number(line: &mut Line, number: Option<u32>) {
+    match *line {
+        Line::Cmd(ref mut cmd) => cmd.line_number = number,
+        Line::Args { ref mut line_number, .. } => *line_number = number,
+        Line::ProgramNumber(_) => {}
+    }
+}
+
+/// Commands which can't move the machine or change its coordinate system.
+fn is_harmless(kind: CommandKind, number: Number) -> bool {
+    matches!((kind, number),
+             (CommandKind::G, Number::Integer(4)) |
+             (CommandKind::G, Number::Integer(40)) |
+             (CommandKind::M, Number::Integer(3..=5)) |
+             (CommandKind::M, Number::Integer(7..=9)) |
+             (CommandKind::T, _))
+}
+
+
+/// What the machine is doing, as far as the minifier can tell.
+#[derive(Debug, Default)]
+struct State {
+    modal: Modal,
+    modes: [Option<Number>; 5],
+    feed_rate: Option<f32>,
+}
+
+impl State {
+    fn minify(&mut self, line: Line, minified: &mut Vec<Line>) {
+        if let Line::ProgramNumber(_) = line {
+            // a subprogram can be called from anywhere
+            *self = State::default();
+            minified.push(line);
+            return;
+        }
+
+        let start = self.modal.position;
+        let motion = self.modal.motion;
+        self.modal.update(&line);
+
+        match line {
+            Line::Args { args, line_number, span } => {
+                let args = self.motion_args(args, span, start);
+                push_args(minified, args, line_number, span);
+            }
+            Line::Cmd(cmd) => self.minify_command(cmd, start, motion, minified),
+            Line::ProgramNumber(_) => unreachable!(),
+        }
+
+        // we can't follow axis words until we know whether they're absolute
+        // or incremental
+        if self.modes[DISTANCE].is_none() {
+            self.modal.position = [None; 3];
+        }
+    }
+
+    fn minify_command(&mut self,
+                      cmd: Command,
+                      start: [Option<f32>; 3],
+                      motion: Option<Number>,
+                      minified: &mut Vec<Line>) {
+        if cmd.kind == CommandKind::G && is_motion(cmd.number) {
+            let redundant = motion == Some(cmd.number);
+            if cmd.number == Number::Integer(80) {
+                // some controllers let G80 cancel a G0 earlier on the same
+                // line, so the next motion command always gets written
+                self.modal.motion = None;
+            }
+
+            self.push(cmd, redundant, start, minified);
+        } else if let Some(group) = self.modal_group(cmd) {
+            let redundant = self.modes[group] == Some(cmd.number);
+            if !redundant {
+                self.modes[group] = Some(cmd.number);
+                if group == UNITS {
+                    self.modal.position = [None; 3];
+                    self.feed_rate = None;
+                } else if group == FEED_MODE {
+                    self.feed_rate = None;
+                }
+            }
+
+            self.push(cmd, redundant, start, minified);
+        } else if cmd.kind == CommandKind::G && cmd.number == Number::Integer(53) {
+            minified.push(Line::Cmd(cmd));
+        } else {
+            if !is_harmless(cmd.kind, cmd.number) {
+                self.modal.position = [None; 3];
+            }
+            if cmd.args.f.is_some() {
+                self.feed_rate = cmd.args.f;
+            }
+
+            minified.push(Line::Cmd(cmd));
+        }
+    }
+
+    fn modal_group(&self, cmd: Command) -> Option<usize> {
+        if cmd.kind != CommandKind::G {
+            return None;
+        }
+
+        MODAL_GROUPS.iter().position(|group| group.contains(&cmd.number))
+    }
+
+    /// Push a modal command, turning it into plain arguments if it's already
+    /// in effect.
+    fn push(&mut self,
+            mut cmd: Command,
+            redundant: bool,
+            start: [Option<f32>; 3],
+            minified: &mut Vec<Line>) {
+        cmd.args = self.motion_args(cmd.args, cmd.span, start);
+
+        if redundant {
+            push_args(minified, cmd.args, cmd.line_number, cmd.span);
+        } else {
+            minified.push(Line::Cmd(cmd));
+        }
+    }
+
+    /// Remove any arguments which wouldn't change anything when moving from
+    /// `start`.
+    fn motion_args(&mut self, mut args: Args, span: Span, start: [Option<f32>; 3]) -> Args {
+        if args.f.is_some() {
+            let inverse_time = self.modes[FEED_MODE] == Some(Number::Integer(93));
+            if args.f == self.feed_rate && !inverse_time {
+                args.f = None;
+            } else {
+                self.feed_rate = args.f;
+            }
+        }
+
+        let motion = self.modal.motion;
+        let straight = motion == Some(Number::Integer(0)) || motion == Some(Number::Integer(1));
+        let arc = motion == Some(Number::Integer(2)) || motion == Some(Number::Integer(3));
+        let incremental = match self.modes[DISTANCE] {
+            Some(Number::Integer(90)) => false,
+            Some(_) => true,
+            None => return args,
+        };
+
+        if !straight && !arc {
+            if has_axis_words(&args) {
+                self.modal.position = [None; 3];
+            }
+            return args;
+        }
+
+        if self.modal.in_machine_coordinates(span) {
+            return args;
+        }
+
+        let mut words = [&mut args.x, &mut args.y, &mut args.z];
+        for (word, &previous) in words.iter_mut().zip(&start) {
+            let value = match **word {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let unchanged = if incremental {
+                abs(value) < SAME
+            } else {
+                match previous {
+                    Some(p) => abs(p - value) < SAME,
+                    None => false,
+                }
+            };
+            if straight && unchanged {
+                **word = None;
+            }
+        }
+
+        args
+    }
+}
+
+fn push_args(minified: &mut Vec<Line>, args: Args, line_number: Option<u32>, span: Span) {
+    if !args.is_empty() || line_number.is_some() {
+        minified.push(Line::Args {
+                          args,
+                          line_number,
+                          span,
+                      });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use writer::write_lines;
+    use dialect::Dialect;
+    use helpers::{parse, parse_dialect};
+
+    fn minify(src: &str, line_numbers: LineNumbers) -> String {
+        let lines = parse(src);
+
+        let minified = Minifier { line_numbers }.minify(&lines);
+
+        let mut written = String::new();
+        write_lines(&minified, &mut written).unwrap();
+        written
+    }
+
+    #[test]
+    fn remove_repeated_words_and_values() {
+        let src = "G90 G1 X10 Y5 F100\nG1 X20 Y5 F100\nG1 X20 Y5\nX20 Y10 F200";
+        let should_be = "G90 G1 X10 Y5 F100\nX20\nY10 F200\n";
+
+        assert_eq!(minify(src, LineNumbers::Keep), should_be);
+    }
+
+    #[test]
+    fn zero_length_incremental_moves_are_removed() {
+        let src = "G91 G0 X0 Y10\nX0 Y0\nG90 X5";
+        let should_be = "G91 G0 Y10\nG90 X5\n";
+
+        assert_eq!(minify(src, LineNumbers::Keep), should_be);
+    }
+
+    #[test]
+    fn forget_the_position_when_the_coordinate_system_changes() {
+        let src = "G90 G0 X0 Y0\nG92 X10 Y10\nG0 X0 Y0\nG2 X0 Y0 I5 J0\nG2 X0 Y0 I5 J0";
+        let should_be = "G90 G0 X0 Y0\nG92 X10 Y10\nX0 Y0\nG2 X0 Y0 I5 J0\nX0 Y0 I5 J0\n";
+
+        assert_eq!(minify(src, LineNumbers::Keep), should_be);
+    }
+
+    #[test]
+    fn machine_coordinate_moves_make_the_position_unknown() {
+        let src = "G90 G0 X10 Z0\nG53 G0 Z0\nG0 X10 Z0\nG0 X10 Z0";
+        let should_be = "G90 G0 X10 Z0\nG53 Z0\nZ0\n";
+
+        assert_eq!(minify(src, LineNumbers::Keep), should_be);
+    }
+
+    #[test]
+    fn inverse_time_moves_keep_their_feed_rate() {
+        let src = "G90 G93 G1 X1 F10\nX2 F10";
+
+        assert_eq!(minify(src, LineNumbers::Keep), "G90 G93 G1 X1 F10\nX2 F10\n");
+    }
+
+    #[test]
+    fn compact_programs_keep_the_spaces_in_strings() {
+        let src = "G28 X Y\nM117 5 layers to go\nG0 X1";
+        let lines = parse_dialect(src, Dialect::Marlin);
+
+        let mut written = String::new();
+        write_compact(&lines, &mut written).unwrap();
+
+        assert_eq!(written, "G28XY\nM117 5 layers to go\nG0X1\n");
+    }
+
+    #[test]
+    fn line_numbers() {
+        let src = "N5 G90 G0 X1\nN6 X1\nN7 Y2\nN8 M30";
+
+        assert_eq!(minify(src, LineNumbers::Keep), "N5 G90 G0 X1\nN6\nN7 Y2\nN8 M30\n");
+        assert_eq!(minify(src, LineNumbers::Strip), "G90 G0 X1\nY2\nM30\n");
+        assert_eq!(minify(src, LineNumbers::Renumber { start: 10, step: 10 }),
+                   "N10 G90 G0 X1\nN20 Y2\nN30 M30\n");
+    }
+
+    #[test]
+    fn compact_programs_parse_to_the_same_thing() {
+        let src = "O100\nN10 G90.1 G17\nN20 G02 X1.250 Y-3 I0.5 J0.5 F200 (arc)\nG4 P0.25";
+        let lines = parse(src);
+
+        let mut written = String::new();
+        write_compact(&lines, &mut written).unwrap();
+        assert_eq!(written, "O100\nN10G90.1G17\nN20G2X1.25Y-3I0.5J0.5F200\nG4P0.25\n");
+
+        let reparsed = parse(&written);
+        assert_eq!(reparsed.len(), lines.len());
+        for (got, should_be) in reparsed.iter().zip(&lines) {
+            match (*got, *should_be) {
+                (Line::Cmd(got), Line::Cmd(should_be)) => {
+                    assert_eq!(got.number, should_be.number);
+                    assert_eq!(got.args, should_be.args);
+                    assert_eq!(got.line_number, should_be.line_number);
+                }
+                (got, should_be) => assert_eq!(got, should_be),
+            }
+        }
+    }
+}
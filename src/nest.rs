@@ -0,0 +1,345 @@
+//! Step-and-repeat, cutting several copies of a part from one sheet.
+//!
+//! A `StepAndRepeat` takes the `Line`s of a single part and generates one
+//! combined program which cuts a copy at each offset, retracting to a safe Z
+//! height and rapiding over to the next copy in between. The copies can be
+//! written out in full, run in their own work coordinate systems (`G54` to
+//! `G59`), or call a single subprogram with `M98`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Point;
+//! use gcode::nest::StepAndRepeat;
+//! use gcode::writer::write_lines;
+//!
+//! let src = "G90 G0 X0 Y0 Z1\nG1 Z-1 F100\nX10\nG0 Z1\nM30";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let part: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let nesting = StepAndRepeat::grid(2, 1, Point::new(20.0, 0.0, 0.0), false);
+//! let program = nesting.generate(&part).unwrap();
+//!
+//! let mut written = String::new();
+//! write_lines(&program, &mut written).unwrap();
+//!
+//! let should_be = "G90 G0 Z5\nG0 X0 Y0\n\
+//!                  G90 G0 X0 Y0 Z1\nG1 Z-1 F100\nX10\nG0 Z1\n\
+//!                  G90 G0 Z5\nG0 X20 Y0\n\
+//!                  G90 G0 X20 Y0 Z1\nG1 Z-1 F100\nX30\nG0 Z1\n\
+//!                  M30\n";
+//! assert_eq!(written, should_be);
+//! ```
+
+use std::vec::Vec;
+
+use parser::{Line, CommandKind, Number, Args};
+use interpreter::{Event, Interpreter, Point};
+use transform::{Transform, Transformer};
+use helpers::Output;
+use errors::*;
+
+
+/// How each copy of the part is moved into place.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Placement {
+    /// Write out the whole part for each copy, with its coordinates moved.
+    Inline,
+    /// Give each copy its own work coordinate system (`G54` to `G59`),
+    /// setting them up with `G10 L2`. The offsets are the machine
+    /// coordinates of each copy's origin.
+    WorkOffsets,
+    /// Put the part in a subprogram with this number and call it with
+    /// `M98` for each copy, using `G52` to shift the origin.
+    Subprogram(u32),
+}
+
+
+/// A generator for programs which cut several copies of the same part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepAndRepeat {
+    /// Where each copy goes, in the order they're cut.
+    pub offsets: Vec<Point>,
+    /// The Z height to retract to before moving between copies.
+    pub safe_z: f32,
+    /// How copies are moved into place.
+    pub placement: Placement,
+}
+
+impl StepAndRepeat {
+    /// Create a `StepAndRepeat` which cuts copies at particular offsets,
+    /// inline and with a safe Z of 5.
+    pub fn new(offsets: Vec<Point>) -> StepAndRepeat {
+        StepAndRepeat {
+            offsets,
+            safe_z: 5.0,
+            placement: Placement::Inline,
+        }
+    }
+
+    /// Lay copies out in a grid, row by row starting from the origin. Only
+    /// the X and Y of `spacing` are used.
+    ///
+    /// In serpentine order every second row is cut backwards, so the
+    /// machine never has to travel back across the sheet.
+    pub fn grid(columns: usize, rows: usize, spacing: Point, serpentine: bool) -> StepAndRepeat {
+        let mut offsets = Vec::with_capacity(columns * rows);
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let column = if serpentine && row % 2 == 1 {
+                    columns - 1 - column
+                } else {
+                    column
+                };
+
+                offsets.push(Point::new(spacing.x * column as f32,
+                                        spacing.y * row as f32,
+                                        0.0));
+            }
+        }
+
+        StepAndRepeat::new(offsets)
+    }
+
+    /// Generate the combined program.
+    ///
+    /// Program numbers and program ends (`M2` and `M30`) are removed from
+    /// the part, with a single `M30` at the end of the combined program.
+    pub fn generate(&self, part: &[Line]) -> Result<Vec<Line>> {
+        let body: Vec<Line> = part.iter().filter(|line| !is_program_end(line)).cloned().collect();
+        let entry = entry_point(&body)?;
+        let mut out = Output::default();
+
+        match self.placement {
+            Placement::Inline => {
+                for &offset in &self.offsets {
+                    self.move_to(&mut out, entry + offset);
+
+                    let transform = Transform::translate(offset);
+                    for line in Transformer::new(body.iter().cloned(), transform) {
+                        out.copy(line?);
+                    }
+                }
+            }
+            Placement::WorkOffsets => {
+                if self.offsets.len() > 6 {
+                    return Err(Error::InvalidCommand("Only 6 work offsets (G54 to G59) are \
+                                                      available"));
+                }
+
+                for (i, offset) in self.offsets.iter().enumerate() {
+                    let args = Args {
+                        l: Some(2.0),
+                        p: Some(i as f32 + 1.0),
+                        x: Some(offset.x),
+                        y: Some(offset.y),
+                        z: Some(offset.z),
+                        ..Default::default()
+                    };
+                    out.new_line();
+                    out.push(CommandKind::G, Number::Integer(10), args);
+                }
+
+                for i in 0..self.offsets.len() {
+                    out.new_line();
+                    out.push(CommandKind::G, Number::Integer(90), Args::default());
+                    out.push(CommandKind::G, Number::Integer(0), z(self.safe_z));
+                    out.new_line();
+                    out.push(CommandKind::G, Number::Integer(54 + i as u32), Args::default());
+                    self.move_to(&mut out, entry);
+
+                    for &line in &body {
+                        out.copy(line);
+                    }
+                }
+            }
+            Placement::Subprogram(number) => {
+                for &offset in &self.offsets {
+                    out.new_line();
+                    out.push(CommandKind::G, Number::Integer(90), Args::default());
+                    out.push(CommandKind::G, Number::Integer(0), z(self.safe_z));
+                    let args = Args {
+                        x: Some(offset.x),
+                        y: Some(offset.y),
+                        z: Some(offset.z),
+                        ..Default::default()
+                    };
+                    out.new_line();
+                    out.push(CommandKind::G, Number::Integer(52), args);
+                    self.move_to(&mut out, entry);
+
+                    let call = Args {
+                        p: Some(number as f32),
+                        ..Default::default()
+                    };
+                    out.new_line();
+                    out.push(CommandKind::M, Number::Integer(98), call);
+                }
+
+                let origin = Args {
+                    x: Some(0.0),
+                    y: Some(0.0),
+                    z: Some(0.0),
+                    ..Default::default()
+                };
+                out.new_line();
+                out.push(CommandKind::G, Number::Integer(52), origin);
+                out.new_line();
+                out.push(CommandKind::M, Number::Integer(30), Args::default());
+
+                out.new_line();
+                out.lines.push(Line::ProgramNumber(number));
+                for &line in &body {
+                    out.copy(line);
+                }
+                out.new_line();
+                out.push(CommandKind::M, Number::Integer(99), Args::default());
+
+                return Ok(out.lines);
+            }
+        }
+
+        out.new_line();
+        out.push(CommandKind::M, Number::Integer(30), Args::default());
+        Ok(out.lines)
+    }
+
+    /// Retract and rapid over to a point (for `Inline` placement this also
+    /// makes sure we're in absolute mode).
+    fn move_to(&self, out: &mut Output, point: Point) {
+        if self.placement == Placement::Inline {
+            out.new_line();
+            out.push(CommandKind::G, Number::Integer(90), Args::default());
+            out.push(CommandKind::G, Number::Integer(0), z(self.safe_z));
+        }
+
+        let args = Args {
+            x: Some(point.x),
+            y: Some(point.y),
+            ..Default::default()
+        };
+        out.new_line();
+        out.push(CommandKind::G, Number::Integer(0), args);
+    }
+}
+
+
+fn z(value: f32) -> Args {
+    Args {
+        z: Some(value),
+        ..Default::default()
+    }
+}
+
+fn is_program_end(line: &Line) -> bool {
+    match *line {
+        Line::ProgramNumber(_) => true,
+        Line::Cmd(ref cmd) => {
+            cmd.kind == CommandKind::M &&
+            (cmd.number == Number::Integer(2) || cmd.number == Number::Integer(30))
+        }
+        Line::Args { .. } => false,
+    }
+}
+
+/// Where the part's first move goes.
+fn entry_point(part: &[Line]) -> Result<Point> {
+    for event in Interpreter::new(part.iter().cloned()) {
+        if let Event::Move(m) = event? {
+            return Ok(m.end);
+        }
+    }
+
+    Ok(Point::default())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use writer::write_lines;
+    use helpers::parse;
+
+    const PART: &str = "O10\nG90 G0 X1 Y1 Z1\nG1 Z-1 F100\nX5\nG0 Z1\nM30";
+
+    fn nest(nesting: &StepAndRepeat) -> Result<String> {
+        let part = parse(PART);
+
+        let lines = nesting.generate(&part)?;
+        let mut written = String::new();
+        write_lines(&lines, &mut written).unwrap();
+        Ok(written)
+    }
+
+    #[test]
+    fn grid_layouts() {
+        let spacing = Point::new(10.0, 20.0, 0.0);
+
+        let got = StepAndRepeat::grid(3, 2, spacing, false).offsets;
+        let xy: Vec<_> = got.iter().map(|p| (p.x, p.y)).collect();
+        assert_eq!(xy,
+                   vec![(0.0, 0.0), (10.0, 0.0), (20.0, 0.0), (0.0, 20.0), (10.0, 20.0),
+                        (20.0, 20.0)]);
+
+        let got = StepAndRepeat::grid(3, 2, spacing, true).offsets;
+        let xy: Vec<_> = got.iter().map(|p| (p.x, p.y)).collect();
+        assert_eq!(xy,
+                   vec![(0.0, 0.0), (10.0, 0.0), (20.0, 0.0), (20.0, 20.0), (10.0, 20.0),
+                        (0.0, 20.0)]);
+    }
+
+    #[test]
+    fn inline_copies() {
+        let nesting = StepAndRepeat {
+            safe_z: 10.0,
+            ..StepAndRepeat::new(vec![Point::default(), Point::new(0.0, 50.0, 0.0)])
+        };
+
+        let should_be = "G90 G0 Z10\nG0 X1 Y1\n\
+                         G90 G0 X1 Y1 Z1\nG1 Z-1 F100\nX5\nG0 Z1\n\
+                         G90 G0 Z10\nG0 X1 Y51\n\
+                         G90 G0 X1 Y51 Z1\nG1 Z-1 F100\nX5\nG0 Z1\n\
+                         M30\n";
+        assert_eq!(nest(&nesting).unwrap(), should_be);
+    }
+
+    #[test]
+    fn copies_with_work_offsets() {
+        let nesting = StepAndRepeat {
+            placement: Placement::WorkOffsets,
+            ..StepAndRepeat::new(vec![Point::new(100.0, 100.0, -50.0),
+                                      Point::new(200.0, 100.0, -50.0)])
+        };
+
+        let should_be = "G10 X100 Y100 Z-50 L2 P1\nG10 X200 Y100 Z-50 L2 P2\n\
+                         G90 G0 Z5\nG54\nG0 X1 Y1\n\
+                         G90 G0 X1 Y1 Z1\nG1 Z-1 F100\nX5\nG0 Z1\n\
+                         G90 G0 Z5\nG55\nG0 X1 Y1\n\
+                         G90 G0 X1 Y1 Z1\nG1 Z-1 F100\nX5\nG0 Z1\n\
+                         M30\n";
+        assert_eq!(nest(&nesting).unwrap(), should_be);
+
+        let too_many = StepAndRepeat {
+            placement: Placement::WorkOffsets,
+            ..StepAndRepeat::grid(7, 1, Point::new(10.0, 0.0, 0.0), false)
+        };
+        assert!(nest(&too_many).is_err());
+    }
+
+    #[test]
+    fn copies_calling_a_subprogram() {
+        let nesting = StepAndRepeat {
+            placement: Placement::Subprogram(1000),
+            ..StepAndRepeat::grid(2, 1, Point::new(10.0, 0.0, 0.0), false)
+        };
+
+        let should_be = "G90 G0 Z5\nG52 X0 Y0 Z0\nG0 X1 Y1\nM98 P1000\n\
+                         G90 G0 Z5\nG52 X10 Y0 Z0\nG0 X1 Y1\nM98 P1000\n\
+                         G52 X0 Y0 Z0\nM30\n\
+                         O1000\nG90 G0 X1 Y1 Z1\nG1 Z-1 F100\nX5\nG0 Z1\nM99\n";
+        assert_eq!(nest(&nesting).unwrap(), should_be);
+    }
+}
@@ -0,0 +1,321 @@
+//! Work coordinate systems and the LinuxCNC-style `.var` parameter file they
+//! are saved in.
+
+use core::fmt::{self, Write};
+
+use interpreter::Point;
+use lexer::Span;
+use errors::*;
+
+
+/// The parameter holding the first coordinate system's X offset, each
+/// following coordinate system starts 20 parameters later.
+const FIRST_COORDINATE_SYSTEM: u32 = 5221;
+/// Parameters between the start of one coordinate system and the next.
+const COORDINATE_SYSTEM_STRIDE: u32 = 20;
+/// The `G28` home position.
+const G28_HOME: u32 = 5161;
+/// The `G30` home position.
+const G30_HOME: u32 = 5181;
+/// Set to 1 when the `G92` offset is being applied.
+const G92_ENABLED: u32 = 5210;
+/// The `G92` offset.
+const G92_OFFSET: u32 = 5211;
+/// The number of the active coordinate system.
+const ACTIVE_COORDINATE_SYSTEM: u32 = 5220;
+/// Each group of parameters has room for 9 axes (XYZABCUVW).
+const AXES: u32 = 9;
+
+
+/// One of the nine work coordinate systems.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[allow(missing_docs)]
+pub enum CoordinateSystem {
+    #[default]
+    G54,
+    G55,
+    G56,
+    G57,
+    G58,
+    G59,
+    G59_1,
+    G59_2,
+    G59_3,
+}
+
+impl CoordinateSystem {
+    /// Every coordinate system, in order.
+    pub const ALL: [CoordinateSystem; 9] = [CoordinateSystem::G54,
+                                            CoordinateSystem::G55,
+                                            CoordinateSystem::G56,
+                                            CoordinateSystem::G57,
+                                            CoordinateSystem::G58,
+                                            CoordinateSystem::G59,
+                                            CoordinateSystem::G59_1,
+                                            CoordinateSystem::G59_2,
+                                            CoordinateSystem::G59_3];
+
+    /// Look up a coordinate system by the number `G10` uses for it in its
+    /// `P` word (`G54` is 1, `G59.3` is 9).
+    pub fn from_number(n: u32) -> Option<CoordinateSystem> {
+        if (1..=9).contains(&n) {
+            Some(CoordinateSystem::ALL[n as usize - 1])
+        } else {
+            None
+        }
+    }
+
+    /// The number used to refer to this coordinate system in a `G10`
+    /// command's `P` word.
+    pub fn number(&self) -> u32 {
+        *self as u32 + 1
+    }
+
+    fn first_parameter(&self) -> u32 {
+        FIRST_COORDINATE_SYSTEM + (self.number() - 1) * COORDINATE_SYSTEM_STRIDE
+    }
+}
+
+
+/// The table of offsets which translate program coordinates into machine
+/// coordinates.
+///
+/// All offsets are in millimetres.
+///
+/// # Examples
+///
+/// ```rust
+/// use gcode::offsets::{WorkOffsets, CoordinateSystem};
+///
+/// let src = "5220\t2.000000\n5241\t100.000000\n5242\t-25.000000\n";
+/// let offsets = WorkOffsets::from_var_file(src).unwrap();
+///
+/// assert_eq!(offsets.active, CoordinateSystem::G55);
+/// assert_eq!(offsets.current().x, 100.0);
+///
+/// let mut saved = String::new();
+/// offsets.write_var_file(&mut saved).unwrap();
+/// assert_eq!(WorkOffsets::from_var_file(&saved).unwrap(), offsets);
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct WorkOffsets {
+    /// The active coordinate system.
+    pub active: CoordinateSystem,
+    /// The origin of each coordinate system, in the order given by
+    /// `CoordinateSystem::ALL`.
+    pub coordinate_systems: [Point; 9],
+    /// The `G92` offset currently being applied.
+    pub g92: Point,
+    /// The `G92` offset which was last set, restored by `G92.3`.
+    pub saved_g92: Point,
+    /// The position stored for `G28`.
+    pub g28_home: Point,
+    /// The position stored for `G30`.
+    pub g30_home: Point,
+}
+
+impl WorkOffsets {
+    /// Get a coordinate system's origin.
+    pub fn get(&self, coordinate_system: CoordinateSystem) -> Point {
+        self.coordinate_systems[coordinate_system.number() as usize - 1]
+    }
+
+    /// Set a coordinate system's origin.
+    pub fn set(&mut self, coordinate_system: CoordinateSystem, origin: Point) {
+        self.coordinate_systems[coordinate_system.number() as usize - 1] = origin;
+    }
+
+    /// The total offset currently applied to program coordinates.
+    pub fn current(&self) -> Point {
+        self.get(self.active) + self.g92
+    }
+
+    /// Read the offsets from a LinuxCNC parameter (`.var`) file.
+    ///
+    /// Each line contains a parameter number and its value, separated by
+    /// whitespace. Parameters which don't relate to work offsets are
+    /// ignored.
+    pub fn from_var_file(src: &str) -> Result<WorkOffsets> {
+        let mut offsets = WorkOffsets::default();
+        let mut g92_enabled = false;
+
+        for (line, text) in src.lines().enumerate() {
+            let mut words = text.split_whitespace();
+            let span = Span { line, column: 0 };
+
+            let (number, value) = match (words.next(), words.next(), words.next()) {
+                (None, _, _) => continue,
+                (Some(n), Some(v), None) => (n, v),
+                _ => return Err(Error::SyntaxError("Expected a parameter number and value", span)),
+            };
+
+            let number: u32 = number.parse()
+                .map_err(|_| Error::SyntaxError("Invalid parameter number", span))?;
+            let value: f32 = value.parse()
+                .map_err(|_| Error::SyntaxError("Invalid parameter value", span))?;
+
+            match number {
+                G92_ENABLED => g92_enabled = value != 0.0,
+                ACTIVE_COORDINATE_SYSTEM => {
+                    offsets.active = match CoordinateSystem::from_number(value as u32) {
+                        Some(cs) => cs,
+                        None => return Err(Error::SyntaxError("Invalid coordinate system", span)),
+                    };
+                }
+                _ => {
+                    match offsets.parameter(number) {
+                        Some((point, 0)) => point.x = value,
+                        Some((point, 1)) => point.y = value,
+                        Some((point, 2)) => point.z = value,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if g92_enabled {
+            offsets.g92 = offsets.saved_g92;
+        }
+
+        Ok(offsets)
+    }
+
+    /// Write the offsets out in the LinuxCNC parameter file format.
+    ///
+    /// Axes which aren't tracked (ABC, UVW and coordinate system rotation)
+    /// are written as zero so the file can be read by LinuxCNC.
+    pub fn write_var_file<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write_point(w, G28_HOME, self.g28_home)?;
+        write_point(w, G30_HOME, self.g30_home)?;
+
+        let enabled = if self.g92 == Point::default() { 0 } else { 1 };
+        writeln!(w, "{}\t{:.6}", G92_ENABLED, enabled as f32)?;
+        write_point(w, G92_OFFSET, self.saved_g92)?;
+        writeln!(w, "{}\t{:.6}", ACTIVE_COORDINATE_SYSTEM, self.active.number() as f32)?;
+
+        for cs in &CoordinateSystem::ALL {
+            let first = cs.first_parameter();
+            write_point(w, first, self.get(*cs))?;
+
+            // rotation
+            writeln!(w, "{}\t{:.6}", first + AXES, 0.0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the point a parameter refers to, and which of its axes.
+    fn parameter(&mut self, number: u32) -> Option<(&mut Point, u32)> {
+        let (point, first) = match number {
+            n if in_group(n, G28_HOME) => (&mut self.g28_home, G28_HOME),
+            n if in_group(n, G30_HOME) => (&mut self.g30_home, G30_HOME),
+            n if in_group(n, G92_OFFSET) => (&mut self.saved_g92, G92_OFFSET),
+            n => {
+                let cs = *CoordinateSystem::ALL
+                              .iter()
+                              .find(|cs| in_group(n, cs.first_parameter()))?;
+                (&mut self.coordinate_systems[cs.number() as usize - 1], cs.first_parameter())
+            }
+        };
+
+        Some((point, number - first))
+    }
+}
+
+/// Is this parameter part of the group of axes starting at `first`?
+fn in_group(number: u32, first: u32) -> bool {
+    first <= number && number < first + AXES
+}
+
+fn write_point<W: Write>(w: &mut W, first: u32, point: Point) -> fmt::Result {
+    let values = [point.x, point.y, point.z];
+
+    for axis in 0..AXES {
+        let value = values.get(axis as usize).cloned().unwrap_or(0.0);
+        writeln!(w, "{}\t{:.6}", first + axis, value)?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn coordinate_system_numbers() {
+        for (i, cs) in CoordinateSystem::ALL.iter().enumerate() {
+            assert_eq!(cs.number(), i as u32 + 1);
+            assert_eq!(CoordinateSystem::from_number(cs.number()), Some(*cs));
+        }
+
+        assert_eq!(CoordinateSystem::G59_3.first_parameter(), 5381);
+        assert_eq!(CoordinateSystem::from_number(0), None);
+        assert_eq!(CoordinateSystem::from_number(10), None);
+    }
+
+    #[test]
+    fn parse_a_var_file() {
+        let src = "5161\t1.000000\n\
+                   5183\t-2.500000\n\
+                   \n\
+                   5210\t1.000000\n\
+                   5211\t3.000000\n\
+                   5220\t9.000000\n\
+                   5223\t-10.000000\n\
+                   5226\t45.000000\n\
+                   5382\t7.500000\n\
+                   5400\t12.000000\n";
+
+        let got = WorkOffsets::from_var_file(src).unwrap();
+
+        assert_eq!(got.g28_home, Point::new(1.0, 0.0, 0.0));
+        assert_eq!(got.g30_home, Point::new(0.0, 0.0, -2.5));
+        assert_eq!(got.g92, Point::new(3.0, 0.0, 0.0));
+        assert_eq!(got.saved_g92, got.g92);
+        assert_eq!(got.active, CoordinateSystem::G59_3);
+        assert_eq!(got.get(CoordinateSystem::G54), Point::new(0.0, 0.0, -10.0));
+        assert_eq!(got.get(CoordinateSystem::G59_3), Point::new(0.0, 7.5, 0.0));
+        assert_eq!(got.current(), Point::new(3.0, 7.5, 0.0));
+    }
+
+    #[test]
+    fn disabled_g92_offsets_are_only_saved() {
+        let got = WorkOffsets::from_var_file("5210 0\n5211 3\n").unwrap();
+
+        assert_eq!(got.g92, Point::default());
+        assert_eq!(got.saved_g92, Point::new(3.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn invalid_var_files() {
+        let inputs = ["5221", "5221 1.0 2.0", "x 1.0", "5221 abc", "5220 10"];
+
+        for src in &inputs {
+            assert!(WorkOffsets::from_var_file(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn round_trip_through_a_var_file() {
+        let mut offsets = WorkOffsets {
+            active: CoordinateSystem::G57,
+            ..Default::default()
+        };
+        offsets.set(CoordinateSystem::G55, Point::new(1.5, -2.0, 3.25));
+        offsets.set(CoordinateSystem::G59_2, Point::new(0.0, 0.0, -100.0));
+        offsets.saved_g92 = Point::new(10.0, 0.0, 0.0);
+        offsets.g92 = offsets.saved_g92;
+        offsets.g28_home = Point::new(0.0, 0.0, 50.0);
+
+        let mut src = String::new();
+        offsets.write_var_file(&mut src).unwrap();
+
+        assert!(src.contains("5241\t1.500000\n"));
+        assert!(src.contains("5220\t4.000000\n"));
+        assert_eq!(src.lines().count(), 9 + 9 + 1 + 9 + 1 + 9 * 10);
+        assert_eq!(WorkOffsets::from_var_file(&src).unwrap(), offsets);
+    }
+}
@@ -2,7 +2,8 @@
 
 use core::iter::Peekable;
 
-use lexer::{Token, TokenKind};
+use lexer::{Token, TokenKind, Span, Text};
+use dialect::Dialect;
 use errors::*;
 
 
@@ -28,6 +29,11 @@ pub struct Parser<I>
     where I: Iterator<Item = Token>
 {
     tokens: Peekable<I>,
+    /// The line we're currently parsing, arguments can't span multiple lines.
+    line: usize,
+    dialect: Dialect,
+    /// Are `T` words arguments to the command being parsed?
+    t_is_argument: bool,
 }
 
 impl<I> Parser<I>
@@ -35,16 +41,48 @@ impl<I> Parser<I>
 {
     /// Create a new parser using the provided stream of tokens.
     pub fn new(tokens: I) -> Parser<I> {
-        Parser { tokens: tokens.peekable() }
+        Parser::with_dialect(tokens, Dialect::Rs274Ngc)
+    }
+
+    /// Create a parser for a particular dialect of gcode.
+    pub fn with_dialect(tokens: I, dialect: Dialect) -> Parser<I> {
+        Parser {
+            tokens: tokens.peekable(),
+            line: 0,
+            dialect,
+            t_is_argument: false,
+        }
     }
 
     fn next_command(&mut self) -> Result<Line> {
+        let span = match self.tokens.peek() {
+            Some(t) => t.span(),
+            None => return Err(Error::UnexpectedEOF),
+        };
+        self.line = span.line;
+
         if let Ok(number) = self.program_number() {
             return Ok(Line::ProgramNumber(number));
         };
 
         let line_number = self.line_number()?;
+
+        // Arguments on their own (or a lonely line number) continue on from
+        // whatever command came before them
+        let is_command = matches!(self.peek(),
+                                  Some(TokenKind::G) | Some(TokenKind::M) | Some(TokenKind::T));
+        if !self.on_current_line() || !is_command && self.peek().and_then(argument_kind).is_some() {
+            self.t_is_argument = false;
+            let args = self.args()?;
+            return Ok(Line::Args {
+                          args,
+                          line_number,
+                          span,
+                      });
+        }
+
         let (kind, number) = self.command_type()?;
+        self.t_is_argument = self.dialect.t_is_argument(kind);
         let args = self.args()?;
 
         let cmd = Command {
@@ -52,6 +90,7 @@ impl<I> Parser<I>
             number,
             args,
             line_number,
+            span,
         };
         Ok(Line::Cmd(cmd))
     }
@@ -102,12 +141,16 @@ impl<I> Parser<I>
 
         let n = match self.unchecked_next() {
             TokenKind::Number(n) => {
-                // TODO: Need to amend the lexer's definition of a Number
-                if n == n as u32 as f32 {
-                    Number::Integer(n as u32)
+                // The lexer only gives us a float, but G and M codes never
+                // have more than a single digit after the decimal point
+                // (e.g. "G33.1" or "G59.3")
+                let integer_part = n as u32;
+                let fractional_part = ((n - integer_part as f32) * 10.0 + 0.5) as u32;
+
+                if fractional_part == 0 {
+                    Number::Integer(integer_part)
                 } else {
-                    // FIXME: This is wrong, read above TODO
-                    Number::Decimal(n as u32, 0)
+                    Number::Decimal(integer_part, fractional_part)
                 }
             }
             _ => unreachable!(),
@@ -119,40 +162,63 @@ impl<I> Parser<I>
     fn args(&mut self) -> Result<Args> {
         let mut a = Args::default();
 
-        while let Ok((kind, value)) = self.argument() {
+        while self.on_current_line() {
+            let is_argument = match self.peek() {
+                Some(TokenKind::Text(text)) => {
+                    let _ = self.tokens.next();
+                    a.text = Some(text);
+                    continue;
+                }
+                Some(TokenKind::T) => self.t_is_argument,
+                Some(kind) => argument_kind(kind).is_some(),
+                None => false,
+            };
+            if !is_argument {
+                break;
+            }
+
+            let (kind, value) = self.argument()?;
             a.set(kind, value);
         }
 
         Ok(a)
     }
 
-    fn argument(&mut self) -> Result<(ArgumentKind, f32)> {
-        lookahead!(self, "Expected an argument kind",
-        TokenKind::X | TokenKind::Y | TokenKind::Z | TokenKind::S | 
-        TokenKind::FeedRate | TokenKind::I | TokenKind::J | TokenKind::H |
-        TokenKind::P);
-
-        let kind = match self.unchecked_next() {
-            TokenKind::X => ArgumentKind::X,
-            TokenKind::Y => ArgumentKind::Y,
-            TokenKind::Z => ArgumentKind::Z,
-            TokenKind::S => ArgumentKind::S,
-            TokenKind::I => ArgumentKind::I,
-            TokenKind::J => ArgumentKind::J,
-            TokenKind::H => ArgumentKind::H,
-            TokenKind::P => ArgumentKind::P,
-            TokenKind::FeedRate => ArgumentKind::F,
-            _ => unreachable!(),
+    /// Parse a single argument, which may be a flag without a value (e.g.
+    /// the `X` in Marlin's `G28 X`) if the dialect allows it.
+    fn argument(&mut self) -> Result<(ArgumentKind, Option<f32>)> {
+        let (kind, span) = match self.tokens.peek() {
+            Some(t) => {
+                match argument_kind(t.kind()) {
+                    Some(kind) => (kind, t.span()),
+                    None if self.t_is_argument && t.kind() == TokenKind::T => {
+                        (ArgumentKind::T, t.span())
+                    }
+                    None => return Err(Error::SyntaxError("Expected an argument kind", t.span())),
+                }
+            }
+            None => return Err(Error::UnexpectedEOF),
         };
+        let _ = self.tokens.next();
 
         // Check for a negative number
-        let is_negative = if self.peek() == Some(TokenKind::Minus) {
+        let is_negative = if self.on_current_line() && self.peek() == Some(TokenKind::Minus) {
             let _ = self.tokens.next();
             true
         } else {
             false
         };
 
+        let is_number = matches!(self.peek(), Some(TokenKind::Number(_)));
+        let has_value = is_negative || (is_number && self.on_current_line());
+        if !has_value {
+            if self.dialect.allows_flags() {
+                return Ok((kind, None));
+            } else {
+                return Err(Error::SyntaxError("All arguments must be followed by a number", span));
+            }
+        }
+
         let n = match self.tokens.next() {
             Some(t) => {
                 match t.kind() {
@@ -167,9 +233,9 @@ impl<I> Parser<I>
         };
 
         if is_negative {
-            Ok((kind, -1.0 * n))
+            Ok((kind, Some(-n)))
         } else {
-            Ok((kind, n))
+            Ok((kind, Some(n)))
         }
     }
 
@@ -177,6 +243,11 @@ impl<I> Parser<I>
         self.tokens.peek().map(|t| t.kind())
     }
 
+    fn on_current_line(&mut self) -> bool {
+        let line = self.line;
+        matches!(self.tokens.peek(), Some(t) if t.span().line == line)
+    }
+
     fn unchecked_next(&mut self) -> TokenKind {
         self.tokens
             .next()
@@ -192,22 +263,76 @@ impl<I> Iterator for Parser<I>
     fn next(&mut self) -> Option<Self::Item> {
         match self.next_command() {
             Err(Error::UnexpectedEOF) => None,
+            Err(Error::SyntaxError(msg, span)) => {
+                // Skip past the offending token so we don't keep running
+                // into the same error
+                if self.tokens.peek().map(|t| t.span()) == Some(span) {
+                    let _ = self.tokens.next();
+                }
+                Some(Err(Error::SyntaxError(msg, span)))
+            }
             other => Some(other),
         }
     }
 }
 
+fn argument_kind(kind: TokenKind) -> Option<ArgumentKind> {
+    match kind {
+        TokenKind::X => Some(ArgumentKind::X),
+        TokenKind::Y => Some(ArgumentKind::Y),
+        TokenKind::Z => Some(ArgumentKind::Z),
+        TokenKind::S => Some(ArgumentKind::S),
+        TokenKind::I => Some(ArgumentKind::I),
+        TokenKind::J => Some(ArgumentKind::J),
+        TokenKind::H => Some(ArgumentKind::H),
+        TokenKind::P => Some(ArgumentKind::P),
+        TokenKind::R => Some(ArgumentKind::R),
+        TokenKind::E => Some(ArgumentKind::E),
+        TokenKind::K => Some(ArgumentKind::K),
+        TokenKind::L => Some(ArgumentKind::L),
+        TokenKind::Q => Some(ArgumentKind::Q),
+        TokenKind::D => Some(ArgumentKind::D),
+        TokenKind::FeedRate => Some(ArgumentKind::F),
+        _ => None,
+    }
+}
+
 /// A single line of gcode.
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Line {
     /// A program number.
     ProgramNumber(u32),
     /// An actual command.
     Cmd(Command),
+    /// A line containing only arguments (e.g. `X10 Y20`), which continue
+    /// on from the previous motion command.
+    Args {
+        /// The arguments.
+        args: Args,
+        /// The line number (if any).
+        line_number: Option<u32>,
+        /// Where the line starts in the source code.
+        span: Span,
+    },
+}
+
+impl Line {
+    /// Where the line starts in the source code, if known.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Line::Cmd(ref cmd) => Some(cmd.span),
+            Line::Args { span, .. } => Some(span),
+            Line::ProgramNumber(_) => None,
+        }
+    }
 }
 
 /// A type which can either be an integer or a float.
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Number {
     /// A plain integer.
     Integer(u32),
@@ -218,6 +343,7 @@ pub enum Number {
 
 /// A single command.
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Command {
     /// Which kind of `Command` is this?
     pub kind: CommandKind,
@@ -227,37 +353,232 @@ pub struct Command {
     pub args: Args,
     /// The line number the command is on (if any).
     pub line_number: Option<u32>,
+    /// Where the command starts in the source code.
+    pub span: Span,
 }
 
 /// A *good ol' bag-o-floats* which contains all the possible arguments and their values.
+///
+/// Words given without a value (e.g. the `X` and `Y` in `G28 X Y`) are kept
+/// in `flags`, and string arguments (e.g. the message in `M117 Hello`) are
+/// kept in `text`.
 #[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[allow(missing_docs)]
 pub struct Args {
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub x: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub y: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub z: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub s: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub t: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub f: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub i: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub j: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub h: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub p: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub r: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub e: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub k: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub l: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub q: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub d: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Flags::is_empty"))]
+    pub flags: Flags,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub text: Option<Text>,
 }
 
 impl Args {
-    fn set(&mut self, kind: ArgumentKind, value: f32) {
+    /// Were no arguments provided?
+    pub fn is_empty(&self) -> bool {
+        *self == Args::default()
+    }
+
+    /// Was this word given, either with a value or as a flag?
+    pub fn has(&self, letter: char) -> bool {
+        let value = match letter.to_ascii_uppercase() {
+            'X' => self.x,
+            'Y' => self.y,
+            'Z' => self.z,
+            'S' => self.s,
+            'T' => self.t,
+            'F' => self.f,
+            'I' => self.i,
+            'J' => self.j,
+            'H' => self.h,
+            'P' => self.p,
+            'R' => self.r,
+            'E' => self.e,
+            'K' => self.k,
+            'L' => self.l,
+            'Q' => self.q,
+            'D' => self.d,
+            _ => None,
+        };
+
+        value.is_some() || self.flags.contains(letter)
+    }
+
+    fn set(&mut self, kind: ArgumentKind, value: Option<f32>) {
+        let value = match value {
+            Some(value) => value,
+            None => return self.flags.insert(kind.letter()),
+        };
+
         match kind {
             ArgumentKind::X => self.x = Some(value),
             ArgumentKind::Y => self.y = Some(value),
             ArgumentKind::Z => self.z = Some(value),
             ArgumentKind::S => self.s = Some(value),
+            ArgumentKind::T => self.t = Some(value),
             ArgumentKind::F => self.f = Some(value),
             ArgumentKind::I => self.i = Some(value),
             ArgumentKind::J => self.j = Some(value),
             ArgumentKind::H => self.h = Some(value),
             ArgumentKind::P => self.p = Some(value),
+            ArgumentKind::R => self.r = Some(value),
+            ArgumentKind::E => self.e = Some(value),
+            ArgumentKind::K => self.k = Some(value),
+            ArgumentKind::L => self.l = Some(value),
+            ArgumentKind::Q => self.q = Some(value),
+            ArgumentKind::D => self.d = Some(value),
+        }
+    }
+}
+
+/// The letters of the words given without a value, such as the `X` and `Y`
+/// in `G28 X Y`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Flags(u32);
+
+impl Flags {
+    /// Was this letter given as a flag?
+    pub fn contains(&self, letter: char) -> bool {
+        match bit(letter) {
+            Some(bit) => self.0 & bit != 0,
+            None => false,
+        }
+    }
+
+    /// Add a letter. Anything other than `A` to `Z` (in either case) is
+    /// ignored.
+    pub fn insert(&mut self, letter: char) {
+        if let Some(bit) = bit(letter) {
+            self.0 |= bit;
+        }
+    }
+
+    /// Were there no flags?
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The letters, in alphabetical order.
+    pub fn letters(&self) -> Letters {
+        Letters {
+            flags: *self,
+            next: b'A',
+        }
+    }
+}
+
+fn bit(letter: char) -> Option<u32> {
+    let letter = letter.to_ascii_uppercase();
+
+    if letter.is_ascii_uppercase() {
+        Some(1 << (letter as u32 - 'A' as u32))
+    } else {
+        None
+    }
+}
+
+/// An iterator over the letters in some `Flags`.
+#[derive(Debug, Copy, Clone)]
+pub struct Letters {
+    flags: Flags,
+    next: u8,
+}
+
+impl Iterator for Letters {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        while self.next <= b'Z' {
+            let letter = self.next as char;
+            self.next += 1;
+
+            if self.flags.contains(letter) {
+                return Some(letter);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Flags {
+    /// Flags are written as a string of letters (e.g. `"XY"`).
+    fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+        where S: ::serde::Serializer
+    {
+        let mut letters = ::arrayvec::ArrayString::<[u8; 32]>::new();
+        for letter in self.letters() {
+            letters.push(letter);
         }
+
+        serializer.serialize_str(&letters)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Flags {
+    fn deserialize<D>(deserializer: D) -> ::core::result::Result<Flags, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        struct FlagsVisitor;
+
+        impl<'de> ::serde::de::Visitor<'de> for FlagsVisitor {
+            type Value = Flags;
+
+            fn expecting(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+                f.write_str("a string of letters")
+            }
+
+            fn visit_str<E>(self, s: &str) -> ::core::result::Result<Flags, E>
+                where E: ::serde::de::Error
+            {
+                let mut flags = Flags::default();
+
+                for c in s.chars() {
+                    if bit(c).is_none() {
+                        return Err(E::invalid_value(::serde::de::Unexpected::Char(c), &self));
+                    }
+                    flags.insert(c);
+                }
+
+                Ok(flags)
+            }
+        }
+
+        deserializer.deserialize_str(FlagsVisitor)
     }
 }
 
@@ -270,14 +591,45 @@ enum ArgumentKind {
     Z,
     F,
     S,
+    T,
     I,
     J,
     H,
     P,
+    R,
+    E,
+    K,
+    L,
+    Q,
+    D,
+}
+
+impl ArgumentKind {
+    fn letter(self) -> char {
+        match self {
+            ArgumentKind::X => 'X',
+            ArgumentKind::Y => 'Y',
+            ArgumentKind::Z => 'Z',
+            ArgumentKind::F => 'F',
+            ArgumentKind::S => 'S',
+            ArgumentKind::T => 'T',
+            ArgumentKind::I => 'I',
+            ArgumentKind::J => 'J',
+            ArgumentKind::H => 'H',
+            ArgumentKind::P => 'P',
+            ArgumentKind::R => 'R',
+            ArgumentKind::E => 'E',
+            ArgumentKind::K => 'K',
+            ArgumentKind::L => 'L',
+            ArgumentKind::Q => 'Q',
+            ArgumentKind::D => 'D',
+        }
+    }
 }
 
 /// The type of command.
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub enum CommandKind {
     G,
@@ -335,18 +687,25 @@ mod tests {
     parser_test!(FAIL: invalid_command_type, command_type, "N15");
     parser_test!(FAIL: command_type_with_no_number, command_type, "G X15.0");
 
-    // TODO: Uncomment this when the lexer has been adjusted
-    // parser_test!(gcode_with_decimal_command, command_type, "G91.1"
-    //              => (CommandKind::G, Number::Decimal(91, 1)));
-
-
-    parser_test!(negative_x_argument, argument, "X-10.0" => (ArgumentKind::X, -10.0));
-    parser_test!(x_argument, argument, "X10.0" => (ArgumentKind::X, 10.0));
-    parser_test!(y_argument, argument, "Y10.0" => (ArgumentKind::Y, 10.0));
-    parser_test!(z_argument, argument, "Z3.14" => (ArgumentKind::Z, 3.14));
-    parser_test!(s_argument, argument, "S10.0" => (ArgumentKind::S, 10.0));
-    parser_test!(i_argument, argument, "I10" => (ArgumentKind::I, 10.0));
-    parser_test!(j_argument, argument, "J10.0" => (ArgumentKind::J, 10.0));
+    parser_test!(gcode_with_decimal_command, command_type, "G91.1"
+                 => (CommandKind::G, Number::Decimal(91, 1)));
+    parser_test!(work_offset_decimal_command, command_type, "G59.3"
+                 => (CommandKind::G, Number::Decimal(59, 3)));
+
+
+    parser_test!(negative_x_argument, argument, "X-10.0" => (ArgumentKind::X, Some(-10.0)));
+    parser_test!(x_argument, argument, "X10.0" => (ArgumentKind::X, Some(10.0)));
+    parser_test!(y_argument, argument, "Y10.0" => (ArgumentKind::Y, Some(10.0)));
+    parser_test!(z_argument, argument, "Z3.14" => (ArgumentKind::Z, Some(3.14)));
+    parser_test!(s_argument, argument, "S10.0" => (ArgumentKind::S, Some(10.0)));
+    parser_test!(i_argument, argument, "I10" => (ArgumentKind::I, Some(10.0)));
+    parser_test!(j_argument, argument, "J10.0" => (ArgumentKind::J, Some(10.0)));
+    parser_test!(k_argument, argument, "K1.5" => (ArgumentKind::K, Some(1.5)));
+    parser_test!(r_argument, argument, "R-2" => (ArgumentKind::R, Some(-2.0)));
+    parser_test!(e_argument, argument, "E0.5" => (ArgumentKind::E, Some(0.5)));
+    parser_test!(l_argument, argument, "L2" => (ArgumentKind::L, Some(2.0)));
+    parser_test!(q_argument, argument, "Q29.5" => (ArgumentKind::Q, Some(29.5)));
+    parser_test!(d_argument, argument, "D2500" => (ArgumentKind::D, Some(2500.0)));
 
     parser_test!(program_number, program_number, "O500" => 500);
 
@@ -359,8 +718,158 @@ mod tests {
             ..Default::default()
         },
         line_number: Some(15),
+        span: Span::from((0, 1)),
     }));
 
+    #[test]
+    fn arguments_dont_span_multiple_lines() {
+        let src = "G1 X1 Y2\nX3\nN20 Y4 F100\nG0 Z5";
+        let lines: ::std::vec::Vec<_> = Parser::new(tokens!(src)).map(|l| l.unwrap()).collect();
+
+        let should_be = vec![Line::Cmd(Command {
+                                           kind: CommandKind::G,
+                                           number: Number::Integer(1),
+                                           args: Args {
+                                               x: Some(1.0),
+                                               y: Some(2.0),
+                                               ..Default::default()
+                                           },
+                                           line_number: None,
+                                           span: Span::from((0, 1)),
+                                       }),
+                             Line::Args {
+                                 args: Args {
+                                     x: Some(3.0),
+                                     ..Default::default()
+                                 },
+                                 line_number: None,
+                                 span: Span::from((1, 1)),
+                             },
+                             Line::Args {
+                                 args: Args {
+                                     y: Some(4.0),
+                                     f: Some(100.0),
+                                     ..Default::default()
+                                 },
+                                 line_number: Some(20),
+                                 span: Span::from((2, 1)),
+                             },
+                             Line::Cmd(Command {
+                                           kind: CommandKind::G,
+                                           number: Number::Integer(0),
+                                           args: Args {
+                                               z: Some(5.0),
+                                               ..Default::default()
+                                           },
+                                           line_number: None,
+                                           span: Span::from((3, 1)),
+                                       })];
+        assert_eq!(lines, should_be);
+    }
+
+    #[test]
+    fn marlin_t_words_can_be_arguments() {
+        let src = "M104 S200 T1\nT0 M6";
+        let lines: ::std::vec::Vec<_> = Parser::with_dialect(tokens!(src), Dialect::Marlin)
+            .map(|l| l.unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 3);
+        match lines[0] {
+            Line::Cmd(ref cmd) => {
+                assert_eq!(cmd.number, Number::Integer(104));
+                assert_eq!(cmd.args.s, Some(200.0));
+                assert_eq!(cmd.args.t, Some(1.0));
+            }
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+        match lines[1] {
+            Line::Cmd(ref cmd) => assert_eq!(cmd.kind, CommandKind::T),
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+
+        // everyone else treats it as a tool selection
+        let lines: ::std::vec::Vec<_> = Parser::new(tokens!(src)).map(|l| l.unwrap()).collect();
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn words_without_values_are_flags() {
+        let src = "G28 X Y\nG1 X-1 E\nZ";
+        let lines: ::std::vec::Vec<_> = Parser::with_dialect(tokens!(src), Dialect::Marlin)
+            .map(|l| l.unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 3);
+        match lines[0] {
+            Line::Cmd(ref cmd) => {
+                assert_eq!(cmd.args.flags.letters().collect::<::std::string::String>(), "XY");
+                assert_eq!(cmd.args.x, None);
+                assert!(cmd.args.has('x'));
+                assert!(!cmd.args.has('Z'));
+            }
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+        match lines[1] {
+            Line::Cmd(ref cmd) => {
+                assert_eq!(cmd.args.x, Some(-1.0));
+                assert_eq!(cmd.args.flags.letters().collect::<::std::string::String>(), "E");
+            }
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+        match lines[2] {
+            Line::Args { ref args, .. } => assert!(args.flags.contains('Z')),
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+
+        // a minus sign still needs a number
+        let mut parser = Parser::with_dialect(tokens!("G1 X- Y1"), Dialect::Marlin);
+        assert!(parser.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn words_need_values_outside_marlin() {
+        for &src in &["G1 X Y5", "G0 X\nG0 Y1"] {
+            let got = Parser::new(tokens!(src)).next().unwrap();
+            let should_be = Error::SyntaxError("All arguments must be followed by a number",
+                                               Span::from((0, 4)));
+
+            assert_eq!(got, Err(should_be));
+        }
+    }
+
+    #[test]
+    fn string_arguments() {
+        let src = "M117 Hello, World!\nM23 part.gco";
+        let tokens = ::lexer::Tokenizer::with_string_codes(src.chars(),
+                                                           Dialect::Marlin.string_codes());
+        let lines: ::std::vec::Vec<_> = Parser::new(tokens.map(|t| t.unwrap()))
+            .map(|l| l.unwrap())
+            .collect();
+
+        let texts: ::std::vec::Vec<_> = lines.iter()
+            .map(|line| match *line {
+                     Line::Cmd(ref cmd) => cmd.args.text.unwrap(),
+                     ref other => panic!("Unexpected line: {:?}", other),
+                 })
+            .collect();
+        assert_eq!(texts[0].as_str(), "Hello, World!");
+        assert_eq!(texts[1].as_str(), "part.gco");
+    }
+
+    #[test]
+    fn recover_from_syntax_errors() {
+        let src = "G1 X1\n5 G0 X2";
+        let lines: ::std::vec::Vec<_> = Parser::new(tokens!(src)).collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].is_err());
+        match lines[2] {
+            Ok(Line::Cmd(ref cmd)) => assert_eq!(cmd.args.x, Some(2.0)),
+            ref other => panic!("Unexpected line: {:?}", other),
+        }
+    }
+
     #[allow(trivial_casts)]
     mod qc {
         use super::*;
@@ -0,0 +1,672 @@
+//! A pretty-printer which formats programs in a consistent style.
+//!
+//! Every block is written on its own line with its words in a canonical
+//! order (`N`, `G`, the axes, `I`, `J`, `K`, everything else, then `F`, `S`,
+//! `T` and `M`), and numbers are written with a consistent number of decimal
+//! places. Comments are kept, with any in the middle of a block moved to the
+//! end of its line, and trailing comments on consecutive lines are lined up.
+//!
+//! Formatting is idempotent, so formatting an already formatted program
+//! gives back exactly the same text.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::pretty::{format, Style};
+//!
+//! let src = "%\nN10 f100 X1.50 g01 Y2 (cut)\nM30 ; done\n%";
+//!
+//! let mut style = Style::default();
+//! style.leading_zeros = true;
+//! let formatted = format(src, &style).unwrap();
+//!
+//! assert_eq!(formatted, "%\nN10 G01 X1.5 Y2 F100 (cut)\nM30                  ; done\n%\n");
+//! ```
+
+use core::fmt::Write;
+use std::vec::Vec;
+use std::string::String;
+
+use lexer::Tokenizer;
+use parser::{Parser, Line, CommandKind, Number, Args};
+use dialect::Dialect;
+use helpers::{Decimals, error_on_line};
+use writer::PRECISION;
+use errors::*;
+
+
+/// How a program should be formatted.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Style {
+    /// Pad `G` and `M` codes below 10 with a zero (e.g. `G01` instead of
+    /// `G1`).
+    pub leading_zeros: bool,
+    /// The most decimal places a number is written with.
+    pub decimal_places: usize,
+    /// Always write coordinates and feed rates with exactly
+    /// `decimal_places` decimal places (e.g. `X1.0000` instead of `X1`).
+    pub trailing_zeros: bool,
+    /// Line up the trailing comments on consecutive lines.
+    pub align_comments: bool,
+    /// The dialect the program is written in, so things like Marlin's
+    /// `M117 Hello World` are understood. Marlin commands are never
+    /// reordered, since their arguments have to come straight after them.
+    pub dialect: Dialect,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            leading_zeros: false,
+            decimal_places: PRECISION,
+            trailing_zeros: false,
+            align_comments: true,
+            dialect: Dialect::Rs274Ngc,
+        }
+    }
+}
+
+/// Format a program.
+///
+/// The first syntax error (if any) is returned with its location in the
+/// original source code.
+pub fn format(src: &str, style: &Style) -> Result<String> {
+    let mut formatted: Vec<Formatted> = Vec::new();
+    let mut in_comment = false;
+
+    for (line_number, text) in src.lines().enumerate() {
+        let scanned = scan(text, &mut in_comment);
+
+        let percent = scanned.code.trim() == "%";
+        let lines = if percent {
+            Vec::new()
+        } else {
+            parse(&scanned.code, style.dialect).map_err(|e| error_on_line(e, line_number))?
+        };
+        let blocks = blocks(&lines);
+
+        if percent {
+            let mut line = Formatted::new(String::from("%"));
+            if let Some(leading) = scanned.leading {
+                line.code.insert(0, ' ');
+                line.code.insert_str(0, leading);
+            }
+            line.comments.extend(scanned.comments.iter().map(|&(_, comment)| comment));
+
+            formatted.push(line);
+            continue;
+        }
+
+        if blocks.is_empty() {
+            let mut comments = Vec::new();
+            comments.extend(scanned.leading);
+            comments.extend(scanned.comments.iter().map(|&(_, comment)| comment));
+
+            let blank = comments.is_empty();
+            let after_blank = match formatted.last() {
+                Some(last) => last.is_blank(),
+                None => true,
+            };
+            if !blank || !after_blank {
+                formatted.push(Formatted {
+                                   code: String::new(),
+                                   comments,
+                                   padding: None,
+                               });
+            }
+            continue;
+        }
+
+        let first = formatted.len();
+        for block in &blocks {
+            formatted.push(Formatted::new(write_block(block, style)));
+        }
+
+        if let Some(leading) = scanned.leading {
+            formatted[first].code.insert(0, ' ');
+            formatted[first].code.insert_str(0, leading);
+        }
+
+        // comments belong to the block they appear in
+        for &(column, comment) in &scanned.comments {
+            let index = blocks.iter()
+                .rposition(|block| block.column <= column)
+                .unwrap_or(0);
+            formatted[first + index].comments.push(comment);
+        }
+    }
+
+    while matches!(formatted.last(), Some(last) if last.is_blank()) {
+        let _ = formatted.pop();
+    }
+
+    if style.align_comments {
+        align_comments(&mut formatted);
+    }
+
+    let mut written = String::new();
+    for line in &formatted {
+        written.push_str(&line.code);
+
+        if !line.comments.is_empty() {
+            if !line.code.is_empty() {
+                let padding = line.padding.unwrap_or(1);
+                written.extend((0..padding).map(|_| ' '));
+            }
+            written.push_str(&line.comments.join(" "));
+        }
+
+        written.push('\n');
+    }
+
+    Ok(written)
+}
+
+
+/// A line of output.
+#[derive(Debug)]
+struct Formatted<'a> {
+    code: String,
+    comments: Vec<&'a str>,
+    padding: Option<usize>,
+}
+
+impl<'a> Formatted<'a> {
+    fn new(code: String) -> Formatted<'a> {
+        Formatted {
+            code,
+            comments: Vec::new(),
+            padding: None,
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.code.is_empty() && self.comments.is_empty()
+    }
+}
+
+/// Pad the code on runs of consecutive lines with trailing comments so the
+/// comments all start in the same column.
+fn align_comments(formatted: &mut [Formatted]) {
+    let has_trailing_comment = |line: &Formatted| {
+        !line.code.is_empty() && !line.comments.is_empty()
+    };
+
+    let mut start = 0;
+    while start < formatted.len() {
+        if !has_trailing_comment(&formatted[start]) {
+            start += 1;
+            continue;
+        }
+
+        let end = formatted[start..]
+            .iter()
+            .position(|line| !has_trailing_comment(line))
+            .map_or(formatted.len(), |n| start + n);
+        let run = &mut formatted[start..end];
+
+        let widest = run.iter().map(|line| line.code.chars().count()).max().unwrap_or(0);
+        for line in run {
+            line.padding = Some(widest + 1 - line.code.chars().count());
+        }
+
+        start = end;
+    }
+}
+
+/// A source line with its comments pulled out.
+#[derive(Debug)]
+struct Scanned<'a> {
+    /// The end of a comment which started on a previous line.
+    leading: Option<&'a str>,
+    /// The code, with comments replaced by spaces so columns don't change.
+    code: String,
+    /// The comments, and the column each one starts at.
+    comments: Vec<(usize, &'a str)>,
+}
+
+/// Split a line into code and comments, following the same rules as the
+/// `Tokenizer`.
+fn scan<'a>(text: &'a str, in_comment: &mut bool) -> Scanned<'a> {
+    let mut scanned = Scanned {
+        leading: None,
+        code: String::new(),
+        comments: Vec::new(),
+    };
+    let mut chars = text.char_indices().peekable();
+
+    if *in_comment {
+        let end = match text.find(')') {
+            Some(end) => {
+                *in_comment = false;
+                end + 1
+            }
+            None => text.len(),
+        };
+
+        scanned.leading = Some(text[..end].trim_end());
+        while matches!(chars.peek(), Some(&(i, _)) if i < end) {
+            let _ = chars.next();
+            scanned.code.push(' ');
+        }
+    }
+
+    while let Some((i, c)) = chars.next() {
+        let end = match c {
+            ';' => text.len(),
+            '(' => {
+                match text[i..].find(')') {
+                    Some(end) => i + end + 1,
+                    None => {
+                        *in_comment = true;
+                        text.len()
+                    }
+                }
+            }
+            _ => {
+                scanned.code.push(c);
+                continue;
+            }
+        };
+
+        // the tokenizer's columns start at 1
+        let column = scanned.code.chars().count() + 1;
+        scanned.comments.push((column, text[i..end].trim_end()));
+
+        scanned.code.push(' ');
+        while matches!(chars.peek(), Some(&(j, _)) if j < end) {
+            let _ = chars.next();
+            scanned.code.push(' ');
+        }
+    }
+
+    scanned
+}
+
+fn parse(code: &str, dialect: Dialect) -> Result<Vec<Line>> {
+    let tokens = Tokenizer::with_string_codes(code.chars(), dialect.string_codes())
+        .collect::<Result<Vec<_>>>()?;
+    Parser::with_dialect(tokens.into_iter(), dialect).collect()
+}
+
+/// The lines making up a single block, starting at a particular column.
+#[derive(Debug)]
+struct Block {
+    lines: Vec<Line>,
+    column: usize,
+}
+
+/// Split a line into blocks, starting a new one at each program or line
+/// number.
+fn blocks(lines: &[Line]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+
+    for &line in lines {
+        let starts_block = match line {
+            Line::ProgramNumber(_) => true,
+            Line::Cmd(ref cmd) => cmd.line_number.is_some(),
+            Line::Args { line_number, .. } => line_number.is_some(),
+        };
+        let after_program_number = match blocks.last() {
+            Some(b) => matches!(b.lines[0], Line::ProgramNumber(_)),
+            None => false,
+        };
+
+        if starts_block || after_program_number || blocks.is_empty() {
+            let column = match line.span() {
+                Some(span) => span.column,
+                None => blocks.last().map_or(0, |b| b.column),
+            };
+            blocks.push(Block {
+                            lines: Vec::new(),
+                            column,
+                        });
+        }
+
+        blocks.last_mut().unwrap().lines.push(line);
+    }
+
+    blocks
+}
+
+/// All the words in a block, ready to be written in the canonical order.
+#[derive(Debug, Default)]
+struct Words {
+    line_number: Option<u32>,
+    g: Vec<Number>,
+    args: Args,
+    tool: Option<Number>,
+    m: Vec<Number>,
+}
+
+impl Words {
+    /// Gather up a block's words, giving up if two commands in the same
+    /// block set the same argument.
+    fn gather(block: &Block) -> Option<Words> {
+        let mut words = Words::default();
+
+        for &line in &block.lines {
+            match line {
+                Line::ProgramNumber(_) => return None,
+                Line::Args { args, line_number, .. } => {
+                    words.line_number = words.line_number.or(line_number);
+                    if !merge(&mut words.args, args) {
+                        return None;
+                    }
+                }
+                Line::Cmd(cmd) => {
+                    words.line_number = words.line_number.or(cmd.line_number);
+                    match cmd.kind {
+                        CommandKind::G => words.g.push(cmd.number),
+                        CommandKind::M => words.m.push(cmd.number),
+                        CommandKind::T if words.tool.is_none() => words.tool = Some(cmd.number),
+                        CommandKind::T => return None,
+                    }
+                    if !merge(&mut words.args, cmd.args) {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        if words.tool.is_some() && words.args.t.is_some() {
+            return None;
+        }
+        // a string argument has to stay right after its code
+        if words.args.text.is_some() {
+            return None;
+        }
+
+        Some(words)
+    }
+}
+
+fn merge(dest: &mut Args, src: Args) -> bool {
+    macro_rules! merge {
+        ($($field:ident => $letter:expr),*) => {
+            $(
+                if src.$field.is_some() {
+                    if dest.has($letter) {
+                        return false;
+                    }
+                    dest.$field = src.$field;
+                }
+            )*
+        }
+    }
+
+    merge!(x => 'X', y => 'Y', z => 'Z', s => 'S', t => 'T', f => 'F', i => 'I', j => 'J',
+           h => 'H', p => 'P', r => 'R', e => 'E', k => 'K', l => 'L', q => 'Q', d => 'D');
+
+    for letter in src.flags.letters() {
+        if dest.has(letter) {
+            return false;
+        }
+        dest.flags.insert(letter);
+    }
+
+    if src.text.is_some() {
+        if dest.text.is_some() {
+            return false;
+        }
+        dest.text = src.text;
+    }
+
+    true
+}
+
+fn write_block(block: &Block, style: &Style) -> String {
+    let mut w = Writer::new(style);
+
+    // Marlin's arguments belong to the command in front of them, so the
+    // commands can't be moved around
+    let words = if style.dialect == Dialect::Marlin {
+        None
+    } else {
+        Words::gather(block)
+    };
+
+    match words {
+        Some(words) => {
+            if let Some(n) = words.line_number {
+                w.word(&format!("N{}", n));
+            }
+            for &number in &words.g {
+                w.code('G', number);
+            }
+            w.args(&words.args);
+            if let Some(tool) = words.tool {
+                w.code('T', tool);
+            }
+            for &number in &words.m {
+                w.code('M', number);
+            }
+        }
+        None => {
+            // the block can't be reordered without changing what it means,
+            // so just write each part in the order it was given
+            for line in &block.lines {
+                match *line {
+                    Line::ProgramNumber(n) => w.word(&format!("O{}", n)),
+                    Line::Cmd(ref cmd) => {
+                        if let Some(n) = cmd.line_number {
+                            w.word(&format!("N{}", n));
+                        }
+                        w.code(letter(cmd.kind), cmd.number);
+                        w.args(&cmd.args);
+                    }
+                    Line::Args { ref args, line_number, .. } => {
+                        if let Some(n) = line_number {
+                            w.word(&format!("N{}", n));
+                        }
+                        w.args(args);
+                    }
+                }
+            }
+        }
+    }
+
+    w.written
+}
+
+fn letter(kind: CommandKind) -> char {
+    match kind {
+        CommandKind::G => 'G',
+        CommandKind::M => 'M',
+        CommandKind::T => 'T',
+    }
+}
+
+/// Writes words separated by spaces.
+struct Writer<'a> {
+    style: &'a Style,
+    written: String,
+}
+
+impl<'a> Writer<'a> {
+    fn new(style: &'a Style) -> Writer<'a> {
+        Writer {
+            style,
+            written: String::new(),
+        }
+    }
+
+    fn word(&mut self, word: &str) {
+        if !self.written.is_empty() {
+            self.written.push(' ');
+        }
+        self.written.push_str(word);
+    }
+
+    fn code(&mut self, letter: char, number: Number) {
+        let pad = self.style.leading_zeros && letter != 'T';
+
+        let word = match number {
+            Number::Integer(n) if pad => format!("{}{:02}", letter, n),
+            Number::Integer(n) => format!("{}{}", letter, n),
+            Number::Decimal(n, fraction) if pad => format!("{}{:02}.{}", letter, n, fraction),
+            Number::Decimal(n, fraction) => format!("{}{}.{}", letter, n, fraction),
+        };
+        self.word(&word);
+    }
+
+    fn args(&mut self, args: &Args) {
+        // (letter, value, is it a measurement)
+        let words = [('X', args.x, true),
+                     ('Y', args.y, true),
+                     ('Z', args.z, true),
+                     ('E', args.e, true),
+                     ('I', args.i, true),
+                     ('J', args.j, true),
+                     ('K', args.k, true),
+                     ('R', args.r, true),
+                     ('Q', args.q, true),
+                     ('L', args.l, false),
+                     ('P', args.p, false),
+                     ('D', args.d, false),
+                     ('H', args.h, false),
+                     ('F', args.f, true),
+                     ('S', args.s, false),
+                     ('T', args.t, false)];
+
+        for &(letter, value, measurement) in &words {
+            if value.is_none() && args.flags.contains(letter) {
+                self.word(letter.encode_utf8(&mut [0; 4]));
+            }
+
+            if let Some(value) = value {
+                let mut word = String::new();
+                let places = self.style.decimal_places;
+
+                if measurement && self.style.trailing_zeros {
+                    let _ = write!(word, "{}{:.*}", letter, places, value);
+                    if word[1..].starts_with('-') && word[2..].trim_matches(['0', '.']).is_empty() {
+                        // don't write negative zero
+                        let _ = word.remove(1);
+                    }
+                } else {
+                    let _ = write!(word, "{}{}", letter, Decimals(value, places));
+                }
+
+                self.word(&word);
+            }
+        }
+
+        if let Some(text) = args.text {
+            self.word(text.as_str());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_default(src: &str) -> String {
+        format(src, &Style::default()).unwrap()
+    }
+
+    #[test]
+    fn words_are_written_in_canonical_order() {
+        let src = "M3 S1000 G1 X1 F100 T2 N10\nf200 J1 x1 I2 G02 Y3";
+
+        assert_eq!(format_default(src), "G1 X1 F100 S1000 T2 M3\nN10\nG2 X1 Y3 I2 J1 F200\n");
+    }
+
+    #[test]
+    fn flags_and_strings_are_kept() {
+        let style = Style {
+            dialect: Dialect::Marlin,
+            ..Style::default()
+        };
+        let src = "g28 y x\ng28 z\nm117   Hello world ; greeting\nM104 T1 S200";
+
+        assert_eq!(format(src, &style).unwrap(),
+                   "G28 X Y\nG28 Z\nM117 Hello world ; greeting\nM104 S200 T1\n");
+    }
+
+    #[test]
+    fn one_block_per_line() {
+        let src = "O100 N10 G0 X1 (first) N20 G1 Y2 (second)";
+        let should_be = "O100\nN10 G0 X1 (first)\nN20 G1 Y2 (second)\n";
+
+        assert_eq!(format_default(src), should_be);
+    }
+
+    #[test]
+    fn comments_are_kept_and_aligned() {
+        let src = "; header\n\n\n(setup)\nG90 G21 ; absolute mm\nG0 X10 Y10 (rapid) (over)\n\
+                   G1 (plunge) Z-1\nM30";
+        let should_be = "; header\n\n(setup)\nG90 G21    ; absolute mm\n\
+                         G0 X10 Y10 (rapid) (over)\nG1 Z-1     (plunge)\nM30\n";
+
+        assert_eq!(format_default(src), should_be);
+    }
+
+    #[test]
+    fn comments_on_percent_lines_are_kept() {
+        let src = "%   (program start)\nG0 X1\n(a comment\nwhich ends) % ; done";
+        let should_be = "% (program start)\nG0 X1\n(a comment\nwhich ends) % ; done\n";
+
+        assert_eq!(format_default(src), should_be);
+    }
+
+    #[test]
+    fn comments_can_span_lines() {
+        let src = "G0 X1 (a comment\nwhich continues) Y2";
+
+        assert_eq!(format_default(src), "G0 X1 (a comment\nwhich continues) Y2\n");
+    }
+
+    #[test]
+    fn style_options() {
+        let style = Style {
+            leading_zeros: true,
+            decimal_places: 3,
+            trailing_zeros: true,
+            align_comments: false,
+            dialect: Dialect::Rs274Ngc,
+        };
+        let src = "G1 X1 Y-0.00001 Z2.12345 F100 (cut)\nG4 P0.5\nG10 L2 P1 X0 (offset)\nM3 S12000";
+        let should_be = "G01 X1.000 Y0.000 Z2.123 F100.000 (cut)\nG04 P0.5\n\
+                         G10 X0.000 L2 P1 (offset)\nS12000 M03\n";
+
+        assert_eq!(format(src, &style).unwrap(), should_be);
+    }
+
+    #[test]
+    fn conflicting_words_are_left_in_order() {
+        let src = "G10 L20 P1 X0 G0 X5";
+
+        assert_eq!(format_default(src), "G10 X0 L20 P1 G0 X5\n");
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let src = "%\nO0001 (part)\nN10 g21 g90 g17 (mm)\nN20 G00 X0.000 Y0.000 Z5.000\n\n\n\
+                   N30 M03 S12000 (spindle on\nat full speed)\nN40 G01 Z-1.0 F250.0\n\
+                   N50 G02 X10.0 Y0 I5.0 J0 ; arc\nN60 G00 Z5.\nN70 M05 M30\n%";
+
+        for &leading_zeros in &[true, false] {
+            let style = Style {
+                leading_zeros,
+                ..Style::default()
+            };
+            let once = format(src, &style).unwrap();
+            let twice = format(&once, &style).unwrap();
+
+            assert_eq!(once, twice);
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_original_line() {
+        let got = format("G0 X1\n(comment) G1 X$", &Style::default()).unwrap_err();
+
+        match got {
+            Error::UnknownToken('$', span) => assert_eq!(span.line, 1),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Receiving gcode a byte at a time, for firmware.
+//!
+//! The `Tokenizer` and `Parser` need to be given the whole program up
+//! front, which doesn't work when it's arriving over a UART. A `Receiver`
+//! collects bytes into a buffer you give it (so it never allocates) and
+//! parses each line as soon as its newline arrives.
+//!
+//! If a line doesn't fit in the buffer you get a `ReceiveError::Overflow`,
+//! and the rest of that line is skipped so the next one starts cleanly.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::receiver::{Receiver, ReceiveError};
+//! use gcode::parser::Line;
+//!
+//! let mut buffer = [0; 32];
+//! let mut receiver = Receiver::new(&mut buffer);
+//!
+//! // bytes turn up in whatever chunks the DMA gives us
+//! let mut chunk: &[u8] = b"G0 X1";
+//! assert!(receiver.feed_bytes(&mut chunk).unwrap().is_none());
+//!
+//! let mut chunk: &[u8] = b"0 Y2\nG1";
+//! {
+//!     let mut commands = receiver.feed_bytes(&mut chunk).unwrap().unwrap();
+//!     match commands.next() {
+//!         Some(Ok(Line::Cmd(cmd))) => assert_eq!(cmd.args.x, Some(10.0)),
+//!         other => panic!("Unexpected line: {:?}", other),
+//!     }
+//! }
+//! // the rest of the chunk is still waiting to be fed in
+//! assert_eq!(chunk, b"G1");
+//!
+//! let mut chunk: &[u8] = b"G1 X1 Y2 Z3 ; this comment is too long to fit\n";
+//! assert_eq!(receiver.feed_bytes(&mut chunk).err(),
+//!            Some(ReceiveError::Overflow { line: 1, capacity: 32 }));
+//! ```
+
+use core::str;
+use core::iter::FilterMap;
+
+use lexer::{Tokenizer, Token};
+use parser::{Parser, Line};
+use dialect::Dialect;
+use errors::*;
+use helpers::{move_to_line, error_on_line};
+
+
+/// Something went wrong while receiving a line.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReceiveError {
+    /// The line was longer than the buffer. The rest of it will be skipped.
+    Overflow {
+        /// The line which overflowed (counting from zero).
+        line: usize,
+        /// How big the buffer is.
+        capacity: usize,
+    },
+    /// The line wasn't valid UTF-8.
+    InvalidUtf8 {
+        /// The line which was invalid (counting from zero).
+        line: usize,
+    },
+    /// The line contained something the `Tokenizer` didn't understand.
+    Parse(Error),
+}
+
+impl From<Error> for ReceiveError {
+    fn from(other: Error) -> ReceiveError {
+        ReceiveError::Parse(other)
+    }
+}
+
+
+/// Collects bytes into lines and parses them.
+#[derive(Debug)]
+pub struct Receiver<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    /// The line currently being received.
+    line: usize,
+    dialect: Dialect,
+    /// Was the line in the buffer handed out last time?
+    finished: bool,
+    /// Are we skipping the rest of a line which overflowed?
+    skipping: bool,
+}
+
+impl<'a> Receiver<'a> {
+    /// Create a `Receiver` which stores incoming lines in a buffer. The
+    /// buffer's size is the longest line which can be received.
+    pub fn new(buffer: &'a mut [u8]) -> Receiver<'a> {
+        Receiver::with_dialect(buffer, Dialect::Rs274Ngc)
+    }
+
+    /// Create a `Receiver` for a particular dialect of gcode.
+    pub fn with_dialect(buffer: &'a mut [u8], dialect: Dialect) -> Receiver<'a> {
+        Receiver {
+            buffer,
+            length: 0,
+            line: 0,
+            dialect,
+            finished: false,
+            skipping: false,
+        }
+    }
+
+    /// The longest line which can be received.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The bytes received so far on the current line.
+    pub fn pending(&self) -> &[u8] {
+        if self.finished {
+            &[]
+        } else {
+            &self.buffer[..self.length]
+        }
+    }
+
+    /// Throw away anything received on the current line.
+    pub fn clear(&mut self) {
+        self.length = 0;
+        self.finished = false;
+        self.skipping = false;
+    }
+
+    /// Add a single byte, getting back the line's commands if it was a
+    /// newline.
+    pub fn feed(&mut self, byte: u8) -> ::core::result::Result<Option<Commands<'_>>, ReceiveError> {
+        if self.accept(byte)? {
+            self.finish_line().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Add bytes until a line is finished, leaving the ones after the
+    /// newline in `bytes` to be fed in once that line has been dealt with.
+    pub fn feed_bytes(&mut self,
+                      bytes: &mut &[u8])
+                      -> ::core::result::Result<Option<Commands<'_>>, ReceiveError> {
+        while let Some((&byte, rest)) = bytes.split_first() {
+            *bytes = rest;
+
+            if self.accept(byte)? {
+                return self.finish_line().map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Add a byte to the buffer, returning whether it ended a line.
+    fn accept(&mut self, byte: u8) -> ::core::result::Result<bool, ReceiveError> {
+        if self.finished {
+            self.length = 0;
+            self.line += 1;
+            self.finished = false;
+        }
+
+        match byte {
+            b'\n' if self.skipping => {
+                self.skipping = false;
+                self.finished = true;
+                Ok(false)
+            }
+            b'\n' => {
+                self.finished = true;
+                Ok(true)
+            }
+            b'\r' => Ok(false),
+            _ if self.skipping => Ok(false),
+            _ if self.length == self.buffer.len() => {
+                self.skipping = true;
+                Err(ReceiveError::Overflow {
+                        line: self.line,
+                        capacity: self.buffer.len(),
+                    })
+            }
+            _ => {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+                Ok(false)
+            }
+        }
+    }
+
+    fn finish_line(&self) -> ::core::result::Result<Commands<'_>, ReceiveError> {
+        let line = self.line;
+        let src = str::from_utf8(&self.buffer[..self.length])
+            .map_err(|_| ReceiveError::InvalidUtf8 { line })?;
+        let string_codes = self.dialect.string_codes();
+
+        let mut tokens = Tokenizer::with_string_codes(src.chars(), string_codes);
+        if let Some(Err(e)) = tokens.find(|t| t.is_err()) {
+            return Err(error_on_line(e, line).into());
+        }
+
+        let ok: fn(Result<Token>) -> Option<Token> = Result::ok;
+        let tokens = Tokenizer::with_string_codes(src.chars(), string_codes).filter_map(ok);
+
+        Ok(Commands {
+               line,
+               parser: Parser::with_dialect(tokens, self.dialect),
+           })
+    }
+}
+
+
+type Tokens<'a> = FilterMap<Tokenizer<str::Chars<'a>>, fn(Result<Token>) -> Option<Token>>;
+
+/// The commands on a line which has just been received.
+#[derive(Debug)]
+pub struct Commands<'a> {
+    line: usize,
+    parser: Parser<Tokens<'a>>,
+}
+
+impl<'a> Commands<'a> {
+    /// Which line these commands are from (counting from zero).
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl<'a> Iterator for Commands<'a> {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.line;
+
+        match self.parser.next()? {
+            Ok(mut cmd) => {
+                move_to_line(&mut cmd, line);
+                Some(Ok(cmd))
+            }
+            Err(e) => Some(Err(error_on_line(e, line))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Span;
+    use parser::Number;
+
+    #[test]
+    fn lines_come_out_when_their_newline_arrives() {
+        let mut buffer = [0; 64];
+        let mut receiver = Receiver::new(&mut buffer);
+
+        for &byte in b"G90\r\nG0 X1".iter() {
+            let _ = receiver.feed(byte).unwrap();
+        }
+        assert_eq!(receiver.pending(), b"G0 X1");
+
+        let mut commands = receiver.feed(b'\n').unwrap().unwrap();
+        assert_eq!(commands.line(), 1);
+        match commands.next() {
+            Some(Ok(Line::Cmd(cmd))) => {
+                assert_eq!(cmd.number, Number::Integer(0));
+                assert_eq!(cmd.span, Span::from((1, 1)));
+            }
+            other => panic!("Unexpected line: {:?}", other),
+        }
+        assert!(commands.next().is_none());
+    }
+
+    #[test]
+    fn several_commands_on_one_line() {
+        let mut buffer = [0; 64];
+        let mut receiver = Receiver::new(&mut buffer);
+        let mut src: &[u8] = b"G21 G90 G1 X5 F100\n";
+
+        let commands = receiver.feed_bytes(&mut src).unwrap().unwrap();
+
+        assert_eq!(commands.count(), 3);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn overflowing_lines_are_skipped() {
+        let mut buffer = [0; 8];
+        let mut receiver = Receiver::new(&mut buffer);
+        let mut src: &[u8] = b"G1 X100 Y200\nG0 X1\n";
+
+        assert_eq!(receiver.feed_bytes(&mut src).err(),
+                   Some(ReceiveError::Overflow {
+                            line: 0,
+                            capacity: 8,
+                        }));
+        assert_eq!(src, b"200\nG0 X1\n");
+
+        let commands = receiver.feed_bytes(&mut src).unwrap().unwrap();
+        assert_eq!(commands.line(), 1);
+        assert_eq!(commands.count(), 1);
+    }
+
+    #[test]
+    fn errors_say_which_line_they_are_on() {
+        let mut buffer = [0; 16];
+        let mut receiver = Receiver::new(&mut buffer);
+        let mut src: &[u8] = b"G0\nG1 X&\n\xFF\n";
+
+        assert!(receiver.feed_bytes(&mut src).unwrap().is_some());
+        assert_eq!(receiver.feed_bytes(&mut src).err(),
+                   Some(ReceiveError::Parse(Error::UnknownToken('&', Span::from((1, 5))))));
+        assert_eq!(receiver.feed_bytes(&mut src).err(),
+                   Some(ReceiveError::InvalidUtf8 { line: 2 }));
+    }
+
+    #[test]
+    fn string_arguments_in_marlin() {
+        let mut buffer = [0; 32];
+        let mut receiver = Receiver::with_dialect(&mut buffer, Dialect::Marlin);
+        let mut src: &[u8] = b"M117 Hello, World!\n";
+
+        let mut commands = receiver.feed_bytes(&mut src).unwrap().unwrap();
+
+        match commands.next() {
+            Some(Ok(Line::Cmd(cmd))) => assert_eq!(cmd.args.text.unwrap().as_str(), "Hello, World!"),
+            other => panic!("Unexpected line: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! A 2.5D material removal simulation.
+//!
+//! The stock is represented as a grid of heights (a heightmap) when looked
+//! at from above, and the tool's shape is swept along each move, cutting
+//! every cell it passes over down to the bottom of the tool. This can't
+//! represent undercuts, but is plenty for checking engraving and pocketing
+//! depths.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Point;
+//! use gcode::stats::BoundingBox;
+//! use gcode::simulation::{Cutter, Heightmap, Simulator};
+//!
+//! let mut stock = BoundingBox::new(Point::new(0.0, 0.0, -5.0));
+//! stock.add_point(Point::new(20.0, 10.0, 0.0));
+//! let heightmap = Heightmap::from_stock(&stock, 0.25);
+//! let mut sim = Simulator::new(heightmap, Cutter::Flat { diameter: 2.0 });
+//!
+//! let src = "G0 X5 Y5 Z1\nG1 Z-1 F100\nX15\nG0 Z1";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let engagements = sim.run(lines).unwrap();
+//!
+//! assert_eq!(sim.heightmap().height_at(10.0, 5.0), Some(-1.0));
+//! assert_eq!(sim.heightmap().height_at(10.0, 8.0), Some(0.0));
+//! assert!(engagements[2].removed_volume > 0.0);
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::vec::Vec;
+use core::f32::consts::PI;
+use core::fmt::{self, Write};
+
+use lexer::Span;
+use parser::Line;
+use interpreter::{Event, Interpreter, Move, Point, State, Profile};
+use stats::BoundingBox;
+use errors::*;
+use helpers::{sqrt, tan};
+
+
+/// The shape of the end of a tool.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cutter {
+    /// A flat end mill.
+    Flat {
+        /// The tool's diameter.
+        diameter: f32,
+    },
+    /// A ball end mill.
+    Ball {
+        /// The tool's diameter.
+        diameter: f32,
+    },
+    /// A V-bit (or engraving) tool with a pointed tip.
+    VBit {
+        /// The angle between the two cutting edges, in degrees.
+        angle: f32,
+        /// The widest the tool gets.
+        diameter: f32,
+    },
+}
+
+impl Cutter {
+    /// The widest the tool gets.
+    pub fn diameter(&self) -> f32 {
+        match *self {
+            Cutter::Flat { diameter } |
+            Cutter::Ball { diameter } |
+            Cutter::VBit { diameter, .. } => diameter,
+        }
+    }
+
+    /// How far above the tip the tool's surface is at a certain distance
+    /// from its centre, or `None` if the tool isn't that wide.
+    pub fn height_above_tip(&self, distance: f32) -> Option<f32> {
+        let radius = self.diameter() / 2.0;
+        if distance > radius {
+            return None;
+        }
+
+        match *self {
+            Cutter::Flat { .. } => Some(0.0),
+            Cutter::Ball { .. } => Some(radius - sqrt(radius * radius - distance * distance)),
+            Cutter::VBit { angle, .. } => {
+                let half_angle = angle / 2.0 * PI / 180.0;
+                Some(distance / tan(half_angle))
+            }
+        }
+    }
+}
+
+
+/// The height of the stock's top surface over a grid of points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    /// The grid's bottom-left corner (Z is ignored).
+    pub origin: Point,
+    /// The width and length of each cell.
+    pub cell_size: f32,
+    columns: usize,
+    rows: usize,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    /// Create a flat heightmap with a number of `columns` (along X) and
+    /// `rows` (along Y).
+    pub fn new(origin: Point, cell_size: f32, columns: usize, rows: usize, top: f32) -> Heightmap {
+        Heightmap {
+            origin,
+            cell_size,
+            columns,
+            rows,
+            heights: vec![top; columns * rows],
+        }
+    }
+
+    /// Create a heightmap covering a block of stock, with the top of the
+    /// stock at its maximum Z.
+    pub fn from_stock(stock: &BoundingBox, cell_size: f32) -> Heightmap {
+        let size = stock.size();
+        let columns = (size.x / cell_size).round().max(1.0) as usize;
+        let rows = (size.y / cell_size).round().max(1.0) as usize;
+        Heightmap::new(stock.min, cell_size, columns, rows, stock.max.z)
+    }
+
+    /// The number of cells along X.
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// The number of cells along Y.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The height of a particular cell.
+    pub fn get(&self, column: usize, row: usize) -> Option<f32> {
+        if column < self.columns && row < self.rows {
+            Some(self.heights[row * self.columns + column])
+        } else {
+            None
+        }
+    }
+
+    /// The height of the cell containing a point, if it's on the grid.
+    pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+        let column = (x - self.origin.x) / self.cell_size;
+        let row = (y - self.origin.y) / self.cell_size;
+
+        if column < 0.0 || row < 0.0 {
+            return None;
+        }
+        self.get(column as usize, row as usize)
+    }
+
+    /// The centre of a cell, ignoring its height.
+    fn centre(&self, column: usize, row: usize) -> (f32, f32) {
+        (self.origin.x + (column as f32 + 0.5) * self.cell_size,
+         self.origin.y + (row as f32 + 0.5) * self.cell_size)
+    }
+
+    /// The volume between the surface and `bottom`.
+    pub fn volume_above(&self, bottom: f32) -> f32 {
+        // add up in double precision so large grids don't lose the details
+        let total: f64 = self.heights.iter().map(|&h| f64::from((h - bottom).max(0.0))).sum();
+        (total * f64::from(self.cell_size * self.cell_size)) as f32
+    }
+
+    /// Write the heightmap out as plain text.
+    ///
+    /// The first line contains the number of columns, the number of rows and
+    /// the cell size, then each following line is a row of heights with
+    /// three decimal places. Rows are written from the largest Y to the
+    /// smallest so the grid reads the same way as the part looks from above.
+    pub fn write_grid<W: Write>(&self, w: &mut W) -> fmt::Result {
+        writeln!(w, "{} {} {}", self.columns, self.rows, self.cell_size)?;
+
+        for row in self.heights.chunks(self.columns).rev() {
+            for (i, height) in row.iter().enumerate() {
+                if i > 0 {
+                    w.write_char(' ')?;
+                }
+                write!(w, "{:.3}", height)?;
+            }
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Lower every cell under a tool whose tip is at `(x, y, z)`, returning
+    /// the volume removed. The original height of each cell which gets cut
+    /// is saved in `surface`, unless it's already there.
+    fn plunge(&mut self,
+              cutter: &Cutter,
+              x: f32,
+              y: f32,
+              z: f32,
+              surface: &mut HashMap<usize, f32>)
+              -> f32 {
+        let radius = cutter.diameter() / 2.0;
+        let first_column = ((x - radius - self.origin.x) / self.cell_size).floor().max(0.0);
+        let last_column = ((x + radius - self.origin.x) / self.cell_size).ceil();
+        let first_row = ((y - radius - self.origin.y) / self.cell_size).floor().max(0.0);
+        let last_row = ((y + radius - self.origin.y) / self.cell_size).ceil();
+
+        let last_column = (last_column as usize).min(self.columns);
+        let last_row = (last_row as usize).min(self.rows);
+        let area = self.cell_size * self.cell_size;
+
+        let mut removed = 0.0;
+
+        for row in first_row as usize..last_row {
+            for column in first_column as usize..last_column {
+                let (centre_x, centre_y) = self.centre(column, row);
+                let (dx, dy) = (centre_x - x, centre_y - y);
+
+                if let Some(above) = cutter.height_above_tip(sqrt(dx * dx + dy * dy)) {
+                    let bottom = z + above;
+                    let index = row * self.columns + column;
+                    let height = &mut self.heights[index];
+
+                    if bottom < *height {
+                        surface.entry(index).or_insert(*height);
+                        removed += (*height - bottom) * area;
+                        *height = bottom;
+                    }
+                }
+            }
+        }
+
+        removed
+    }
+}
+
+
+/// How much material a move cut.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Engagement {
+    /// The volume of material removed, in mm³.
+    pub removed_volume: f32,
+    /// The deepest the move cut below the surface, in mm.
+    pub max_depth: f32,
+    /// The start of the block containing the move.
+    pub span: Span,
+}
+
+
+/// Sweeps tools along a toolpath, cutting away a `Heightmap`.
+///
+/// Moves are in the same coordinates as the `Interpreter`'s, which include
+/// any tool length offset (`G43`). That offset is taken back off to find
+/// where the tool's tip is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulator {
+    heightmap: Heightmap,
+    /// The shape of each tool.
+    pub tools: BTreeMap<u32, Cutter>,
+    /// The shape used for tools which aren't in `tools`.
+    pub default_tool: Cutter,
+    /// The largest distance between the points sampled along a move. By
+    /// default this is half a cell.
+    pub resolution: f32,
+}
+
+impl Simulator {
+    /// Create a new `Simulator` where every tool has the same shape.
+    pub fn new(heightmap: Heightmap, default_tool: Cutter) -> Simulator {
+        let resolution = heightmap.cell_size / 2.0;
+
+        Simulator {
+            heightmap,
+            tools: BTreeMap::new(),
+            default_tool,
+            resolution,
+        }
+    }
+
+    /// The stock, as it has been cut so far.
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    /// Take the `Heightmap` back out of the simulator.
+    pub fn into_heightmap(self) -> Heightmap {
+        self.heightmap
+    }
+
+    /// Run a program for a milling machine through the simulator, returning
+    /// how much each move cut.
+    pub fn run<I>(&mut self, lines: I) -> Result<Vec<Engagement>>
+        where I: IntoIterator<Item = Line>
+    {
+        self.run_with_state(lines, State::new(Profile::Mill))
+    }
+
+    /// Run a program through the simulator, starting the `Interpreter` with
+    /// a particular state.
+    pub fn run_with_state<I>(&mut self, lines: I, state: State) -> Result<Vec<Engagement>>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut engagements = Vec::new();
+
+        for event in Interpreter::with_state(lines.into_iter(), state) {
+            if let Event::Move(ref m) = event? {
+                engagements.push(self.cut(m));
+            }
+        }
+
+        Ok(engagements)
+    }
+
+    /// Sweep the tool along a single move.
+    pub fn cut(&mut self, m: &Move) -> Engagement {
+        let cutter = *self.tools.get(&m.tool).unwrap_or(&self.default_tool);
+        let steps = (m.length() / self.resolution).ceil().max(1.0) as usize;
+        let mut surface = HashMap::new();
+
+        let mut engagement = Engagement {
+            removed_volume: 0.0,
+            max_depth: 0.0,
+            span: m.span,
+        };
+
+        for step in 0..=steps {
+            let p = m.point_at(step as f32 / steps as f32) - m.tool_offset;
+            engagement.removed_volume +=
+                self.heightmap.plunge(&cutter, p.x, p.y, p.z, &mut surface);
+        }
+
+        for (&index, &original) in &surface {
+            let depth = original - self.heightmap.heights[index];
+            engagement.max_depth = engagement.max_depth.max(depth);
+        }
+
+        engagement
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use helpers::parse;
+
+    fn stock() -> Heightmap {
+        let mut stock = BoundingBox::new(Point::new(0.0, 0.0, -10.0));
+        stock.add_point(Point::new(40.0, 20.0, 0.0));
+
+        Heightmap::from_stock(&stock, 0.1)
+    }
+
+    fn run(sim: &mut Simulator, src: &str) -> Vec<Engagement> {
+        let lines = parse(src);
+
+        sim.run(lines).unwrap()
+    }
+
+    fn assert_close(left: f32, right: f32, tolerance: f32) {
+        assert!((left - right).abs() < tolerance, "{} != {}", left, right);
+    }
+
+    #[test]
+    fn cutter_profiles() {
+        let flat = Cutter::Flat { diameter: 6.0 };
+        assert_eq!(flat.height_above_tip(2.9), Some(0.0));
+        assert_eq!(flat.height_above_tip(3.1), None);
+
+        let ball = Cutter::Ball { diameter: 6.0 };
+        assert_eq!(ball.height_above_tip(0.0), Some(0.0));
+        assert_eq!(ball.height_above_tip(3.0), Some(3.0));
+
+        let v = Cutter::VBit {
+            angle: 90.0,
+            diameter: 6.0,
+        };
+        assert_close(v.height_above_tip(2.0).unwrap(), 2.0, 1e-5);
+    }
+
+    #[test]
+    fn a_flat_slot() {
+        let mut sim = Simulator::new(stock(), Cutter::Flat { diameter: 4.0 });
+        let before = sim.heightmap().volume_above(-10.0);
+
+        let got = run(&mut sim, "G0 X10 Y10 Z5\nG1 Z-2 F100\nX30\nG0 Z5");
+
+        let removed: f32 = got.iter().map(|e| e.removed_volume).sum();
+        assert_close(removed, before - sim.heightmap().volume_above(-10.0), removed * 0.001);
+        // a 20mm long slot with round ends
+        let should_be = (20.0 * 4.0 + PI * 2.0 * 2.0) * 2.0;
+        assert_close(removed, should_be, should_be * 0.02);
+
+        assert_eq!(got[0].removed_volume, 0.0);
+        assert_close(got[1].max_depth, 2.0, 1e-5);
+        assert_eq!(got[3].removed_volume, 0.0);
+        assert_eq!(sim.heightmap().height_at(20.0, 10.0), Some(-2.0));
+        assert_eq!(sim.heightmap().height_at(20.0, 12.5), Some(0.0));
+    }
+
+    #[test]
+    fn tool_length_offsets_are_taken_off_the_tip() {
+        let mut sim = Simulator::new(stock(), Cutter::Flat { diameter: 4.0 });
+
+        let got = run(&mut sim, "G10 L1 P1 Z25\nT1 M6 G43\nG0 X10 Y10 Z5\nG1 Z-2 F100\nX30");
+
+        assert_close(got[1].max_depth, 2.0, 1e-5);
+        assert_eq!(sim.heightmap().height_at(20.0, 10.0), Some(-2.0));
+    }
+
+    #[test]
+    fn ball_end_mills_leave_a_rounded_groove() {
+        let mut sim = Simulator::new(stock(), Cutter::Ball { diameter: 6.0 });
+
+        run(&mut sim, "G0 X5 Y10 Z1\nG1 Z-3 F100\nX35");
+
+        let map = sim.heightmap();
+        assert_close(map.height_at(20.0, 10.05).unwrap(), -3.0, 0.01);
+        assert_close(map.height_at(20.0, 12.05).unwrap(), -3.0 + 3.0 - sqrt(5.0), 0.05);
+        assert_eq!(map.height_at(20.0, 13.5), Some(0.0));
+    }
+
+    #[test]
+    fn v_bits_engrave_wider_as_they_go_deeper() {
+        let v = Cutter::VBit {
+            angle: 60.0,
+            diameter: 10.0,
+        };
+        let mut sim = Simulator::new(stock(), v);
+        sim.tools.insert(2, Cutter::Flat { diameter: 1.0 });
+
+        run(&mut sim, "G0 X5 Y5 Z1\nG1 Z-1 F100\nX35\nG0 Z1\nT2 M6\nY15\nG1 Z-1\nX5");
+
+        // a 60° V-bit 1mm deep is 2 * tan(30°) mm wide
+        let half_width = tan(PI / 6.0);
+        let map = sim.heightmap();
+        assert_close(map.height_at(20.0, 5.05).unwrap(), -1.0, 0.2);
+        assert!(map.height_at(20.0, 5.0 + half_width - 0.1).unwrap() < 0.0);
+        assert_eq!(map.height_at(20.0, 5.0 + half_width + 0.1), Some(0.0));
+        // the flat tool's slot is 1mm wide and all the same depth
+        assert_eq!(map.height_at(20.0, 15.3), Some(-1.0));
+        assert_eq!(map.height_at(20.0, 15.7), Some(0.0));
+    }
+
+    #[test]
+    fn export_a_grid() {
+        let mut map = Heightmap::new(Point::default(), 1.0, 3, 2, 0.0);
+        map.plunge(&Cutter::Flat { diameter: 1.0 }, 0.5, 1.5, -1.25, &mut HashMap::new());
+
+        let mut got = String::new();
+        map.write_grid(&mut got).unwrap();
+
+        assert_eq!(got, "3 2 1\n-1.250 0.000 0.000\n0.000 0.000 0.000\n");
+    }
+}
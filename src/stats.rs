@@ -0,0 +1,449 @@
+//! Statistics about a program, for sanity checking it before it's run.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::stats::{Stats, Code};
+//! use gcode::parser::{CommandKind, Number};
+//!
+//! let src = "T1 M6\nG0 X10 Y10 Z5\nM3 S1000\nG1 Z-2 F100\nX20\nG0 Z5\nM30";
+//!
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let stats = Stats::from_lines(lines).unwrap();
+//!
+//! assert_eq!(stats.cutting_distance, 17.0);
+//! assert_eq!(stats.z_range.unwrap().min, -2.0);
+//! assert_eq!(stats.tool_changes, 1);
+//! assert_eq!(stats.codes[&Code::new(CommandKind::G, Number::Integer(0))], 2);
+//! ```
+
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, BTreeSet};
+use core::cmp::Ordering;
+use core::f32::consts::PI;
+use core::fmt::{self, Display, Formatter};
+
+use parser::{CommandKind, Number};
+#[cfg(feature = "std")]
+use parser::Line;
+use interpreter::{Move, MoveKind, Point, Direction};
+#[cfg(feature = "std")]
+use interpreter::{Event, Interpreter, State, Profile};
+#[cfg(feature = "std")]
+use errors::*;
+use helpers::atan2;
+
+
+/// An axis-aligned box.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingBox {
+    /// The corner with the smallest coordinates.
+    pub min: Point,
+    /// The corner with the largest coordinates.
+    pub max: Point,
+}
+
+impl BoundingBox {
+    /// A box containing a single point.
+    pub fn new(p: Point) -> BoundingBox {
+        BoundingBox { min: p, max: p }
+    }
+
+    /// The smallest box around a move, including the bulge of any arcs.
+    pub fn around(m: &Move) -> BoundingBox {
+        let mut bounds = BoundingBox::new(m.start);
+        bounds.add_move(m);
+        bounds
+    }
+
+    /// Grow the box to contain a point.
+    pub fn add_point(&mut self, p: Point) {
+        self.min = Point::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Point::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    /// Grow the box to contain everywhere a move goes.
+    pub fn add_move(&mut self, m: &Move) {
+        self.add_point(m.start);
+        self.add_point(m.end);
+
+        if let MoveKind::Arc { centre, plane, direction } = m.kind {
+            let sweep = m.sweep().unwrap_or(0.0);
+            let (start_a, start_b, start_normal) = plane.split(m.start - centre);
+            let (_, _, end_normal) = plane.split(m.end - centre);
+            let radius = Point::new(start_a, start_b, 0.0).length();
+            let start_angle = atan2(start_b, start_a);
+
+            // the furthest an arc goes along each axis is where it crosses
+            // one of the quadrant boundaries
+            let quadrants = [(radius, 0.0), (0.0, radius), (-radius, 0.0), (0.0, -radius)];
+            for (i, &(a, b)) in quadrants.iter().enumerate() {
+                let angle = i as f32 * PI / 2.0;
+                let mut travelled = match direction {
+                    Direction::CounterClockwise => angle - start_angle,
+                    Direction::Clockwise => start_angle - angle,
+                };
+                if travelled < 0.0 {
+                    travelled += 2.0 * PI;
+                }
+
+                if travelled <= sweep {
+                    let normal = start_normal + (end_normal - start_normal) * travelled / sweep;
+                    self.add_point(centre + plane.join(a, b, normal));
+                }
+            }
+        }
+    }
+
+    /// Grow the box to contain another box.
+    pub fn merge(&mut self, other: &BoundingBox) {
+        self.add_point(other.min);
+        self.add_point(other.max);
+    }
+
+    /// How big the box is along each axis.
+    pub fn size(&self) -> Point {
+        self.max - self.min
+    }
+
+    /// Is a point inside the box (or on its surface)?
+    pub fn contains(&self, p: Point) -> bool {
+        self.min.x <= p.x && p.x <= self.max.x && self.min.y <= p.y && p.y <= self.max.y &&
+        self.min.z <= p.z && p.z <= self.max.z
+    }
+}
+
+
+/// The smallest and largest of a set of values.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Extent {
+    /// The smallest value.
+    pub min: f32,
+    /// The largest value.
+    pub max: f32,
+}
+
+impl Extent {
+    /// An extent containing a single value.
+    pub fn new(value: f32) -> Extent {
+        Extent {
+            min: value,
+            max: value,
+        }
+    }
+
+    /// Grow the extent to contain a value.
+    pub fn add(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+
+/// A particular G, M or T code (e.g. `G41.1`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Code {
+    /// The kind of command.
+    pub kind: CommandKind,
+    /// The command's number.
+    pub number: Number,
+}
+
+impl Code {
+    /// Create a new `Code`.
+    pub fn new(kind: CommandKind, number: Number) -> Code {
+        Code { kind, number }
+    }
+
+    fn key(&self) -> (CommandKind, u32, Option<u32>) {
+        match self.number {
+            Number::Integer(n) => (self.kind, n, None),
+            Number::Decimal(n, fraction) => (self.kind, n, Some(fraction)),
+        }
+    }
+}
+
+impl PartialOrd for Code {
+    fn partial_cmp(&self, other: &Code) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Code {
+    /// Codes are sorted by kind, then numerically (so `G41.1` comes just
+    /// after `G41`).
+    fn cmp(&self, other: &Code) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.kind, self.number)
+    }
+}
+
+
+/// A summary of what a program does.
+///
+/// Positions are in machine coordinates, the same as the `Interpreter`'s
+/// `Event`s, and all lengths are in millimetres.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Everywhere the tool goes while feeding (`G1`, `G2`, `G3`, etc).
+    pub feed_bounds: Option<BoundingBox>,
+    /// Everywhere the tool goes during rapids (`G0`).
+    pub rapid_bounds: Option<BoundingBox>,
+    /// How far the tool travels while feeding.
+    pub cutting_distance: f32,
+    /// How far the tool travels during rapids.
+    pub rapid_distance: f32,
+    /// The slowest and fastest feed rates used while feeding, in mm/min.
+    pub feed_rate: Option<Extent>,
+    /// The slowest and fastest the spindle turns during a move, in RPM.
+    /// Moves made while the spindle is off are ignored.
+    pub spindle_speed: Option<Extent>,
+    /// The lowest and highest Z the tool reaches during any move.
+    pub z_range: Option<Extent>,
+    /// Every tool which was loaded or cut with.
+    pub tools: BTreeSet<u32>,
+    /// How many times the tool was changed (`M6`).
+    pub tool_changes: usize,
+    /// How many times each G, M and T code was used.
+    pub codes: BTreeMap<Code, usize>,
+}
+
+#[cfg(feature = "std")]
+impl Stats {
+    /// Create an empty `Stats`.
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Run a program through an `Interpreter` for a milling machine and
+    /// collect statistics about it.
+    pub fn from_lines<I>(lines: I) -> Result<Stats>
+        where I: IntoIterator<Item = Line>
+    {
+        Stats::with_state(lines, State::new(Profile::Mill))
+    }
+
+    /// Collect statistics about a program, starting the `Interpreter` with a
+    /// particular state (e.g. for a lathe, or with a tool table loaded).
+    pub fn with_state<I>(lines: I, state: State) -> Result<Stats>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut codes = BTreeMap::new();
+        let mut stats = Stats::new();
+
+        {
+            let lines = lines.into_iter().inspect(|line| count_codes(&mut codes, line));
+
+            for event in Interpreter::with_state(lines, state) {
+                stats.add_event(&event?);
+            }
+        }
+
+        stats.codes = codes;
+        Ok(stats)
+    }
+
+    /// Count the codes used on a line.
+    pub fn add_line(&mut self, line: &Line) {
+        count_codes(&mut self.codes, line);
+    }
+
+    /// Add something the machine did.
+    pub fn add_event(&mut self, event: &Event) {
+        match *event {
+            Event::Move(ref m) => self.add_move(m),
+            Event::ToolChange { tool, .. } => {
+                self.tool_changes += 1;
+                self.tools.insert(tool);
+            }
+            Event::Dwell { .. } | Event::ProgramEnd | Event::WaitForTemperature { .. } => {}
+        }
+    }
+
+    fn add_move(&mut self, m: &Move) {
+        let bounds = BoundingBox::around(m);
+
+        if m.kind == MoveKind::Rapid {
+            self.rapid_distance += m.length();
+            merge(&mut self.rapid_bounds, &bounds);
+        } else {
+            self.cutting_distance += m.length();
+            merge(&mut self.feed_bounds, &bounds);
+            include(&mut self.feed_rate, m.feed_rate);
+
+            if m.tool != 0 {
+                self.tools.insert(m.tool);
+            }
+        }
+
+        if m.spindle_speed > 0.0 && m.spindle_speed.is_finite() {
+            include(&mut self.spindle_speed, m.spindle_speed);
+        }
+
+        include(&mut self.z_range, bounds.min.z);
+        include(&mut self.z_range, bounds.max.z);
+    }
+}
+
+#[cfg(feature = "std")]
+fn merge(existing: &mut Option<BoundingBox>, bounds: &BoundingBox) {
+    match *existing {
+        Some(ref mut existing) => existing.merge(bounds),
+        None => *existing = Some(*bounds),
+    }
+}
+
+#[cfg(feature = "std")]
+fn include(existing: &mut Option<Extent>, value: f32) {
+    match *existing {
+        Some(ref mut existing) => existing.add(value),
+        None => *existing = Some(Extent::new(value)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn count_codes(codes: &mut BTreeMap<Code, usize>, line: &Line) {
+    if let Line::Cmd(ref cmd) = *line {
+        *codes.entry(Code::new(cmd.kind, cmd.number)).or_insert(0) += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use std::vec::Vec;
+    #[cfg(feature = "std")]
+    use std::string::ToString;
+    use interpreter::Plane;
+    #[cfg(feature = "std")]
+    use helpers::{parse, sqrt};
+
+    #[cfg(feature = "std")]
+    fn stats(src: &str) -> Stats {
+        let lines = parse(src);
+
+        Stats::from_lines(lines).unwrap()
+    }
+
+    fn arc(start: Point, end: Point, centre: Point, direction: Direction) -> Move {
+        Move {
+            kind: MoveKind::Arc {
+                centre,
+                direction,
+                plane: Plane::XY,
+            },
+            start,
+            end,
+            feed_rate: 100.0,
+            spindle_speed: 0.0,
+            tool: 0,
+            tool_offset: Point::default(),
+            extrusion: 0.0,
+            line_number: None,
+            span: Default::default(),
+        }
+    }
+
+    #[test]
+    fn arcs_bulge_past_their_end_points() {
+        // a semicircle over the top of the origin
+        let ccw = arc(Point::new(10.0, 0.0, 0.0),
+                      Point::new(-10.0, 0.0, 0.0),
+                      Point::default(),
+                      Direction::CounterClockwise);
+        let got = BoundingBox::around(&ccw);
+        assert_eq!(got.min, Point::new(-10.0, 0.0, 0.0));
+        assert_eq!(got.max, Point::new(10.0, 10.0, 0.0));
+
+        // the same end points going clockwise goes underneath instead
+        let cw = Move {
+            kind: MoveKind::Arc {
+                centre: Point::default(),
+                direction: Direction::Clockwise,
+                plane: Plane::XY,
+            },
+            ..ccw
+        };
+        let got = BoundingBox::around(&cw);
+        assert_eq!(got.min, Point::new(-10.0, -10.0, 0.0));
+        assert_eq!(got.max, Point::new(10.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn full_circles_cover_every_quadrant() {
+        let start = Point::new(5.0, 3.0, 0.0);
+        let circle = arc(start, start, Point::new(2.0, 3.0, 0.0), Direction::Clockwise);
+
+        let got = BoundingBox::around(&circle);
+
+        assert_eq!(got.min, Point::new(-1.0, 0.0, 0.0));
+        assert_eq!(got.max, Point::new(5.0, 6.0, 0.0));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn feeds_and_rapids_are_kept_separate() {
+        let src = "G0 X-10 Y-10 Z10\nG1 Z-3 F200\nX10 F400\nG0 Z10\nX50 Y50";
+
+        let got = stats(src);
+
+        let feeds = got.feed_bounds.unwrap();
+        assert_eq!(feeds.min, Point::new(-10.0, -10.0, -3.0));
+        assert_eq!(feeds.max, Point::new(10.0, -10.0, 10.0));
+        let rapids = got.rapid_bounds.unwrap();
+        assert_eq!(rapids.min, Point::new(-10.0, -10.0, -3.0));
+        assert_eq!(rapids.max, Point::new(50.0, 50.0, 10.0));
+
+        assert_eq!(got.cutting_distance, 13.0 + 20.0);
+        let rapid_distance = sqrt(300.0) + 13.0 + sqrt(40.0 * 40.0 + 60.0 * 60.0);
+        assert!((got.rapid_distance - rapid_distance).abs() < 0.001);
+        assert_eq!(got.feed_rate, Some(Extent { min: 200.0, max: 400.0 }));
+        assert_eq!(got.z_range, Some(Extent { min: -3.0, max: 10.0 }));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn spindle_speeds_ignore_moves_with_the_spindle_off() {
+        let got = stats("G0 X1\nS2000 M3\nG1 X2 F100\nS500\nX3\nM5\nS9000\nX4");
+
+        assert_eq!(got.spindle_speed, Some(Extent { min: 500.0, max: 2000.0 }));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn tools_and_tool_changes() {
+        let got = stats("T1 M6\nG1 X1 F100\nT2 M6\nT1 M6\nX2");
+
+        assert_eq!(got.tool_changes, 3);
+        assert_eq!(got.tools.into_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn count_each_code() {
+        let got = stats("G90 G21\nG0 X1\nG1 X2 F100\nG0 X3\nG54\nG59.1\nM3 S100\nM5\nM30");
+
+        let codes: Vec<_> = got.codes
+            .iter()
+            .map(|(code, &count)| (code.to_string(), count))
+            .collect();
+        let should_be = vec![("G0", 2), ("G1", 1), ("G21", 1), ("G54", 1), ("G59.1", 1),
+                             ("G90", 1), ("M3", 1), ("M5", 1), ("M30", 1)];
+
+        assert_eq!(codes.len(), should_be.len());
+        for (got, should_be) in codes.iter().zip(&should_be) {
+            assert_eq!((got.0.as_str(), got.1), *should_be);
+        }
+    }
+}
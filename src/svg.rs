@@ -0,0 +1,443 @@
+//! Rendering toolpaths as SVG images.
+//!
+//! Each move becomes its own `<path>` element, one per line, so the output
+//! of two versions of a program can be diffed.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::svg::{Renderer, View};
+//!
+//! let src = "G0 X10 Y10\nG1 X20 F500\nG2 X30 Y10 I5 J0";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let svg = Renderer::new(View::XY).render_program(lines).unwrap();
+//!
+//! assert!(svg.starts_with("<svg"));
+//! assert!(svg.contains("stroke-dasharray"));
+//! assert!(svg.contains(" A5 5 0 0 1 30 -10"));
+//! ```
+
+#[cfg(feature = "std")]
+use std::string::String;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+use core::f32::consts::PI;
+use core::fmt::{self, Write};
+
+#[cfg(feature = "std")]
+use parser::Line;
+#[cfg(feature = "std")]
+use interpreter::{Event, Interpreter, Profile};
+use interpreter::{Move, MoveKind, Plane, Point};
+use stats::BoundingBox;
+#[cfg(feature = "std")]
+use errors::*;
+use helpers::{sin, cos, sqrt, Decimals};
+
+
+/// The colours used for each tool.
+const TOOL_COLOURS: [&str; 8] = ["#1f77b4", "#2ca02c", "#ff7f0e", "#9467bd", "#d62728",
+                                 "#8c564b", "#e377c2", "#17becf"];
+/// The colours used for feed rates, from slowest to fastest.
+const FEED_COLOURS: [&str; 8] = ["#0000ff", "#2400db", "#4900b6", "#6d0092", "#92006d",
+                                 "#b60049", "#db0024", "#ff0000"];
+/// The colour used for rapids.
+const RAPID_COLOUR: &str = "#999999";
+/// The most decimal places used for coordinates.
+const PRECISION: usize = 3;
+/// How many straight lines are used to draw arcs which can't be drawn as an
+/// SVG arc.
+const ARC_SEGMENTS: usize = 32;
+
+
+/// The direction the toolpath is looked at from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum View {
+    /// From above, with X to the right and Y up.
+    XY,
+    /// From the front, with X to the right and Z up.
+    XZ,
+    /// From the side, with Y to the right and Z up.
+    YZ,
+    /// An isometric view from the front-left, above the part.
+    Isometric,
+}
+
+impl View {
+    /// Project a point onto the image, using SVG coordinates (where Y goes
+    /// down).
+    pub fn project(&self, p: Point) -> (f32, f32) {
+        match *self {
+            View::XY => (p.x, -p.y),
+            View::XZ => (p.x, -p.z),
+            View::YZ => (p.y, -p.z),
+            View::Isometric => {
+                let angle = PI / 6.0;
+                ((p.x - p.y) * cos(angle), -(p.z + (p.x + p.y) * sin(angle)))
+            }
+        }
+    }
+
+    /// Do arcs in this plane show up as circles in this view?
+    fn shows_circles_in(&self, plane: Plane) -> bool {
+        matches!((*self, plane),
+                 (View::XY, Plane::XY) | (View::XZ, Plane::ZX) | (View::YZ, Plane::YZ))
+    }
+}
+
+/// How feed moves are coloured.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Colouring {
+    /// Each tool gets its own colour.
+    Tool,
+    /// Slow moves are blue and fast moves are red, relative to the range of
+    /// feed rates in the program.
+    FeedRate,
+}
+
+
+/// Renders moves as an SVG image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Renderer {
+    /// The direction the toolpath is looked at from.
+    pub view: View,
+    /// How feed moves are coloured.
+    pub colouring: Colouring,
+    /// Label the start of each block with its (one-based) line number.
+    pub annotate: bool,
+    /// How thick lines are, in mm.
+    pub stroke_width: f32,
+}
+
+impl Renderer {
+    /// Create a `Renderer` which colours moves by tool, without annotations.
+    pub fn new(view: View) -> Renderer {
+        Renderer {
+            view,
+            colouring: Colouring::Tool,
+            annotate: false,
+            stroke_width: 0.2,
+        }
+    }
+
+    /// Run a program through an `Interpreter` for a milling machine and
+    /// render its moves.
+    #[cfg(feature = "std")]
+    pub fn render_program<I>(&self, lines: I) -> Result<String>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut moves = Vec::new();
+
+        for event in Interpreter::with_profile(lines.into_iter(), Profile::Mill) {
+            if let Event::Move(m) = event? {
+                moves.push(m);
+            }
+        }
+
+        let mut svg = String::new();
+        self.render(&moves, &mut svg).expect("Writing to a String never fails");
+        Ok(svg)
+    }
+
+    /// Render a set of moves.
+    pub fn render<W: Write>(&self, moves: &[Move], w: &mut W) -> fmt::Result {
+        let (min_x, min_y, width, height) = self.view_box(moves);
+        writeln!(w,
+                 "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\" \
+                  width=\"{}mm\" height=\"{}mm\">",
+                 num(min_x),
+                 num(min_y),
+                 num(width),
+                 num(height),
+                 num(width),
+                 num(height))?;
+        writeln!(w,
+                 "<g fill=\"none\" stroke-width=\"{}\" stroke-linecap=\"round\">",
+                 num(self.stroke_width))?;
+
+        let (slowest, fastest) = feed_range(moves);
+        for m in moves {
+            self.render_move(m, slowest, fastest, w)?;
+        }
+        writeln!(w, "</g>")?;
+
+        if self.annotate {
+            self.render_annotations(moves, w)?;
+        }
+
+        writeln!(w, "</svg>")
+    }
+
+    fn render_move<W: Write>(&self,
+                             m: &Move,
+                             slowest: f32,
+                             fastest: f32,
+                             w: &mut W)
+                             -> fmt::Result {
+        let (x, y) = self.view.project(m.start);
+        write!(w, "<path d=\"M{} {}", num(x), num(y))?;
+
+        match m.kind {
+            MoveKind::Arc { centre, plane, .. } if self.view.shows_circles_in(plane) => {
+                let sweep = m.sweep().unwrap_or(0.0);
+
+                if sweep > PI {
+                    // an SVG arc can't be more than half a circle without
+                    // using the large arc flag, and can't be a full circle
+                    self.arc_to(m, centre, 0.0, 0.5, w)?;
+                    self.arc_to(m, centre, 0.5, 1.0, w)?;
+                } else {
+                    self.arc_to(m, centre, 0.0, 1.0, w)?;
+                }
+            }
+            MoveKind::Arc { .. } => {
+                for i in 1..=ARC_SEGMENTS {
+                    let (x, y) = self.view.project(m.point_at(i as f32 / ARC_SEGMENTS as f32));
+                    write!(w, " L{} {}", num(x), num(y))?;
+                }
+            }
+            _ => {
+                let (x, y) = self.view.project(m.end);
+                write!(w, " L{} {}", num(x), num(y))?;
+            }
+        }
+
+        if m.kind == MoveKind::Rapid {
+            let dash = self.stroke_width * 4.0;
+            writeln!(w,
+                     "\" stroke=\"{}\" stroke-dasharray=\"{} {}\"/>",
+                     RAPID_COLOUR,
+                     num(dash),
+                     num(dash))
+        } else {
+            writeln!(w, "\" stroke=\"{}\"/>", self.colour(m, slowest, fastest))
+        }
+    }
+
+    /// Draw part of an arc, between two fractions of the way along it. The
+    /// part can't turn through more than half a circle.
+    fn arc_to<W: Write>(&self,
+                        m: &Move,
+                        centre: Point,
+                        from: f32,
+                        to: f32,
+                        w: &mut W)
+                        -> fmt::Result {
+        let (centre_x, centre_y) = self.view.project(centre);
+        let (start_x, start_y) = self.view.project(m.point_at(from));
+        let (mid_x, mid_y) = self.view.project(m.point_at((from + to) / 2.0));
+        let (end_x, end_y) = self.view.project(m.point_at(to));
+
+        let (dx, dy) = (end_x - centre_x, end_y - centre_y);
+        let radius = sqrt(dx * dx + dy * dy);
+
+        // SVG's "positive angle" direction is clockwise on screen
+        let cross = (start_x - centre_x) * (mid_y - centre_y) -
+                    (start_y - centre_y) * (mid_x - centre_x);
+        let sweep_flag = if cross > 0.0 { 1 } else { 0 };
+
+        write!(w,
+               " A{} {} 0 0 {} {} {}",
+               num(radius),
+               num(radius),
+               sweep_flag,
+               num(end_x),
+               num(end_y))
+    }
+
+    fn render_annotations<W: Write>(&self, moves: &[Move], w: &mut W) -> fmt::Result {
+        writeln!(w,
+                 "<g font-family=\"monospace\" font-size=\"{}\" fill=\"#000000\">",
+                 num(self.stroke_width * 10.0))?;
+
+        let mut previous = None;
+        for m in moves {
+            if previous == Some(m.span.line) {
+                continue;
+            }
+            previous = Some(m.span.line);
+
+            let (x, y) = self.view.project(m.end);
+            writeln!(w, "<text x=\"{}\" y=\"{}\">{}</text>", num(x), num(y), m.span.line + 1)?;
+        }
+
+        writeln!(w, "</g>")
+    }
+
+    fn colour(&self, m: &Move, slowest: f32, fastest: f32) -> &'static str {
+        match self.colouring {
+            Colouring::Tool => TOOL_COLOURS[m.tool as usize % TOOL_COLOURS.len()],
+            Colouring::FeedRate if fastest > slowest => {
+                let fraction = (m.feed_rate - slowest) / (fastest - slowest);
+                let index = (fraction * (FEED_COLOURS.len() - 1) as f32 + 0.5) as usize;
+                FEED_COLOURS[index.min(FEED_COLOURS.len() - 1)]
+            }
+            Colouring::FeedRate => FEED_COLOURS[0],
+        }
+    }
+
+    /// The area covered by the moves (plus a margin), as the `x`, `y`,
+    /// `width` and `height` of an SVG `viewBox`.
+    fn view_box(&self, moves: &[Move]) -> (f32, f32, f32, f32) {
+        let mut bounds: Option<BoundingBox> = None;
+
+        for m in moves {
+            let around = BoundingBox::around(m);
+            for &x in &[around.min.x, around.max.x] {
+                for &y in &[around.min.y, around.max.y] {
+                    for &z in &[around.min.z, around.max.z] {
+                        let (u, v) = self.view.project(Point::new(x, y, z));
+                        let p = Point::new(u, v, 0.0);
+
+                        match bounds {
+                            Some(ref mut b) => b.add_point(p),
+                            None => bounds = Some(BoundingBox::new(p)),
+                        }
+                    }
+                }
+            }
+        }
+
+        let bounds = bounds.unwrap_or_else(|| BoundingBox::new(Point::default()));
+        let size = bounds.size();
+        let margin = size.x.max(size.y).max(1.0) * 0.05;
+
+        (bounds.min.x - margin,
+         bounds.min.y - margin,
+         size.x + 2.0 * margin,
+         size.y + 2.0 * margin)
+    }
+}
+
+fn num(n: f32) -> Decimals {
+    Decimals(n, PRECISION)
+}
+
+fn feed_range(moves: &[Move]) -> (f32, f32) {
+    moves.iter()
+        .filter(|m| m.kind != MoveKind::Rapid)
+        .fold((f32::INFINITY, f32::NEG_INFINITY),
+              |(slowest, fastest), m| (slowest.min(m.feed_rate), fastest.max(m.feed_rate)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use std::string::String;
+    #[cfg(feature = "std")]
+    use std::vec::Vec;
+    #[cfg(feature = "std")]
+    use helpers::parse;
+
+    #[cfg(feature = "std")]
+    fn render(renderer: Renderer, src: &str) -> String {
+        let lines = parse(src);
+
+        renderer.render_program(lines).unwrap()
+    }
+
+    #[cfg(feature = "std")]
+    fn paths(svg: &str) -> Vec<&str> {
+        svg.lines().filter(|l| l.starts_with("<path")).collect()
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn render_a_simple_program() {
+        let got = render(Renderer::new(View::XY), "G0 X10 Y10\nG1 X30 F100\nG3 X10 Y10 I-10 J0");
+
+        let should_be = "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"-1.5 -21.5 33 23\" \
+                         width=\"33mm\" height=\"23mm\">\n\
+                         <g fill=\"none\" stroke-width=\"0.2\" stroke-linecap=\"round\">\n\
+                         <path d=\"M0 0 L10 -10\" stroke=\"#999999\" \
+                         stroke-dasharray=\"0.8 0.8\"/>\n\
+                         <path d=\"M10 -10 L30 -10\" stroke=\"#1f77b4\"/>\n\
+                         <path d=\"M30 -10 A10 10 0 0 0 10 -10\" stroke=\"#1f77b4\"/>\n\
+                         </g>\n\
+                         </svg>\n";
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn full_circles_are_drawn_in_two_halves() {
+        let got = render(Renderer::new(View::XY), "G0 X10\nG2 X10 Y0 I-10 J0 F100");
+
+        assert_eq!(paths(&got)[1],
+                   "<path d=\"M10 0 A10 10 0 0 1 -10 0 A10 10 0 0 1 10 0\" stroke=\"#1f77b4\"/>");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn arcs_seen_edge_on_are_drawn_with_lines() {
+        let got = render(Renderer::new(View::XZ), "G0 X10\nG2 X-10 Y0 I-10 J0 F100");
+
+        let arc = paths(&got)[1];
+        assert!(!arc.contains(" A"));
+        assert_eq!(arc.matches(" L").count(), ARC_SEGMENTS);
+
+        // but arcs in the ZX plane are circles, and G2 is clockwise when
+        // looking from +Y, so it goes over the top when seen from the front
+        let got = render(Renderer::new(View::XZ), "G18 G0 X10\nG2 X-10 Z0 I-10 K0 F100");
+        assert_eq!(paths(&got)[1], "<path d=\"M10 0 A10 10 0 0 0 -10 0\" stroke=\"#1f77b4\"/>");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn colour_by_feed_rate() {
+        let renderer = Renderer {
+            colouring: Colouring::FeedRate,
+            ..Renderer::new(View::XY)
+        };
+
+        let got = render(renderer, "G1 X1 F100\nX2 F200\nX3 F300");
+
+        let colours: Vec<_> = paths(&got)
+            .iter()
+            .map(|p| &p[p.find("stroke=").unwrap() + 8..p.len() - 3])
+            .collect();
+        assert_eq!(colours, vec!["#0000ff", "#92006d", "#ff0000"]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn colour_by_tool() {
+        let got = render(Renderer::new(View::XY), "T1 M6\nG1 X1 F100\nT2 M6\nX2");
+
+        let got = paths(&got);
+        assert!(got[0].contains(TOOL_COLOURS[1]));
+        assert!(got[1].contains(TOOL_COLOURS[2]));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn annotate_each_block() {
+        let renderer = Renderer {
+            annotate: true,
+            ..Renderer::new(View::XY)
+        };
+
+        let got = render(renderer, "G0 X10\nG1 X20 F100\n\nY5");
+
+        let labels: Vec<_> = got.lines().filter(|l| l.starts_with("<text")).collect();
+        assert_eq!(labels,
+                   vec!["<text x=\"10\" y=\"0\">1</text>",
+                        "<text x=\"20\" y=\"0\">2</text>",
+                        "<text x=\"20\" y=\"-5\">4</text>"]);
+    }
+
+    #[test]
+    fn isometric_projection() {
+        let view = View::Isometric;
+
+        let (x, y) = view.project(Point::new(0.0, 0.0, 10.0));
+        assert_eq!((x, y), (0.0, -10.0));
+        let (x, y) = view.project(Point::new(10.0, 10.0, 0.0));
+        assert!(x.abs() < 1e-5 && (y + 10.0).abs() < 1e-5);
+    }
+}
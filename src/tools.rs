@@ -0,0 +1,361 @@
+//! Tool tables and the LinuxCNC-style `.tbl` files they are saved in.
+
+use core::fmt::{self, Write};
+use core::slice;
+use arrayvec::{ArrayVec, ArrayString};
+
+use lexer::Span;
+use errors::*;
+
+
+/// The most tools a `ToolTable` can hold.
+pub const MAX_TOOLS: usize = 64;
+
+/// The most bytes of a tool's comment which will be kept.
+pub const MAX_COMMENT_LENGTH: usize = 64;
+
+
+/// A single entry in the tool table.
+///
+/// All lengths are in millimetres.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Tool {
+    /// The tool number, as used by `T`, `H` and `D` words.
+    pub number: u32,
+    /// The pocket the tool is stored in.
+    pub pocket: u32,
+    /// The tool's length offset, applied to Z by `G43`.
+    pub length: f32,
+    /// The tool's X offset, used by lathe tools.
+    pub x_offset: f32,
+    /// The cutter's diameter, used by `G41` and `G42`.
+    pub diameter: f32,
+    /// Which way a lathe tool's tip points (0 to 9).
+    pub orientation: u32,
+    comment: ArrayString<[u8; MAX_COMMENT_LENGTH]>,
+}
+
+impl Tool {
+    /// Create a tool with just a number.
+    pub fn new(number: u32) -> Tool {
+        Tool { number, ..Default::default() }
+    }
+
+    /// Half the tool's diameter.
+    pub fn radius(&self) -> f32 {
+        self.diameter / 2.0
+    }
+
+    /// Any comment attached to the tool.
+    pub fn comment(&self) -> &str {
+        self.comment.as_str()
+    }
+
+    /// Set the tool's comment, as long as it's no more than
+    /// `MAX_COMMENT_LENGTH` bytes.
+    pub fn set_comment(&mut self, comment: &str) -> Result<()> {
+        if comment.len() > MAX_COMMENT_LENGTH {
+            return Err(Error::InvalidCommand("The tool's comment is too long"));
+        }
+
+        self.truncate_comment(comment);
+        Ok(())
+    }
+
+    /// Set the tool's comment, dropping anything which won't fit.
+    fn truncate_comment(&mut self, comment: &str) {
+        self.comment.clear();
+
+        for c in comment.chars() {
+            if self.comment.try_push(c).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+
+/// A table of the tools available to a machine.
+///
+/// # Examples
+///
+/// ```rust
+/// use gcode::tools::ToolTable;
+///
+/// let src = "T1 P1 Z+25.400000 D+6.000000 ;1/4 endmill\n\
+///            T2 P2 Z+12.000000 D+3.000000\n";
+/// let table = ToolTable::from_tbl_file(src).unwrap();
+///
+/// let endmill = table.get(1).unwrap();
+/// assert_eq!(endmill.length, 25.4);
+/// assert_eq!(endmill.radius(), 3.0);
+/// assert_eq!(endmill.comment(), "1/4 endmill");
+///
+/// let mut saved = String::new();
+/// table.write_tbl_file(&mut saved).unwrap();
+/// assert_eq!(ToolTable::from_tbl_file(&saved).unwrap(), table);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolTable {
+    tools: ArrayVec<[Tool; MAX_TOOLS]>,
+}
+
+impl ToolTable {
+    /// Create an empty `ToolTable`.
+    pub fn new() -> ToolTable {
+        ToolTable::default()
+    }
+
+    /// Look up a tool by its number.
+    pub fn get(&self, number: u32) -> Option<&Tool> {
+        self.tools.iter().find(|t| t.number == number)
+    }
+
+    /// Get a mutable reference to a tool.
+    pub fn get_mut(&mut self, number: u32) -> Option<&mut Tool> {
+        self.tools.iter_mut().find(|t| t.number == number)
+    }
+
+    /// Add a tool to the table, replacing any existing tool with the same
+    /// number.
+    pub fn insert(&mut self, tool: Tool) -> Result<()> {
+        if let Some(existing) = self.get_mut(tool.number) {
+            *existing = tool;
+            return Ok(());
+        }
+
+        self.tools
+            .try_push(tool)
+            .map_err(|_| Error::InvalidCommand("The tool table is full"))
+    }
+
+    /// Remove a tool from the table.
+    pub fn remove(&mut self, number: u32) -> Option<Tool> {
+        let index = self.tools.iter().position(|t| t.number == number)?;
+        Some(self.tools.remove(index))
+    }
+
+    /// Iterate over every tool in the table.
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, Tool> {
+        self.tools.iter()
+    }
+
+    /// The number of tools in the table.
+    pub fn len(&self) -> usize {
+        self.tools.len()
+    }
+
+    /// Is the table empty?
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Read a LinuxCNC tool table (`.tbl`) file.
+    ///
+    /// Each line has a `T` word for the tool number followed by any of `P`
+    /// (pocket), `X` and `Z` (offsets), `D` (diameter) and `Q` (orientation),
+    /// then an optional comment starting with a semicolon. Comments are
+    /// truncated to `MAX_COMMENT_LENGTH` bytes. Offsets for other axes and
+    /// the front/back angles are ignored.
+    pub fn from_tbl_file(src: &str) -> Result<ToolTable> {
+        let mut table = ToolTable::new();
+
+        for (line, text) in src.lines().enumerate() {
+            let (words, comment) = match text.find(';') {
+                Some(i) => (&text[..i], Some(text[i + 1..].trim())),
+                None => (text, None),
+            };
+
+            if words.trim().is_empty() {
+                continue;
+            }
+
+            let mut tool = parse_tool(words, line)?;
+            if let Some(comment) = comment {
+                tool.truncate_comment(comment);
+            }
+
+            table.insert(tool)
+                .map_err(|_| Error::SyntaxError("Too many tools", Span { line, column: 0 }))?;
+        }
+
+        Ok(table)
+    }
+
+    /// Write the table out in the LinuxCNC tool table format.
+    pub fn write_tbl_file<W: Write>(&self, w: &mut W) -> fmt::Result {
+        for tool in self.iter() {
+            write!(w, "T{} P{}", tool.number, tool.pocket)?;
+
+            if tool.x_offset != 0.0 {
+                write!(w, " X{:+.6}", tool.x_offset)?;
+            }
+            write!(w, " Z{:+.6} D{:+.6}", tool.length, tool.diameter)?;
+            if tool.orientation != 0 {
+                write!(w, " Q{}", tool.orientation)?;
+            }
+            if !tool.comment.is_empty() {
+                write!(w, " ;{}", tool.comment)?;
+            }
+
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a ToolTable {
+    type Item = &'a Tool;
+    type IntoIter = slice::Iter<'a, Tool>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+fn parse_tool(words: &str, line: usize) -> Result<Tool> {
+    let mut tool = Tool::default();
+    let mut number = None;
+
+    for word in words.split_whitespace() {
+        let column = word.as_ptr() as usize - words.as_ptr() as usize;
+        let span = Span { line, column };
+
+        let mut chars = word.chars();
+        let letter = chars.next().map(|c| c.to_ascii_uppercase());
+        let value: f32 = chars.as_str()
+            .parse()
+            .map_err(|_| Error::SyntaxError("Invalid tool table entry", span))?;
+
+        match letter {
+            Some('T') => number = Some(value as u32),
+            Some('P') => tool.pocket = value as u32,
+            Some('X') => tool.x_offset = value,
+            Some('Z') => tool.length = value,
+            Some('D') => tool.diameter = value,
+            Some('Q') => tool.orientation = value as u32,
+            Some('Y') | Some('A') | Some('B') | Some('C') | Some('U') | Some('V') |
+            Some('W') | Some('I') | Some('J') => {}
+            _ => return Err(Error::SyntaxError("Unknown tool table word", span)),
+        }
+    }
+
+    match number {
+        Some(n) => {
+            tool.number = n;
+            Ok(tool)
+        }
+        None => {
+            let span = Span { line, column: 0 };
+            Err(Error::SyntaxError("Tool entries need a tool number (T)", span))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn parse_a_tbl_file() {
+        let src = "T1 P1 Z0.511 D0.125 ;1/8 end mill\n\
+                   \n\
+                   ; just a comment\n\
+                   t2 p5 x-0.5 y1 z1.2 d0.4 q3 i80 j95\n";
+
+        let got = ToolTable::from_tbl_file(src).unwrap();
+
+        assert_eq!(got.len(), 2);
+        let first = got.get(1).unwrap();
+        assert_eq!(first.pocket, 1);
+        assert_eq!(first.length, 0.511);
+        assert_eq!(first.diameter, 0.125);
+        assert_eq!(first.comment(), "1/8 end mill");
+
+        let second = got.get(2).unwrap();
+        assert_eq!(second.pocket, 5);
+        assert_eq!(second.x_offset, -0.5);
+        assert_eq!(second.orientation, 3);
+        assert!(second.comment().is_empty());
+    }
+
+    #[test]
+    fn invalid_tbl_files() {
+        let inputs = ["P1 Z1", "T1 Zabc", "T1 K5", "T1 Z1 D"];
+
+        for src in &inputs {
+            assert!(ToolTable::from_tbl_file(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn inserting_replaces_existing_tools() {
+        let mut table = ToolTable::new();
+        table.insert(Tool::new(3)).unwrap();
+
+        let mut replacement = Tool::new(3);
+        replacement.diameter = 6.0;
+        table.insert(replacement).unwrap();
+
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.get(3).unwrap().radius(), 3.0);
+        assert_eq!(table.remove(3), Some(replacement));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn the_table_has_a_fixed_capacity() {
+        let mut table = ToolTable::new();
+
+        for n in 0..MAX_TOOLS as u32 {
+            table.insert(Tool::new(n)).unwrap();
+        }
+
+        assert!(table.insert(Tool::new(1000)).is_err());
+    }
+
+    #[test]
+    fn long_comments_are_truncated() {
+        let long: String = "é".repeat(MAX_COMMENT_LENGTH);
+        let src = format!("T1 P1 Z1 D1 ;{}", long);
+
+        let table = ToolTable::from_tbl_file(&src).unwrap();
+
+        let comment = table.get(1).unwrap().comment();
+        assert_eq!(comment.len(), MAX_COMMENT_LENGTH);
+        assert!(long.starts_with(comment));
+    }
+
+    #[test]
+    fn setting_a_long_comment_fails() {
+        let mut tool = Tool::new(1);
+        tool.set_comment("roughing").unwrap();
+
+        assert!(tool.set_comment(&"x".repeat(MAX_COMMENT_LENGTH + 1)).is_err());
+        assert_eq!(tool.comment(), "roughing");
+    }
+
+    #[test]
+    fn round_trip_through_a_tbl_file() {
+        let mut table = ToolTable::new();
+        let mut tool = Tool::new(7);
+        tool.pocket = 2;
+        tool.length = -12.5;
+        tool.x_offset = 1.25;
+        tool.diameter = 0.8;
+        tool.orientation = 6;
+        tool.set_comment("threading tool").unwrap();
+        table.insert(tool).unwrap();
+        table.insert(Tool::new(8)).unwrap();
+
+        let mut src = String::new();
+        table.write_tbl_file(&mut src).unwrap();
+
+        assert_eq!(src.lines().next().unwrap(),
+                   "T7 P2 X+1.250000 Z-12.500000 D+0.800000 Q6 ;threading tool");
+        assert_eq!(ToolTable::from_tbl_file(&src).unwrap(), table);
+    }
+}
@@ -0,0 +1,719 @@
+//! Moving, rotating, scaling and mirroring whole programs.
+//!
+//! A `Transformer` rewrites the axis words of each `Line` so the program
+//! cuts the same shape somewhere else. It keeps track of the distance mode
+//! (`G90`/`G91`), the arc centre mode (`G90.1`/`G91.1`) and the active plane
+//! so `I`, `J`, `K` and `R` words are updated properly, and swaps `G2` and
+//! `G3` when a program is mirrored.
+//!
+//! Distances are in whatever units the program uses. Moves in machine
+//! coordinates (`G53`) and the axis words of `G10`, `G43.1`, `G33.1` and
+//! `G76` are left alone. Canned cycles (`G73`, `G81` to `G89`) are moved
+//! like any other point, with their retract plane (`R`) and peck depth (`Q`)
+//! following the cycle's axis, so they can't be tipped out of their plane.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Point;
+//! use gcode::transform::{Transform, Transformer, Axis};
+//! use gcode::writer::write_lines;
+//!
+//! let src = "G90 G0 X10 Y0\nG2 X0 Y-10 I-10 J0 F100";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! // mirror about the Y axis, then shift everything 50 along X
+//! let transform = Transform::mirror(Axis::X, 0.0)
+//!     .then(&Transform::translate(Point::new(50.0, 0.0, 0.0)));
+//! let lines: Vec<_> = Transformer::new(lines, transform)
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//!
+//! let mut written = String::new();
+//! write_lines(&lines, &mut written).unwrap();
+//! assert_eq!(written, "G90 G0 X40 Y0\nG3 X50 Y-10 I10 J0 F100\n");
+//! ```
+
+use core::f32::consts::PI;
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use interpreter::{Plane, Point, DistanceMode};
+use errors::*;
+use helpers::{abs, sqrt, sin, cos, has_axis_words, Modal};
+
+
+/// Anything smaller than this is treated as zero.
+const EPSILON: f32 = 1e-6;
+
+
+/// One of the linear axes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(missing_docs)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+
+/// A combination of translations, rotations, uniform scaling and mirroring.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    matrix: [[f32; 3]; 3],
+    offset: Point,
+}
+
+impl Transform {
+    /// A transform which leaves everything where it is.
+    pub fn identity() -> Transform {
+        Transform {
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            offset: Point::default(),
+        }
+    }
+
+    /// Move everything by a fixed amount.
+    pub fn translate(by: Point) -> Transform {
+        Transform {
+            offset: by,
+            ..Transform::identity()
+        }
+    }
+
+    /// Rotate counter-clockwise (when looking from the positive end of the
+    /// plane's normal) around a point.
+    pub fn rotate(plane: Plane, degrees: f32, about: Point) -> Transform {
+        let radians = degrees * PI / 180.0;
+        let (sin, cos) = (sin(radians), cos(radians));
+
+        let basis = [Point::new(1.0, 0.0, 0.0),
+                     Point::new(0.0, 1.0, 0.0),
+                     Point::new(0.0, 0.0, 1.0)];
+        let columns = basis
+            .iter()
+            .map(|&basis| {
+                     let (a, b, normal) = plane.split(basis);
+                     plane.join(a * cos - b * sin, a * sin + b * cos, normal)
+                 });
+
+        let mut matrix = [[0.0; 3]; 3];
+        for (j, column) in columns.enumerate() {
+            matrix[0][j] = column.x;
+            matrix[1][j] = column.y;
+            matrix[2][j] = column.z;
+        }
+
+        // get rid of rounding errors so right angles don't mix axes together
+        for cell in matrix.iter_mut().flat_map(|row| row.iter_mut()) {
+            if abs(*cell) < EPSILON {
+                *cell = 0.0;
+            }
+        }
+
+        Transform::about(matrix, about)
+    }
+
+    /// Make everything bigger or smaller, keeping one point where it is.
+    pub fn scale(factor: f32, about: Point) -> Transform {
+        let matrix = [[factor, 0.0, 0.0], [0.0, factor, 0.0], [0.0, 0.0, factor]];
+        Transform::about(matrix, about)
+    }
+
+    /// Mirror across the plane where `axis` is `at`.
+    pub fn mirror(axis: Axis, at: f32) -> Transform {
+        let mut t = Transform::identity();
+
+        let (index, offset) = match axis {
+            Axis::X => (0, &mut t.offset.x),
+            Axis::Y => (1, &mut t.offset.y),
+            Axis::Z => (2, &mut t.offset.z),
+        };
+        t.matrix[index][index] = -1.0;
+        *offset = 2.0 * at;
+
+        t
+    }
+
+    /// A transform which does `self`, then `next`.
+    pub fn then(&self, next: &Transform) -> Transform {
+        let mut matrix = [[0.0; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell = (0..3).map(|k| next.matrix[i][k] * self.matrix[k][j]).sum();
+            }
+        }
+
+        Transform {
+            matrix,
+            offset: next.apply(self.offset),
+        }
+    }
+
+    /// Transform a point.
+    pub fn apply(&self, p: Point) -> Point {
+        self.apply_vector(p) + self.offset
+    }
+
+    /// Transform a direction or distance, ignoring any translation.
+    pub fn apply_vector(&self, v: Point) -> Point {
+        let m = &self.matrix;
+
+        Point::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                   m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                   m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+    }
+
+    /// A transform which applies `matrix` while keeping `about` fixed.
+    fn about(matrix: [[f32; 3]; 3], about: Point) -> Transform {
+        let linear = Transform {
+            matrix,
+            offset: Point::default(),
+        };
+
+        Transform {
+            matrix,
+            offset: about - linear.apply(about),
+        }
+    }
+
+    /// Transform a point where some coordinates may be unknown. An output
+    /// coordinate is only unknown if it depends on an unknown input.
+    fn apply_partial(&self, p: [Option<f32>; 3], offset: bool) -> [Option<f32>; 3] {
+        let offsets = [self.offset.x, self.offset.y, self.offset.z];
+        let mut out = [None; 3];
+
+        for (i, row) in self.matrix.iter().enumerate() {
+            let mut value = if offset { Some(offsets[i]) } else { Some(0.0) };
+
+            for (j, &factor) in row.iter().enumerate() {
+                if factor != 0.0 {
+                    value = match (value, p[j]) {
+                        (Some(value), Some(coordinate)) => Some(value + factor * coordinate),
+                        _ => None,
+                    };
+                }
+            }
+
+            out[i] = value;
+        }
+
+        out
+    }
+
+    /// Which output axes are affected by a set of input axes.
+    fn affected(&self, inputs: [bool; 3]) -> [bool; 3] {
+        let mut out = [false; 3];
+
+        for (i, row) in self.matrix.iter().enumerate() {
+            out[i] = row.iter().zip(&inputs).any(|(&factor, &input)| input && factor != 0.0);
+        }
+
+        out
+    }
+
+    /// How arcs in a plane are changed, as whether their direction is
+    /// reversed and how much their radius is scaled by.
+    fn arc_changes(&self, plane: Plane) -> Result<(bool, f32)> {
+        let a = self.apply_vector(plane.join(1.0, 0.0, 0.0));
+        let b = self.apply_vector(plane.join(0.0, 1.0, 0.0));
+        let n = self.apply_vector(plane.join(0.0, 0.0, 1.0));
+
+        let (aa, ab, a_normal) = plane.split(a);
+        let (ba, bb, b_normal) = plane.split(b);
+        let (na, nb, _) = plane.split(n);
+
+        if abs(a_normal) > EPSILON || abs(b_normal) > EPSILON || abs(na) > EPSILON ||
+           abs(nb) > EPSILON {
+            return Err(Error::InvalidCommand("Arcs can't be rotated out of their plane"));
+        }
+
+        let determinant = aa * bb - ab * ba;
+        Ok((determinant < 0.0, sqrt(abs(determinant))))
+    }
+
+    /// How canned cycles in a plane are changed, as how much distances
+    /// along the plane's normal are scaled by and how far they are moved.
+    fn cycle_changes(&self, plane: Plane) -> Result<(f32, f32)> {
+        let a = self.apply_vector(plane.join(1.0, 0.0, 0.0));
+        let b = self.apply_vector(plane.join(0.0, 1.0, 0.0));
+        let n = self.apply_vector(plane.join(0.0, 0.0, 1.0));
+
+        let (_, _, a_normal) = plane.split(a);
+        let (_, _, b_normal) = plane.split(b);
+        let (na, nb, scale) = plane.split(n);
+
+        // the tool has to keep drilling along the same axis
+        if abs(a_normal) > EPSILON || abs(b_normal) > EPSILON || abs(na) > EPSILON ||
+           abs(nb) > EPSILON || scale < EPSILON {
+            return Err(Error::InvalidCommand("Canned cycles can only be moved, scaled or \
+                                              rotated within their plane"));
+        }
+
+        let (_, _, offset) = plane.split(self.offset);
+        Ok((scale, offset))
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+
+/// An iterator adaptor which applies a `Transform` to every line of a
+/// program.
+#[derive(Debug)]
+pub struct Transformer<I>
+    where I: Iterator<Item = Line>
+{
+    lines: I,
+    transform: Transform,
+    modal: Modal,
+    arc_distance_mode: DistanceMode,
+    plane: Plane,
+    /// The arc command most recently written out.
+    written_arc_command: Option<Number>,
+}
+
+impl<I> Transformer<I>
+    where I: Iterator<Item = Line>
+{
+    /// Create a new `Transformer`.
+    pub fn new(lines: I, transform: Transform) -> Transformer<I> {
+        Transformer {
+            lines,
+            transform,
+            modal: Modal::default(),
+            arc_distance_mode: DistanceMode::Incremental,
+            plane: Plane::XY,
+            written_arc_command: None,
+        }
+    }
+
+    fn transform_line(&mut self, line: Line) -> Result<Line> {
+        let start = self.modal.position;
+        self.modal.update(&line);
+
+        match line {
+            Line::ProgramNumber(_) => Ok(line),
+            Line::Cmd(cmd) => self.transform_command(cmd, start).map(Line::Cmd),
+            Line::Args { args, line_number, span } => {
+                if self.modal.in_machine_coordinates(span) {
+                    return Ok(Line::Args {
+                                  args,
+                                  line_number,
+                                  span,
+                              });
+                }
+
+                let args = self.modal_move(args, start)?;
+
+                // mirroring may swap G2 and G3 differently in each plane, so
+                // continuing an arc after changing planes can need a new
+                // command
+                if has_axis_words(&args) {
+                    if let Some(number) = self.arc_number()? {
+                        if self.written_arc_command != Some(number) {
+                            self.written_arc_command = Some(number);
+                            return Ok(Line::Cmd(Command {
+                                                    kind: CommandKind::G,
+                                                    number,
+                                                    args,
+                                                    line_number,
+                                                    span,
+                                                }));
+                        }
+                    }
+                }
+
+                Ok(Line::Args {
+                       args,
+                       line_number,
+                       span,
+                   })
+            }
+        }
+    }
+
+    fn transform_command(&mut self, mut cmd: Command, start: [Option<f32>; 3]) -> Result<Command> {
+        // M codes use X, Y and Z for their own settings (e.g. M92)
+        if cmd.kind != CommandKind::G {
+            return Ok(cmd);
+        }
+
+        match cmd.number {
+            Number::Integer(2) | Number::Integer(3) => {
+                if let Some(number) = self.arc_number()? {
+                    self.written_arc_command = Some(number);
+                    cmd.number = number;
+                }
+            }
+            Number::Integer(17) => self.plane = Plane::XY,
+            Number::Integer(18) => self.plane = Plane::ZX,
+            Number::Integer(19) => self.plane = Plane::YZ,
+            Number::Decimal(90, 1) => self.arc_distance_mode = DistanceMode::Absolute,
+            Number::Decimal(91, 1) => self.arc_distance_mode = DistanceMode::Incremental,
+            Number::Integer(28) | Number::Integer(30) => {
+                // the axis words are a point to go through on the way home
+                if has_axis_words(&cmd.args) {
+                    let mode = self.modal.distance_mode;
+                    cmd.args = self.straight_move(cmd.args, start, mode)?;
+                }
+                return Ok(cmd);
+            }
+            Number::Integer(92) => {
+                // the current position becomes the axis words
+                cmd.args = self.straight_move(cmd.args, start, DistanceMode::Absolute)?;
+                return Ok(cmd);
+            }
+            Number::Decimal(92, _) |
+            Number::Integer(10) |
+            Number::Decimal(33, 1) |
+            Number::Decimal(43, 1) |
+            Number::Integer(76) => return Ok(cmd),
+            // anything else (G43, G54, etc.) leaves its axis words to the
+            // current motion command
+            _ => {}
+        }
+
+        if self.modal.in_machine_coordinates(cmd.span) {
+            return Ok(cmd);
+        }
+
+        cmd.args = self.modal_move(cmd.args, start)?;
+        Ok(cmd)
+    }
+
+    /// The arc command to use for the current motion, taking mirroring
+    /// into account.
+    fn arc_number(&self) -> Result<Option<Number>> {
+        let original = match self.modal.motion {
+            Some(n @ Number::Integer(2)) | Some(n @ Number::Integer(3)) => n,
+            _ => return Ok(None),
+        };
+
+        let (flip, _) = self.transform.arc_changes(self.plane)?;
+        let swapped = match original {
+            Number::Integer(2) => Number::Integer(3),
+            _ => Number::Integer(2),
+        };
+
+        Ok(Some(if flip { swapped } else { original }))
+    }
+
+    fn modal_move(&mut self, args: Args, start: [Option<f32>; 3]) -> Result<Args> {
+        if !has_axis_words(&args) {
+            return Ok(args);
+        }
+
+        match self.modal.motion {
+            Some(Number::Integer(2)) | Some(Number::Integer(3)) => self.arc(args, start),
+            Some(Number::Integer(73)) | Some(Number::Integer(81..=89)) => self.cycle(args, start),
+            Some(_) => self.straight_move(args, start, self.modal.distance_mode),
+            None => {
+                Err(Error::InvalidCommand("Axis words were provided without an active motion mode"))
+            }
+        }
+    }
+
+    /// Rewrite the axis words of a move from `start`.
+    fn straight_move(&self,
+                     mut args: Args,
+                     start: [Option<f32>; 3],
+                     mode: DistanceMode)
+                     -> Result<Args> {
+        let words = [args.x, args.y, args.z];
+        let specified = [words[0].is_some(), words[1].is_some(), words[2].is_some()];
+        let emit = self.transform.affected(specified);
+
+        let new_values = match mode {
+            DistanceMode::Absolute => {
+                let mut target = start;
+                for (target, &word) in target.iter_mut().zip(&words) {
+                    if word.is_some() {
+                        *target = word;
+                    }
+                }
+
+                self.transform.apply_partial(target, true)
+            }
+            DistanceMode::Incremental => {
+                let delta = [Some(words[0].unwrap_or(0.0)),
+                             Some(words[1].unwrap_or(0.0)),
+                             Some(words[2].unwrap_or(0.0))];
+
+                self.transform.apply_partial(delta, false)
+            }
+        };
+
+        let mut output = [None; 3];
+        for i in 0..3 {
+            if emit[i] {
+                match new_values[i] {
+                    Some(value) => output[i] = Some(value),
+                    None => {
+                        return Err(Error::InvalidCommand("Can't transform a move from an unknown \
+                                                          position"))
+                    }
+                }
+            }
+        }
+
+        args.x = output[0];
+        args.y = output[1];
+        args.z = output[2];
+        Ok(args)
+    }
+
+    fn arc(&self, args: Args, start: [Option<f32>; 3]) -> Result<Args> {
+        let (_, scale) = self.transform.arc_changes(self.plane)?;
+        let mut args = self.straight_move(args, start, self.modal.distance_mode)?;
+
+        if let Some(r) = args.r {
+            args.r = Some(r * scale);
+            return Ok(args);
+        }
+
+        let words = [args.i, args.j, args.k];
+        let centre = match self.arc_distance_mode {
+            DistanceMode::Incremental => {
+                let offset = [Some(words[0].unwrap_or(0.0)),
+                              Some(words[1].unwrap_or(0.0)),
+                              Some(words[2].unwrap_or(0.0))];
+                self.transform.apply_partial(offset, false)
+            }
+            DistanceMode::Absolute => {
+                let mut centre = start;
+                for (centre, &word) in centre.iter_mut().zip(&words) {
+                    if word.is_some() {
+                        *centre = word;
+                    }
+                }
+                self.transform.apply_partial(centre, true)
+            }
+        };
+
+        // only write the centre's in-plane coordinates
+        let in_plane = self.plane.join(1.0, 1.0, 0.0);
+        let in_plane = [in_plane.x != 0.0, in_plane.y != 0.0, in_plane.z != 0.0];
+        let mut output = [None; 3];
+
+        for i in 0..3 {
+            if in_plane[i] {
+                match centre[i] {
+                    Some(value) => output[i] = Some(value),
+                    None => {
+                        return Err(Error::InvalidCommand("Can't transform an arc from an unknown \
+                                                          position"))
+                    }
+                }
+            }
+        }
+
+        args.i = output[0];
+        args.j = output[1];
+        args.k = output[2];
+        Ok(args)
+    }
+
+    /// Rewrite a canned cycle. The hole is moved like any other point, but
+    /// the retract plane (`R`) is a level along the plane's normal and the
+    /// peck depth (`Q`) is always a positive distance.
+    fn cycle(&mut self, args: Args, start: [Option<f32>; 3]) -> Result<Args> {
+        let (scale, offset) = self.transform.cycle_changes(self.plane)?;
+        let mut args = self.straight_move(args, start, self.modal.distance_mode)?;
+
+        if let Some(r) = args.r {
+            args.r = Some(match self.modal.distance_mode {
+                              DistanceMode::Absolute => r * scale + offset,
+                              DistanceMode::Incremental => r * scale,
+                          });
+        }
+        if let Some(q) = args.q {
+            args.q = Some(q * scale);
+        }
+
+        // the tool goes back to either the R plane or where it started
+        let normal = self.plane.join(0.0, 0.0, 1.0);
+        let normal = [normal.x, normal.y, normal.z];
+        for (position, &n) in self.modal.position.iter_mut().zip(&normal) {
+            if n != 0.0 {
+                *position = None;
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+impl<I> Iterator for Transformer<I>
+    where I: Iterator<Item = Line>
+{
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(self.transform_line(line))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use std::string::String;
+    use writer::write_lines;
+    use helpers::parse;
+
+    fn transformed(src: &str, transform: Transform) -> Result<String> {
+        let lines = parse(src).into_iter();
+        let lines = Transformer::new(lines, transform).collect::<Result<Vec<_>>>()?;
+
+        let mut written = String::new();
+        write_lines(&lines, &mut written).unwrap();
+        Ok(written)
+    }
+
+    #[test]
+    fn translate_a_program() {
+        let t = Transform::translate(Point::new(10.0, -5.0, 1.0));
+        let got = transformed("G0 X0 Y0 Z5\nG1 Z-1 F100\nX20\nG2 X30 Y10 I0 J10", t).unwrap();
+
+        assert_eq!(got, "G0 X10 Y-5 Z6\nG1 Z0 F100\nX30\nG2 X40 Y5 I0 J10\n");
+    }
+
+    #[test]
+    fn rotate_a_quarter_turn() {
+        let t = Transform::rotate(Plane::XY, 90.0, Point::new(10.0, 0.0, 0.0));
+        let got = transformed("G0 X0 Y0 Z5\nG1 X20 F100\nZ-1\nG3 X20 Y10 I0 J5", t).unwrap();
+
+        // moving along X becomes moving along Y, so X isn't needed
+        assert_eq!(got, "G0 X10 Y-10 Z5\nG1 Y10 F100\nZ-1\nG3 X0 Y10 I-5 J0\n");
+    }
+
+    #[test]
+    fn scaling_changes_radii() {
+        let t = Transform::scale(2.0, Point::default());
+        let got = transformed("G0 X1 Y1\nG2 X3 Y1 R1 F100\nG1 Z-0.5", t).unwrap();
+
+        assert_eq!(got, "G0 X2 Y2\nG2 X6 Y2 R2 F100\nG1 Z-1\n");
+    }
+
+    #[test]
+    fn mirroring_reverses_arcs() {
+        let t = Transform::mirror(Axis::Y, 5.0);
+        let got = transformed("G0 X0 Y0 Z0\nG2 X10 Y0 I5 J0 F100\nG3 X0 Y0 I-5 J0", t).unwrap();
+
+        assert_eq!(got, "G0 X0 Y10 Z0\nG3 X10 Y10 I5 J0 F100\nG2 X0 Y10 I-5 J0\n");
+    }
+
+    #[test]
+    fn changing_plane_can_change_the_arc_direction() {
+        // mirroring along Y only affects arcs in the XY and YZ planes
+        let t = Transform::mirror(Axis::Y, 0.0);
+        let got = transformed("G0 X0 Y0 Z0\nG18 G2 X10 Z0 I5 K0 F100\nG17\nX0 Y0 I-5 J0", t)
+            .unwrap();
+
+        assert_eq!(got, "G0 X0 Y0 Z0\nG18 G2 X10 Z0 I5 K0 F100\nG17\nG3 X0 Y0 I-5 J0\n");
+    }
+
+    #[test]
+    fn incremental_moves_only_use_the_linear_part() {
+        let t = Transform::rotate(Plane::XY, 90.0, Point::default())
+            .then(&Transform::translate(Point::new(100.0, 0.0, 0.0)));
+        let got = transformed("G91 G1 X5 F100\nY2\nG90 X0", t).unwrap();
+
+        // we never knew where Y was, but X only affects Y
+        assert_eq!(got, "G91 G1 Y5 F100\nX-2\nG90 Y0\n");
+
+        let got = transformed("G0 X0 Y0\nG91 G1 X5 Y5 F100\nG90 Y2", t).unwrap();
+        assert_eq!(got, "G0 X100 Y0\nG91 G1 X-5 Y5 F100\nG90 X98\n");
+    }
+
+    #[test]
+    fn absolute_arc_centres() {
+        let t = Transform::translate(Point::new(1.0, 2.0, 3.0));
+        let got = transformed("G90.1 G0 X0 Y0\nG2 X10 Y0 I5", t).unwrap();
+
+        assert_eq!(got, "G90.1 G0 X1 Y2\nG2 X11 Y2 I6 J2\n");
+    }
+
+    #[test]
+    fn moves_from_an_unknown_position() {
+        let t = Transform::rotate(Plane::XY, 45.0, Point::default());
+        let got = transformed("G0 X10", t);
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn arcs_cant_leave_their_plane() {
+        let t = Transform::rotate(Plane::YZ, 90.0, Point::default());
+        let got = transformed("G0 X0 Y0 Z0\nG2 X10 Y0 I5 J0 F100", t);
+
+        assert_eq!(got,
+                   Err(Error::InvalidCommand("Arcs can't be rotated out of their plane")));
+    }
+
+    #[test]
+    fn machine_coordinates_are_left_alone() {
+        let t = Transform::translate(Point::new(10.0, 10.0, 10.0));
+        let got = transformed("G0 X0 Y0 Z0\nG53 G0 Z0\nX5", t).unwrap();
+
+        assert_eq!(got, "G0 X10 Y10 Z10\nG53 G0 Z0\nX15\n");
+    }
+
+    #[test]
+    fn other_codes_use_the_current_motion() {
+        let t = Transform::translate(Point::new(10.0, 10.0, 10.0));
+        let got = transformed("G0 G43 Z15 H1\nG0 G54 X1 Y1\nG1 G91 X1 F100", t).unwrap();
+
+        assert_eq!(got, "G0 G43 Z25 H1\nG0 G54 X11 Y11\nG1 G91 X1 F100\n");
+    }
+
+    #[test]
+    fn axis_words_need_a_motion_mode() {
+        let t = Transform::translate(Point::new(10.0, 10.0, 10.0));
+        let got = transformed("G54 X1 Y1", t);
+
+        assert_eq!(got,
+                   Err(Error::InvalidCommand("Axis words were provided without an active motion \
+                                              mode")));
+    }
+
+    #[test]
+    fn canned_cycles() {
+        let t = Transform::rotate(Plane::XY, 90.0, Point::default())
+            .then(&Transform::scale(2.0, Point::default()))
+            .then(&Transform::translate(Point::new(0.0, 0.0, 1.0)));
+        let src = "G0 X0 Y0 Z5\nG83 X10 Y0 Z-5 R1 Q2 F100\nX20\nG80\nG0 X0";
+        let got = transformed(src, t).unwrap();
+
+        assert_eq!(got,
+                   "G0 X0 Y0 Z11\nG83 X0 Y20 Z-9 R3 Q4 F100\nY40\nG80\nG0 Y0\n");
+    }
+
+    #[test]
+    fn canned_cycles_cant_be_flipped() {
+        let t = Transform::mirror(Axis::Z, 0.0);
+        let got = transformed("G0 X0 Y0 Z5\nG81 X10 Y0 Z-5 R1 F100", t);
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn transform_a_whole_program() {
+        let src = include_str!("../tests/data/program_1.gcode");
+        let t = Transform::translate(Point::new(100.0, 50.0, 0.0));
+        let got = transformed(src, t).unwrap();
+
+        assert_eq!(got,
+                   "O1000\nT1 M6\nG0 G90 G40 G21 G17 G94 G80\nG54 X25 Y-25 S500 M3\n\
+                    G43 Z100 H1\nG1 Z5\nG1 Z-20 F100\nG1 X60\nG1 Y90 M8\nG1 X140\n\
+                    G1 Y10\nG1 X25\nG1 Y-25\nG0 Z100\nM30\n");
+    }
+}
@@ -0,0 +1,872 @@
+//! Reordering a program to spend less time moving between cuts.
+//!
+//! Plotting, laser cutting and drilling programs are usually made of lots
+//! of independent toolpaths (e.g. a letter, a shape or a hole) where the
+//! order they're done in doesn't matter, but the time spent rapiding between
+//! them does. The `TravelOptimiser` splits a program into groups which start
+//! when the tool goes down (or the laser is switched on) and end when it
+//! comes back up. It then reorders them using a nearest neighbour search
+//! followed by 2-opt, optionally cutting groups backwards, and writes new
+//! rapids between them.
+//!
+//! Anything other than moves happening between groups (e.g. a tool change)
+//! acts as a barrier, and groups are never moved across it. The program must
+//! use absolute coordinates.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::travel::{TravelOptimiser, Split};
+//! use gcode::writer::write_lines;
+//!
+//! // drawing three short lines, in a silly order
+//! let src = "G0 X50 Y0 Z1\nG1 Z-1 F100\nX60\nG0 Z1\n\
+//!            G0 X0 Y0\nG1 Z-1\nX10\nG0 Z1\n\
+//!            G0 X30 Y0\nG1 Z-1\nX20\nG0 Z1\nM30";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let optimised = TravelOptimiser::new(Split::Retract(0.0)).optimise(&lines).unwrap();
+//!
+//! let mut written = String::new();
+//! write_lines(&optimised.lines, &mut written).unwrap();
+//! // the last line gets drawn backwards
+//! let should_be = "G0 Z1\nG0 X0 Y0\nG1 Z-1 F100\nX10\nG0 Z1\n\
+//!                  G0 X20 Y0\nG1 Z-1\nX30\nG0 Z1\n\
+//!                  G0 X50 Y0\nG1 Z-1 F100\nX60\nG0 Z1\nM30\n";
+//! assert_eq!(written, should_be);
+//! assert!(optimised.rapid_after < optimised.rapid_before);
+//! ```
+
+use std::vec::Vec;
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use interpreter::Point;
+use helpers::{Output, has_axis_words, distance};
+use errors::*;
+
+
+/// The most times 2-opt goes over a section looking for improvements.
+const MAX_PASSES: usize = 20;
+
+
+/// How to tell when the tool is cutting.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Split {
+    /// The tool is cutting whenever it's below this Z height.
+    Retract(f32),
+    /// The tool is cutting while the spindle or laser is on (between `M3`
+    /// or `M4` and `M5`).
+    Laser,
+}
+
+
+/// Settings for reordering a program's toolpaths.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TravelOptimiser {
+    /// How to split the program into groups.
+    pub split: Split,
+    /// Allow groups made only of straight moves to be cut backwards.
+    pub reverse: bool,
+    /// Where the tool is when the program starts.
+    pub start: Point,
+}
+
+impl TravelOptimiser {
+    /// Create a new `TravelOptimiser` which may reverse groups, starting at
+    /// the origin.
+    pub fn new(split: Split) -> TravelOptimiser {
+        TravelOptimiser {
+            split,
+            reverse: true,
+            start: Point::default(),
+        }
+    }
+
+    /// Reorder a program.
+    pub fn optimise(&self, lines: &[Line]) -> Result<Optimised> {
+        let mut tracker = Tracker::new(self.start);
+        let mut program = Program::new();
+        let mut section = Section::new(self.start);
+        let mut group: Option<Group> = None;
+        let mut travel = Vec::new();
+
+        for &line in lines {
+            let was_cutting = self.cutting(&tracker);
+            let info = tracker.step(&line)?;
+            let cutting = self.cutting(&tracker);
+
+            if let Some(mut g) = group.take() {
+                g.lines.push((line, info));
+                if cutting {
+                    group = Some(g);
+                } else {
+                    g.finished = true;
+                    section.groups.push(g);
+                }
+            } else if !was_cutting && cutting {
+                travel.clear();
+                group = Some(Group::new(line, info));
+            } else if info.is_cycle() {
+                travel.clear();
+                let mut g = Group::new(line, info);
+                g.finished = true;
+                section.groups.push(g);
+            } else if is_travel(&line, &info) {
+                travel.push((line, info));
+            } else {
+                // a barrier, so do the groups we've got so far
+                self.write_section(&mut program, section);
+                for (line, info) in travel.drain(..) {
+                    program.copy(line, &info);
+                }
+                program.copy(line, &info);
+                section = Section::new(program.position);
+            }
+        }
+
+        section.groups.extend(group.take());
+        self.write_section(&mut program, section);
+        for (line, info) in travel.drain(..) {
+            program.copy(line, &info);
+        }
+
+        let rapid_before = rapid_distance(lines, self.start)?;
+        let rapid_after = rapid_distance(&program.out.lines, self.start)?;
+
+        Ok(Optimised {
+               lines: program.out.lines,
+               rapid_before,
+               rapid_after,
+           })
+    }
+
+    fn cutting(&self, tracker: &Tracker) -> bool {
+        match self.split {
+            Split::Retract(height) => tracker.z_known && tracker.position.z < height,
+            Split::Laser => tracker.laser_on,
+        }
+    }
+
+    /// Order a section's groups and write them out.
+    fn write_section(&self, program: &mut Program, section: Section) {
+        let order = self.order(&section);
+
+        for (index, reversed) in order {
+            let group = &section.groups[index];
+            program.write_group(group, reversed, self.split);
+        }
+    }
+
+    /// Find a good order (and direction) for the groups.
+    fn order(&self, section: &Section) -> Vec<(usize, bool)> {
+        let groups = &section.groups;
+        let can_reverse = |i: usize| self.reverse && groups[i].reversible(self.split);
+        let mut order = Vec::with_capacity(groups.len());
+        let mut visited = vec![false; groups.len()];
+        let mut position = section.start;
+
+        // nearest neighbour
+        for _ in 0..groups.len() {
+            let mut best: Option<(f32, usize, bool)> = None;
+
+            for (i, group) in groups.iter().enumerate() {
+                if visited[i] {
+                    continue;
+                }
+
+                let mut candidates = vec![(xy_distance(position, group.entry()), false)];
+                if can_reverse(i) {
+                    candidates.push((xy_distance(position, group.exit()), true));
+                }
+
+                for (d, reversed) in candidates {
+                    let closer = match best {
+                        Some((best, _, _)) => d < best,
+                        None => true,
+                    };
+                    if closer {
+                        best = Some((d, i, reversed));
+                    }
+                }
+            }
+
+            let (_, i, reversed) = best.expect("There is always an unvisited group");
+            visited[i] = true;
+            position = groups[i].end(reversed);
+            order.push((i, reversed));
+        }
+
+        // then 2-opt, reversing runs of groups. Only the travel around and
+        // inside the run changes, and that can be kept up to date as the run
+        // gets longer, so each candidate is checked in constant time.
+        let start = |(i, reversed): (usize, bool)| groups[i].start(reversed);
+        let end = |(i, reversed): (usize, bool)| groups[i].end(reversed);
+        let flip = |(i, reversed): (usize, bool)| (i, reversed != can_reverse(i));
+
+        for _ in 0..MAX_PASSES {
+            let mut improved = false;
+
+            for i in 0..order.len() {
+                let before = if i == 0 { section.start } else { end(order[i - 1]) };
+                let mut inside = 0.0;
+                let mut inside_reversed = 0.0;
+
+                for j in i..order.len() {
+                    if j > i {
+                        inside += xy_distance(end(order[j - 1]), start(order[j]));
+                        inside_reversed += xy_distance(end(flip(order[j])),
+                                                       start(flip(order[j - 1])));
+                    }
+
+                    let (first, last) = (order[i], order[j]);
+                    let mut current = xy_distance(before, start(first)) + inside;
+                    let mut candidate = xy_distance(before, start(flip(last))) + inside_reversed;
+                    if let Some(&after) = order.get(j + 1) {
+                        current += xy_distance(end(last), start(after));
+                        candidate += xy_distance(end(flip(first)), start(after));
+                    }
+
+                    if candidate < current - 1e-3 {
+                        order[i..j + 1].reverse();
+                        for item in &mut order[i..j + 1] {
+                            *item = flip(*item);
+                        }
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+
+            if !improved {
+                break;
+            }
+        }
+
+        order
+    }
+}
+
+
+/// A reordered program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimised {
+    /// The new program.
+    pub lines: Vec<Line>,
+    /// How far the original program moved at rapid, including moves between
+    /// holes in canned cycles.
+    pub rapid_before: f32,
+    /// How far the new program moves at rapid.
+    pub rapid_after: f32,
+}
+
+
+/// What a line did.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Info {
+    start: Point,
+    end: Point,
+    /// The motion command used, if the line is a move.
+    motion: Option<Number>,
+    feed_rate: Option<f32>,
+    spindle_speed: Option<f32>,
+    /// The sticky arguments used by canned cycles.
+    cycle_args: Args,
+}
+
+impl Info {
+    fn is_cycle(&self) -> bool {
+        match self.motion {
+            Some(Number::Integer(n)) => is_cycle(n),
+            _ => false,
+        }
+    }
+
+    fn is_rapid(&self) -> bool {
+        self.motion == Some(Number::Integer(0)) || self.is_cycle()
+    }
+}
+
+
+/// Just enough of an interpreter to follow the tool around.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Tracker {
+    position: Point,
+    z_known: bool,
+    motion: Option<Number>,
+    feed_rate: Option<f32>,
+    spindle_speed: Option<f32>,
+    laser_on: bool,
+    cycle_args: Args,
+}
+
+impl Tracker {
+    fn new(start: Point) -> Tracker {
+        Tracker {
+            position: start,
+            z_known: false,
+            motion: None,
+            feed_rate: None,
+            spindle_speed: None,
+            laser_on: false,
+            cycle_args: Args::default(),
+        }
+    }
+
+    fn step(&mut self, line: &Line) -> Result<Info> {
+        let start = self.position;
+        let mut is_move = false;
+
+        let args = match *line {
+            Line::ProgramNumber(_) => None,
+            Line::Args { ref args, .. } => {
+                is_move = has_axis_words(args) && self.motion.is_some();
+                Some(args)
+            }
+            Line::Cmd(ref cmd) => {
+                match (cmd.kind, cmd.number) {
+                    (CommandKind::G, Number::Integer(91)) => {
+                        return Err(Error::InvalidCommand("Only programs using absolute \
+                                                          coordinates can be reordered"))
+                    }
+                    (CommandKind::G, Number::Integer(n)) if n <= 3 || is_cycle(n) => {
+                        self.motion = Some(cmd.number);
+                        is_move = has_axis_words(&cmd.args);
+
+                        if is_cycle(n) {
+                            self.cycle_args = Args {
+                                z: cmd.args.z.or(self.cycle_args.z),
+                                r: cmd.args.r.or(self.cycle_args.r),
+                                q: cmd.args.q.or(self.cycle_args.q),
+                                p: cmd.args.p.or(self.cycle_args.p),
+                                ..Default::default()
+                            };
+                        }
+                    }
+                    (CommandKind::G, Number::Integer(80)) => self.motion = None,
+                    (CommandKind::M, Number::Integer(3)) |
+                    (CommandKind::M, Number::Integer(4)) => self.laser_on = true,
+                    (CommandKind::M, Number::Integer(5)) => self.laser_on = false,
+                    _ => {}
+                }
+                Some(&cmd.args)
+            }
+        };
+
+        if let Some(args) = args {
+            if args.f.is_some() {
+                self.feed_rate = args.f;
+            }
+            if args.s.is_some() {
+                self.spindle_speed = args.s;
+            }
+
+            if is_move {
+                let cycle = match self.motion {
+                    Some(Number::Integer(n)) => is_cycle(n),
+                    _ => false,
+                };
+
+                self.position.x = args.x.unwrap_or(self.position.x);
+                self.position.y = args.y.unwrap_or(self.position.y);
+                // canned cycles finish back where they started
+                if !cycle {
+                    if let Some(z) = args.z {
+                        self.position.z = z;
+                        self.z_known = true;
+                    }
+                }
+            }
+        }
+
+        Ok(Info {
+               start,
+               end: self.position,
+               motion: if is_move { self.motion } else { None },
+               feed_rate: self.feed_rate,
+               spindle_speed: self.spindle_speed,
+               cycle_args: self.cycle_args,
+           })
+    }
+}
+
+
+/// A toolpath which must be cut in one go.
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    lines: Vec<(Line, Info)>,
+    /// Did the tool come back up again?
+    finished: bool,
+}
+
+impl Group {
+    fn new(line: Line, info: Info) -> Group {
+        Group {
+            lines: vec![(line, info)],
+            finished: false,
+        }
+    }
+
+    fn entry(&self) -> Point {
+        let first = &self.lines[0].1;
+        // canned cycles move to the hole and drill it in one go
+        if first.is_cycle() { first.end } else { first.start }
+    }
+
+    fn exit(&self) -> Point {
+        self.lines[self.lines.len() - 1].1.end
+    }
+
+    fn start(&self, reversed: bool) -> Point {
+        if reversed { self.exit() } else { self.entry() }
+    }
+
+    fn end(&self, reversed: bool) -> Point {
+        if reversed { self.entry() } else { self.exit() }
+    }
+
+    /// The straight moves which would need to be reversed.
+    fn moves(&self, split: Split) -> &[(Line, Info)] {
+        match split {
+            Split::Retract(_) => &self.lines,
+            // skip the M3 and M5
+            Split::Laser => &self.lines[1..self.lines.len() - 1],
+        }
+    }
+
+    fn reversible(&self, split: Split) -> bool {
+        if !self.finished || self.lines[0].1.is_cycle() || self.lines.len() < 2 {
+            return false;
+        }
+
+        self.moves(split).iter().all(|(line, info)| {
+            let straight = info.motion == Some(Number::Integer(0)) ||
+                           info.motion == Some(Number::Integer(1));
+            straight && is_travel(line, info)
+        })
+    }
+}
+
+
+/// The groups between two barriers.
+#[derive(Debug, Clone, PartialEq)]
+struct Section {
+    start: Point,
+    groups: Vec<Group>,
+}
+
+impl Section {
+    fn new(start: Point) -> Section {
+        Section {
+            start,
+            groups: Vec::new(),
+        }
+    }
+}
+
+
+/// The new program, making sure every line still uses the motion command,
+/// feed rate and spindle speed it did originally.
+#[derive(Debug)]
+struct Program {
+    out: Output,
+    motion: Option<Number>,
+    feed_rate: Option<f32>,
+    spindle_speed: Option<f32>,
+    /// Where the tool is, with NaN for unknown coordinates.
+    position: Point,
+}
+
+impl Program {
+    fn new() -> Program {
+        Program {
+            out: Output::default(),
+            motion: None,
+            feed_rate: None,
+            spindle_speed: None,
+            position: Point::new(f32::NAN, f32::NAN, f32::NAN),
+        }
+    }
+
+    fn copy(&mut self, line: Line, info: &Info) {
+        let line = match (line, info.motion) {
+            (Line::Args { args, line_number, span }, Some(motion)) if self.motion !=
+                                                                       Some(motion) => {
+                let mut args = args;
+                if info.is_cycle() {
+                    args.z = args.z.or(info.cycle_args.z);
+                    args.r = args.r.or(info.cycle_args.r);
+                    args.q = args.q.or(info.cycle_args.q);
+                    args.p = args.p.or(info.cycle_args.p);
+                }
+
+                Line::Cmd(Command {
+                              kind: CommandKind::G,
+                              number: motion,
+                              args,
+                              line_number,
+                              span,
+                          })
+            }
+            (line, _) => line,
+        };
+
+        let line = if info.motion.is_some() && info.motion != Some(Number::Integer(0)) {
+            self.with_feed_rate(line, info.feed_rate)
+        } else {
+            line
+        };
+        let line = self.with_spindle_speed(line, info.spindle_speed);
+
+        self.track(&line, info);
+        self.out.copy(line);
+    }
+
+    /// Add a feed rate to a line if it's needed.
+    fn with_feed_rate(&self, mut line: Line, feed_rate: Option<f32>) -> Line {
+        if feed_rate.is_none() || feed_rate == self.feed_rate {
+            return line;
+        }
+
+        match line {
+            Line::Cmd(Command { ref mut args, .. }) |
+            Line::Args { ref mut args, .. } => {
+                if args.f.is_none() {
+                    args.f = feed_rate;
+                }
+            }
+            Line::ProgramNumber(_) => {}
+        }
+
+        line
+    }
+
+    /// Make sure the spindle (or laser) is turned on at the right speed.
+    fn with_spindle_speed(&self, mut line: Line, spindle_speed: Option<f32>) -> Line {
+        if spindle_speed.is_none() || spindle_speed == self.spindle_speed {
+            return line;
+        }
+
+        if let Line::Cmd(ref mut cmd) = line {
+            let turns_on = cmd.number == Number::Integer(3) || cmd.number == Number::Integer(4);
+            if cmd.kind == CommandKind::M && turns_on && cmd.args.s.is_none() {
+                cmd.args.s = spindle_speed;
+            }
+        }
+
+        line
+    }
+
+    fn track(&mut self, line: &Line, info: &Info) {
+        let args = match *line {
+            Line::Cmd(ref cmd) => {
+                if cmd.kind == CommandKind::G {
+                    if let Number::Integer(n) = cmd.number {
+                        if n <= 3 || is_cycle(n) {
+                            self.motion = Some(cmd.number);
+                        } else if n == 80 {
+                            self.motion = None;
+                        }
+                    }
+                }
+                &cmd.args
+            }
+            Line::Args { ref args, .. } => args,
+            Line::ProgramNumber(_) => return,
+        };
+
+        if args.f.is_some() {
+            self.feed_rate = args.f;
+        }
+        if args.s.is_some() {
+            self.spindle_speed = args.s;
+        }
+        if info.motion.is_some() {
+            self.position = info.end;
+        }
+    }
+
+    /// Rapid over to a point, going up first if we need to.
+    fn travel_to(&mut self, target: Point, xy: bool, z: bool) {
+        let current = self.position;
+        let unknown_xy = current.x.is_nan() || current.y.is_nan();
+        let needs_z = z && (current.z.is_nan() || target.z != current.z);
+        let needs_xy = xy && (unknown_xy || xy_distance(current, target) > 1e-6);
+        // from an unknown height, always go up first
+        let up_first = current.z.is_nan() || target.z > current.z;
+
+        if needs_z && up_first {
+            self.rapid(Args {
+                           z: Some(target.z),
+                           ..Default::default()
+                       });
+        }
+        if needs_xy {
+            self.rapid(Args {
+                           x: Some(target.x),
+                           y: Some(target.y),
+                           ..Default::default()
+                       });
+        }
+        if needs_z && !up_first {
+            self.rapid(Args {
+                           z: Some(target.z),
+                           ..Default::default()
+                       });
+        }
+
+        if xy {
+            self.position.x = target.x;
+            self.position.y = target.y;
+        }
+        if z {
+            self.position.z = target.z;
+        }
+    }
+
+    fn rapid(&mut self, args: Args) {
+        self.out.new_line();
+        self.out.push(CommandKind::G, Number::Integer(0), args);
+        self.motion = Some(Number::Integer(0));
+    }
+
+    /// Add a straight move to a point.
+    fn move_to(&mut self, from: Point, to: Point, motion: Number, feed_rate: Option<f32>) {
+        let mut args = Args::default();
+        if to.x != from.x {
+            args.x = Some(to.x);
+        }
+        if to.y != from.y {
+            args.y = Some(to.y);
+        }
+        if to.z != from.z || args.is_empty() {
+            args.z = Some(to.z);
+        }
+
+        let args = if motion == Number::Integer(1) && feed_rate != self.feed_rate {
+            Args {
+                f: feed_rate,
+                ..args
+            }
+        } else {
+            args
+        };
+
+        self.out.new_line();
+        if self.motion == Some(motion) {
+            self.out.push_args(args);
+        } else {
+            self.out.push(CommandKind::G, motion, args);
+        }
+
+        self.motion = Some(motion);
+        self.feed_rate = args.f.or(self.feed_rate);
+        self.position = to;
+    }
+
+    fn write_group(&mut self, group: &Group, reversed: bool, split: Split) {
+        let cycle = group.lines[0].1.is_cycle();
+        let xy = !cycle;
+        let z = match split {
+            Split::Retract(_) => true,
+            Split::Laser => false,
+        };
+        self.travel_to(group.start(reversed), xy, z);
+
+        if !reversed {
+            for &(line, ref info) in &group.lines {
+                let line = if cycle { with_position(line, info.end) } else { line };
+                self.copy(line, info);
+            }
+            return;
+        }
+
+        let moves = group.moves(split);
+        let mut steps: Vec<(Point, Point, Number, Option<f32>)> = moves.iter()
+            .rev()
+            .map(|(_, info)| {
+                     (info.end,
+                      info.start,
+                      info.motion.unwrap_or(Number::Integer(1)),
+                      info.feed_rate)
+                 })
+            .collect();
+
+        if let Split::Retract(_) = split {
+            // plunge and retract the same way as before
+            let last = steps.len() - 1;
+            let (plunge, retract) = ((steps[last].2, steps[last].3), (steps[0].2, steps[0].3));
+            steps[0].2 = plunge.0;
+            steps[0].3 = plunge.1;
+            steps[last].2 = retract.0;
+            steps[last].3 = retract.1;
+        }
+
+        if let Split::Laser = split {
+            let (line, ref info) = group.lines[0];
+            self.copy(line, info);
+        }
+        for (from, to, motion, feed_rate) in steps {
+            self.move_to(from, to, motion, feed_rate);
+        }
+        if let Split::Laser = split {
+            let (line, ref info) = group.lines[group.lines.len() - 1];
+            self.copy(line, info);
+        }
+    }
+}
+
+
+fn is_cycle(n: u32) -> bool {
+    n == 73 || (81..=89).contains(&n)
+}
+
+/// Is this line just moving the tool around (or setting the feed rate)?
+fn is_travel(line: &Line, info: &Info) -> bool {
+    let args = match *line {
+        Line::Cmd(ref cmd) => {
+            let straight = cmd.number == Number::Integer(0) || cmd.number == Number::Integer(1);
+            if cmd.kind != CommandKind::G || !straight {
+                return false;
+            }
+            &cmd.args
+        }
+        Line::Args { ref args, .. } => args,
+        Line::ProgramNumber(_) => return false,
+    };
+
+    let movement = Args {
+        x: args.x,
+        y: args.y,
+        z: args.z,
+        f: args.f,
+        ..Default::default()
+    };
+    let straight = info.motion.is_none() || info.motion == Some(Number::Integer(0)) ||
+                   info.motion == Some(Number::Integer(1));
+
+    *args == movement && straight
+}
+
+/// Make sure a move says where it goes in X and Y.
+fn with_position(mut line: Line, end: Point) -> Line {
+    match line {
+        Line::Cmd(Command { ref mut args, .. }) |
+        Line::Args { ref mut args, .. } => {
+            args.x = args.x.or(Some(end.x));
+            args.y = args.y.or(Some(end.y));
+        }
+        Line::ProgramNumber(_) => {}
+    }
+
+    line
+}
+
+/// How far apart two points are, ignoring Z.
+fn xy_distance(a: Point, b: Point) -> f32 {
+    distance((a.x, a.y), (b.x, b.y))
+}
+
+/// How far a program moves at rapid.
+fn rapid_distance(lines: &[Line], start: Point) -> Result<f32> {
+    let mut tracker = Tracker::new(start);
+    let mut total = 0.0;
+
+    for line in lines {
+        let info = tracker.step(line)?;
+        if info.is_rapid() {
+            total += (info.end - info.start).length();
+        }
+    }
+
+    Ok(total)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use writer::write_lines;
+    use helpers::{parse, sqrt};
+
+    fn optimise(optimiser: &TravelOptimiser, src: &str) -> Result<(String, f32, f32)> {
+        let lines = parse(src);
+
+        let optimised = optimiser.optimise(&lines)?;
+        let mut written = String::new();
+        write_lines(&optimised.lines, &mut written).unwrap();
+        Ok((written, optimised.rapid_before, optimised.rapid_after))
+    }
+
+    #[test]
+    fn laser_groups_and_barriers() {
+        let src = "G0 X100 Y0\nM3 S500\nG1 X110 F1000\nM5\n\
+                   G0 X20 Y0\nM3\nG1 X10\nM5\n\
+                   T2 M6\n\
+                   G0 X0 Y50\nM3\nG1 Y60\nM5\n\
+                   G0 X0 Y0\nM3\nG1 Y10\nM5";
+        let (got, before, after) = optimise(&TravelOptimiser::new(Split::Laser), src).unwrap();
+
+        // the tool change stops groups from being moved past it
+        // the first group to be cut now needs to set the laser power
+        let should_be = "G0 X10 Y0\nM3 S500\nG1 X20 F1000\nM5\n\
+                         G0 X100 Y0\nM3 S500\nG1 X110 F1000\nM5\n\
+                         T2 M6\n\
+                         G0 X0 Y0\nM3\nG1 Y10\nM5\n\
+                         G0 X0 Y50\nM3\nG1 Y60\nM5\n";
+        assert_eq!(got, should_be);
+        assert_eq!(before, 100.0 + 90.0 + sqrt(10.0 * 10.0 + 50.0 * 50.0) + 60.0);
+        assert_eq!(after, 10.0 + 80.0 + 110.0 + 40.0);
+    }
+
+    #[test]
+    fn groups_can_be_kept_the_right_way_around() {
+        let optimiser = TravelOptimiser {
+            reverse: false,
+            ..TravelOptimiser::new(Split::Laser)
+        };
+        let src = "G0 X20 Y0\nM3\nG1 X10 F100\nM5\nG0 X30\nM3\nG1 X40\nM5";
+        let (got, _, _) = optimise(&optimiser, src).unwrap();
+
+        assert_eq!(got, "G0 X20 Y0\nM3\nG1 X10 F100\nM5\nG0 X30 Y0\nM3\nG1 X40\nM5\n");
+    }
+
+    #[test]
+    fn drilling_holes_in_a_better_order() {
+        let src = "G0 Z5\nG81 X50 Y0 Z-5 R2 F100\nX10\nX40\nX20\nG80\nM30";
+        let (got, before, after) = optimise(&TravelOptimiser::new(Split::Retract(0.0)), src)
+            .unwrap();
+
+        // holes are always given in full once they've been moved around
+        let should_be = "G0 Z5\nG81 X10 Y0 Z-5 R2 F100\nX20 Y0\nX40 Y0\nG81 X50 Y0 Z-5 R2 F100\n\
+                         G80\nM30\n";
+        assert_eq!(got, should_be);
+        assert_eq!(before, 5.0 + 50.0 + 40.0 + 30.0 + 20.0);
+        assert_eq!(after, 5.0 + 10.0 + 10.0 + 20.0 + 10.0);
+    }
+
+    #[test]
+    fn groups_with_arcs_are_never_reversed() {
+        let src = "G0 X0 Y0 Z1\nG1 Z-1 F100\nG2 X10 Y0 I5 J0\nG0 Z1\n\
+                   G0 X30 Y0\nG1 Z-1\nX20\nG0 Z1";
+        let optimiser = TravelOptimiser {
+            start: Point::new(8.0, 0.0, 1.0),
+            ..TravelOptimiser::new(Split::Retract(0.0))
+        };
+        let (got, _, _) = optimise(&optimiser, src).unwrap();
+
+        // only the straight line can be cut backwards
+        let should_be = "G0 Z1\nG0 X0 Y0\nG1 Z-1 F100\nG2 X10 Y0 I5 J0\nG0 Z1\n\
+                         G0 X20 Y0\nG1 Z-1\nX30\nG0 Z1\n";
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn incremental_programs_cant_be_reordered() {
+        let got = optimise(&TravelOptimiser::new(Split::Laser), "G91 G0 X10");
+
+        assert!(got.is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Checking a program stays within what a machine can actually do.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::interpreter::Point;
+//! use gcode::stats::BoundingBox;
+//! use gcode::validate::{Machine, Problem};
+//!
+//! let mut travel = BoundingBox::new(Point::new(0.0, 0.0, -100.0));
+//! travel.add_point(Point::new(300.0, 15.0, 0.0));
+//! let machine = Machine::new(travel);
+//!
+//! // both ends of the arc are inside, but it bulges up to Y=20
+//! let src = "G0 X10 Y10\nG1 Z-5 F100\nG2 X30 Y10 I10 J0";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines = Parser::new(tokens).filter_map(|line| line.ok());
+//!
+//! let violations = machine.validate(lines).unwrap();
+//!
+//! assert_eq!(violations.len(), 1);
+//! assert_eq!(violations[0].span.line, 2);
+//! match violations[0].problem {
+//!     Problem::ArcTravel(bounds) => assert_eq!(bounds.max.y, 20.0),
+//!     ref other => panic!("Unexpected problem: {:?}", other),
+//! }
+//! ```
+
+use std::collections::BTreeSet;
+use std::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+
+use lexer::Span;
+use parser::{Line, CommandKind, Number};
+use interpreter::{Event, Interpreter, Move, MoveKind, Point, State, Profile};
+use stats::{BoundingBox, Code};
+use dialect::Dialect;
+use errors::*;
+
+
+/// A description of a machine's capabilities.
+///
+/// All positions are in machine coordinates and lengths are in millimetres.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Machine {
+    /// How far each axis can travel.
+    pub travel: BoundingBox,
+    /// Where the machine is when the program starts.
+    pub home: Point,
+    /// The fastest the machine can feed, in mm/min.
+    pub max_feed_rate: f32,
+    /// The fastest the spindle can turn, in RPM.
+    pub max_spindle_speed: f32,
+    /// The tools which are available, or `None` if any tool may be used.
+    pub tools: Option<BTreeSet<u32>>,
+    /// The M codes the machine understands, or `None` if they are all
+    /// supported.
+    pub m_codes: Option<BTreeSet<u32>>,
+    /// The kind of machine.
+    pub profile: Profile,
+    /// The flavour of gcode the machine's controller reads, which decides
+    /// the G codes it understands.
+    pub dialect: Dialect,
+}
+
+impl Machine {
+    /// Create a milling machine which starts at the origin, with no limits
+    /// other than its travel.
+    pub fn new(travel: BoundingBox) -> Machine {
+        Machine {
+            travel,
+            home: Point::default(),
+            max_feed_rate: f32::INFINITY,
+            max_spindle_speed: f32::INFINITY,
+            tools: None,
+            m_codes: None,
+            profile: Profile::Mill,
+            dialect: Dialect::Rs274Ngc,
+        }
+    }
+
+    /// Run a program through the `Interpreter` and find everything it does
+    /// which the machine can't, in the order it appears in the source code.
+    pub fn validate<I>(&self, lines: I) -> Result<Vec<Violation>>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut state = State::new(self.profile);
+        state.position = self.home;
+        self.validate_with_state(lines, state)
+    }
+
+    /// Validate a program, starting the `Interpreter` with a particular
+    /// state (e.g. with work offsets or a tool table loaded).
+    pub fn validate_with_state<I>(&self, lines: I, state: State) -> Result<Vec<Violation>>
+        where I: IntoIterator<Item = Line>
+    {
+        let mut from_lines = Vec::new();
+        let mut from_events = Vec::new();
+
+        {
+            let lines = lines.into_iter().inspect(|line| {
+                from_lines.extend(self.check_line(line));
+            });
+
+            for event in Interpreter::with_state(lines, state) {
+                if let Event::Move(ref m) = event? {
+                    self.check_move(m, &mut from_events);
+                }
+            }
+        }
+
+        from_lines.append(&mut from_events);
+        from_lines.sort_by_key(|v| (v.span.line, v.span.column));
+        Ok(from_lines)
+    }
+
+    /// Check a line for commands the machine doesn't support.
+    pub fn check_line(&self, line: &Line) -> Option<Violation> {
+        let cmd = match *line {
+            Line::Cmd(ref cmd) => cmd,
+            _ => return None,
+        };
+
+        let problem = match (cmd.kind, cmd.number) {
+            (CommandKind::T, Number::Integer(tool))
+                if matches!(self.tools, Some(ref tools) if !tools.contains(&tool)) => {
+                Problem::UnknownTool(tool)
+            }
+            (CommandKind::G, number) => {
+                let lathe_only = matches!(number, Number::Integer(7) | Number::Integer(8));
+                let code = Code::new(cmd.kind, number);
+
+                if self.dialect.supports(code) && (!lathe_only || self.profile == Profile::Lathe) {
+                    return None;
+                }
+                Problem::UnsupportedCommand(code)
+            }
+            (CommandKind::M, number) => {
+                let supported = match (number, self.m_codes.as_ref()) {
+                    (_, None) => true,
+                    (Number::Integer(n), Some(codes)) => codes.contains(&n),
+                    (Number::Decimal(..), Some(_)) => false,
+                };
+
+                if supported {
+                    return None;
+                }
+                Problem::UnsupportedCommand(Code::new(cmd.kind, number))
+            }
+            _ => return None,
+        };
+
+        Some(Violation {
+                 problem,
+                 span: cmd.span,
+             })
+    }
+
+    /// Check a single move, adding any problems to `violations`.
+    pub fn check_move(&self, m: &Move, violations: &mut Vec<Violation>) {
+        let mut report = |problem| {
+            violations.push(Violation {
+                                problem,
+                                span: m.span,
+                            })
+        };
+
+        match m.kind {
+            MoveKind::Arc { .. } => {
+                let bounds = BoundingBox::around(m);
+                if !self.travel.contains(bounds.min) || !self.travel.contains(bounds.max) {
+                    report(Problem::ArcTravel(bounds));
+                }
+            }
+            _ => {
+                if !self.travel.contains(m.end) {
+                    report(Problem::Travel(m.end));
+                }
+            }
+        }
+
+        if m.kind != MoveKind::Rapid && m.feed_rate > self.max_feed_rate {
+            report(Problem::FeedRate(m.feed_rate));
+        }
+        if m.spindle_speed > self.max_spindle_speed {
+            report(Problem::SpindleSpeed(m.spindle_speed));
+        }
+    }
+}
+
+
+/// Something a program does which the machine can't.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Violation {
+    /// What went wrong.
+    pub problem: Problem,
+    /// The start of the block which caused it.
+    pub span: Span,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.problem)
+    }
+}
+
+/// The different ways a program can exceed a machine's limits.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Problem {
+    /// A move would finish outside the machine's travel.
+    Travel(Point),
+    /// Part of an arc leaves the machine's travel, even if its end points
+    /// are inside.
+    ArcTravel(BoundingBox),
+    /// A feed move is faster than the machine can go (mm/min).
+    FeedRate(f32),
+    /// The spindle would need to turn faster than it can (RPM).
+    SpindleSpeed(f32),
+    /// A tool which isn't available was selected.
+    UnknownTool(u32),
+    /// A command the machine doesn't support.
+    UnsupportedCommand(Code),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Problem::Travel(p) => {
+                write!(f, "moving to ({}, {}, {}) exceeds the machine's travel", p.x, p.y, p.z)
+            }
+            Problem::ArcTravel(bounds) => {
+                write!(f,
+                       "the arc between ({}, {}, {}) and ({}, {}, {}) exceeds the machine's \
+                        travel",
+                       bounds.min.x,
+                       bounds.min.y,
+                       bounds.min.z,
+                       bounds.max.x,
+                       bounds.max.y,
+                       bounds.max.z)
+            }
+            Problem::FeedRate(feed) => write!(f, "the feed rate of {} mm/min is too fast", feed),
+            Problem::SpindleSpeed(rpm) => write!(f, "the spindle speed of {} RPM is too fast", rpm),
+            Problem::UnknownTool(tool) => write!(f, "tool {} isn't available", tool),
+            Problem::UnsupportedCommand(code) => write!(f, "{} isn't supported", code),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use helpers::parse;
+
+    fn machine() -> Machine {
+        let mut travel = BoundingBox::new(Point::new(0.0, 0.0, -50.0));
+        travel.add_point(Point::new(100.0, 100.0, 0.0));
+
+        Machine {
+            home: Point::new(0.0, 0.0, 0.0),
+            max_feed_rate: 1000.0,
+            max_spindle_speed: 10000.0,
+            tools: Some(vec![1, 2].into_iter().collect()),
+            m_codes: Some(vec![3, 5, 6, 30].into_iter().collect()),
+            ..Machine::new(travel)
+        }
+    }
+
+    fn problems(src: &str) -> Vec<(usize, Problem)> {
+        let lines = parse(src);
+
+        machine()
+            .validate(lines)
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.span.line, v.problem))
+            .collect()
+    }
+
+    #[test]
+    fn programs_inside_the_envelope_are_fine() {
+        let src = "T1 M6\nM3 S5000\nG0 X50 Y50\nG1 Z-10 F500\nG2 X50 Y50 I10 J0\nG0 Z0\nM5\nM30";
+
+        assert!(problems(src).is_empty());
+    }
+
+    #[test]
+    fn moves_past_the_travel_limits() {
+        let got = problems("G0 X10 Y10\nG1 X120 F100\nX50\nG0 Z10");
+
+        assert_eq!(got,
+                   vec![(1, Problem::Travel(Point::new(120.0, 10.0, 0.0))),
+                        (3, Problem::Travel(Point::new(50.0, 10.0, 10.0)))]);
+    }
+
+    #[test]
+    fn arcs_are_checked_along_their_whole_length() {
+        let got = problems("G0 X5 Y50\nG2 X15 Y50 I5 J0 F100\nG3 X5 Y50 I-5 J0");
+
+        // the clockwise half-circle stays above Y=50, while the second one
+        // dips below it, both staying inside the envelope
+        assert!(got.is_empty());
+
+        let got = problems("G0 X5 Y50\nG2 X5 Y50 I-10 J0 F100");
+        assert_eq!(got.len(), 1);
+        match got[0] {
+            (1, Problem::ArcTravel(bounds)) => {
+                assert_eq!(bounds.min, Point::new(-15.0, 40.0, 0.0));
+                assert_eq!(bounds.max, Point::new(5.0, 60.0, 0.0));
+            }
+            ref other => panic!("Unexpected problem: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn feeds_and_spindle_speeds_over_the_max() {
+        let got = problems("M3 S12000\nG0 X10\nG1 X20 F2000\nG0 X30");
+
+        assert_eq!(got,
+                   vec![(1, Problem::SpindleSpeed(12000.0)),
+                        (2, Problem::FeedRate(2000.0)),
+                        (2, Problem::SpindleSpeed(12000.0)),
+                        (3, Problem::SpindleSpeed(12000.0))]);
+    }
+
+    #[test]
+    fn unsupported_commands_and_tools() {
+        let got = problems("T3 M6\nM8\nG1 X10 F100\nM30");
+
+        let should_be = vec![(0, Problem::UnknownTool(3)),
+                             (1,
+                              Problem::UnsupportedCommand(Code::new(CommandKind::M,
+                                                                    Number::Integer(8))))];
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn unsupported_g_codes() {
+        let got = problems("G0 X10\nG12\nG7\nG17 G1 X20 F100");
+
+        let g = |n| Problem::UnsupportedCommand(Code::new(CommandKind::G, Number::Integer(n)));
+        assert_eq!(got, vec![(1, g(12)), (2, g(7))]);
+
+        let grbl = Machine {
+            dialect: Dialect::Grbl,
+            ..machine()
+        };
+        let got = grbl.validate(parse("G0 X10\nG64 G1 X20 F100")).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].problem,
+                   Problem::UnsupportedCommand(Code::new(CommandKind::G, Number::Integer(64))));
+    }
+
+    #[test]
+    fn violations_can_be_displayed() {
+        let v = Violation {
+            problem: Problem::UnsupportedCommand(Code::new(CommandKind::M, Number::Integer(8))),
+            span: Span { line: 4, column: 1 },
+        };
+
+        assert_eq!(v.to_string(), "line: 4, column: 1: M8 isn't supported");
+    }
+}
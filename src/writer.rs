@@ -0,0 +1,230 @@
+//! Turning parsed `Line`s back into gcode.
+//!
+//! Each `Line` implements `Display`, writing its words in a fixed order with
+//! at most four decimal places. Use `write_lines()` to write a whole program,
+//! which puts lines that came from the same line of source code back
+//! together.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use gcode::{Tokenizer, Parser};
+//! use gcode::writer::write_lines;
+//!
+//! let src = "N10 G90 G00 X10.0 Y-2.50\nY5 F500";
+//! let tokens = Tokenizer::new(src.chars()).filter_map(|t| t.ok());
+//! let lines: Vec<_> = Parser::new(tokens).filter_map(|line| line.ok()).collect();
+//!
+//! let mut written = String::new();
+//! write_lines(&lines, &mut written).unwrap();
+//!
+//! assert_eq!(written, "N10 G90 G0 X10 Y-2.5\nY5 F500\n");
+//! ```
+
+use core::fmt::{self, Display, Formatter, Write};
+
+use parser::{Line, Command, CommandKind, Number, Args};
+use helpers::Decimals;
+
+
+/// The most decimal places a number is written with.
+pub const PRECISION: usize = 4;
+
+
+/// Write a program, starting a new line whenever the source line changes.
+pub fn write_lines<'a, W, I>(lines: I, w: &mut W) -> fmt::Result
+    where W: Write,
+          I: IntoIterator<Item = &'a Line>
+{
+    let mut current_line = None;
+    let mut first = true;
+
+    for line in lines {
+        let source_line = line.span().map(|s| s.line);
+
+        if !first {
+            if source_line.is_some() && source_line == current_line {
+                w.write_char(' ')?;
+            } else {
+                writeln!(w)?;
+            }
+        }
+
+        write!(w, "{}", line)?;
+        current_line = source_line;
+        first = false;
+    }
+
+    if !first {
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+impl Display for Line {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Line::ProgramNumber(n) => write!(f, "O{}", n),
+            Line::Cmd(ref cmd) => write!(f, "{}", cmd),
+            Line::Args { ref args, line_number, .. } => {
+                if let Some(n) = line_number {
+                    write!(f, "N{}", n)?;
+                    if !args.is_empty() {
+                        f.write_char(' ')?;
+                    }
+                }
+                write!(f, "{}", args)
+            }
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(n) = self.line_number {
+            write!(f, "N{} ", n)?;
+        }
+
+        write!(f, "{}{}", self.kind, self.number)?;
+
+        if !self.args.is_empty() {
+            write!(f, " {}", self.args)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for CommandKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let letter = match *self {
+            CommandKind::G => 'G',
+            CommandKind::M => 'M',
+            CommandKind::T => 'T',
+        };
+
+        f.write_char(letter)
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Number::Integer(n) => write!(f, "{}", n),
+            Number::Decimal(n, fraction) => write!(f, "{}.{}", n, fraction),
+        }
+    }
+}
+
+impl Display for Args {
+    /// Write the arguments separated by spaces, in the order `X`, `Y`, `Z`,
+    /// `I`, `J`, `K`, `R`, `D`, `H`, `L`, `P`, `Q`, `E`, `F`, `S`, then `T`.
+    /// Flags are written as a bare letter in the same place as their value
+    /// would be, and any string argument comes last.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let words = [('X', self.x),
+                     ('Y', self.y),
+                     ('Z', self.z),
+                     ('I', self.i),
+                     ('J', self.j),
+                     ('K', self.k),
+                     ('R', self.r),
+                     ('D', self.d),
+                     ('H', self.h),
+                     ('L', self.l),
+                     ('P', self.p),
+                     ('Q', self.q),
+                     ('E', self.e),
+                     ('F', self.f),
+                     ('S', self.s),
+                     ('T', self.t)];
+
+        let mut first = true;
+        for &(letter, value) in &words {
+            let flag = self.flags.contains(letter);
+            if value.is_none() && !flag {
+                continue;
+            }
+
+            if !first {
+                f.write_char(' ')?;
+            }
+            match value {
+                Some(value) => write!(f, "{}{}", letter, Decimals(value, PRECISION))?,
+                None => f.write_char(letter)?,
+            }
+            first = false;
+        }
+
+        if let Some(text) = self.text {
+            if !first {
+                f.write_char(' ')?;
+            }
+            f.write_str(text.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::{String, ToString};
+    use dialect::Dialect;
+    use helpers::{parse, parse_dialect};
+
+    fn rewrite(src: &str) -> String {
+        let lines = parse(src);
+
+        let mut written = String::new();
+        write_lines(&lines, &mut written).unwrap();
+        written
+    }
+
+    #[test]
+    fn words_are_written_in_a_fixed_order() {
+        let got = rewrite("G01 F100 Z-1.5 Y2 X1.00000\nM03 S1000\nT2 M6");
+
+        assert_eq!(got, "G1 X1 Y2 Z-1.5 F100\nM3 S1000\nT2 M6\n");
+    }
+
+    #[test]
+    fn written_programs_parse_to_the_same_thing() {
+        let src = "O100\nN10 G90.1 G17\nN20 G2 X1.25 Y-3 I0.5 J0.5 F200\nN30\nX5 R2.5\nG4 P0.25";
+        let first = rewrite(src);
+
+        assert_eq!(first,
+                   "O100\nN10 G90.1 G17\nN20 G2 X1.25 Y-3 I0.5 J0.5 F200\nN30\nX5 R2.5\nG4 P0.25\n");
+        assert_eq!(rewrite(&first), first);
+    }
+
+    #[test]
+    fn flags_and_strings_survive_a_round_trip() {
+        let src = "G28 X Y\nM117 Layer 2 of 10\nM84 E";
+        let lines = parse_dialect(src, Dialect::Marlin);
+
+        let mut written = String::new();
+        write_lines(&lines, &mut written).unwrap();
+
+        assert_eq!(written, "G28 X Y\nM117 Layer 2 of 10\nM84 E\n");
+    }
+
+    #[test]
+    fn numbers_are_rounded() {
+        let cmd = Command {
+            kind: CommandKind::G,
+            number: Number::Decimal(43, 1),
+            args: Args {
+                z: Some(0.123456),
+                ..Default::default()
+            },
+            line_number: None,
+            span: Default::default(),
+        };
+
+        assert_eq!(cmd.to_string(), "G43.1 Z0.1235");
+    }
+}
@@ -0,0 +1,168 @@
+//! Tests which run the `gcode` binary the same way a user would.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run `gcode` with some arguments, passing `stdin` to it.
+fn gcode(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_gcode"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn check_valid_programs() {
+    let output = gcode(&["check", "tests/data/program_1.gcode", "tests/data/program_2.gcode"], "");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn check_reports_where_errors_are() {
+    let output = gcode(&["check"], "G0 X1\nG1 X$");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "<stdin>:2:5: unknown character '$'\n");
+}
+
+#[test]
+fn interpreter_errors_say_which_line_they_are_on() {
+    let output = gcode(&["check"], "G0 X1\nG1 X2 F100\nG2 X3 Y3");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("<stdin>:3:1: "), "{}", stderr(&output));
+
+    let output = gcode(&["transform", "--translate", "1,1"], "G0 X1\nG54 Y1\nG80\nX2");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output),
+               "<stdin>:4:1: Axis words were provided without an active motion mode\n");
+}
+
+#[test]
+fn check_printer_programs() {
+    let src = "M140 S60\nM104 S210 T0\nG28 X Y\nM117 Printing...\nM83\nG1 X10 E.5 F1800";
+
+    let output = gcode(&["check", "--dialect", "marlin"], src);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn check_grbl_programs() {
+    let src = "$X\n$H\n$J=G91 X10 F500\nG21 G90\nG0 X1 Y1?\nM30";
+
+    let output = gcode(&["check", "--dialect", "grbl"], src);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = gcode(&["check", "--dialect", "grbl"], "G0 X1\n$J=X1");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "<stdin>:2:1: Invalid jog command\n");
+}
+
+#[test]
+fn format_from_stdin() {
+    let output = gcode(&["fmt", "--leading-zeros"], "f100 g1 x1.500 (cut)\nM30");
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "G01 X1.5 F100 (cut)\nM30\n");
+}
+
+#[test]
+fn format_check_fails_on_unformatted_programs() {
+    let output = gcode(&["fmt", "--check"], "G01 X1");
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = gcode(&["fmt", "--check"], "G1 X1\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn lint_with_rules_turned_off() {
+    let src = "G21 G90\nG0 X0\nG1 X10\nM30";
+
+    let output = gcode(&["lint"], src);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output),
+               "<stdin>:3:1: feed move without a feed rate [no-feed-rate]\n\
+                <stdin>:3:1: feed move while the spindle is off [spindle-off]\n");
+
+    let output = gcode(&["lint", "--disable", "no-feed-rate", "--disable", "spindle-off"], src);
+    assert!(output.status.success());
+}
+
+#[test]
+fn transforms_are_applied_in_order() {
+    let output = gcode(&["transform", "--mirror", "x", "--translate", "50,0"],
+                       "G90 G0 X10 Y0\nG2 X0 Y-10 I-10 J0 F100");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "G90 G0 X40 Y0\nG3 X50 Y-10 I10 J0 F100\n");
+}
+
+#[test]
+fn other_commands_produce_output() {
+    for &command in &["stats", "tokens", "ast", "render-svg"] {
+        let output = gcode(&[command], "G0 X10 Y10\nG1 X20 F500");
+
+        assert!(output.status.success(), "{}: {}", command, stderr(&output));
+        assert!(!output.stdout.is_empty(), "{}", command);
+    }
+}
+
+#[test]
+fn every_command_takes_a_dialect() {
+    let src = "G21\nM104 S200\nG28 X Y\nM117 Hello world\nG1 X10 E1 F1200";
+
+    for &command in &["check", "fmt", "stats", "lint", "tokens", "ast", "transform", "render-svg"] {
+        let output = gcode(&[command, "--dialect", "marlin"], src);
+
+        assert!(output.status.success(), "{}: {}", command, stderr(&output));
+    }
+
+    let output = gcode(&["fmt", "--dialect", "marlin"], src);
+    assert_eq!(stdout(&output),
+               "G21\nM104 S200\nG28 X Y\nM117 Hello world\nG1 X10 E1 F1200\n");
+    assert_eq!(gcode(&["fmt"], src).status.code(), Some(1));
+}
+
+#[test]
+fn ast_as_json() {
+    let output = gcode(&["ast", "--json"], "G1 X1");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let json: String = stdout(&output).split_whitespace().collect();
+    assert_eq!(json,
+               "{\"schema_version\":1,\"lines\":[{\"cmd\":{\"kind\":\"G\",\"number\":\
+                {\"integer\":1},\"args\":{\"x\":1.0},\"line_number\":null,\"span\":\
+                {\"line\":0,\"column\":1}}}]}");
+}
+
+#[test]
+fn render_svg_only_takes_one_program() {
+    let output = gcode(&["render-svg", "tests/data/program_1.gcode", "tests/data/program_2.gcode"],
+                       "");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn usage_errors() {
+    assert_eq!(gcode(&["frobnicate"], "").status.code(), Some(2));
+    assert_eq!(gcode(&["fmt", "--decimals"], "").status.code(), Some(2));
+    assert_eq!(gcode(&["lint", "--disable", "no-such-rule"], "").status.code(), Some(2));
+}